@@ -0,0 +1,270 @@
+//! Admission control for incoming connections.
+//!
+//! Connections are counted globally and per IP, and an IP that repeatedly
+//! fails `common_auth` is banned for a while. The limits are read from the
+//! config whenever a connection arrives.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Limits for incoming connections, configurable as `admission`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdmissionConfig {
+    #[serde(rename = "maxConnections")]
+    pub max_connections: usize,
+    #[serde(rename = "maxConnectionsPerIp")]
+    pub max_connections_per_ip: usize,
+    /// Time a client has to complete the TLS handshake
+    #[serde(rename = "handshakeTimeoutSecs")]
+    pub handshake_timeout_secs: u64,
+    /// Authentication failures within `authFailureWindowSecs` that get an IP banned
+    #[serde(rename = "authFailureLimit")]
+    pub auth_failure_limit: u32,
+    #[serde(rename = "authFailureWindowSecs")]
+    pub auth_failure_window_secs: u64,
+    #[serde(rename = "banSecs")]
+    pub ban_secs: u64,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 256,
+            max_connections_per_ip: 32,
+            handshake_timeout_secs: 10,
+            auth_failure_limit: 5,
+            auth_failure_window_secs: 60,
+            ban_secs: 600,
+        }
+    }
+}
+
+impl AdmissionConfig {
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_secs.max(1))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Rejection {
+    #[error("too many connections")]
+    TooManyConnections,
+    #[error("too many connections from {0}")]
+    TooManyConnectionsFromIp(IpAddr),
+    #[error("{0} is banned after repeated authentication failures")]
+    Banned(IpAddr),
+}
+
+#[derive(Default)]
+struct ConnectionCounter {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+struct FailureRecord {
+    count: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Tracks authentication failures and bans IPs that fail too often.
+#[derive(Default)]
+struct PenaltyBox {
+    records: HashMap<IpAddr, FailureRecord>,
+}
+
+impl PenaltyBox {
+    fn is_banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let Some(record) = self.records.get(&ip) else {
+            return false;
+        };
+        match record.banned_until {
+            Some(until) if until > now => true,
+            Some(_) => {
+                self.records.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Records a failure, returns whether the IP is banned from now on.
+    fn record_failure(&mut self, ip: IpAddr, now: Instant, config: &AdmissionConfig) -> bool {
+        let window = Duration::from_secs(config.auth_failure_window_secs);
+        // Forget IPs whose failures are long past, so that the map does not grow forever.
+        self.records.retain(|_, r| match r.banned_until {
+            Some(until) => until > now,
+            None => now.duration_since(r.window_start) < window,
+        });
+        let record = self.records.entry(ip).or_insert(FailureRecord {
+            count: 0,
+            window_start: now,
+            banned_until: None,
+        });
+        if record.banned_until.is_some() {
+            return true;
+        }
+        record.count += 1;
+        if record.count >= config.auth_failure_limit.max(1) {
+            record.banned_until = Some(now + Duration::from_secs(config.ban_secs));
+            return true;
+        }
+        false
+    }
+
+    fn clear(&mut self, ip: IpAddr) {
+        if let Some(record) = self.records.get(&ip)
+            && record.banned_until.is_none()
+        {
+            self.records.remove(&ip);
+        }
+    }
+}
+
+static CONNECTIONS: LazyLock<Mutex<ConnectionCounter>> = LazyLock::new(Default::default);
+static PENALTY_BOX: LazyLock<Mutex<PenaltyBox>> = LazyLock::new(Default::default);
+
+/// Counts a connection until it is dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        release(&mut CONNECTIONS.lock().unwrap(), self.ip);
+    }
+}
+
+fn acquire(
+    counter: &mut ConnectionCounter,
+    ip: IpAddr,
+    config: &AdmissionConfig,
+) -> Result<(), Rejection> {
+    if counter.total >= config.max_connections {
+        return Err(Rejection::TooManyConnections);
+    }
+    let count = counter.per_ip.entry(ip).or_default();
+    if *count >= config.max_connections_per_ip {
+        return Err(Rejection::TooManyConnectionsFromIp(ip));
+    }
+    *count += 1;
+    counter.total += 1;
+    Ok(())
+}
+
+fn release(counter: &mut ConnectionCounter, ip: IpAddr) {
+    counter.total = counter.total.saturating_sub(1);
+    if let Some(count) = counter.per_ip.get_mut(&ip) {
+        *count -= 1;
+        if *count == 0 {
+            counter.per_ip.remove(&ip);
+        }
+    }
+}
+
+/// Admits a new connection from `ip`, the returned permit must live as long as the connection.
+pub fn try_admit(ip: IpAddr) -> Result<ConnectionPermit, Rejection> {
+    let config = crate::config::read_config().admission.clone();
+    if PENALTY_BOX.lock().unwrap().is_banned(ip, Instant::now()) {
+        return Err(Rejection::Banned(ip));
+    }
+    acquire(&mut CONNECTIONS.lock().unwrap(), ip, &config)?;
+    Ok(ConnectionPermit { ip })
+}
+
+/// Records a failed `common_auth` from `ip`.
+pub fn record_auth_failure(ip: IpAddr) {
+    let config = crate::config::read_config().admission.clone();
+    if PENALTY_BOX
+        .lock()
+        .unwrap()
+        .record_failure(ip, Instant::now(), &config)
+    {
+        warn!(
+            "{} failed authentication {} times, banned for {}s",
+            ip, config.auth_failure_limit, config.ban_secs
+        );
+    }
+}
+
+/// Resets the failure count of `ip` after a successful `common_auth`.
+pub fn record_auth_success(ip: IpAddr) {
+    PENALTY_BOX.lock().unwrap().clear(ip);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 168, 1, last])
+    }
+
+    #[test]
+    fn connections_are_limited_globally_and_per_ip() {
+        let config = AdmissionConfig {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            ..Default::default()
+        };
+        let mut counter = ConnectionCounter::default();
+        assert!(acquire(&mut counter, ip(1), &config).is_ok());
+        assert!(acquire(&mut counter, ip(1), &config).is_ok());
+        assert_eq!(
+            acquire(&mut counter, ip(1), &config),
+            Err(Rejection::TooManyConnectionsFromIp(ip(1)))
+        );
+        assert!(acquire(&mut counter, ip(2), &config).is_ok());
+        assert_eq!(
+            acquire(&mut counter, ip(3), &config),
+            Err(Rejection::TooManyConnections)
+        );
+
+        release(&mut counter, ip(1));
+        assert!(acquire(&mut counter, ip(3), &config).is_ok());
+        release(&mut counter, ip(2));
+        assert!(!counter.per_ip.contains_key(&ip(2)));
+    }
+
+    #[test]
+    fn repeated_auth_failures_ban_the_ip() {
+        let config = AdmissionConfig {
+            auth_failure_limit: 3,
+            auth_failure_window_secs: 60,
+            ban_secs: 600,
+            ..Default::default()
+        };
+        let mut penalty = PenaltyBox::default();
+        let start = Instant::now();
+        assert!(!penalty.record_failure(ip(1), start, &config));
+        assert!(!penalty.record_failure(ip(1), start, &config));
+        assert!(penalty.record_failure(ip(1), start, &config));
+        assert!(penalty.is_banned(ip(1), start + Duration::from_secs(599)));
+        assert!(!penalty.is_banned(ip(2), start));
+
+        // A success does not lift a ban
+        penalty.clear(ip(1));
+        assert!(penalty.is_banned(ip(1), start + Duration::from_secs(1)));
+        assert!(!penalty.is_banned(ip(1), start + Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn failures_outside_the_window_are_forgotten() {
+        let config = AdmissionConfig {
+            auth_failure_limit: 2,
+            auth_failure_window_secs: 60,
+            ..Default::default()
+        };
+        let mut penalty = PenaltyBox::default();
+        let start = Instant::now();
+        assert!(!penalty.record_failure(ip(1), start, &config));
+        assert!(!penalty.record_failure(ip(1), start + Duration::from_secs(61), &config));
+        assert!(penalty.record_failure(ip(1), start + Duration::from_secs(62), &config));
+    }
+}
@@ -0,0 +1,437 @@
+//! Directory archives generated and extracted on the fly.
+//!
+//! The archive work is synchronous, so it runs on a blocking thread and
+//! exchanges data with the connection through channels of byte chunks.
+
+use crate::route::protocol::{ArchiveFormat, ConflictPolicy};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Size of the chunks passed between the archive thread and the connection
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// A writer that hands its data to an async task in chunks.
+pub struct ChannelWriter {
+    tx: tokio::sync::mpsc::Sender<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl ChannelWriter {
+    pub fn new(tx: tokio::sync::mpsc::Sender<Vec<u8>>) -> Self {
+        Self {
+            tx,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn send_buf(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        self.tx
+            .blocking_send(chunk)
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_buf()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_buf()
+    }
+}
+
+/// A reader over chunks received from an async task, EOF once the sender is dropped.
+pub struct ChannelReader {
+    rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    pub fn new(rx: tokio::sync::mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos >= self.chunk.len() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Writes `root` (a directory or a single file) as an archive and returns the number of files.
+///
+/// Entry names start with the name of `root`, like the save paths of `send_files`.
+pub fn write_archive<W: Write>(
+    root: &Path,
+    format: ArchiveFormat,
+    compress: bool,
+    writer: W,
+) -> std::io::Result<u64> {
+    let base = root.parent().unwrap_or(root);
+    match (format, compress) {
+        (ArchiveFormat::Tar, false) => write_tar(root, base, writer).map(|(_, n)| n),
+        (ArchiveFormat::Tar, true) => {
+            let encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::fast());
+            let (encoder, n) = write_tar(root, base, encoder)?;
+            encoder.finish()?;
+            Ok(n)
+        }
+        (ArchiveFormat::Zip, compress) => write_zip(root, base, compress, writer),
+    }
+}
+
+fn archive_entries(root: &Path) -> impl Iterator<Item = walkdir::DirEntry> {
+    walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_map(|entry| {
+            entry
+                .inspect_err(|e| warn!("walkdir entry failed, err: {}", e))
+                .ok()
+        })
+        // Links are skipped, they could point anywhere on this machine.
+        .filter(|entry| entry.file_type().is_dir() || entry.file_type().is_file())
+}
+
+fn entry_name(base: &Path, path: &Path) -> Option<String> {
+    crate::utils::relative_slash_path(base, path).filter(|name| !name.is_empty())
+}
+
+fn write_tar<W: Write>(root: &Path, base: &Path, writer: W) -> std::io::Result<(W, u64)> {
+    let mut builder = tar::Builder::new(writer);
+    // The modification times are kept for the `keepNewer` policy of the receiver.
+    builder.mode(tar::HeaderMode::Complete);
+    let mut count = 0;
+    for entry in archive_entries(root) {
+        let Some(name) = entry_name(base, entry.path()) else {
+            continue;
+        };
+        if entry.file_type().is_dir() {
+            builder.append_dir(&name, entry.path())?;
+        } else {
+            builder.append_path_with_name(entry.path(), &name)?;
+            count += 1;
+        }
+    }
+    Ok((builder.into_inner()?, count))
+}
+
+fn write_zip<W: Write>(
+    root: &Path,
+    base: &Path,
+    compress: bool,
+    writer: W,
+) -> std::io::Result<u64> {
+    use zip::write::SimpleFileOptions;
+    let method = if compress {
+        zip::CompressionMethod::Deflated
+    } else {
+        zip::CompressionMethod::Stored
+    };
+    let mut zip = zip::ZipWriter::new_stream(writer);
+    let mut count = 0;
+    for entry in archive_entries(root) {
+        let Some(name) = entry_name(base, entry.path()) else {
+            continue;
+        };
+        let metadata = entry.metadata().map_err(std::io::Error::other)?;
+        let options = SimpleFileOptions::default()
+            .compression_method(method)
+            .large_file(metadata.len() >= u32::MAX as u64);
+        if entry.file_type().is_dir() {
+            zip.add_directory(name, options)
+                .map_err(std::io::Error::other)?;
+        } else {
+            zip.start_file(name, options)
+                .map_err(std::io::Error::other)?;
+            std::io::copy(&mut std::fs::File::open(entry.path())?, &mut zip)?;
+            count += 1;
+        }
+    }
+    zip.finish()
+        .map_err(std::io::Error::other)?
+        .into_inner()
+        .flush()?;
+    Ok(count)
+}
+
+/// Extracts a tar archive into `dest` and returns the number of files.
+///
+/// Only regular files and directories are extracted, entries that would
+/// leave `dest` are skipped. Existing files are handled by `policy`, the
+/// skipped ones are not counted. The reader is drained, so that the caller
+/// can tell the end of the stream from an error.
+pub fn extract_archive<R: Read>(
+    format: ArchiveFormat,
+    compress: bool,
+    mut reader: R,
+    dest: &Path,
+    policy: ConflictPolicy,
+) -> std::io::Result<u64> {
+    if format != ArchiveFormat::Tar {
+        // Streamed zip entries do not record their size before the data.
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "only tar archives can be extracted on the fly",
+        ));
+    }
+    let count = if compress {
+        extract_tar(flate2::read::GzDecoder::new(&mut reader), dest, policy)?
+    } else {
+        extract_tar(&mut reader, dest, policy)?
+    };
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    Ok(count)
+}
+
+fn extract_tar<R: Read>(reader: R, dest: &Path, policy: ConflictPolicy) -> std::io::Result<u64> {
+    let mut archive = tar::Archive::new(reader);
+    let mut count = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        let path = entry.path()?.to_string_lossy().to_string();
+        let target = match crate::utils::join_sanitized(dest, &path) {
+            Ok(target) if target != dest => target,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("skip archive entry: {}", e);
+                continue;
+            }
+        };
+        if entry_type.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            continue;
+        }
+        if !entry_type.is_file() {
+            debug!("skip archive entry {:?} of type {:?}", path, entry_type);
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mod_time = entry.header().mtime().ok();
+        if extract_file(&mut entry, &target, policy, mod_time)? {
+            count += 1;
+        } else {
+            debug!("skip existing file {}", target.display());
+        }
+    }
+    Ok(count)
+}
+
+/// Writes an entry to `target`, an existing file is handled like the files of
+/// `pasteFile` are. Returns false when the entry is skipped.
+///
+/// `mod_time` is the entry's modification time in seconds. Entries carry no
+/// hash, so `skipIfIdentical` compares the extracted file with the existing one.
+fn extract_file(
+    entry: &mut impl Read,
+    target: &Path,
+    policy: ConflictPolicy,
+    mod_time: Option<u64>,
+) -> std::io::Result<bool> {
+    let existing = match std::fs::metadata(target) {
+        Ok(meta) if meta.is_file() => meta,
+        Ok(_) => return write_new(entry, &unique_path(target)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return write_new(entry, target),
+        Err(e) => return Err(e),
+    };
+    match policy {
+        ConflictPolicy::Rename => write_new(entry, &unique_path(target)?),
+        ConflictPolicy::Overwrite => replace_file(entry, target),
+        ConflictPolicy::KeepNewer => {
+            let existing_mod_time = existing
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs());
+            match (mod_time, existing_mod_time) {
+                (Some(incoming), Some(existing)) if incoming <= existing => Ok(false),
+                (Some(_), _) => replace_file(entry, target),
+                (None, _) => write_new(entry, &unique_path(target)?),
+            }
+        }
+        ConflictPolicy::SkipIfIdentical => {
+            let partial = partial_path(target);
+            write_new(entry, &partial)?;
+            let identical = std::fs::metadata(&partial)?.len() == existing.len()
+                && file_sha256(&partial)? == file_sha256(target)?;
+            if identical {
+                std::fs::remove_file(&partial)?;
+                return Ok(false);
+            }
+            std::fs::rename(&partial, unique_path(target)?)?;
+            Ok(true)
+        }
+    }
+}
+
+fn write_new(entry: &mut impl Read, path: &Path) -> std::io::Result<bool> {
+    std::io::copy(entry, &mut std::fs::File::create(path)?)?;
+    Ok(true)
+}
+
+/// Replaces `target` once the entry is written completely.
+fn replace_file(entry: &mut impl Read, target: &Path) -> std::io::Result<bool> {
+    let partial = partial_path(target);
+    if let Err(e) = write_new(entry, &partial) {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
+    }
+    std::fs::rename(&partial, target)?;
+    Ok(true)
+}
+
+fn partial_path(target: &Path) -> PathBuf {
+    PathBuf::from(crate::file::partial_path(&target.to_string_lossy()))
+}
+
+fn unique_path(target: &Path) -> std::io::Result<PathBuf> {
+    crate::utils::generate_unique_filepath(target).map(PathBuf::from)
+}
+
+fn file_sha256(path: &Path) -> std::io::Result<Vec<u8>> {
+    use sha2::Digest;
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = vec![0u8; 256 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("windsend-archive-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn tar_round_trip_keeps_root_name() {
+        let dir = temp_dir("tar");
+        let src = dir.join("photos");
+        std::fs::create_dir_all(src.join("2024").join("empty")).unwrap();
+        std::fs::write(src.join("a.txt"), b"hello").unwrap();
+        std::fs::write(src.join("2024").join("b.txt"), b"world").unwrap();
+
+        for compress in [false, true] {
+            let mut archive = Vec::new();
+            let n = write_archive(&src, ArchiveFormat::Tar, compress, &mut archive).unwrap();
+            assert_eq!(n, 2);
+
+            let dest = dir.join(format!("out-{compress}"));
+            let n = extract_archive(
+                ArchiveFormat::Tar,
+                compress,
+                &archive[..],
+                &dest,
+                ConflictPolicy::Rename,
+            )
+            .unwrap();
+            assert_eq!(n, 2);
+            assert_eq!(std::fs::read(dest.join("photos/a.txt")).unwrap(), b"hello");
+            assert_eq!(
+                std::fs::read(dest.join("photos/2024/b.txt")).unwrap(),
+                b"world"
+            );
+            assert!(dest.join("photos/2024/empty").is_dir());
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn zip_stream_lists_all_entries() {
+        let dir = temp_dir("zip");
+        let src = dir.join("docs");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("sub").join("c.txt"), b"zip me").unwrap();
+
+        let mut archive = Vec::new();
+        write_archive(&src, ArchiveFormat::Zip, true, &mut archive).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(archive)).unwrap();
+        let mut content = String::new();
+        zip.by_name("docs/sub/c.txt")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "zip me");
+        assert!(zip.by_name("docs/sub/").unwrap().is_dir());
+    }
+
+    #[test]
+    fn extraction_follows_the_conflict_policy() {
+        let dir = temp_dir("conflict");
+        let src = dir.join("notes");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("same.txt"), b"same").unwrap();
+        std::fs::write(src.join("changed.txt"), b"new").unwrap();
+        let mut archive = Vec::new();
+        write_archive(&src, ArchiveFormat::Tar, false, &mut archive).unwrap();
+
+        let extract = |policy| {
+            let dest = dir.join(format!("out-{policy:?}"));
+            std::fs::create_dir_all(dest.join("notes")).unwrap();
+            std::fs::write(dest.join("notes/same.txt"), b"same").unwrap();
+            std::fs::write(dest.join("notes/changed.txt"), b"old").unwrap();
+            let n = extract_archive(ArchiveFormat::Tar, false, &archive[..], &dest, policy);
+            (n.unwrap(), dest.join("notes"))
+        };
+
+        let (n, out) = extract(ConflictPolicy::SkipIfIdentical);
+        assert_eq!(n, 1);
+        assert!(!out.join("same(1).txt").exists());
+        assert_eq!(std::fs::read(out.join("changed.txt")).unwrap(), b"old");
+        assert_eq!(std::fs::read(out.join("changed(1).txt")).unwrap(), b"new");
+
+        // The existing files were written after the archived ones.
+        let (n, out) = extract(ConflictPolicy::KeepNewer);
+        assert_eq!(n, 0);
+        assert_eq!(std::fs::read(out.join("changed.txt")).unwrap(), b"old");
+
+        let (n, out) = extract(ConflictPolicy::Overwrite);
+        assert_eq!(n, 2);
+        assert_eq!(std::fs::read(out.join("changed.txt")).unwrap(), b"new");
+        assert!(!partial_path(&out.join("changed.txt")).exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -67,13 +67,21 @@ lazy_static! {
 }
 lazy_static! {
     pub static ref GLOBAL_CONFIG: RwLock<Config> = RwLock::new(init_global_config());
-    pub static ref LOG_LEVEL: tracing::Level = GLOBAL_CONFIG
-        .read()
-        .map(|config| config
-            .log_level
-            .parse::<tracing::Level>()
-            .unwrap_or(tracing::Level::INFO))
-        .unwrap_or(tracing::Level::INFO);
+    pub static ref LOG_LEVEL: RwLock<tracing::Level> = RwLock::new(
+        GLOBAL_CONFIG
+            .read()
+            .map(|config| parse_log_level(&config.log_level))
+            .unwrap_or(tracing::Level::INFO)
+    );
+}
+
+/// Levels that do not parse fall back to INFO
+pub fn parse_log_level(log_level: &str) -> tracing::Level {
+    log_level.parse().unwrap_or(tracing::Level::INFO)
+}
+
+pub fn log_level() -> tracing::Level {
+    *LOG_LEVEL.read().unwrap()
 }
 lazy_static! {
     pub static ref ALLOW_TO_BE_SEARCHED: Mutex<bool> = Mutex::new(false);
@@ -99,8 +107,13 @@ fn app_icon_path() -> String {
     icon_path.display().to_string()
 }
 
-pub static TLS_ACCEPTOR: std::sync::LazyLock<tokio_rustls::TlsAcceptor> =
-    std::sync::LazyLock::new(|| get_tls_acceptor().expect("get_tls_acceptor error"));
+static TLS_ACCEPTOR: std::sync::LazyLock<RwLock<tokio_rustls::TlsAcceptor>> =
+    std::sync::LazyLock::new(|| RwLock::new(get_tls_acceptor().expect("get_tls_acceptor error")));
+
+/// The acceptor for new TLS connections, replaced by [`reload_tls`]
+pub fn tls_acceptor() -> tokio_rustls::TlsAcceptor {
+    TLS_ACCEPTOR.read().unwrap().clone()
+}
 
 pub fn get_cipher() -> Result<utils::encrypt::AesGcmCipher, Box<dyn std::error::Error>> {
     let cipher = utils::encrypt::AesGcmCipher::new(
@@ -117,7 +130,7 @@ pub fn write_config() -> std::sync::RwLockWriteGuard<'static, Config> {
     GLOBAL_CONFIG.write().unwrap()
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Config {
     #[serde(rename = "serverPort")]
     pub server_port: String,
@@ -129,6 +142,12 @@ pub struct Config {
     pub auto_start: bool,
     #[serde(rename = "savePath")]
     pub save_path: String,
+    /// Rules that route received items to directories other than `save_path`
+    #[serde(rename = "savePathRules", default)]
+    pub save_path_rules: Vec<crate::save_rules::SavePathRule>,
+    /// What to do when a received file already exists
+    #[serde(rename = "fileConflictPolicy", default)]
+    pub file_conflict_policy: crate::route::protocol::ConflictPolicy,
     #[serde(rename = "language")]
     pub language: crate::language::Language,
     #[serde(rename = "logLevel", default)]
@@ -149,9 +168,100 @@ pub struct Config {
     pub relay_secret_key: Option<String>,
     #[serde(rename = "enableRelay", default)]
     pub enable_relay: bool,
+    /// `relaySecretKey` before the last rotation, still used until `relaySecretGraceUntil`
+    #[serde(rename = "relayPreviousSecretKey", default)]
+    pub relay_previous_secret_key: Option<String>,
+    /// Unix seconds
+    #[serde(rename = "relaySecretGraceUntil", default)]
+    pub relay_secret_grace_until: i64,
+    /// TLS and WebSocket wrapping of the connections to the relay server
+    #[serde(rename = "relayTransport", default)]
+    pub relay_transport: crate::relay::transport::RelayTransportConfig,
+    /// More relay servers, see `relay::select`
+    #[serde(rename = "relayServers", default)]
+    pub relay_servers: Vec<crate::relay::select::RelayEndpoint>,
+    /// Stay registered on a second relay server at the same time
+    #[serde(rename = "relayStandby", default)]
+    pub relay_standby: bool,
+    /// Idle connections kept registered on the relay server
+    #[serde(rename = "relayPool", default)]
+    pub relay_pool: crate::relay::pool::RelayPoolConfig,
+    /// Proxy of connections to relay servers, remote servers and the web clipboard
+    #[serde(rename = "outboundProxy", default)]
+    pub outbound_proxy: crate::utils::proxy::ProxyConfig,
     /// TLS certificate domain name generation mode
     #[serde(rename = "tlsDomainMode", default)]
     pub tls_domain_mode: u8,
+    /// Other WindSend servers this program can push to
+    #[serde(rename = "remoteServers", default)]
+    pub remote_servers: Vec<RemoteServer>,
+    /// Local folders mirrored to a remote server
+    #[serde(rename = "folderSyncJobs", default)]
+    pub folder_sync_jobs: Vec<crate::folder_sync::FolderSyncJob>,
+    /// Local folders whose new files are sent to a remote server
+    #[serde(rename = "watchFolders", default)]
+    pub watch_folders: Vec<crate::watch_folder::WatchFolder>,
+    /// Maximum sizes of request bodies
+    #[serde(rename = "bodyLimits", default)]
+    pub body_limits: BodyLimits,
+    /// Limits for incoming connections
+    #[serde(default)]
+    pub admission: crate::admission::AdmissionConfig,
+    /// Optional QUIC listener next to the TCP one
+    #[serde(default)]
+    pub quic: crate::quic::QuicConfig,
+    /// Addresses to listen on, `[::]:serverPort` if empty
+    #[serde(rename = "listenEndpoints", default)]
+    pub listen_endpoints: Vec<crate::listen::ListenEndpoint>,
+    /// Map `serverPort` on the gateway with NAT-PMP/PCP or UPnP IGD
+    #[serde(rename = "portMapping", default)]
+    pub port_mapping: crate::port_mapping::PortMappingConfig,
+}
+
+/// Another WindSend server, referenced by name from background jobs
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RemoteServer {
+    pub name: String,
+    /// `host:port`
+    pub address: String,
+    #[serde(rename = "secretKeyHex")]
+    pub secret_key_hex: String,
+    /// The server's `tls/cert.pem`: PEM content, a file path or its SHA-256 fingerprint
+    pub certificate: String,
+}
+
+/// Maximum sizes in bytes of the request bodies that are read into memory
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BodyLimits {
+    /// Pasted or synced text, larger pastes are saved to a file instead
+    #[serde(rename = "maxTextBytes")]
+    pub max_text_bytes: u64,
+    /// Pasted text that is saved to a file
+    #[serde(rename = "maxTextFileBytes")]
+    pub max_text_file_bytes: u64,
+    /// JSON bodies such as upload operation info
+    #[serde(rename = "maxJsonBytes")]
+    pub max_json_bytes: u64,
+    /// Synced clipboard images
+    #[serde(rename = "maxImageBytes")]
+    pub max_image_bytes: u64,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self {
+            max_text_bytes: 16 * 1024 * 1024,
+            max_text_file_bytes: 1024 * 1024 * 1024,
+            max_json_bytes: 32 * 1024 * 1024,
+            max_image_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Serde default of `enabled` switches that are on unless configured otherwise
+pub fn default_enabled() -> bool {
+    true
 }
 
 #[cfg(not(feature = "disable-systray-support"))]
@@ -189,23 +299,61 @@ impl Config {
     }
     pub fn set(&self) -> Result<(), String> {
         self.empty_check()?;
-        #[cfg(not(all(target_os = "linux", feature = "disable-systray-support")))]
-        if self.auto_start {
-            if let Err(e) = START_HELPER.set_auto_start() {
-                return Err(format!("set_auto_start error: {e}"));
-            }
-        } else if let Err(e) = START_HELPER.unset_auto_start() {
-            return Err(format!("unset_auto_start error: {e}"));
-        }
+        // A failed auto start setting does not keep the rest from being applied.
+        let result = self.set_auto_start();
 
         crate::language::LANGUAGE_MANAGER
             .write()
             .unwrap()
             .set_language(self.language);
 
+        // The listeners read the new config once it is stored.
+        crate::listen::reload();
+
+        result
+    }
+
+    fn set_auto_start(&self) -> Result<(), String> {
+        #[cfg(not(all(target_os = "linux", feature = "disable-systray-support")))]
+        if self.auto_start {
+            START_HELPER
+                .set_auto_start()
+                .map_err(|e| format!("set_auto_start error: {e}"))?;
+        } else {
+            START_HELPER
+                .unset_auto_start()
+                .map_err(|e| format!("unset_auto_start error: {e}"))?;
+        }
         Ok(())
     }
 
+    pub fn find_remote_server(&self, name: &str) -> Option<&RemoteServer> {
+        self.remote_servers
+            .iter()
+            .find(|server| server.name == name)
+    }
+
+    /// All configured relay servers in order, empty if the relay is disabled
+    pub fn relay_endpoints(&self) -> Vec<crate::relay::select::RelayEndpoint> {
+        if !self.enable_relay {
+            return Vec::new();
+        }
+        let primary = crate::relay::select::RelayEndpoint {
+            address: self.relay_server_address.clone(),
+            secret_key: self.relay_secret_key.clone(),
+            previous_secret_key: self
+                .relay_previous_secret_key
+                .clone()
+                .filter(|_| chrono::Utc::now().timestamp() < self.relay_secret_grace_until),
+            priority: 0,
+            transport: self.relay_transport.clone(),
+        };
+        std::iter::once(primary)
+            .chain(self.relay_servers.iter().cloned())
+            .filter(|relay| !relay.address.is_empty())
+            .collect()
+    }
+
     pub fn get_secret_key_id(&self) -> String {
         let r_key = self.secret_key_hex.as_bytes();
         let r_key = crate::utils::encrypt::compute_sha256(r_key);
@@ -227,6 +375,8 @@ impl Config {
                 warn!("get_desktop_path error: {}", err);
                 "./".to_string()
             }),
+            save_path_rules: Vec::new(),
+            file_conflict_policy: Default::default(),
             language: lang.unwrap_or_default(),
             log_level: "INFO".to_string(),
             allow_to_be_searched_once: default_allow_to_be_searched_once(),
@@ -239,7 +389,22 @@ impl Config {
             relay_server_address: "".to_string(),
             relay_secret_key: Some("".to_string()),
             enable_relay: false,
+            relay_previous_secret_key: None,
+            relay_secret_grace_until: 0,
+            relay_transport: Default::default(),
+            relay_servers: Vec::new(),
+            relay_standby: false,
+            relay_pool: Default::default(),
+            outbound_proxy: Default::default(),
             tls_domain_mode: 0,
+            remote_servers: Vec::new(),
+            folder_sync_jobs: Vec::new(),
+            watch_folders: Vec::new(),
+            body_limits: Default::default(),
+            admission: Default::default(),
+            quic: Default::default(),
+            listen_endpoints: Vec::new(),
+            port_mapping: Default::default(),
         }
     }
 }
@@ -290,14 +455,14 @@ impl LogWriter {
 impl std::io::Write for LogWriter {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.0.write_all(&utils::eliminate_color(buf))?;
-        if *LOG_LEVEL >= tracing::Level::DEBUG {
+        if log_level() >= tracing::Level::DEBUG {
             std::io::stdout().write_all(buf)?;
         }
         Ok(buf.len())
     }
     fn flush(&mut self) -> std::io::Result<()> {
         self.0.flush()?;
-        if *LOG_LEVEL >= tracing::Level::DEBUG {
+        if log_level() >= tracing::Level::DEBUG {
             std::io::stdout().flush()?;
         }
         Ok(())
@@ -305,7 +470,7 @@ impl std::io::Write for LogWriter {
 }
 
 pub fn init() {
-    init_global_logger(*LOG_LEVEL);
+    init_global_logger(log_level());
     init_tls_config();
 }
 
@@ -323,17 +488,9 @@ fn init_global_logger(log_level: tracing::Level) {
     // tracing::subscriber::set_global_default(subscriber).unwrap();
     // Box::leak(Box::new(writer_guard));
 
-    use tracing_subscriber::{
-        filter::EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt,
-    };
-    let filter = tracing_subscriber::filter::EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new(log_level.to_string()))
-        .unwrap()
-        // 屏蔽掉reqwest的日志
-        .add_directive("reqwest=off".parse().unwrap())
-        // 屏蔽掉hyper的日志(设置等级为info)
-        .add_directive("hyper=info".parse().unwrap())
-        .add_directive("rustls=error".parse().unwrap());
+    use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
+    let (filter, filter_handle) = tracing_subscriber::reload::Layer::new(log_filter(log_level));
+    LOG_FILTER_HANDLE.set(filter_handle).ok();
     let fmt_layer = fmt::layer()
         // .pretty()
         .with_line_number(true)
@@ -348,6 +505,34 @@ fn init_global_logger(log_level: tracing::Level) {
     tracing::info!("init_global_logger success");
 }
 
+type LogFilterHandle = tracing_subscriber::reload::Handle<
+    tracing_subscriber::filter::EnvFilter,
+    tracing_subscriber::Registry,
+>;
+
+static LOG_FILTER_HANDLE: std::sync::OnceLock<LogFilterHandle> = std::sync::OnceLock::new();
+
+fn log_filter(log_level: tracing::Level) -> tracing_subscriber::filter::EnvFilter {
+    use tracing_subscriber::filter::EnvFilter;
+    EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(log_level.to_string()))
+        .unwrap()
+        // 屏蔽掉reqwest的日志
+        .add_directive("reqwest=off".parse().unwrap())
+        // 屏蔽掉hyper的日志(设置等级为info)
+        .add_directive("hyper=info".parse().unwrap())
+        .add_directive("rustls=error".parse().unwrap())
+}
+
+/// Replaces the log filter, `RUST_LOG` still takes precedence.
+pub fn set_log_level(log_level: tracing::Level) -> Result<(), String> {
+    *LOG_LEVEL.write().unwrap() = log_level;
+    let handle = LOG_FILTER_HANDLE.get().ok_or("logger is not initialized")?;
+    handle
+        .reload(log_filter(log_level))
+        .map_err(|err| format!("reload log filter error: {err}"))
+}
+
 fn init_tls_config() {
     // mkdir tls
     if !TLS_DIR.exists() {
@@ -367,23 +552,80 @@ fn init_tls_config() {
             .read()
             .map(|config| config.tls_domain_mode)
             .unwrap_or(0);
-        let result = utils::tls::generate_ca_and_signed_certificate_pair(domain_mode);
-        if let Err(err) = result {
+        if let Err(err) = generate_tls_files(domain_mode) {
             panic!("init_tls_config error: {err}");
         }
-        let ([cert_pem, priv_pem], [ca_cert_pem, ca_key_pem]) = result.unwrap();
-        std::fs::write(cert_path, cert_pem).unwrap();
-        std::fs::write(key_path, priv_pem).unwrap();
-        std::fs::write(ca_cert_path, ca_cert_pem).unwrap();
-        std::fs::write(ca_key_path, ca_key_pem).unwrap();
     }
 }
 
+fn generate_tls_files(domain_mode: u8) -> Result<(), Box<dyn std::error::Error>> {
+    let ([cert_pem, priv_pem], [ca_cert_pem, ca_key_pem]) =
+        utils::tls::generate_ca_and_signed_certificate_pair(domain_mode)?;
+    std::fs::write(TLS_DIR.join(TLS_CERT_FILE), cert_pem)?;
+    std::fs::write(TLS_DIR.join(TLS_KEY_FILE), priv_pem)?;
+    std::fs::write(TLS_DIR.join(TLS_CA_CERT_FILE), ca_cert_pem)?;
+    std::fs::write(TLS_DIR.join(TLS_CA_KEY_FILE), ca_key_pem)?;
+    Ok(())
+}
+
+/// Signs a new server certificate for a domain of `domain_mode` with the
+/// existing CA, and uses it for new connections.
+///
+/// Paired devices trust the CA, so they don't have to pair again.
+pub fn reload_tls(domain_mode: u8) -> Result<(), Box<dyn std::error::Error>> {
+    // The external address of the port mapping stays valid.
+    let extra_ips: Vec<_> = crate::port_mapping::external_ip().into_iter().collect();
+    let [cert_pem, priv_pem] = utils::tls::resign_for_domain_mode(
+        &std::fs::read_to_string(TLS_DIR.join(TLS_CA_CERT_FILE))?,
+        &std::fs::read_to_string(TLS_DIR.join(TLS_CA_KEY_FILE))?,
+        domain_mode,
+        &extra_ips,
+    )?;
+    std::fs::write(TLS_DIR.join(TLS_CERT_FILE), cert_pem)?;
+    std::fs::write(TLS_DIR.join(TLS_KEY_FILE), priv_pem)?;
+    use_new_certificate()
+}
+
+/// Makes the TCP and QUIC listeners use the certificate in `TLS_DIR`.
+fn use_new_certificate() -> Result<(), Box<dyn std::error::Error>> {
+    *TLS_ACCEPTOR.write().unwrap() = get_tls_acceptor()?;
+    crate::quic::reload_certificate()
+}
+
+/// Signs a new server certificate with the existing CA if the current one is
+/// not valid for `ip`, and uses it for new connections.
+///
+/// Returns whether the certificate changed.
+pub fn add_certificate_ip(ip: std::net::IpAddr) -> Result<bool, Box<dyn std::error::Error>> {
+    let cert_pem = std::fs::read_to_string(TLS_DIR.join(TLS_CERT_FILE))?;
+    if utils::tls::certificate_has_ip(&cert_pem, ip) {
+        return Ok(false);
+    }
+    let [cert_pem, priv_pem] = utils::tls::reissue_signed_certificate(
+        &cert_pem,
+        &std::fs::read_to_string(TLS_DIR.join(TLS_CA_CERT_FILE))?,
+        &std::fs::read_to_string(TLS_DIR.join(TLS_CA_KEY_FILE))?,
+        &[ip],
+    )?;
+    std::fs::write(TLS_DIR.join(TLS_CERT_FILE), cert_pem)?;
+    std::fs::write(TLS_DIR.join(TLS_KEY_FILE), priv_pem)?;
+    use_new_certificate()?;
+    Ok(true)
+}
+
 pub fn read_ca_certificate_pem() -> std::io::Result<String> {
     std::fs::read_to_string(TLS_DIR.join(TLS_CA_CERT_FILE))
 }
 
 pub fn get_tls_acceptor() -> Result<tokio_rustls::TlsAcceptor, Box<dyn std::error::Error>> {
+    Ok(tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(
+        tls_server_config()?,
+    )))
+}
+
+/// The server side TLS configuration with the certificate in `TLS_DIR`
+pub fn tls_server_config() -> Result<tokio_rustls::rustls::ServerConfig, Box<dyn std::error::Error>>
+{
     use tokio_rustls::rustls;
     use tokio_rustls::rustls::pki_types::PrivateKeyDer;
     let private_key_bytes = std::fs::read(TLS_DIR.join(TLS_KEY_FILE))?;
@@ -410,7 +652,5 @@ pub fn get_tls_acceptor() -> Result<tokio_rustls::TlsAcceptor, Box<dyn std::erro
     let server_conf = rustls::ServerConfig::builder()
         .with_no_client_auth()
         .with_single_cert(vec![ca_cert], private_key)?;
-    Ok(tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(
-        server_conf,
-    )))
+    Ok(server_conf)
 }
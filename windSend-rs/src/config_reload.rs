@@ -0,0 +1,239 @@
+//! Applies edits of `config.yaml` while the program runs.
+//!
+//! The directory of the config file is watched, and an edited file is parsed
+//! and compared with the config in use. Each changed setting is applied live:
+//! the listeners are rebound, the server certificate is signed again for a new
+//! `tlsDomainMode`, the relay connection is restarted and the log filter is
+//! replaced. An edit that does not parse or misses required fields is reported
+//! and the running config is kept. Settings that are only read at startup are
+//! stored, and reported to take effect after a restart.
+//!
+//! Saving the config from within the program also touches the file, but the
+//! stored config then equals the running one, so nothing is applied twice.
+
+use crate::config::Config;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+/// Editors write a file in several steps, the last event of a burst wins.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// What differs between two configs
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ConfigChanges {
+    /// `serverPort` or `listenEndpoints`
+    pub listen: bool,
+    pub tls: bool,
+    pub relay: bool,
+    pub log_level: bool,
+    pub folder_sync: bool,
+    /// Changed settings that are only read at startup
+    pub need_restart: Vec<&'static str>,
+}
+
+impl ConfigChanges {
+    pub fn diff(old: &Config, new: &Config) -> Self {
+        let mut need_restart = Vec::new();
+        if old.show_systray_icon != new.show_systray_icon {
+            need_restart.push("showToolbarIcon");
+        }
+        if old.quic != new.quic {
+            need_restart.push("quic");
+        }
+        if old.watch_folders != new.watch_folders {
+            need_restart.push("watchFolders");
+        }
+        if old.port_mapping != new.port_mapping {
+            need_restart.push("portMapping");
+        }
+        Self {
+            listen: old.server_port != new.server_port
+                || old.listen_endpoints != new.listen_endpoints,
+            tls: old.tls_domain_mode != new.tls_domain_mode,
+            relay: old.enable_relay != new.enable_relay
+                || old.relay_server_address != new.relay_server_address
+                || old.relay_secret_key != new.relay_secret_key
+                || old.relay_previous_secret_key != new.relay_previous_secret_key
+                || old.relay_secret_grace_until != new.relay_secret_grace_until
+                || old.relay_transport != new.relay_transport
+                || old.relay_servers != new.relay_servers
+                || old.relay_standby != new.relay_standby
+                || old.relay_pool != new.relay_pool
+                || old.outbound_proxy != new.outbound_proxy,
+            log_level: crate::config::parse_log_level(&old.log_level)
+                != crate::config::parse_log_level(&new.log_level),
+            folder_sync: old.folder_sync_jobs != new.folder_sync_jobs,
+            need_restart,
+        }
+    }
+}
+
+/// Starts watching the config file.
+pub fn start() {
+    crate::RUNTIME.spawn(watch());
+}
+
+async fn watch() {
+    use notify::{EventKind, RecursiveMode, Watcher};
+    let path = crate::config::CONFIG_FILE_PATH.as_path();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => std::path::PathBuf::from("."),
+    };
+    let Some(file_name) = path.file_name().map(|name| name.to_os_string()) else {
+        error!("invalid config file path: {}", path.display());
+        return;
+    };
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    // The directory is watched, editors often replace the file instead of writing it.
+    let watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                    && event
+                        .paths
+                        .iter()
+                        .any(|path| path.file_name() == Some(file_name.as_os_str()))
+                {
+                    let _ = tx.send(());
+                }
+            }
+            Err(e) => warn!("watch config error: {}", e),
+        });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("create config watcher error: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+        error!("watch config dir {} error: {}", dir.display(), e);
+        return;
+    }
+    info!("watching config file: {}", path.display());
+    while rx.recv().await.is_some() {
+        tokio::time::sleep(DEBOUNCE).await;
+        while rx.try_recv().is_ok() {}
+        reload_from_file().await;
+    }
+}
+
+fn read_config_file() -> Result<Config, String> {
+    let content = std::fs::read_to_string(&*crate::config::CONFIG_FILE_PATH)
+        .map_err(|err| format!("read config file error: {err}"))?;
+    let config: Config = serde_yaml::from_str(&content)
+        .map_err(|err| format!("deserialize config file error: {err}"))?;
+    config.empty_check()?;
+    Ok(config)
+}
+
+/// Applies the config file if it differs from the running config.
+pub async fn reload_from_file() {
+    match read_config_file() {
+        Ok(config) => {
+            apply(config).await;
+        }
+        Err(err) => {
+            error!("config file not applied, keep the running config: {}", err);
+            use crate::language::{LanguageKey, translate};
+            crate::utils::inform(&err, translate(LanguageKey::ConfigNotApplied), None);
+        }
+    }
+}
+
+/// Replaces the running config with `new` and applies what changed.
+pub async fn apply(new: Config) -> ConfigChanges {
+    let old = {
+        let mut config = crate::config::write_config();
+        if *config == new {
+            debug!("config file unchanged");
+            return ConfigChanges::default();
+        }
+        std::mem::replace(&mut *config, new.clone())
+    };
+    let changes = ConfigChanges::diff(&old, &new);
+    info!("config changed: {:?}", changes);
+
+    // Auto start, language, and the listeners.
+    if let Err(err) = new.set() {
+        error!("apply config error: {}", err);
+    }
+    if changes.log_level
+        && let Err(err) =
+            crate::config::set_log_level(crate::config::parse_log_level(&new.log_level))
+    {
+        error!("set log level error: {}", err);
+    }
+    if changes.tls {
+        match crate::config::reload_tls(new.tls_domain_mode) {
+            Ok(()) => info!(
+                "signed a new certificate for tlsDomainMode {}",
+                new.tls_domain_mode
+            ),
+            Err(err) => error!("reload tls error: {}", err),
+        }
+    }
+    if changes.folder_sync {
+        crate::folder_sync::start();
+    }
+    if changes.relay {
+        // Draining the old relay connection must not hold up later reloads.
+        crate::RUNTIME.spawn(crate::relay::run::restart_relay());
+    }
+    for name in &changes.need_restart {
+        warn!("{} changed, effective after program restart", name);
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        serde_yaml::from_str(
+            "serverPort: '6779'\nsecretKeyHex: '00ff'\nshowToolbarIcon: true\n\
+             autoStart: false\nsavePath: ./\nlanguage: en\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn diff_reports_each_setting() {
+        let old = config();
+        assert_eq!(
+            ConfigChanges::diff(&old, &old.clone()),
+            ConfigChanges::default()
+        );
+
+        let mut new = old.clone();
+        new.server_port = "7000".to_string();
+        new.relay_server_address = "relay.example.com:16779".to_string();
+        new.quic.enabled = true;
+        let changes = ConfigChanges::diff(&old, &new);
+        assert_eq!(
+            changes,
+            ConfigChanges {
+                listen: true,
+                relay: true,
+                need_restart: vec!["quic"],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn log_levels_are_compared_after_parsing() {
+        let mut old = config();
+        let mut new = old.clone();
+        old.log_level = "INFO".to_string();
+        new.log_level = "info".to_string();
+        assert!(!ConfigChanges::diff(&old, &new).log_level);
+        // An unknown level falls back to INFO as well.
+        new.log_level = "verbose".to_string();
+        assert!(!ConfigChanges::diff(&old, &new).log_level);
+        new.log_level = "debug".to_string();
+        assert!(ConfigChanges::diff(&old, &new).log_level);
+    }
+}
@@ -1,11 +1,13 @@
 use crate::RUNTIME;
-use std::collections::HashMap;
+use crate::route::protocol::ConflictPolicy;
+use std::collections::{HashMap, HashSet};
 use std::io::SeekFrom;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::Ordering::Relaxed;
 use tokio::io::{AsyncReadExt, AsyncSeekExt, Take};
 use tokio::sync::Mutex as TokioMutex;
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 
 pub struct FilePartReader {
     file_part: Take<tokio::fs::File>,
@@ -121,11 +123,22 @@ pub struct FileReceiveSessionManager {
 pub struct OpProgress {
     pub success_count: std::sync::atomic::AtomicI32,
     pub failure_count: std::sync::atomic::AtomicI32,
+    /// Files not written because of the conflict policy
+    pub skipped_count: std::sync::atomic::AtomicI32,
     /// The position of the last progress notification
     pub inform_pos: std::sync::atomic::AtomicU64,
     pub current_pos: std::sync::atomic::AtomicU64,
 }
 
+impl OpProgress {
+    /// The number of files that have been dealt with, successfully or not
+    pub fn finished_count(&self) -> i32 {
+        self.success_count.load(Relaxed)
+            + self.failure_count.load(Relaxed)
+            + self.skipped_count.load(Relaxed)
+    }
+}
+
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct OpInfo {
@@ -134,10 +147,38 @@ pub struct OpInfo {
     total_expectation: u64,
     expected_count: i32,
     requested_device_name: Arc<String>,
+    conflict_policy: ConflictPolicy,
+    /// Normalized relative paths the client was told to skip
+    skipped_paths: Arc<HashSet<String>>,
+    /// Save relative to `savePath`, see `UploadOperationInfo::ignore_save_path_rules`
+    ignore_save_path_rules: bool,
 
     progress: Arc<OpProgress>,
 }
 
+/// How the parts of a received file should be handled
+pub enum FileReception {
+    /// Write the part into this file
+    Write(tokio::fs::File),
+    /// The file is skipped by the conflict policy, the part data must be discarded
+    Skip,
+}
+
+/// What is known about an incoming file when its save path already exists
+struct IncomingFile<'a> {
+    size: i64,
+    hash: Option<&'a str>,
+    mod_time: Option<i64>,
+}
+
+enum ConflictResolution {
+    /// Create a new file at this path
+    Create(String),
+    /// Replace the existing file at this path
+    Overwrite(String),
+    Skip,
+}
+
 #[derive(Debug)]
 pub struct RecvFileInfo {
     expected_size: i64,
@@ -151,8 +192,13 @@ struct LockedItem {
     down_chan: Option<tokio::sync::oneshot::Sender<bool>>,
     /// The path where the file is actually saved, including the filename
     save_path: String,
+    /// The existing file that `save_path` is renamed over once it is complete,
+    /// see `ConflictPolicy::Overwrite`
+    replaces: Option<String>,
     /// Task completion flag (no error occurred)
     is_done: bool,
+    /// The file already exists and is not written, see `ConflictPolicy`
+    skipped: bool,
     first_err: Option<String>,
 }
 
@@ -175,25 +221,107 @@ impl FileReceiveSessionManager {
     pub async fn setup_file_reception(
         self: &Arc<Self>,
         head: &crate::route::protocol::RouteRecvHead,
-    ) -> std::io::Result<tokio::fs::File> {
+    ) -> std::io::Result<FileReception> {
         let file_id = head.file_id;
         let file_size = head.file_size;
         debug!("head.path: {}", head.path);
+        let (conflict_policy, skipped_by_op, ignore_save_path_rules) = {
+            use crate::utils::NormalizePath;
+            let ops_map = self.operation_sessions.lock().await;
+            match ops_map.get(&head.op_id) {
+                Some(op) => (
+                    op.conflict_policy,
+                    op.skipped_paths.contains(&head.path.normalize_path()),
+                    op.ignore_save_path_rules,
+                ),
+                None => (
+                    crate::config::read_config().file_conflict_policy,
+                    false,
+                    false,
+                ),
+            }
+        };
         let actual_save_path;
         let already_exist;
+        let mut skipped = false;
+        let mut replaces = None;
         let mut file_recv_map = self.file_sessions.lock().await;
         if let Some(info) = file_recv_map.get(&file_id) {
-            actual_save_path = info.metadata.lock().await.save_path.clone();
+            let metadata = info.metadata.lock().await;
+            if metadata.skipped {
+                return Ok(FileReception::Skip);
+            }
+            actual_save_path = metadata.save_path.clone();
             already_exist = true;
         } else {
+            if skipped_by_op {
+                // Already counted when the operation was created.
+                return Ok(FileReception::Skip);
+            }
             already_exist = false;
-            let file_path =
-                std::path::Path::new(&crate::config::GLOBAL_CONFIG.read().unwrap().save_path)
-                    .join(&head.path);
-            actual_save_path = crate::utils::generate_unique_filepath(file_path)?;
+            let save_dir = file_save_dir(&head.device_name, &head.path, ignore_save_path_rules);
+            let file_path = crate::utils::join_sanitized(&save_dir, &head.path)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            let incoming = IncomingFile {
+                size: head.file_size,
+                hash: head.file_hash.as_deref(),
+                mod_time: head.mod_time,
+            };
+            actual_save_path =
+                match resolve_conflict(&file_path, conflict_policy, &incoming).await? {
+                    ConflictResolution::Create(path) => path,
+                    ConflictResolution::Overwrite(path) => {
+                        // The existing file stays intact until the new one is complete.
+                        let partial = partial_path(&path);
+                        replaces = Some(path);
+                        partial
+                    }
+                    ConflictResolution::Skip => {
+                        skipped = true;
+                        file_path.to_string_lossy().to_string()
+                    }
+                };
         }
         use crate::utils::NormalizePath;
         let actual_save_path = actual_save_path.normalize_path();
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let overwrite = replaces.is_some();
+        let new_recv_info = |save_path: String, skipped: bool| RecvFileInfo {
+            expected_size: file_size,
+            metadata: TokioMutex::new(LockedItem {
+                part: Vec::new(),
+                down_chan: Some(tx),
+                save_path,
+                replaces,
+                is_done: false,
+                first_err: None,
+                skipped,
+            }),
+        };
+        let manager = Arc::clone(self);
+        let op_id = head.op_id;
+        let spawn_monitor = |save_path: String| {
+            crate::RUNTIME.spawn(async move {
+                manager
+                    .monitor_single_file_reception(file_id, op_id, save_path, rx)
+                    .await
+            });
+        };
+
+        if skipped {
+            info!(
+                "skip receiving {} by conflict policy {:?}",
+                actual_save_path, conflict_policy
+            );
+            file_recv_map.insert(
+                file_id,
+                Arc::new(new_recv_info(actual_save_path.clone(), true)),
+            );
+            spawn_monitor(actual_save_path);
+            return Ok(FileReception::Skip);
+        }
+
         debug!("uploading file: {}", actual_save_path);
         let dir = std::path::Path::new(&actual_save_path)
             .parent()
@@ -202,11 +330,11 @@ impl FileReceiveSessionManager {
         let file = tokio::fs::OpenOptions::new()
             .create(true)
             .write(true)
-            .truncate(false)
+            .truncate(overwrite)
             .open(&actual_save_path)
             .await?;
         if already_exist {
-            return Ok(file);
+            return Ok(FileReception::Write(file));
         }
 
         let mut file = file;
@@ -219,19 +347,10 @@ impl FileReceiveSessionManager {
         }
         let file = file;
 
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        let items = LockedItem {
-            part: Vec::new(),
-            down_chan: Some(tx),
-            save_path: actual_save_path.clone(),
-            is_done: false,
-            first_err: None,
-        };
-        let info = RecvFileInfo {
-            expected_size: file_size,
-            metadata: TokioMutex::new(items),
-        };
-        file_recv_map.insert(file_id, Arc::new(info));
+        file_recv_map.insert(
+            file_id,
+            Arc::new(new_recv_info(actual_save_path.clone(), false)),
+        );
 
         // check is this opID exist
         // let ops_map = self.operation_sessions.lock().await;
@@ -239,31 +358,76 @@ impl FileReceiveSessionManager {
         //     self.create_op_info_inner(head, ops_map);
         // }
 
-        let manager = Arc::clone(self);
-        let op_id = head.op_id;
-        crate::RUNTIME.spawn(async move {
-            manager
-                .monitor_single_file_reception(file_id, op_id, actual_save_path, rx)
-                .await
-        });
-        Ok(file)
+        spawn_monitor(actual_save_path);
+        Ok(FileReception::Write(file))
     }
 
+    /// Registers a new upload operation and returns the relative paths of the
+    /// listed files that will be skipped because of the conflict policy.
     pub async fn create_op_info(
         &self,
         head: &crate::route::protocol::RouteRecvHead,
         upload_info: &crate::route::protocol::UploadOperationInfo,
-    ) -> Result<(), String> {
+    ) -> Result<Vec<String>, String> {
+        let conflict_policy = upload_info
+            .conflict_policy
+            .unwrap_or_else(|| crate::config::read_config().file_conflict_policy);
+        let skipped_paths = self
+            .precheck_conflicts(head, upload_info, conflict_policy)
+            .await;
         let ops_map = self.operation_sessions.lock().await;
-        self.create_op_info_inner(head, upload_info, ops_map)
+        self.create_op_info_inner(head, upload_info, conflict_policy, &skipped_paths, ops_map)?;
+        Ok(skipped_paths)
+    }
+
+    /// Resolves conflicts for the files listed in `upload_info`, if any.
+    async fn precheck_conflicts(
+        &self,
+        head: &crate::route::protocol::RouteRecvHead,
+        upload_info: &crate::route::protocol::UploadOperationInfo,
+        conflict_policy: ConflictPolicy,
+    ) -> Vec<String> {
+        let mut skipped_paths = Vec::new();
+        if conflict_policy == ConflictPolicy::Rename || conflict_policy == ConflictPolicy::Overwrite
+        {
+            return skipped_paths;
+        }
+        for file in upload_info.files.iter().flatten() {
+            let save_dir = file_save_dir(
+                &head.device_name,
+                &file.path,
+                upload_info.ignore_save_path_rules,
+            );
+            let incoming = IncomingFile {
+                size: file.size,
+                hash: file.hash.as_deref(),
+                mod_time: file.mod_time,
+            };
+            let file_path = match crate::utils::join_sanitized(&save_dir, &file.path) {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!("{}", e);
+                    continue;
+                }
+            };
+            match resolve_conflict(&file_path, conflict_policy, &incoming).await {
+                Ok(ConflictResolution::Skip) => skipped_paths.push(file.path.clone()),
+                Ok(_) => {}
+                Err(e) => warn!("check conflict of {} failed: {}", file.path, e),
+            }
+        }
+        skipped_paths
     }
 
     fn create_op_info_inner(
         &self,
         head: &crate::route::protocol::RouteRecvHead,
         upload_info: &crate::route::protocol::UploadOperationInfo,
+        conflict_policy: ConflictPolicy,
+        skipped_paths: &[String],
         mut ops_map: tokio::sync::MutexGuard<HashMap<u32, OpInfo>>,
     ) -> Result<(), String> {
+        use crate::utils::NormalizePath;
         // create new opertion
         let op_info = OpInfo {
             _op_id: head.op_id,
@@ -271,13 +435,31 @@ impl FileReceiveSessionManager {
             total_expectation: upload_info.files_size_in_this_op as u64,
             requested_device_name: Arc::new(String::clone(&head.device_name)),
             expected_count: upload_info.files_count_in_this_op,
+            conflict_policy,
+            skipped_paths: Arc::new(skipped_paths.iter().map(|p| p.normalize_path()).collect()),
+            ignore_save_path_rules: upload_info.ignore_save_path_rules,
             progress: Arc::new(OpProgress {
                 inform_pos: std::sync::atomic::AtomicU64::new(0),
                 current_pos: std::sync::atomic::AtomicU64::new(0),
                 success_count: std::sync::atomic::AtomicI32::new(0),
                 failure_count: std::sync::atomic::AtomicI32::new(0),
+                skipped_count: std::sync::atomic::AtomicI32::new(skipped_paths.len() as i32),
             }),
         };
+        if !skipped_paths.is_empty() && skipped_paths.len() as i32 >= op_info.expected_count {
+            // Nothing will be received for this operation.
+            info!(
+                "all {} files of opID {} skipped",
+                skipped_paths.len(),
+                head.op_id
+            );
+            crate::utils::inform(
+                format!("{} files skipped", skipped_paths.len()),
+                &head.device_name,
+                None,
+            );
+            return Ok(());
+        }
         #[cfg(not(target_os = "windows"))]
         let old_op = ops_map.insert(head.op_id, op_info);
         #[cfg(target_os = "windows")]
@@ -327,11 +509,10 @@ impl FileReceiveSessionManager {
                     let current = op_info.progress.current_pos.load(Relaxed);
                     let inform_pos = op_info.progress.inform_pos.load(Relaxed);
                     let success_count = op_info.progress.success_count.load(Relaxed);
-                    let failure_count = op_info.progress.failure_count.load(Relaxed);
                     if current == total {
                         break;
                     }
-                    if success_count + failure_count == op_info.expected_count {
+                    if op_info.progress.finished_count() == op_info.expected_count {
                         break;
                     }
                     if useless_times > MAX_USELESS_TIMES {
@@ -505,19 +686,41 @@ impl FileReceiveSessionManager {
             .unwrap()
             .clone();
 
-        if success {
+        // It should be deleted regardless of whether the download was successful or not,
+        // because the fileID will not be the same next time the same file is transferred.
+        let file_recv_info = self.file_sessions.lock().await.remove(&file_id).unwrap();
+        let skipped = file_recv_info.metadata.lock().await.skipped;
+        let mut success = success;
+        {
+            let mut metadata = file_recv_info.metadata.lock().await;
+            if let Some(target) = metadata.replaces.take() {
+                if success {
+                    match tokio::fs::rename(&metadata.save_path, &target).await {
+                        Ok(()) => metadata.save_path = target,
+                        Err(e) => {
+                            error!("replace {} failed: {}", target, e);
+                            success = false;
+                        }
+                    }
+                }
+                if !success && let Err(e) = tokio::fs::remove_file(&metadata.save_path).await {
+                    warn!("remove {} failed: {}", metadata.save_path, e);
+                }
+            }
+        }
+
+        if success && skipped {
+            op_info.progress.skipped_count.fetch_add(1, Relaxed);
+        } else if success {
             op_info.progress.success_count.fetch_add(1, Relaxed);
         } else {
             op_info.progress.failure_count.fetch_add(1, Relaxed);
         }
 
-        // It should be deleted regardless of whether the download was successful or not,
-        // because the fileID will not be the same next time the same file is transferred.
-        let file_recv_info = self.file_sessions.lock().await.remove(&file_id).unwrap();
-
         // Paste it to the clipboard if only receive one image
         let save_path = &file_recv_info.metadata.lock().await.save_path;
         if success
+            && !skipped
             && op_info.expected_count == 1
             && file_recv_info.expected_size < 1024 * 1024 * 4
             && crate::utils::has_img_ext(save_path)
@@ -534,12 +737,14 @@ impl FileReceiveSessionManager {
 
         let success_count = op_info.progress.success_count.load(Relaxed);
         let failure_count = op_info.progress.failure_count.load(Relaxed);
-        if success_count + failure_count == op_info.expected_count {
+        let skipped_count = op_info.progress.skipped_count.load(Relaxed);
+        let finished = op_info.progress.finished_count() == op_info.expected_count;
+        if finished {
             // This operation has been completed
             self.operation_sessions.lock().await.remove(&op_id);
         }
         // tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
-        if !is_timeout && success_count + failure_count == op_info.expected_count {
+        if !is_timeout && finished {
             #[cfg(not(target_os = "windows"))]
             {
                 use crate::language::LanguageKey;
@@ -553,12 +758,18 @@ impl FileReceiveSessionManager {
                 if failure_count > 0 {
                     msg = format!("{msg}\n{failure_count} files failed to save");
                 }
+                if skipped_count > 0 {
+                    msg = format!("{msg}\n{skipped_count} files skipped");
+                }
                 crate::utils::inform(&msg, &op_info.requested_device_name, Some(save_path));
             }
             #[cfg(target_os = "windows")]
             {
                 let progress_tag = format!("{op_id}");
-                let value_string = format!("{}/{} files", success_count, op_info.expected_count);
+                let value_string = format!(
+                    "{}/{} files, {} skipped",
+                    success_count, op_info.expected_count, skipped_count
+                );
                 let _guard = self.notify_lock.lock().unwrap();
                 let _ = win_toast_notify::WinToastNotify::progress_complete(
                     None,
@@ -571,6 +782,191 @@ impl FileReceiveSessionManager {
     }
 }
 
+/// Decides where (and whether) an incoming file is saved when `path` may already exist.
+async fn resolve_conflict(
+    path: &Path,
+    policy: ConflictPolicy,
+    incoming: &IncomingFile<'_>,
+) -> std::io::Result<ConflictResolution> {
+    let path_str = || {
+        path.to_str()
+            .map(str::to_string)
+            .ok_or_else(|| std::io::Error::other("path to str error"))
+    };
+    let existing = match tokio::fs::metadata(path).await {
+        Ok(meta) if meta.is_file() => meta,
+        Ok(_) => {
+            return Ok(ConflictResolution::Create(
+                crate::utils::generate_unique_filepath(path)?,
+            ));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(ConflictResolution::Create(path_str()?));
+        }
+        Err(e) => return Err(e),
+    };
+    match policy {
+        ConflictPolicy::Overwrite => Ok(ConflictResolution::Overwrite(path_str()?)),
+        ConflictPolicy::SkipIfIdentical => {
+            if let Some(hash) = incoming.hash
+                && existing.len() == incoming.size as u64
+                && file_sha256_hex(path).await?.eq_ignore_ascii_case(hash)
+            {
+                return Ok(ConflictResolution::Skip);
+            }
+            Ok(ConflictResolution::Create(
+                crate::utils::generate_unique_filepath(path)?,
+            ))
+        }
+        ConflictPolicy::KeepNewer => {
+            let existing_mod_time = existing
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_millis() as i64);
+            match (incoming.mod_time, existing_mod_time) {
+                (Some(incoming), Some(existing)) if incoming <= existing => {
+                    Ok(ConflictResolution::Skip)
+                }
+                (Some(_), _) => Ok(ConflictResolution::Overwrite(path_str()?)),
+                // Without the client's modification time there is nothing to compare.
+                (None, _) => Ok(ConflictResolution::Create(
+                    crate::utils::generate_unique_filepath(path)?,
+                )),
+            }
+        }
+        ConflictPolicy::Rename => Ok(ConflictResolution::Create(
+            crate::utils::generate_unique_filepath(path)?,
+        )),
+    }
+}
+
+/// Where a file that replaces `path` is written until it is complete
+pub fn partial_path(path: &str) -> String {
+    format!("{path}.windsend-part")
+}
+
+/// The directory a received file is saved into, `savePath` or the one chosen
+/// by the save path rules.
+fn file_save_dir(
+    device_name: &str,
+    file_path: &str,
+    ignore_save_path_rules: bool,
+) -> std::path::PathBuf {
+    use crate::save_rules::{
+        ReceiveItemKind, SaveTarget, default_save_dir, resolve_save_dir, top_level_item,
+    };
+    if ignore_save_path_rules {
+        return default_save_dir();
+    }
+    // All files of an uploaded folder stay together.
+    resolve_save_dir(&SaveTarget {
+        device_name,
+        file_name: Some(top_level_item(file_path)),
+        kind: ReceiveItemKind::File,
+    })
+}
+
+/// Computes the SHA-256 of a file, hex encoded.
+pub async fn file_sha256_hex(path: impl AsRef<Path>) -> std::io::Result<String> {
+    use sha2::Digest;
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = vec![0u8; 256 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
 lazy_static::lazy_static!(
     pub static ref GLOBAL_RECEIVER_SESSION_MANAGER:Arc<FileReceiveSessionManager> = Arc::new(FileReceiveSessionManager::new());
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("windsend-conflict-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn incoming(size: i64, hash: Option<&str>, mod_time: Option<i64>) -> IncomingFile<'_> {
+        IncomingFile {
+            size,
+            hash,
+            mod_time,
+        }
+    }
+
+    #[tokio::test]
+    async fn conflict_policies_resolve_existing_files() {
+        let dir = temp_dir("policies");
+        let existing = dir.join("a.txt");
+        std::fs::write(&existing, b"hello").unwrap();
+        let hash = file_sha256_hex(&existing).await.unwrap();
+        let renamed = dir.join("a(1).txt").to_string_lossy().to_string();
+        let same_path = existing.to_string_lossy().to_string();
+
+        let missing = dir.join("missing.txt");
+        assert!(matches!(
+            resolve_conflict(&missing, ConflictPolicy::KeepNewer, &incoming(1, None, None)).await,
+            Ok(ConflictResolution::Create(p)) if p == missing.to_string_lossy()
+        ));
+        assert!(matches!(
+            resolve_conflict(&existing, ConflictPolicy::Rename, &incoming(5, None, None)).await,
+            Ok(ConflictResolution::Create(p)) if p == renamed
+        ));
+        assert!(matches!(
+            resolve_conflict(&existing, ConflictPolicy::Overwrite, &incoming(5, None, None)).await,
+            Ok(ConflictResolution::Overwrite(p)) if p == same_path
+        ));
+        assert!(matches!(
+            resolve_conflict(
+                &existing,
+                ConflictPolicy::SkipIfIdentical,
+                &incoming(5, Some(&hash.to_uppercase()), None)
+            )
+            .await,
+            Ok(ConflictResolution::Skip)
+        ));
+        assert!(matches!(
+            resolve_conflict(
+                &existing,
+                ConflictPolicy::SkipIfIdentical,
+                &incoming(5, Some("00"), None)
+            )
+            .await,
+            Ok(ConflictResolution::Create(p)) if p == renamed
+        ));
+        assert!(matches!(
+            resolve_conflict(
+                &existing,
+                ConflictPolicy::KeepNewer,
+                &incoming(5, None, Some(0))
+            )
+            .await,
+            Ok(ConflictResolution::Skip)
+        ));
+        assert!(matches!(
+            resolve_conflict(
+                &existing,
+                ConflictPolicy::KeepNewer,
+                &incoming(5, None, Some(i64::MAX))
+            )
+            .await,
+            Ok(ConflictResolution::Overwrite(p)) if p == same_path
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
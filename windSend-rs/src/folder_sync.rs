@@ -0,0 +1,311 @@
+//! One-way folder mirroring.
+//!
+//! Each job periodically compares a local directory with the manifest of a
+//! directory on a remote WindSend server and uploads the files that are
+//! missing or changed. Optionally, files that only exist remotely are deleted.
+
+use crate::route::client::{ClientError, RouteClient, UploadItem};
+use crate::route::protocol::{
+    DeleteFilesReq, FolderManifest, FolderManifestReq, ManifestEntry, RouteAction,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{error, info, warn};
+
+fn default_interval_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FolderSyncJob {
+    pub name: String,
+    #[serde(rename = "localDir")]
+    pub local_dir: String,
+    /// Name of an entry in `remoteServers`
+    #[serde(rename = "remoteServer")]
+    pub remote_server: String,
+    /// Directory relative to the remote `savePath`, empty for `savePath` itself
+    #[serde(rename = "remoteDir", default)]
+    pub remote_dir: String,
+    #[serde(rename = "intervalSecs", default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Compare SHA-256 of files with equal size instead of modification time
+    #[serde(rename = "compareHash", default)]
+    pub compare_hash: bool,
+    /// Delete remote files that no longer exist locally
+    #[serde(rename = "propagateDeletes", default)]
+    pub propagate_deletes: bool,
+    #[serde(default = "crate::config::default_enabled")]
+    pub enabled: bool,
+}
+
+/// What a sync round has to do, paths are relative and separated by `/`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    pub upload: Vec<String>,
+    pub delete: Vec<String>,
+}
+
+/// Tasks of the running jobs
+static JOBS: Mutex<Vec<tokio::task::JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// Spawns a task for every enabled job, the jobs of a previous call are
+/// stopped. Called again when `folderSyncJobs` changes.
+pub fn start() {
+    let jobs = crate::config::read_config().folder_sync_jobs.clone();
+    let mut tasks = JOBS.lock().unwrap();
+    for task in tasks.drain(..) {
+        task.abort();
+    }
+    for job in jobs.into_iter().filter(|job| job.enabled) {
+        info!("start folder sync job: {}", job.name);
+        tasks.push(crate::RUNTIME.spawn(run_job(job)));
+    }
+}
+
+async fn run_job(job: FolderSyncJob) {
+    let interval = std::time::Duration::from_secs(job.interval_secs.max(1));
+    loop {
+        match sync_once(&job).await {
+            Ok(plan) if plan.upload.is_empty() && plan.delete.is_empty() => {}
+            Ok(plan) => info!(
+                "folder sync job {}: uploaded {}, deleted {}",
+                job.name,
+                plan.upload.len(),
+                plan.delete.len()
+            ),
+            Err(e) => error!("folder sync job {} failed: {}", job.name, e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn sync_once(job: &FolderSyncJob) -> Result<SyncPlan, ClientError> {
+    let server = crate::config::read_config()
+        .find_remote_server(&job.remote_server)
+        .cloned()
+        .ok_or_else(|| {
+            ClientError::Config(format!("remote server {} not found", job.remote_server))
+        })?;
+    let local_dir = PathBuf::from(&job.local_dir);
+    let local = build_manifest(&local_dir, job.compare_hash).await?;
+
+    let mut client = RouteClient::connect(&server).await?;
+    let remote_dir = job.remote_dir.trim_matches(['/', '\\']).replace('\\', "/");
+    let remote: FolderManifest = client
+        .request_json(
+            RouteAction::FolderManifest,
+            &FolderManifestReq {
+                path: remote_dir.clone(),
+                with_hash: job.compare_hash,
+            },
+        )
+        .await?;
+
+    let mut plan = diff_manifests(&local, &remote.entries, job.compare_hash);
+    if !job.propagate_deletes {
+        plan.delete.clear();
+    }
+
+    let sizes: HashMap<&str, i64> = local.iter().map(|e| (e.path.as_str(), e.size)).collect();
+    let local_paths: Vec<PathBuf> = plan.upload.iter().map(|p| local_dir.join(p)).collect();
+    let items: Vec<UploadItem> = plan
+        .upload
+        .iter()
+        .zip(&local_paths)
+        .map(|(rel, local_path)| UploadItem {
+            local_path,
+            remote_path: if remote_dir.is_empty() {
+                rel.clone()
+            } else {
+                format!("{remote_dir}/{rel}")
+            },
+            size: sizes[rel.as_str()],
+        })
+        .collect();
+    client.upload_files(&items, true).await?;
+
+    if !plan.delete.is_empty() {
+        let head = client.new_head(RouteAction::DeleteFiles)?;
+        let req = DeleteFilesReq {
+            path: remote_dir,
+            files: plan.delete.clone(),
+        };
+        client.request(head, &serde_json::to_vec(&req)?).await?;
+    }
+    client.close().await;
+    Ok(plan)
+}
+
+/// Lists the regular files under `dir`. A missing directory is treated as empty.
+pub async fn build_manifest(dir: &Path, with_hash: bool) -> std::io::Result<Vec<ManifestEntry>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let root = dir.to_path_buf();
+    let mut entries = tokio::task::spawn_blocking(move || list_files(&root))
+        .await
+        .map_err(std::io::Error::other)??;
+    if with_hash {
+        for entry in &mut entries {
+            match crate::file::file_sha256_hex(dir.join(&entry.path)).await {
+                Ok(hash) => entry.hash = Some(hash),
+                Err(e) => warn!("hash {} failed: {}", entry.path, e),
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn list_files(root: &Path) -> std::io::Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+    for entry in walkdir::WalkDir::new(root).min_depth(1) {
+        let entry = entry.map_err(std::io::Error::other)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let metadata = entry.metadata().map_err(std::io::Error::other)?;
+        let rel = entry
+            .path()
+            .strip_prefix(root)
+            .map_err(std::io::Error::other)?;
+        let path = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let mod_time = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+        entries.push(ManifestEntry {
+            path,
+            size: metadata.len() as i64,
+            mod_time,
+            hash: None,
+        });
+    }
+    Ok(entries)
+}
+
+/// Decides which local files must be uploaded and which remote files no longer exist locally.
+///
+/// A file is uploaded when it is missing remotely or its size differs. Otherwise,
+/// hashes are compared when `compare_hash` is set, modification times when not.
+pub fn diff_manifests(
+    local: &[ManifestEntry],
+    remote: &[ManifestEntry],
+    compare_hash: bool,
+) -> SyncPlan {
+    let remote_map: HashMap<&str, &ManifestEntry> =
+        remote.iter().map(|e| (e.path.as_str(), e)).collect();
+    let local_map: HashMap<&str, &ManifestEntry> =
+        local.iter().map(|e| (e.path.as_str(), e)).collect();
+
+    let mut plan = SyncPlan::default();
+    for entry in local {
+        let changed = match remote_map.get(entry.path.as_str()) {
+            None => true,
+            Some(r) if r.size != entry.size => true,
+            Some(r) if compare_hash => r.hash.is_none() || r.hash != entry.hash,
+            Some(r) => entry.mod_time > r.mod_time,
+        };
+        if changed {
+            plan.upload.push(entry.path.clone());
+        }
+    }
+    plan.delete = remote
+        .iter()
+        .filter(|e| !local_map.contains_key(e.path.as_str()))
+        .map(|e| e.path.clone())
+        .collect();
+    plan.upload.sort();
+    plan.delete.sort();
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, size: i64, mod_time: i64, hash: Option<&str>) -> ManifestEntry {
+        ManifestEntry {
+            path: path.to_string(),
+            size,
+            mod_time,
+            hash: hash.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn diff_by_size_and_mod_time() {
+        let local = vec![
+            entry("same.txt", 3, 100, None),
+            entry("newer.txt", 3, 200, None),
+            entry("resized.txt", 4, 50, None),
+            entry("dir/missing.txt", 1, 10, None),
+        ];
+        let remote = vec![
+            entry("same.txt", 3, 150, None),
+            entry("newer.txt", 3, 150, None),
+            entry("resized.txt", 3, 150, None),
+            entry("gone.txt", 1, 1, None),
+        ];
+        let plan = diff_manifests(&local, &remote, false);
+        assert_eq!(
+            plan,
+            SyncPlan {
+                upload: vec![
+                    "dir/missing.txt".to_string(),
+                    "newer.txt".to_string(),
+                    "resized.txt".to_string()
+                ],
+                delete: vec!["gone.txt".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn diff_by_hash_ignores_mod_time() {
+        let local = vec![
+            entry("a", 1, 999, Some("aa")),
+            entry("b", 1, 1, Some("bb")),
+            entry("c", 1, 1, Some("cc")),
+        ];
+        let remote = vec![
+            entry("a", 1, 1, Some("aa")),
+            entry("b", 1, 999, Some("xx")),
+            entry("c", 1, 999, None),
+        ];
+        let plan = diff_manifests(&local, &remote, true);
+        assert_eq!(plan.upload, vec!["b".to_string(), "c".to_string()]);
+        assert!(plan.delete.is_empty());
+    }
+
+    #[tokio::test]
+    async fn manifest_uses_relative_slash_paths() {
+        let dir = std::env::temp_dir().join(format!("windsend-manifest-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub").join("a.txt"), b"abc").unwrap();
+        std::fs::write(dir.join("b.txt"), b"").unwrap();
+
+        let mut entries = build_manifest(&dir, true).await.unwrap();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, "b.txt");
+        assert_eq!(entries[1].path, "sub/a.txt");
+        assert_eq!(entries[1].size, 3);
+        assert_eq!(
+            entries[1].hash.as_deref(),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert!(build_manifest(&dir, false).await.unwrap().is_empty());
+    }
+}
@@ -72,6 +72,7 @@ pub enum LanguageKey {
     RelayDisabled,
     SettingSuccess,
     EffectiveAfterProgramRestart,
+    ConfigNotApplied,
 }
 
 impl LanguageKey {
@@ -163,6 +164,10 @@ lazy_static! {
             LanguageKey::EffectiveAfterProgramRestart,
             String::from("Effective after program restart")
         ),
+        (
+            LanguageKey::ConfigNotApplied,
+            String::from("Config file not applied")
+        ),
     ]
     .into_iter()
     .collect();
@@ -230,6 +235,10 @@ lazy_static! {
             LanguageKey::EffectiveAfterProgramRestart,
             String::from("程序重启后生效")
         ),
+        (
+            LanguageKey::ConfigNotApplied,
+            String::from("配置文件未生效")
+        ),
     ]
     .into_iter()
     .collect();
@@ -0,0 +1,494 @@
+//! Listen endpoints of the route server.
+//!
+//! Without `listenEndpoints` the server listens on `[::]:serverPort` in
+//! dual-stack mode. Otherwise every endpoint selects local addresses by IP,
+//! interface name or CIDR, and each selected address gets its own listener and
+//! accept task. An explicit `::` only accepts IPv6, so that it can be combined
+//! with `0.0.0.0`.
+//!
+//! The listeners are reconciled with the config whenever it is applied, and
+//! periodically so that addresses that appear on an interface later are
+//! picked up. Connections that were already accepted are not affected. A
+//! policy change is applied to the running listener, it is not bound again.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+/// How often interface addresses are checked for changes
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListenEndpoint {
+    /// An IP address, the name of a network interface, or a CIDR like `192.168.1.0/24`
+    pub host: String,
+    /// 0 to use `serverPort`
+    #[serde(default)]
+    pub port: u16,
+    /// Answer `match` requests, which hand out the secret key while quick pairing is on.
+    /// QUIC and relayed connections only answer them when every endpoint does.
+    #[serde(rename = "allowMatch", default = "default_allow_match")]
+    pub allow_match: bool,
+}
+
+fn default_allow_match() -> bool {
+    true
+}
+
+/// What clients of an endpoint may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EndpointPolicy {
+    pub allow_match: bool,
+}
+
+impl Default for EndpointPolicy {
+    fn default() -> Self {
+        Self { allow_match: true }
+    }
+}
+
+impl EndpointPolicy {
+    /// The policy of connections that are not accepted on a listen endpoint,
+    /// like QUIC and relayed ones: the strictest policy of all `endpoints`.
+    pub fn unbound(endpoints: &[ListenEndpoint]) -> Self {
+        Self {
+            allow_match: endpoints.iter().all(|endpoint| endpoint.allow_match),
+        }
+    }
+
+    /// [`Self::unbound`] for the configured endpoints
+    pub fn unbound_from_config() -> Self {
+        Self::unbound(&crate::config::read_config().listen_endpoints)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ListenError {
+    #[error("invalid port: {0}")]
+    InvalidPort(String),
+    #[error("no local address matches {0}")]
+    NoMatch(String),
+}
+
+/// An address of a local network interface
+#[derive(Debug, Clone)]
+struct LocalAddr {
+    interface: String,
+    ip: IpAddr,
+    index: Option<u32>,
+}
+
+fn local_addrs() -> Vec<LocalAddr> {
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .map(|interface| LocalAddr {
+                ip: interface.ip(),
+                index: interface.index,
+                interface: interface.name,
+            })
+            .collect(),
+        Err(e) => {
+            error!("get interface addresses failed, err: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// A listener the config asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ListenTarget {
+    addr: SocketAddr,
+    dual_stack: bool,
+    policy: EndpointPolicy,
+}
+
+impl ListenTarget {
+    /// What identifies the listener, its policy can change while it runs
+    fn key(&self) -> (SocketAddr, bool) {
+        (self.addr, self.dual_stack)
+    }
+}
+
+/// A running listener
+struct Listener {
+    policy: tokio::sync::watch::Sender<EndpointPolicy>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+fn socket_addr(ip: IpAddr, port: u16, scope_id: u32) -> SocketAddr {
+    match ip {
+        IpAddr::V6(ip) if ip.is_unicast_link_local() => {
+            SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))
+        }
+        ip => SocketAddr::new(ip, port),
+    }
+}
+
+impl ListenEndpoint {
+    fn resolve(
+        &self,
+        server_port: &str,
+        local: &[LocalAddr],
+    ) -> Result<Vec<SocketAddr>, ListenError> {
+        let port = match self.port {
+            0 => server_port
+                .parse()
+                .map_err(|_| ListenError::InvalidPort(server_port.to_string()))?,
+            port => port,
+        };
+        if let Ok(ip) = self.host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let addrs: Vec<SocketAddr> = match self.host.parse::<ipnet::IpNet>() {
+            Ok(net) => local
+                .iter()
+                .filter(|addr| net.contains(&addr.ip))
+                .map(|addr| socket_addr(addr.ip, port, addr.index.unwrap_or(0)))
+                .collect(),
+            Err(_) => local
+                .iter()
+                .filter(|addr| addr.interface == self.host)
+                .map(|addr| socket_addr(addr.ip, port, addr.index.unwrap_or(0)))
+                .collect(),
+        };
+        if addrs.is_empty() {
+            return Err(ListenError::NoMatch(self.host.clone()));
+        }
+        Ok(addrs)
+    }
+}
+
+fn listen_targets(
+    endpoints: &[ListenEndpoint],
+    server_port: &str,
+    local: &[LocalAddr],
+) -> (Vec<ListenTarget>, Vec<ListenError>) {
+    if endpoints.is_empty() {
+        return match server_port.parse() {
+            Ok(port) => (
+                vec![ListenTarget {
+                    addr: SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port)),
+                    dual_stack: true,
+                    policy: EndpointPolicy::default(),
+                }],
+                Vec::new(),
+            ),
+            Err(_) => (
+                Vec::new(),
+                vec![ListenError::InvalidPort(server_port.to_string())],
+            ),
+        };
+    }
+    let mut targets: Vec<ListenTarget> = Vec::new();
+    let mut errors = Vec::new();
+    for endpoint in endpoints {
+        let addrs = match endpoint.resolve(server_port, local) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        let policy = EndpointPolicy {
+            allow_match: endpoint.allow_match,
+        };
+        for addr in addrs {
+            match targets.iter_mut().find(|target| target.addr == addr) {
+                // An address selected twice gets the stricter policy.
+                Some(target) => target.policy.allow_match &= policy.allow_match,
+                None => targets.push(ListenTarget {
+                    addr,
+                    dual_stack: false,
+                    policy,
+                }),
+            }
+        }
+    }
+    (targets, errors)
+}
+
+fn bind(target: &ListenTarget) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = match target.addr {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+    };
+    {
+        let socket_ref = socket2::SockRef::from(&socket);
+        if target.addr.is_ipv6()
+            && let Err(e) = socket_ref.set_only_v6(!target.dual_stack)
+        {
+            warn!("set_only_v6 error: {}", e);
+        }
+        // Enable SO_REUSEADDR
+        if let Err(e) = socket_ref.set_reuse_address(true) {
+            warn!("Failed to set SO_REUSEADDR: {}", e);
+        }
+    }
+    socket.bind(target.addr)?;
+    socket.listen(1024)
+}
+
+static RELOAD: LazyLock<tokio::sync::watch::Sender<()>> =
+    LazyLock::new(|| tokio::sync::watch::channel(()).0);
+
+/// Asks the listeners to be rebuilt from the current config.
+pub fn reload() {
+    RELOAD.send_replace(());
+}
+
+/// Keeps the listeners in line with the config, runs forever.
+pub async fn run() {
+    let mut reload_rx = RELOAD.subscribe();
+    let mut interval = tokio::time::interval(RECONCILE_INTERVAL);
+    let mut listeners = HashMap::new();
+    let mut last_errors = Vec::new();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = reload_rx.changed() => debug!("reload listeners"),
+        }
+        reconcile(&mut listeners, &mut last_errors).await;
+    }
+}
+
+async fn reconcile(
+    listeners: &mut HashMap<(SocketAddr, bool), Listener>,
+    last_errors: &mut Vec<ListenError>,
+) {
+    let (endpoints, server_port) = {
+        let config = crate::config::read_config();
+        (config.listen_endpoints.clone(), config.server_port.clone())
+    };
+    let local = if endpoints
+        .iter()
+        .all(|endpoint| endpoint.host.parse::<IpAddr>().is_ok())
+    {
+        Vec::new()
+    } else {
+        local_addrs()
+    };
+    let (targets, errors) = listen_targets(&endpoints, &server_port, &local);
+    // Only report changes, this runs every `RECONCILE_INTERVAL`.
+    if errors != *last_errors {
+        for e in &errors {
+            warn!("skip listen endpoint: {}", e);
+        }
+        *last_errors = errors;
+    }
+    apply(listeners, targets).await;
+}
+
+/// Starts, stops and updates the listeners to match `targets`.
+async fn apply(listeners: &mut HashMap<(SocketAddr, bool), Listener>, targets: Vec<ListenTarget>) {
+    let stale: Vec<_> = listeners
+        .iter()
+        .filter(|(key, listener)| {
+            listener.handle.is_finished() || !targets.iter().any(|target| target.key() == **key)
+        })
+        .map(|(key, _)| *key)
+        .collect();
+    for key in stale {
+        let Some(listener) = listeners.remove(&key) else {
+            continue;
+        };
+        if !listener.handle.is_finished() {
+            info!("stop listening on {}", key.0);
+            listener.handle.abort();
+        }
+        // The socket is only closed once the task is dropped, it may be bound again below.
+        let _ = listener.handle.await;
+    }
+    for target in targets {
+        if let Some(listener) = listeners.get(&target.key()) {
+            listener.policy.send_if_modified(|policy| {
+                if *policy == target.policy {
+                    return false;
+                }
+                info!(
+                    "listener on {}, allow match: {}",
+                    target.addr, target.policy.allow_match
+                );
+                *policy = target.policy;
+                true
+            });
+            continue;
+        }
+        match bind(&target) {
+            Ok(listener) => {
+                info!(
+                    "program listening on {}, allow match: {}",
+                    target.addr, target.policy.allow_match
+                );
+                let (policy, policy_rx) = tokio::sync::watch::channel(target.policy);
+                let handle = tokio::spawn(accept_loop(listener, policy_rx));
+                listeners.insert(target.key(), Listener { policy, handle });
+            }
+            Err(e) => error!("listen on {} failed, err: {}", target.addr, e),
+        }
+    }
+}
+
+async fn accept_loop(
+    listener: tokio::net::TcpListener,
+    policy: tokio::sync::watch::Receiver<EndpointPolicy>,
+) {
+    loop {
+        let result = listener.accept().await;
+        if let Err(e) = result {
+            error!("accept error: {}", e);
+            #[cfg(target_os = "windows")]
+            if e.to_string().contains("WSAStartup") {
+                // The listener is bound again on the next reconcile.
+                return;
+            }
+            continue;
+        }
+        let (stream, addr) = result.unwrap();
+        info!("accept a new connection from {}", addr);
+        let permit = match crate::admission::try_admit(addr.ip().to_canonical()) {
+            Ok(permit) => permit,
+            Err(err) => {
+                warn!("reject connection({}): {}", addr, err);
+                continue;
+            }
+        };
+        // Taken per connection, the acceptor changes when the certificate is regenerated.
+        let tls_acceptor = crate::config::tls_acceptor();
+        let policy = *policy.borrow();
+        // The handshake runs in its own task, so that a slow client does not block the accept loop.
+        crate::RUNTIME.spawn(async move {
+            let _permit = permit;
+            let handshake_timeout = crate::config::read_config().admission.handshake_timeout();
+            let tls_stream =
+                match tokio::time::timeout(handshake_timeout, tls_acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => tls_stream,
+                    Ok(Err(err)) => {
+                        error!("unknown connection({}), tls accept error: {}", addr, err);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("tls handshake with {} timed out", addr);
+                        return;
+                    }
+                };
+            debug!("tls accept success");
+            crate::route::serve_transport(tls_stream, addr, policy).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local() -> Vec<LocalAddr> {
+        [
+            ("lo", "127.0.0.1", 1),
+            ("eth0", "192.168.1.20", 2),
+            ("eth0", "fe80::1", 2),
+            ("wg0", "10.8.0.2", 3),
+        ]
+        .into_iter()
+        .map(|(interface, ip, index)| LocalAddr {
+            interface: interface.to_string(),
+            ip: ip.parse().unwrap(),
+            index: Some(index),
+        })
+        .collect()
+    }
+
+    fn endpoint(host: &str, port: u16, allow_match: bool) -> ListenEndpoint {
+        ListenEndpoint {
+            host: host.to_string(),
+            port,
+            allow_match,
+        }
+    }
+
+    #[test]
+    fn no_endpoints_listen_dual_stack_on_server_port() {
+        let (targets, errors) = listen_targets(&[], "6779", &[]);
+        assert!(errors.is_empty());
+        assert_eq!(
+            targets,
+            vec![ListenTarget {
+                addr: "[::]:6779".parse().unwrap(),
+                dual_stack: true,
+                policy: EndpointPolicy::default(),
+            }]
+        );
+    }
+
+    #[test]
+    fn endpoints_select_addresses_by_ip_interface_and_cidr() {
+        let endpoints = [
+            endpoint("0.0.0.0", 7000, true),
+            endpoint("eth0", 0, false),
+            endpoint("10.8.0.0/24", 0, true),
+            endpoint("192.168.1.0/24", 0, true),
+            endpoint("tun9", 0, true),
+        ];
+        let (targets, errors) = listen_targets(&endpoints, "6779", &local());
+        assert_eq!(errors, vec![ListenError::NoMatch("tun9".to_string())]);
+        let addrs: Vec<_> = targets
+            .iter()
+            .map(|t| (t.addr.to_string(), t.policy.allow_match))
+            .collect();
+        assert_eq!(
+            addrs,
+            [
+                ("0.0.0.0:7000", true),
+                // Selected again by the CIDR, but `eth0` does not allow match.
+                ("192.168.1.20:6779", false),
+                ("[fe80::1%2]:6779", false),
+                ("10.8.0.2:6779", true),
+            ]
+            .map(|(addr, allow)| (addr.to_string(), allow))
+        );
+        assert!(targets.iter().all(|t| !t.dual_stack));
+    }
+
+    #[test]
+    fn unbound_connections_get_the_strictest_policy() {
+        assert!(EndpointPolicy::unbound(&[]).allow_match);
+        assert!(EndpointPolicy::unbound(&[endpoint("eth0", 0, true)]).allow_match);
+        let endpoints = [endpoint("eth0", 0, true), endpoint("wlan0", 0, false)];
+        assert!(!EndpointPolicy::unbound(&endpoints).allow_match);
+    }
+
+    #[tokio::test]
+    async fn policy_changes_keep_the_listener_and_stopped_ones_free_their_address() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let target = |dual_stack, allow_match| ListenTarget {
+            addr,
+            dual_stack,
+            policy: EndpointPolicy { allow_match },
+        };
+        let mut listeners = HashMap::new();
+        apply(&mut listeners, vec![target(false, true)]).await;
+        let policy = listeners[&(addr, false)].policy.subscribe();
+
+        apply(&mut listeners, vec![target(false, false)]).await;
+        assert!(!policy.borrow().allow_match);
+        assert!(!listeners[&(addr, false)].handle.is_finished());
+
+        // A listener on the same address is only bound after the old one is gone.
+        apply(&mut listeners, vec![target(true, false)]).await;
+        assert_eq!(listeners.len(), 1);
+        assert!(!listeners[&(addr, true)].handle.is_finished());
+    }
+
+    #[test]
+    fn invalid_server_port_is_reported() {
+        let (targets, errors) = listen_targets(&[endpoint("eth0", 0, true)], "", &local());
+        assert!(targets.is_empty());
+        assert_eq!(errors, vec![ListenError::InvalidPort(String::new())]);
+    }
+}
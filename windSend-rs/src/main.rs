@@ -1,15 +1,24 @@
 // hide console window on Windows in release
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use tracing::{debug, error, info, trace, warn};
+use tracing::{error, trace};
+mod admission;
+mod archive;
 mod config;
+mod config_reload;
 mod file;
+mod folder_sync;
 mod language;
+mod listen;
+mod port_mapping;
+mod quic;
 mod relay;
 mod route;
+mod save_rules;
 mod status;
 mod sync;
 mod utils;
+mod watch_folder;
 use std::sync::LazyLock;
 
 // #[cfg(not(all(target_os = "linux", target_env = "musl")))]
@@ -72,6 +81,11 @@ fn panic_hook(info: &std::panic::PanicHookInfo) {
 }
 
 fn main() {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("relay-server") {
+        relay::server::main(args.next());
+        return;
+    }
     utils::fix_working_directory_if_needed();
     init();
     utils::log_path_info();
@@ -90,10 +104,10 @@ fn main() {
     {
         let show_systray_icon = config::GLOBAL_CONFIG.read().unwrap().show_systray_icon;
         if !show_systray_icon {
-            return RUNTIME.block_on(async_main());
+            return RUNTIME.block_on(run_until_shutdown());
         }
 
-        let main_handle = std::thread::spawn(|| {
+        std::thread::spawn(|| {
             RUNTIME.block_on(async_main());
         });
 
@@ -110,19 +124,58 @@ fn main() {
         };
 
         let return_code = systray::show_systray(rm);
-        info!("systray return code: {:?}", return_code);
+        tracing::info!("systray return code: {:?}", return_code);
         match return_code {
-            systray::ReturnCode::Quit => (),
-            systray::ReturnCode::HideIcon => main_handle.join().unwrap(),
+            systray::ReturnCode::Quit => RUNTIME.block_on(port_mapping::stop()),
+            systray::ReturnCode::HideIcon => RUNTIME.block_on(async {
+                shutdown_signal().await;
+                port_mapping::stop().await;
+            }),
         }
     }
     #[cfg(feature = "disable-systray-support")]
     {
-        RUNTIME.block_on(async_main());
+        RUNTIME.block_on(run_until_shutdown());
+    }
+}
+
+/// Runs without the systray until ctrl-c or SIGTERM, then cleans up like the
+/// systray's quit does.
+async fn run_until_shutdown() {
+    tokio::select! {
+        _ = async_main() => {}
+        _ = shutdown_signal() => {}
+    }
+    port_mapping::stop().await;
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => tracing::warn!("listen for SIGTERM error: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::warn!("listen for ctrl-c error: {}", e);
+        std::future::pending::<()>().await;
     }
 }
 
 async fn async_main() {
+    folder_sync::start();
+    watch_folder::start();
+    quic::start();
+    port_mapping::start();
+    config_reload::start();
     loop {
         _async_main().await;
         tokio::time::sleep(std::time::Duration::from_secs(5)).await;
@@ -132,59 +185,9 @@ async fn async_main() {
 async fn _async_main() {
     trace!("async_main");
 
-    {
-        let config = config::read_config();
-        if config.enable_relay && !config.relay_server_address.is_empty() {
-            relay::run::tick_relay();
-        }
+    if !config::read_config().relay_endpoints().is_empty() {
+        relay::run::tick_relay();
     }
 
-    let server_port = config::GLOBAL_CONFIG
-        .read()
-        .unwrap()
-        .server_port
-        .parse::<u16>()
-        .expect("parse server port error");
-    let socket = tokio::net::TcpSocket::new_v6().unwrap();
-    {
-        let socket_ref = socket2::SockRef::from(&socket);
-        if let Err(e) = socket_ref.set_only_v6(false) {
-            warn!("set_only_v6 error: {}", e);
-        }
-        // Enable SO_REUSEADDR
-        if let Err(e) = socket_ref.set_reuse_address(true) {
-            warn!("Failed to set SO_REUSEADDR: {}", e);
-        }
-    }
-    socket
-        .bind((std::net::Ipv6Addr::UNSPECIFIED, server_port).into())
-        .expect("bind error");
-    let listener = socket.listen(1024).expect("listen error");
-    info!("program listening on {}", listener.local_addr().unwrap());
-    let tls_acceptor = config::TLS_ACCEPTOR.clone();
-    loop {
-        let result = listener.accept().await;
-        if let Err(e) = result {
-            error!("accept error: {}", e);
-            #[cfg(target_os = "windows")]
-            if e.to_string().contains("WSAStartup") {
-                // restart listener
-                return;
-            }
-            continue;
-        }
-        let (stream, addr) = result.unwrap();
-        info!("accept a new connection from {}", addr);
-        // tls_acceptor.accept_with(stream, f)
-        let tls_stream = match tls_acceptor.accept(stream).await {
-            Ok(tls_stream) => tls_stream,
-            Err(err) => {
-                error!("unknown connection({}), tls accept error: {}", addr, err);
-                // panic!("tls accept error: {}", err);
-                continue;
-            }
-        };
-        debug!("tls accept success");
-        RUNTIME.spawn(route::main_process(tls_stream));
-    }
+    listen::run().await;
 }
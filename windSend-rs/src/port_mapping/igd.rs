@@ -0,0 +1,495 @@
+//! UPnP Internet Gateway Device: the router is found with SSDP, its device
+//! description lists the WANIPConnection or WANPPPConnection service, and
+//! port mappings are SOAP calls to the control URL of that service.
+
+use super::{Mapping, Protocol};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Multicast address of SSDP searches
+pub const SSDP_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+
+const SEARCH_TARGETS: [&str; 2] = [
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:1",
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:2",
+];
+
+/// In order of preference
+const SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+const DISCOVER_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_HTTP_RESPONSE: u64 = 256 * 1024;
+
+const MAPPING_DESCRIPTION: &str = "WindSend";
+
+/// The gateway only supports leases without expiry
+const ERROR_ONLY_PERMANENT_LEASES: u32 = 725;
+
+/// Searches for a gateway device with SSDP and returns the URL of its
+/// description. Only answers from `gateway` are accepted if it is set.
+pub async fn discover(ssdp: SocketAddr, gateway: Option<IpAddr>) -> Result<String, String> {
+    let socket = tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .map_err(|e| format!("bind ssdp socket error: {e}"))?;
+    for target in SEARCH_TARGETS {
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {target}\r\n\r\n"
+        );
+        socket
+            .send_to(search.as_bytes(), ssdp)
+            .await
+            .map_err(|e| format!("send ssdp search error: {e}"))?;
+    }
+    let deadline = tokio::time::Instant::now() + DISCOVER_TIMEOUT;
+    let mut buf = [0u8; 2048];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (n, from) = received.map_err(|e| format!("receive ssdp answer error: {e}"))?;
+        if gateway.is_some_and(|gateway| gateway != from.ip()) {
+            continue;
+        }
+        let answer = String::from_utf8_lossy(&buf[..n]);
+        if let Some(location) = header(&answer, "location") {
+            return Ok(location.to_string());
+        }
+    }
+    Err("no UPnP gateway answered".to_string())
+}
+
+/// The connection service of a gateway device
+#[derive(Debug)]
+pub struct Service {
+    service_type: String,
+    /// `host:port` and path of the control URL
+    authority: String,
+    path: String,
+    /// Address of this device on the gateway's network
+    local_ip: IpAddr,
+}
+
+impl Service {
+    /// Reads the device description at `location`.
+    pub async fn from_description(location: &str) -> Result<Self, String> {
+        let (authority, path) = split_url(location)?;
+        let req = format!("GET {path} HTTP/1.1\r\nHost: {authority}\r\nConnection: close\r\n\r\n");
+        let (status, description, local_ip) = http(authority, &req).await?;
+        if status != 200 {
+            return Err(format!("get {location} failed, status: {status}"));
+        }
+        let base = element(&description, "URLBase")
+            .filter(|base| !base.is_empty())
+            .unwrap_or(location);
+        let services = elements(&description, "service");
+        for service_type in SERVICE_TYPES {
+            let control_url = services.iter().find_map(|service| {
+                (element(service, "serviceType")? == service_type)
+                    .then(|| element(service, "controlURL"))?
+            });
+            if let Some(control_url) = control_url {
+                let url = resolve_url(base, &unescape(control_url))?;
+                let (authority, path) = split_url(&url)?;
+                return Ok(Self {
+                    service_type: service_type.to_string(),
+                    authority: authority.to_string(),
+                    path: path.to_string(),
+                    local_ip,
+                });
+            }
+        }
+        Err(format!("{location} has no WAN connection service"))
+    }
+
+    pub async fn map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<Mapping, String> {
+        // The gateway does not pick a port, the request names one.
+        let external_port = if external_port == 0 {
+            internal_port
+        } else {
+            external_port
+        };
+        match self
+            .add_port_mapping(protocol, internal_port, external_port, lifetime)
+            .await
+        {
+            Err(SoapError::Fault(ERROR_ONLY_PERMANENT_LEASES, _)) if lifetime != 0 => {
+                // Renewing keeps working, the mapping is deleted on shutdown.
+                self.add_port_mapping(protocol, internal_port, external_port, 0)
+                    .await
+            }
+            result => result,
+        }
+        .map_err(|e| e.to_string())?;
+        let ip = self
+            .call("GetExternalIPAddress", &[])
+            .await
+            .map_err(|e| e.to_string())?;
+        let ip = element(&ip, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .ok_or("invalid GetExternalIPAddress response")?;
+        Ok(Mapping {
+            protocol,
+            internal_port,
+            external: SocketAddr::new(ip, external_port),
+            lifetime: Duration::from_secs(lifetime.into()),
+        })
+    }
+
+    pub async fn unmap(&self, mapping: &Mapping) -> Result<(), String> {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", mapping.external.port().to_string()),
+            ("NewProtocol", mapping.protocol.name().to_string()),
+        ];
+        self.call("DeletePortMapping", &args)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn add_port_mapping(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<String, SoapError> {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", external_port.to_string()),
+            ("NewProtocol", protocol.name().to_string()),
+            ("NewInternalPort", internal_port.to_string()),
+            ("NewInternalClient", self.local_ip.to_string()),
+            ("NewEnabled", "1".to_string()),
+            ("NewPortMappingDescription", MAPPING_DESCRIPTION.to_string()),
+            ("NewLeaseDuration", lifetime.to_string()),
+        ];
+        self.call("AddPortMapping", &args).await
+    }
+
+    /// Calls `action` and returns the response body.
+    async fn call(&self, action: &str, args: &[(&str, String)]) -> Result<String, SoapError> {
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{name}>{}</{name}>", escape(value)))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+             <u:{action} xmlns:u=\"{}\">{args}</u:{action}></s:Body></s:Envelope>\r\n",
+            self.service_type
+        );
+        let req = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+             SOAPAction: \"{}#{action}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.path,
+            self.authority,
+            self.service_type,
+            body.len()
+        );
+        let (status, resp, _) = http(&self.authority, &req)
+            .await
+            .map_err(SoapError::Other)?;
+        if status == 200 {
+            return Ok(resp);
+        }
+        let code = element(&resp, "errorCode").and_then(|code| code.parse().ok());
+        let description = element(&resp, "errorDescription").unwrap_or_default();
+        Err(match code {
+            Some(code) => SoapError::Fault(code, format!("{action} failed: {code} {description}")),
+            None => SoapError::Other(format!("{action} failed, status: {status}")),
+        })
+    }
+}
+
+#[derive(Debug)]
+enum SoapError {
+    /// UPnP error code and message
+    Fault(u32, String),
+    Other(String),
+}
+
+impl std::fmt::Display for SoapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fault(_, msg) | Self::Other(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+/// Sends `req` to `authority` and returns the status, the body, and the
+/// local address of the connection.
+async fn http(authority: &str, req: &str) -> Result<(u16, String, IpAddr), String> {
+    let exchange = async {
+        let mut conn = tokio::net::TcpStream::connect(authority).await?;
+        let local_ip = conn.local_addr()?.ip();
+        conn.write_all(req.as_bytes()).await?;
+        let mut resp = Vec::new();
+        (&mut conn)
+            .take(MAX_HTTP_RESPONSE)
+            .read_to_end(&mut resp)
+            .await?;
+        std::io::Result::Ok((resp, local_ip))
+    };
+    let (resp, local_ip) = tokio::time::timeout(HTTP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| format!("http request to {authority} timed out"))?
+        .map_err(|e| format!("http request to {authority} error: {e}"))?;
+    let head_len = find(&resp, b"\r\n\r\n")
+        .ok_or_else(|| format!("invalid http response from {authority}"))?;
+    let head = String::from_utf8_lossy(&resp[..head_len]);
+    let body = &resp[head_len + 4..];
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| format!("invalid http response from {authority}"))?;
+    let chunked =
+        header(&head, "transfer-encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked"));
+    let body = match chunked {
+        true => String::from_utf8_lossy(&dechunk(body)).into_owned(),
+        false => String::from_utf8_lossy(body).into_owned(),
+    };
+    Ok((status, body, local_ip))
+}
+
+/// Chunk sizes count bytes, the body is only decoded once it is joined.
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    while let Some(line_len) = find(body, b"\r\n") {
+        let size = String::from_utf8_lossy(&body[..line_len]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let Ok(size) = usize::from_str_radix(size, 16) else {
+            break;
+        };
+        let rest = &body[line_len + 2..];
+        if size == 0 || rest.len() < size {
+            break;
+        }
+        out.extend_from_slice(&rest[..size]);
+        body = &rest[size..];
+        while let Some(next) = body.strip_prefix(b"\r\n") {
+            body = next;
+        }
+    }
+    out
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Value of the header `name` in an HTTP head
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// `host:port` and path of an http URL
+fn split_url(url: &str) -> Result<(&str, &str), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("unsupported url: {url}"))?;
+    Ok(match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    })
+}
+
+fn resolve_url(base: &str, url: &str) -> Result<String, String> {
+    if url.starts_with("http://") {
+        return Ok(url.to_string());
+    }
+    let (authority, path) = split_url(base)?;
+    if url.starts_with('/') {
+        return Ok(format!("http://{authority}{url}"));
+    }
+    let dir = &path[..=path.rfind('/').unwrap_or(0)];
+    Ok(format!("http://{authority}{dir}{url}"))
+}
+
+/// Contents of the elements called `name`, with any namespace prefix
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(tag_end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[..tag_end];
+        let tag_name = tag.split_whitespace().next().unwrap_or_default();
+        if tag_name.rsplit(':').next() != Some(name) {
+            continue;
+        }
+        if tag.ends_with('/') {
+            found.push("");
+            continue;
+        }
+        let content = &rest[tag_end + 1..];
+        let close = format!("</{tag_name}>");
+        if let Some(end) = content.find(&close) {
+            found.push(content[..end].trim());
+            rest = &content[end + close.len()..];
+        }
+    }
+    found
+}
+
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    elements(xml, name).into_iter().next()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const DESCRIPTION: &str = "<?xml version=\"1.0\"?>\
+        <root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device>\
+        <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>\
+        <deviceList><device><deviceList><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>\
+        <controlURL>/ppp</controlURL></service>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <controlURL>ctl/IPConn</controlURL></service>\
+        </serviceList></device></deviceList></device></deviceList></device></root>";
+
+    /// Answers SSDP searches and serves the description and the SOAP actions
+    /// of a gateway that only supports permanent leases. Returns the SSDP
+    /// address and the SOAP actions it got.
+    async fn fake_gateway() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_addr = listener.local_addr().unwrap();
+        let ssdp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ssdp_addr = ssdp.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let (n, from) = ssdp.recv_from(&mut buf).await.unwrap();
+            assert!(buf[..n].starts_with(b"M-SEARCH * HTTP/1.1\r\n"));
+            let answer = format!(
+                "HTTP/1.1 200 OK\r\nST: {}\r\nLocation: http://{http_addr}/rootDesc.xml\r\n\r\n",
+                SEARCH_TARGETS[0]
+            );
+            ssdp.send_to(answer.as_bytes(), from).await.unwrap();
+        });
+
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        let seen = actions.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0u8; 4096];
+                let req = loop {
+                    let n = conn.read(&mut buf).await.unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&req).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let len =
+                            header(head, "content-length").map_or(0, |len| len.parse().unwrap());
+                        if body.len() >= len {
+                            break text;
+                        }
+                    }
+                };
+                let (status, body) = if req.starts_with("GET /rootDesc.xml ") {
+                    (200, DESCRIPTION.to_string())
+                } else {
+                    assert!(req.starts_with("POST /ctl/IPConn "));
+                    let action = header(&req, "soapaction").unwrap();
+                    let action = action.trim_matches('"').rsplit('#').next().unwrap();
+                    let lease = element(&req, "NewLeaseDuration");
+                    seen.lock()
+                        .unwrap()
+                        .push(format!("{action} {}", lease.unwrap_or_default()));
+                    match action {
+                        "AddPortMapping" if lease != Some("0") => (
+                            500,
+                            "<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                             <errorCode>725</errorCode>\
+                             <errorDescription>OnlyPermanentLeasesSupported</errorDescription>\
+                             </UPnPError></detail></s:Fault></s:Body></s:Envelope>"
+                                .to_string(),
+                        ),
+                        "GetExternalIPAddress" => (
+                            200,
+                            "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                             <NewExternalIPAddress>198.51.100.4</NewExternalIPAddress>\
+                             </u:GetExternalIPAddressResponse></s:Body></s:Envelope>"
+                                .to_string(),
+                        ),
+                        _ => (200, "<s:Envelope><s:Body/></s:Envelope>".to_string()),
+                    }
+                };
+                let resp = format!(
+                    "HTTP/1.1 {status} X\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{body}\r\n0\r\n\r\n",
+                    body.len()
+                );
+                conn.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        (ssdp_addr, actions)
+    }
+
+    #[test]
+    fn chunks_are_joined_before_decoding() {
+        // "é" split between two chunks.
+        let body = b"3\r\nab\xc3\r\n2\r\n\xa9c\r\n0\r\n\r\n";
+        assert_eq!(dechunk(body), "abéc".as_bytes());
+        assert_eq!(dechunk(b"5\r\nab"), b"");
+        assert_eq!(dechunk(b"zz\r\nab"), b"");
+    }
+
+    #[tokio::test]
+    async fn map_on_a_gateway_device() {
+        let (ssdp, actions) = fake_gateway().await;
+        let location = discover(ssdp, None).await.unwrap();
+        let service = Service::from_description(&location).await.unwrap();
+        assert_eq!(service.path, "/ctl/IPConn");
+
+        let mapping = service.map(Protocol::Tcp, 6779, 0, 3600).await.unwrap();
+        assert_eq!(mapping.external, "198.51.100.4:6779".parse().unwrap());
+        service.unmap(&mapping).await.unwrap();
+        assert_eq!(
+            *actions.lock().unwrap(),
+            [
+                "AddPortMapping 3600",
+                "AddPortMapping 0",
+                "GetExternalIPAddress ",
+                "DeletePortMapping "
+            ]
+        );
+    }
+}
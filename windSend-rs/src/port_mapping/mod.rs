@@ -0,0 +1,463 @@
+//! Optional port mapping on the gateway, so that devices outside the local
+//! network reach this one directly, without a relay server.
+//!
+//! With `portMapping.enabled`, the TCP port `serverPort`, and the UDP port of
+//! the QUIC listener if it is enabled, are mapped with PCP or NAT-PMP, see
+//! [`pcp`], or else with UPnP IGD, see [`igd`]. Mappings are renewed at half
+//! their lifetime and removed on shutdown.
+//!
+//! The external address of the TCP mapping is added to `externalIPs` and to
+//! the server certificate, which is signed by the same CA so paired devices
+//! keep trusting it, and it is sent to devices when they pair, see
+//! [`external_addresses`].
+
+pub mod igd;
+pub mod pcp;
+
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Configurable as `portMapping`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PortMappingConfig {
+    pub enabled: bool,
+    /// PCP/NAT-PMP server as `ip` or `ip:port`, the `.1` address of the
+    /// local network if empty. UPnP gateways only answer from this address
+    /// when it is set.
+    pub gateway: String,
+    /// Port requested on the gateway, 0 for the internal port
+    #[serde(rename = "externalPort")]
+    pub external_port: u16,
+    /// Requested lifetime of the mappings, at least [`MIN_LEASE_SECS`]
+    #[serde(rename = "leaseSecs")]
+    pub lease_secs: u32,
+}
+
+impl Default for PortMappingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            gateway: String::new(),
+            external_port: 0,
+            lease_secs: 3600,
+        }
+    }
+}
+
+/// A lifetime of 0 deletes PCP and NAT-PMP mappings
+pub const MIN_LEASE_SECS: u32 = 120;
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(30);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Time the mappings have to be removed on shutdown
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    /// IANA protocol number
+    fn number(self) -> u8 {
+        match self {
+            Self::Tcp => 6,
+            Self::Udp => 17,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Tcp => "TCP",
+            Self::Udp => "UDP",
+        }
+    }
+}
+
+/// A port mapped on the gateway
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    pub external: SocketAddr,
+    /// Granted by the gateway
+    pub lifetime: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Request {
+    protocol: Protocol,
+    internal_port: u16,
+    external_port: u16,
+}
+
+enum Gateway {
+    Pcp(pcp::Client),
+    Igd(igd::Service),
+}
+
+impl Gateway {
+    /// Tries PCP/NAT-PMP, then UPnP IGD, and keeps the first one that maps `req`.
+    async fn find(
+        config: &PortMappingConfig,
+        req: Request,
+        lifetime: u32,
+    ) -> Result<(Self, Mapping), String> {
+        let mut errors = Vec::new();
+        let gateway = match pcp_server(&config.gateway) {
+            Ok(server) => {
+                let mut client = pcp::Client::new(server);
+                match client
+                    .map(req.protocol, req.internal_port, req.external_port, lifetime)
+                    .await
+                {
+                    Ok(mapping) => return Ok((Self::Pcp(client), mapping)),
+                    Err(e) => errors.push(format!("pcp/nat-pmp {server}: {e}")),
+                }
+                Some(server.ip())
+            }
+            Err(e) => {
+                errors.push(format!("pcp/nat-pmp: {e}"));
+                None
+            }
+        };
+        let gateway = gateway.filter(|_| !config.gateway.is_empty());
+        let igd = async {
+            let location = igd::discover(igd::SSDP_ADDR, gateway).await?;
+            let service = igd::Service::from_description(&location).await?;
+            let mapping = service
+                .map(req.protocol, req.internal_port, req.external_port, lifetime)
+                .await?;
+            Ok::<_, String>((Self::Igd(service), mapping))
+        };
+        match igd.await {
+            Ok(found) => Ok(found),
+            Err(e) => {
+                errors.push(format!("upnp: {e}"));
+                Err(errors.join("; "))
+            }
+        }
+    }
+
+    async fn map(&mut self, req: Request, lifetime: u32) -> Result<Mapping, String> {
+        match self {
+            Self::Pcp(client) => {
+                client
+                    .map(req.protocol, req.internal_port, req.external_port, lifetime)
+                    .await
+            }
+            Self::Igd(service) => {
+                service
+                    .map(req.protocol, req.internal_port, req.external_port, lifetime)
+                    .await
+            }
+        }
+    }
+
+    async fn unmap(&mut self, mapping: &Mapping) -> Result<(), String> {
+        match self {
+            Self::Pcp(client) => client.unmap(mapping).await,
+            Self::Igd(service) => service.unmap(mapping).await,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Pcp(client) if client.is_nat_pmp() => "NAT-PMP",
+            Self::Pcp(_) => "PCP",
+            Self::Igd(_) => "UPnP IGD",
+        }
+    }
+}
+
+struct Active {
+    gateway: Gateway,
+    mappings: Vec<Mapping>,
+}
+
+static ACTIVE: LazyLock<tokio::sync::Mutex<Option<Active>>> =
+    LazyLock::new(|| tokio::sync::Mutex::new(None));
+static TASK: Mutex<Option<tokio::task::JoinHandle<()>>> = Mutex::new(None);
+/// External address of `serverPort`
+static EXTERNAL: Mutex<Option<SocketAddr>> = Mutex::new(None);
+
+/// Starts mapping the ports if it is enabled.
+pub fn start() {
+    let (config, requests) = {
+        let config = crate::config::read_config();
+        let mut requests = Vec::new();
+        match config.server_port.parse() {
+            Ok(port) => requests.push(Request {
+                protocol: Protocol::Tcp,
+                internal_port: port,
+                external_port: config.port_mapping.external_port,
+            }),
+            Err(_) => error!("invalid serverPort: {}", config.server_port),
+        }
+        if config.quic.enabled
+            && let Some(port) = config.quic.listen_port(&config.server_port)
+        {
+            requests.push(Request {
+                protocol: Protocol::Udp,
+                internal_port: port,
+                external_port: config.port_mapping.external_port,
+            });
+        }
+        (config.port_mapping.clone(), requests)
+    };
+    if !config.enabled || requests.is_empty() {
+        return;
+    }
+    *TASK.lock().unwrap() = Some(crate::RUNTIME.spawn(run(config, requests)));
+}
+
+/// Removes the mappings from the gateway, called on shutdown.
+pub async fn stop() {
+    if let Some(task) = TASK.lock().unwrap().take() {
+        task.abort();
+    }
+    let unmap = async {
+        let Some(mut active) = ACTIVE.lock().await.take() else {
+            return;
+        };
+        for mapping in &active.mappings {
+            match active.gateway.unmap(mapping).await {
+                Ok(()) => info!("removed port mapping {}", mapping.external),
+                Err(e) => warn!("remove port mapping {} error: {}", mapping.external, e),
+            }
+        }
+    };
+    if tokio::time::timeout(STOP_TIMEOUT, unmap).await.is_err() {
+        warn!("removing port mappings timed out");
+    }
+}
+
+/// `ip:port` addresses other devices reach `serverPort` at through the gateway
+pub fn external_addresses() -> Vec<String> {
+    EXTERNAL
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect()
+}
+
+/// The external IP of `serverPort`, if it is mapped
+pub fn external_ip() -> Option<std::net::IpAddr> {
+    EXTERNAL.lock().unwrap().map(|addr| addr.ip())
+}
+
+async fn run(config: PortMappingConfig, requests: Vec<Request>) {
+    let lifetime = config.lease_secs.max(MIN_LEASE_SECS);
+    loop {
+        let wait = match map_all(&config, &requests, lifetime).await {
+            Ok(lifetime) => (lifetime / 2).max(MIN_RENEW_INTERVAL),
+            Err(e) => {
+                warn!("port mapping failed: {}", e);
+                RETRY_INTERVAL
+            }
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Maps or renews all ports and returns the shortest granted lifetime.
+async fn map_all(
+    config: &PortMappingConfig,
+    requests: &[Request],
+    lifetime: u32,
+) -> Result<Duration, String> {
+    let mut active = ACTIVE.lock().await;
+    let mut mappings = Vec::new();
+    let current = match &mut *active {
+        Some(current) => current,
+        None => {
+            let (gateway, mapping) = Gateway::find(config, requests[0], lifetime).await?;
+            info!("port mapping with {}", gateway.name());
+            mappings.push(mapping);
+            active.insert(Active {
+                gateway,
+                mappings: Vec::new(),
+            })
+        }
+    };
+    for &req in &requests[mappings.len()..] {
+        match current.gateway.map(req, lifetime).await {
+            Ok(mapping) => mappings.push(mapping),
+            Err(e) => {
+                // Find the gateway again next time, it may have changed. Nothing
+                // removes the granted mappings once they are forgotten.
+                let renewed = |old: &Mapping| {
+                    mappings.iter().any(|mapping| {
+                        mapping.protocol == old.protocol
+                            && mapping.internal_port == old.internal_port
+                    })
+                };
+                let granted: Vec<Mapping> = current
+                    .mappings
+                    .iter()
+                    .filter(|old| !renewed(old))
+                    .chain(&mappings)
+                    .copied()
+                    .collect();
+                for mapping in &granted {
+                    if let Err(e) = current.gateway.unmap(mapping).await {
+                        warn!("remove port mapping {} error: {}", mapping.external, e);
+                    }
+                }
+                *active = None;
+                return Err(e);
+            }
+        }
+    }
+    publish(mappings[0].external);
+    let shortest = mappings.iter().map(|mapping| mapping.lifetime).min();
+    current.mappings = mappings;
+    Ok(shortest.unwrap_or_default())
+}
+
+/// Makes the external address of `serverPort` known. Until the certificate
+/// covers it, this is retried with every renewal.
+fn publish(external: SocketAddr) {
+    let previous = *EXTERNAL.lock().unwrap();
+    if previous == Some(external) {
+        return;
+    }
+    let ip = external.ip();
+    if !ip.is_unspecified() {
+        {
+            let mut config = crate::config::write_config();
+            if replace_ip(&mut config.external_ips, previous.map(|addr| addr.ip()), ip)
+                && let Err(e) = config.save()
+            {
+                error!("save config error: {}", e);
+            }
+        }
+        match crate::config::add_certificate_ip(ip) {
+            Ok(true) => info!("added {} to the server certificate", ip),
+            Ok(false) => {}
+            Err(e) => {
+                error!("add {} to the server certificate error: {}", ip, e);
+                return;
+            }
+        }
+    }
+    info!("serverPort is mapped to {}", external);
+    *EXTERNAL.lock().unwrap() = Some(external);
+}
+
+/// Replaces the previously mapped address in `externalIPs` with `ip`.
+/// Returns whether the list changed.
+fn replace_ip(ips: &mut Option<Vec<String>>, previous: Option<IpAddr>, ip: IpAddr) -> bool {
+    let list = ips.get_or_insert_with(Vec::new);
+    let before = list.clone();
+    if let Some(previous) = previous {
+        list.retain(|listed| listed.parse() != Ok(previous));
+    }
+    if !list.iter().any(|listed| listed.parse() == Ok(ip)) {
+        list.push(ip.to_string());
+    }
+    *list != before
+}
+
+/// The PCP/NAT-PMP server: `gateway`, or the `.1` address of the network
+/// of the default route.
+fn pcp_server(gateway: &str) -> Result<SocketAddr, String> {
+    if !gateway.is_empty() {
+        return gateway
+            .parse::<SocketAddr>()
+            .or_else(|_| gateway.parse::<IpAddr>().map(|ip| (ip, pcp::PORT).into()))
+            .map_err(|_| format!("invalid gateway: {gateway}"));
+    }
+    // Connecting a UDP socket only picks the route, nothing is sent.
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9))?;
+            socket.local_addr()
+        })
+        .map_err(|e| format!("find the local network error: {e}"))?;
+    match socket.ip() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Ok((Ipv4Addr::new(a, b, c, 1), pcp::PORT).into())
+        }
+        IpAddr::V6(_) => Err("no IPv4 default route".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_failed_mapping_removes_the_granted_ones() {
+        // A PCP gateway that maps TCP but has no room for UDP.
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway = socket.local_addr().unwrap();
+        let seen = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let requests_seen = seen.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+                let req = &buf[..n];
+                let lifetime = u32::from_be_bytes(req[4..8].try_into().unwrap());
+                let protocol = req[36];
+                requests_seen.lock().unwrap().push((protocol, lifetime));
+                let result = if protocol == 17 && lifetime > 0 { 8 } else { 0 };
+                let mut resp = vec![2, 0x81, 0, result];
+                resp.extend(lifetime.to_be_bytes());
+                resp.extend([0; 16]);
+                resp.extend(&req[24..42]);
+                resp.extend(40000u16.to_be_bytes());
+                resp.extend(Ipv4Addr::new(203, 0, 113, 9).to_ipv6_mapped().octets());
+                socket.send_to(&resp, from).await.unwrap();
+            }
+        });
+
+        let config = PortMappingConfig {
+            enabled: true,
+            gateway: gateway.to_string(),
+            ..Default::default()
+        };
+        let requests = [Protocol::Tcp, Protocol::Udp].map(|protocol| Request {
+            protocol,
+            internal_port: 6779,
+            external_port: 0,
+        });
+        assert!(map_all(&config, &requests, 3600).await.is_err());
+        assert!(ACTIVE.lock().await.is_none());
+        assert_eq!(*seen.lock().unwrap(), [(6, 3600), (17, 3600), (6, 0)]);
+    }
+
+    #[test]
+    fn mapped_ip_replaces_the_previous_one() {
+        let old: IpAddr = "198.51.100.4".parse().unwrap();
+        let new: IpAddr = "198.51.100.5".parse().unwrap();
+        let mut ips = None;
+        assert!(replace_ip(&mut ips, None, old));
+        assert_eq!(ips, Some(vec!["198.51.100.4".to_string()]));
+        assert!(!replace_ip(&mut ips, None, old));
+
+        ips.as_mut().unwrap().insert(0, "203.0.113.1".to_string());
+        assert!(replace_ip(&mut ips, Some(old), new));
+        assert_eq!(
+            ips,
+            Some(vec!["203.0.113.1".to_string(), "198.51.100.5".to_string()])
+        );
+
+        assert_eq!(
+            pcp_server("192.168.1.254"),
+            Ok("192.168.1.254:5351".parse().unwrap())
+        );
+        assert_eq!(
+            pcp_server("127.0.0.1:15351"),
+            Ok("127.0.0.1:15351".parse().unwrap())
+        );
+        assert!(pcp_server("router").is_err());
+    }
+}
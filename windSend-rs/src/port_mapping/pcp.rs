@@ -0,0 +1,340 @@
+//! PCP (RFC 6887) and the older NAT-PMP (RFC 6886) it replaces.
+//!
+//! Both run over UDP on the gateway's port [`PORT`]. A NAT-PMP gateway
+//! answers a PCP request with "unsupported version", the client then keeps
+//! using NAT-PMP with that gateway.
+
+use super::{Mapping, Protocol};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// UDP port of PCP and NAT-PMP servers
+pub const PORT: u16 = 5351;
+
+const NATPMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const RESPONSE_BIT: u8 = 0x80;
+
+const PCP_OP_MAP: u8 = 1;
+const NATPMP_OP_EXTERNAL_ADDRESS: u8 = 0;
+
+const RESULT_SUCCESS: u16 = 0;
+/// The same code in both protocols
+const RESULT_UNSUPPORTED_VERSION: u16 = 1;
+
+const PCP_RESPONSE_LEN: usize = 60;
+
+/// Requests are resent after 250 ms, then after twice as long each time
+const ATTEMPTS: u32 = 4;
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    Pcp,
+    NatPmp,
+}
+
+pub struct Client {
+    gateway: SocketAddr,
+    version: Version,
+    /// Identifies the mappings of this client when they are renewed or deleted
+    nonce: [u8; 12],
+}
+
+impl Client {
+    pub fn new(gateway: SocketAddr) -> Self {
+        Self {
+            gateway,
+            version: Version::Pcp,
+            nonce: rand::random(),
+        }
+    }
+
+    pub fn is_nat_pmp(&self) -> bool {
+        self.version == Version::NatPmp
+    }
+
+    pub async fn map(
+        &mut self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<Mapping, String> {
+        if self.version == Version::Pcp {
+            match self
+                .pcp_map(protocol, internal_port, external_port, None, lifetime)
+                .await
+            {
+                Err(PcpError::UnsupportedVersion) => {
+                    tracing::debug!("{} does not support PCP, using NAT-PMP", self.gateway);
+                    self.version = Version::NatPmp;
+                }
+                result => return result.map_err(|e| e.to_string()),
+            }
+        }
+        self.natpmp_map(protocol, internal_port, external_port, lifetime)
+            .await
+    }
+
+    pub async fn unmap(&mut self, mapping: &Mapping) -> Result<(), String> {
+        match self.version {
+            Version::Pcp => self
+                .pcp_map(
+                    mapping.protocol,
+                    mapping.internal_port,
+                    mapping.external.port(),
+                    Some(mapping.external.ip()),
+                    0,
+                )
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            // The suggested external port has to be 0 when deleting.
+            Version::NatPmp => self
+                .natpmp_map(mapping.protocol, mapping.internal_port, 0, 0)
+                .await
+                .map(|_| ()),
+        }
+    }
+
+    async fn pcp_map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        external_ip: Option<IpAddr>,
+        lifetime: u32,
+    ) -> Result<Mapping, PcpError> {
+        let (socket, local_ip) = self.socket().await?;
+        let mut req = Vec::with_capacity(60);
+        req.extend([PCP_VERSION, PCP_OP_MAP, 0, 0]);
+        req.extend(lifetime.to_be_bytes());
+        req.extend(ipv6_octets(local_ip));
+        req.extend(self.nonce);
+        req.push(protocol.number());
+        req.extend([0; 3]);
+        req.extend(internal_port.to_be_bytes());
+        req.extend(external_port.to_be_bytes());
+        // All zeros in the address family of the client: no preference.
+        let suggested = external_ip.unwrap_or(match local_ip {
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        req.extend(ipv6_octets(suggested));
+
+        let resp = transact(&socket, &req).await?;
+        if resp[0] == NATPMP_VERSION {
+            return Err(PcpError::UnsupportedVersion);
+        }
+        if resp.len() < PCP_RESPONSE_LEN || resp[1] != PCP_OP_MAP | RESPONSE_BIT {
+            return Err(PcpError::Other("invalid PCP response".to_string()));
+        }
+        match u16::from(resp[3]) {
+            RESULT_SUCCESS => {}
+            RESULT_UNSUPPORTED_VERSION => return Err(PcpError::UnsupportedVersion),
+            code => return Err(PcpError::Other(format!("PCP result code {code}"))),
+        }
+        if resp[24..36] != self.nonce {
+            return Err(PcpError::Other("PCP response nonce mismatch".to_string()));
+        }
+        let granted = u32::from_be_bytes(resp[4..8].try_into().unwrap());
+        let port = u16::from_be_bytes([resp[42], resp[43]]);
+        let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&resp[44..60]).unwrap());
+        let ip = ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4);
+        Ok(Mapping {
+            protocol,
+            internal_port,
+            external: SocketAddr::new(ip, port),
+            lifetime: Duration::from_secs(granted.into()),
+        })
+    }
+
+    async fn natpmp_map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<Mapping, String> {
+        let (socket, _) = self.socket().await.map_err(|e| e.to_string())?;
+        let op = match protocol {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        };
+        let mut req = vec![NATPMP_VERSION, op, 0, 0];
+        req.extend(internal_port.to_be_bytes());
+        req.extend(external_port.to_be_bytes());
+        req.extend(lifetime.to_be_bytes());
+        let resp = natpmp_transact(&socket, &req).await?;
+        if resp.len() < 16 {
+            return Err("invalid NAT-PMP response".to_string());
+        }
+        let port = u16::from_be_bytes([resp[10], resp[11]]);
+        let granted = u32::from_be_bytes(resp[12..16].try_into().unwrap());
+        let ip = if lifetime == 0 {
+            Ipv4Addr::UNSPECIFIED
+        } else {
+            let resp =
+                natpmp_transact(&socket, &[NATPMP_VERSION, NATPMP_OP_EXTERNAL_ADDRESS]).await?;
+            if resp.len() < 12 {
+                return Err("invalid NAT-PMP response".to_string());
+            }
+            Ipv4Addr::from(<[u8; 4]>::try_from(&resp[8..12]).unwrap())
+        };
+        Ok(Mapping {
+            protocol,
+            internal_port,
+            external: SocketAddr::new(ip.into(), port),
+            lifetime: Duration::from_secs(granted.into()),
+        })
+    }
+
+    /// A socket connected to the gateway, and the local address it uses
+    async fn socket(&self) -> std::io::Result<(UdpSocket, IpAddr)> {
+        let unspecified: SocketAddr = match self.gateway {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(unspecified).await?;
+        socket.connect(self.gateway).await?;
+        let local_ip = socket.local_addr()?.ip();
+        Ok((socket, local_ip))
+    }
+}
+
+#[derive(Debug)]
+enum PcpError {
+    UnsupportedVersion,
+    Other(String),
+}
+
+impl std::fmt::Display for PcpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedVersion => write!(f, "PCP version not supported by the gateway"),
+            Self::Other(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for PcpError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Other(e.to_string())
+    }
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// Sends `req` until the gateway answers, and returns the answer.
+async fn transact(socket: &UdpSocket, req: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut buf = [0u8; 1100];
+    let mut timeout = INITIAL_TIMEOUT;
+    for _ in 0..ATTEMPTS {
+        socket.send(req).await?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let n = received?;
+            if n >= 4 && buf[1] & RESPONSE_BIT != 0 {
+                return Ok(buf[..n].to_vec());
+            }
+        }
+        timeout *= 2;
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "gateway did not answer",
+    ))
+}
+
+async fn natpmp_transact(socket: &UdpSocket, req: &[u8]) -> Result<Vec<u8>, String> {
+    let resp = transact(socket, req).await.map_err(|e| e.to_string())?;
+    if resp[0] != NATPMP_VERSION || resp[1] != req[1] | RESPONSE_BIT {
+        return Err("invalid NAT-PMP response".to_string());
+    }
+    match u16::from_be_bytes([resp[2], resp[3]]) {
+        RESULT_SUCCESS => Ok(resp),
+        code => Err(format!("NAT-PMP result code {code}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 9);
+
+    /// Answers like a gateway that speaks PCP, or only NAT-PMP. Returns its
+    /// address and the lifetimes of the map requests it got.
+    async fn fake_gateway(pcp: bool) -> (SocketAddr, Arc<Mutex<Vec<u32>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let lifetimes = Arc::new(Mutex::new(Vec::new()));
+        let seen = lifetimes.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+                let req = &buf[..n];
+                let resp = match (req[0], req[1]) {
+                    (PCP_VERSION, PCP_OP_MAP) if pcp => {
+                        let lifetime = u32::from_be_bytes(req[4..8].try_into().unwrap());
+                        seen.lock().unwrap().push(lifetime);
+                        let mut resp = vec![PCP_VERSION, PCP_OP_MAP | RESPONSE_BIT, 0, 0];
+                        resp.extend(lifetime.min(600).to_be_bytes());
+                        resp.extend([0; 16]);
+                        // Nonce, protocol and internal port as requested.
+                        resp.extend(&req[24..42]);
+                        resp.extend(40000u16.to_be_bytes());
+                        resp.extend(EXTERNAL_IP.to_ipv6_mapped().octets());
+                        resp
+                    }
+                    (PCP_VERSION, _) => vec![NATPMP_VERSION, req[1] | RESPONSE_BIT, 0, 1],
+                    (NATPMP_VERSION, NATPMP_OP_EXTERNAL_ADDRESS) => {
+                        let mut resp = vec![NATPMP_VERSION, RESPONSE_BIT, 0, 0, 0, 0, 0, 1];
+                        resp.extend(EXTERNAL_IP.octets());
+                        resp
+                    }
+                    (NATPMP_VERSION, op) => {
+                        let lifetime = u32::from_be_bytes(req[8..12].try_into().unwrap());
+                        seen.lock().unwrap().push(lifetime);
+                        let mut resp = vec![NATPMP_VERSION, op | RESPONSE_BIT, 0, 0, 0, 0, 0, 1];
+                        resp.extend(&req[4..6]);
+                        resp.extend(if lifetime == 0 { [0, 0] } else { [0x9c, 0x41] });
+                        resp.extend(lifetime.min(600).to_be_bytes());
+                        resp
+                    }
+                    _ => continue,
+                };
+                socket.send_to(&resp, from).await.unwrap();
+            }
+        });
+        (addr, lifetimes)
+    }
+
+    #[tokio::test]
+    async fn map_with_pcp_or_nat_pmp() {
+        for (pcp, external_port) in [(true, 40000), (false, 40001)] {
+            let (gateway, lifetimes) = fake_gateway(pcp).await;
+            let mut client = Client::new(gateway);
+            let mapping = client.map(Protocol::Tcp, 6779, 0, 3600).await.unwrap();
+            assert_eq!(client.is_nat_pmp(), !pcp);
+            assert_eq!(mapping.internal_port, 6779);
+            assert_eq!(
+                mapping.external,
+                SocketAddr::new(EXTERNAL_IP.into(), external_port)
+            );
+            assert_eq!(mapping.lifetime, Duration::from_secs(600));
+
+            client.unmap(&mapping).await.unwrap();
+            assert_eq!(*lifetimes.lock().unwrap(), [3600, 0]);
+        }
+    }
+}
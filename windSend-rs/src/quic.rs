@@ -0,0 +1,355 @@
+//! Optional QUIC listener next to the TCP+TLS one.
+//!
+//! The listener uses the certificate in `config::TLS_DIR` and the ALPN
+//! protocol [`ALPN`]. Each bidirectional stream a client opens is served like
+//! a TLS connection: it carries route requests, and `subscribeClipboard` or
+//! `multiplex` take the stream over. Streams of one connection do not block
+//! each other, and QUIC connections survive address changes, so a clipboard
+//! subscription keeps running when a phone switches networks.
+
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error, info, warn};
+
+/// ALPN protocol of route connections over QUIC
+pub const ALPN: &[u8] = b"windsend/1";
+
+/// Configurable as `quic`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuicConfig {
+    pub enabled: bool,
+    /// UDP port, 0 to use `serverPort`
+    pub port: u16,
+    /// Connections without any packets for this long are closed
+    #[serde(rename = "maxIdleTimeoutSecs")]
+    pub max_idle_timeout_secs: u64,
+    /// Streams a client may have open at the same time on one connection
+    #[serde(rename = "maxStreamsPerConnection")]
+    pub max_streams_per_connection: u32,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 0,
+            max_idle_timeout_secs: 60,
+            max_streams_per_connection: 64,
+        }
+    }
+}
+
+impl QuicConfig {
+    pub fn listen_port(&self, server_port: &str) -> Option<u16> {
+        if self.port != 0 {
+            return Some(self.port);
+        }
+        server_port.parse().ok()
+    }
+}
+
+/// The endpoint of the listener, to replace its certificate
+static ENDPOINT: Mutex<Option<quinn::Endpoint>> = Mutex::new(None);
+
+/// Starts the QUIC listener if it is enabled.
+pub fn start() {
+    let (config, server_port) = {
+        let config = crate::config::read_config();
+        (config.quic.clone(), config.server_port.clone())
+    };
+    if !config.enabled {
+        return;
+    }
+    let Some(port) = config.listen_port(&server_port) else {
+        error!("invalid quic port, serverPort: {}", server_port);
+        return;
+    };
+    let endpoint = match crate::config::tls_server_config()
+        .and_then(|tls| server_config(tls, &config))
+        .and_then(|server_config| Ok(bind_endpoint(server_config, port)?))
+    {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("start quic listener failed, err: {}", e);
+            return;
+        }
+    };
+    match endpoint.local_addr() {
+        Ok(addr) => info!("quic listening on {}", addr),
+        Err(e) => warn!("get quic local addr failed, err: {}", e),
+    }
+    *ENDPOINT.lock().unwrap() = Some(endpoint.clone());
+    crate::RUNTIME.spawn(accept_loop(endpoint));
+}
+
+/// Makes the listener use the certificate in `config::TLS_DIR` for new connections.
+pub fn reload_certificate() -> Result<(), Box<dyn std::error::Error>> {
+    let Some(endpoint) = ENDPOINT.lock().unwrap().clone() else {
+        return Ok(());
+    };
+    let config = crate::config::read_config().quic.clone();
+    endpoint.set_server_config(Some(server_config(
+        crate::config::tls_server_config()?,
+        &config,
+    )?));
+    Ok(())
+}
+
+fn server_config(
+    mut tls: tokio_rustls::rustls::ServerConfig,
+    config: &QuicConfig,
+) -> Result<quinn::ServerConfig, Box<dyn std::error::Error>> {
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)?;
+    let mut transport = quinn::TransportConfig::default();
+    transport
+        .max_concurrent_bidi_streams(config.max_streams_per_connection.into())
+        .max_concurrent_uni_streams(0u8.into())
+        .max_idle_timeout(Some(
+            Duration::from_secs(config.max_idle_timeout_secs.max(1)).try_into()?,
+        ));
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(Arc::new(transport));
+    Ok(server_config)
+}
+
+/// Binds a dual-stack UDP socket, like the TCP listener does.
+fn bind_endpoint(
+    server_config: quinn::ServerConfig,
+    port: u16,
+) -> std::io::Result<quinn::Endpoint> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV6,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    if let Err(e) = socket.set_only_v6(false) {
+        warn!("set_only_v6 error: {}", e);
+    }
+    socket.bind(&SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port)).into())?;
+    quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(server_config),
+        socket.into(),
+        Arc::new(quinn::TokioRuntime),
+    )
+}
+
+async fn accept_loop(endpoint: quinn::Endpoint) {
+    while let Some(incoming) = endpoint.accept().await {
+        crate::RUNTIME.spawn(handle_incoming(incoming));
+    }
+    info!("quic listener closed");
+}
+
+async fn handle_incoming(incoming: quinn::Incoming) {
+    let addr = incoming.remote_address();
+    let _permit = match crate::admission::try_admit(addr.ip().to_canonical()) {
+        Ok(permit) => permit,
+        Err(err) => {
+            warn!("reject quic connection({}): {}", addr, err);
+            incoming.refuse();
+            return;
+        }
+    };
+    let handshake_timeout = crate::config::read_config().admission.handshake_timeout();
+    let connection = match tokio::time::timeout(handshake_timeout, incoming).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(err)) => {
+            error!(
+                "unknown quic connection({}), handshake error: {}",
+                addr, err
+            );
+            return;
+        }
+        Err(_) => {
+            warn!("quic handshake with {} timed out", addr);
+            return;
+        }
+    };
+    info!("accept a new quic connection from {}", addr);
+    serve_connection(
+        connection,
+        crate::listen::EndpointPolicy::unbound_from_config(),
+    )
+    .await;
+}
+
+/// Serves the connections that arrive on a socket, until none has been open
+/// for `wait`. The socket's NAT mapping was opened towards the client by hole
+/// punching, see [`crate::relay::rendezvous`].
+pub async fn serve_punched(socket: std::net::UdpSocket, wait: Duration) -> Result<(), String> {
+    let config = crate::config::read_config().quic.clone();
+    let server_config = crate::config::tls_server_config()
+        .and_then(|tls| server_config(tls, &config))
+        .map_err(|e| e.to_string())?;
+    accept_punched(socket, server_config, wait, handle_incoming).await
+}
+
+async fn accept_punched<F, Fut>(
+    socket: std::net::UdpSocket,
+    server_config: quinn::ServerConfig,
+    wait: Duration,
+    serve: F,
+) -> Result<(), String>
+where
+    F: Fn(quinn::Incoming) -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(server_config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )
+    .map_err(|e| e.to_string())?;
+    let mut connections = tokio::task::JoinSet::new();
+    let mut idle_since = tokio::time::Instant::now();
+    loop {
+        tokio::select! {
+            incoming = endpoint.accept() => {
+                let Some(incoming) = incoming else {
+                    break;
+                };
+                connections.spawn_on(serve(incoming), crate::RUNTIME.handle());
+            }
+            Some(_) = connections.join_next() => {
+                if connections.is_empty() {
+                    idle_since = tokio::time::Instant::now();
+                }
+            }
+            _ = tokio::time::sleep_until(idle_since + wait), if connections.is_empty() => {
+                debug!("no direct quic connection for {:?}", wait);
+                break;
+            }
+        }
+    }
+    endpoint.close(0u32.into(), b"");
+    Ok(())
+}
+
+async fn serve_connection(connection: quinn::Connection, policy: crate::listen::EndpointPolicy) {
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(quinn::ConnectionError::ApplicationClosed(_)) => break,
+            Err(e) => {
+                info!(
+                    "quic connection with {} ended: {}",
+                    connection.remote_address(),
+                    e
+                );
+                break;
+            }
+        };
+        // The address at the time the stream was opened, it changes when the client migrates.
+        let remote_addr = connection.remote_address();
+        debug!("accept a quic stream {} from {}", send.id(), remote_addr);
+        crate::RUNTIME.spawn(async move {
+            let stream = tokio::io::join(recv, send);
+            if let Some(mut stream) =
+                crate::route::serve_transport(stream, remote_addr, policy).await
+            {
+                let _ = stream.shutdown().await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_rustls::rustls;
+
+    #[test]
+    fn port_defaults_to_the_server_port() {
+        let config = QuicConfig::default();
+        assert_eq!(config.listen_port("6779"), Some(6779));
+        assert_eq!(config.listen_port(""), None);
+        let config = QuicConfig {
+            port: 7000,
+            ..Default::default()
+        };
+        assert_eq!(config.listen_port("6779"), Some(7000));
+    }
+
+    fn self_signed_server_config() -> (
+        quinn::ServerConfig,
+        rustls::pki_types::CertificateDer<'static>,
+    ) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key =
+            rustls::pki_types::PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der());
+        let tls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key.into())
+            .unwrap();
+        (server_config(tls, &QuicConfig::default()).unwrap(), cert)
+    }
+
+    fn client_trusting(cert: rustls::pki_types::CertificateDer<'static>) -> quinn::Endpoint {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let mut tls = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let mut client = quinn::Endpoint::client(([127, 0, 0, 1], 0).into()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            quinn::crypto::rustls::QuicClientConfig::try_from(tls).unwrap(),
+        )));
+        client
+    }
+
+    #[tokio::test]
+    async fn punched_sockets_serve_every_connection_until_idle() {
+        let (server_config, cert) = self_signed_server_config();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let wait = Duration::from_millis(500);
+        let served = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = served.clone();
+        let punched = tokio::spawn(accept_punched(
+            socket,
+            server_config,
+            wait,
+            move |incoming| {
+                let counter = counter.clone();
+                async move {
+                    let connection = incoming.await.unwrap();
+                    counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    let (mut send, _recv) = connection.accept_bi().await.unwrap();
+                    send.write_all(b"served").await.unwrap();
+                    send.finish().unwrap();
+                    connection.closed().await;
+                }
+            },
+        ));
+
+        let client = client_trusting(cert);
+        for _ in 0..2 {
+            let connection = client
+                .connect(server_addr, "localhost")
+                .unwrap()
+                .await
+                .unwrap();
+            let (mut send, mut recv) = connection.open_bi().await.unwrap();
+            send.write_all(b"hi").await.unwrap();
+            assert_eq!(recv.read_to_end(1024).await.unwrap(), b"served");
+            // Longer than `wait`, the open connection keeps the socket served.
+            tokio::time::sleep(wait * 2).await;
+            connection.close(0u32.into(), b"");
+        }
+        tokio::time::timeout(wait * 4, punched)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(served.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,413 @@
+//! Store-and-forward of transfers for devices that are offline.
+//!
+//! A sender that finds a device offline deposits a letter for the device's
+//! key id with `mailboxPut`: the body carries the size and the expiry, and
+//! the letter follows as raw bytes. A letter is a JSON [`Letter`] encrypted
+//! with the device's secret key and the AAD [`LETTER_AAD`], so the relay
+//! server only ever holds ciphertext. It keeps letters in memory, within its
+//! quotas, until they expire or are acknowledged.
+//!
+//! Relay servers that keep letters say so in the handshake. A device drains
+//! its mailbox whenever it connects to one, before registering the
+//! connection: `mailboxFetch` answers with the list of letters followed by
+//! their bytes in the same order, and `mailboxAck` deletes the delivered ones.
+//! Since key ids are no secret, the fetch carries a [`key_proof`], and an ack
+//! only deletes letters that were fetched on the same connection.
+
+use crate::relay::protocol::{
+    Action, CommonReq, CommonReqHead, MailboxAckReq, MailboxFetchReq, MailboxFetchResp,
+    MailboxItem, RespHead, StatusCode,
+};
+use crate::relay::transport::RelayStream;
+use crate::utils::encrypt::{AesGcmCipher, compute_sha256};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// Additional data of the encrypted letters
+pub const LETTER_AAD: &[u8] = b"mailbox";
+
+/// Largest letter list a device accepts in a `mailboxFetch` response
+const MAX_FETCH_RESP_LEN: i32 = 1024 * 1024;
+
+/// Largest letter a device accepts
+const MAX_LETTER_LEN: u64 = 64 * 1024 * 1024;
+
+/// The hash of the device's secret key whose hash is the key id, see
+/// [`crate::config::Config::get_secret_key_id`]. Only holders of the secret
+/// key know it, the relay server checks it against the id.
+pub fn key_proof(secret_key_hex: &str) -> String {
+    hex::encode(compute_sha256(secret_key_hex.as_bytes()))
+}
+
+/// Whether `proof` is the [`key_proof`] of the device `id`
+pub fn proves_key_id(proof: &str, id: &str) -> bool {
+    let Ok(proof) = hex::decode(proof) else {
+        return false;
+    };
+    let hash = hex::encode(compute_sha256(&proof));
+    id.len() == 16 && hash[..16] == *id
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LetterKind {
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "image")]
+    Image,
+    #[serde(rename = "file")]
+    File,
+}
+
+/// What a sender leaves for an offline device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Letter {
+    #[serde(rename = "kind")]
+    pub kind: LetterKind,
+    #[serde(rename = "deviceName", default)]
+    pub device_name: String,
+    /// Only used for files
+    #[serde(rename = "fileName", default)]
+    pub file_name: String,
+    #[serde(rename = "dataB64")]
+    pub data_b64: String,
+}
+
+impl Letter {
+    pub fn open(sealed: &mut [u8], cipher: &AesGcmCipher) -> Result<Self, String> {
+        let plain = cipher
+            .decrypt(sealed, LETTER_AAD)
+            .map_err(|e| format!("decrypt letter error: {e}"))?;
+        serde_json::from_slice(plain).map_err(|e| format!("parse letter error: {e}"))
+    }
+}
+
+/// Quotas of the relay server's mailboxes
+#[derive(Debug, Clone)]
+pub struct MailboxLimits {
+    pub max_letter_bytes: u64,
+    pub max_device_bytes: u64,
+    pub max_total_bytes: u64,
+    pub max_ttl: Duration,
+}
+
+struct StoredLetter {
+    item: MailboxItem,
+    sealed: Vec<u8>,
+}
+
+/// Letters kept by the relay server, by device key id
+#[derive(Default)]
+pub struct Mailboxes {
+    boxes: HashMap<String, VecDeque<StoredLetter>>,
+    total_bytes: u64,
+    next_seq: u64,
+}
+
+impl Mailboxes {
+    /// Stores a letter, returning its sequence number.
+    pub fn put(
+        &mut self,
+        id: &str,
+        sealed: Vec<u8>,
+        expires_at: i64,
+        limits: &MailboxLimits,
+        now: i64,
+    ) -> Result<u64, &'static str> {
+        self.expire(now);
+        let size = sealed.len() as u64;
+        if size > limits.max_letter_bytes {
+            return Err("letter too large");
+        }
+        if expires_at <= now {
+            return Err("letter already expired");
+        }
+        let device_bytes: u64 = self
+            .boxes
+            .get(id)
+            .map(|letters| letters.iter().map(|letter| letter.item.size).sum())
+            .unwrap_or(0);
+        if device_bytes + size > limits.max_device_bytes {
+            return Err("mailbox full");
+        }
+        if self.total_bytes + size > limits.max_total_bytes {
+            return Err("relay server mailboxes full");
+        }
+        self.next_seq += 1;
+        let item = MailboxItem {
+            seq: self.next_seq,
+            size,
+            expires_at: expires_at.min(now.saturating_add(limits.max_ttl.as_secs() as i64)),
+        };
+        self.boxes
+            .entry(id.to_string())
+            .or_default()
+            .push_back(StoredLetter { item, sealed });
+        self.total_bytes += size;
+        Ok(self.next_seq)
+    }
+
+    /// The letters of a device that have not expired, oldest first
+    pub fn fetch(&mut self, id: &str, now: i64) -> Vec<(MailboxItem, Vec<u8>)> {
+        self.expire(now);
+        self.boxes
+            .get(id)
+            .map(|letters| {
+                letters
+                    .iter()
+                    .map(|letter| (letter.item.clone(), letter.sealed.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn ack(&mut self, id: &str, seqs: &[u64]) {
+        let Some(letters) = self.boxes.get_mut(id) else {
+            return;
+        };
+        letters.retain(|letter| {
+            let acked = seqs.contains(&letter.item.seq);
+            if acked {
+                self.total_bytes -= letter.item.size;
+            }
+            !acked
+        });
+        if letters.is_empty() {
+            self.boxes.remove(id);
+        }
+    }
+
+    fn expire(&mut self, now: i64) {
+        self.boxes.retain(|_, letters| {
+            letters.retain(|letter| {
+                let expired = letter.item.expires_at <= now;
+                if expired {
+                    self.total_bytes -= letter.item.size;
+                }
+                !expired
+            });
+            !letters.is_empty()
+        });
+    }
+}
+
+/// Only one connection drains the mailbox at a time, so that letters are not
+/// delivered twice.
+static DRAINING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Fetches, delivers and acknowledges the letters for the device `id`, whose
+/// [`key_proof`] is `proof`.
+///
+/// Letters that cannot be opened are acknowledged too, they would never be.
+/// Letters whose delivery failed stay in the mailbox until they expire.
+pub async fn drain(
+    conn: &mut RelayStream,
+    cipher: &AesGcmCipher,
+    id: &str,
+    proof: &str,
+    letter_cipher: &AesGcmCipher,
+) -> Result<(), ()> {
+    use tokio::io::AsyncReadExt;
+    use tracing::{debug, error, info, warn};
+
+    let Ok(_draining) = DRAINING.try_lock() else {
+        return Ok(());
+    };
+    let req = MailboxFetchReq {
+        common: CommonReq { id: id.to_string() },
+        key_proof: proof.to_string(),
+    };
+    CommonReqHead::write_with_body(Action::MailboxFetch, conn, Some(cipher), &req).await?;
+    let head = RespHead::read_from(conn, Some(cipher)).await?;
+    if head.code != StatusCode::Success {
+        error!(
+            "mailbox fetch failed, code: {:?}, msg: {}",
+            head.code, head.msg
+        );
+        return Err(());
+    }
+    if head.data_len <= 0 || head.data_len > MAX_FETCH_RESP_LEN {
+        error!("bad mailbox fetch response, data_len: {}", head.data_len);
+        return Err(());
+    }
+    let resp: MailboxFetchResp =
+        crate::relay::transfer::read_from(conn, head.data_len, Some(cipher)).await?;
+    if resp.items.is_empty() {
+        return Ok(());
+    }
+    info!("{} letters in the relay mailbox", resp.items.len());
+
+    let mut seqs = Vec::new();
+    for item in resp.items {
+        if item.size > MAX_LETTER_LEN {
+            error!("letter {} too large: {}", item.seq, item.size);
+            return Err(());
+        }
+        let mut sealed = vec![0u8; item.size as usize];
+        conn.read_exact(&mut sealed)
+            .await
+            .map_err(|e| error!("read letter error: {}", e))?;
+        let letter = match Letter::open(&mut sealed, letter_cipher) {
+            Ok(letter) => letter,
+            Err(e) => {
+                warn!("drop letter {}: {}", item.seq, e);
+                seqs.push(item.seq);
+                continue;
+            }
+        };
+        match deliver(letter).await {
+            Ok(()) => seqs.push(item.seq),
+            Err(e) => error!("deliver letter {} error: {}", item.seq, e),
+        }
+    }
+    if seqs.is_empty() {
+        return Ok(());
+    }
+    let req = MailboxAckReq {
+        common: CommonReq { id: id.to_string() },
+        seqs,
+    };
+    CommonReqHead::write_with_body(Action::MailboxAck, conn, Some(cipher), &req).await?;
+    let head = RespHead::read_from(conn, Some(cipher)).await?;
+    debug!("recv mailbox ack resp head: {:?}", head);
+    if head.code != StatusCode::Success {
+        error!(
+            "mailbox ack failed, code: {:?}, msg: {}",
+            head.code, head.msg
+        );
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Puts a letter where a direct transfer of the same kind would have gone.
+async fn deliver(letter: Letter) -> Result<(), String> {
+    use crate::save_rules::ReceiveItemKind;
+    use base64::prelude::*;
+
+    let data = BASE64_STANDARD
+        .decode(&letter.data_b64)
+        .map_err(|e| format!("decode letter data error: {e}"))?;
+    match letter.kind {
+        LetterKind::Text => {
+            let text = String::from_utf8_lossy(&data);
+            crate::config::CLIPBOARD
+                .write_text(text.to_string())
+                .map_err(|e| format!("set clipboard text failed, err: {e}"))?;
+            crate::utils::inform(&text, &letter.device_name, None);
+        }
+        LetterKind::Image => {
+            let file_name = format!(
+                "mailbox_{}.png",
+                chrono::Local::now().format("%Y%m%d%H%M%S%3f")
+            );
+            let path = save(
+                &data,
+                &file_name,
+                &letter.device_name,
+                ReceiveItemKind::ClipImage,
+            )
+            .await?;
+            crate::config::CLIPBOARD
+                .write_image_from_bytes(&data)
+                .map_err(|e| format!("set clipboard image failed, err: {e}"))?;
+            crate::utils::inform(&path, &letter.device_name, Some(&path));
+        }
+        LetterKind::File => {
+            let file_name = crate::utils::sanitize_file_name(&letter.file_name);
+            let path = save(
+                &data,
+                &file_name,
+                &letter.device_name,
+                ReceiveItemKind::File,
+            )
+            .await?;
+            crate::utils::inform(&path, &letter.device_name, Some(&path));
+        }
+    }
+    Ok(())
+}
+
+async fn save(
+    data: &[u8],
+    file_name: &str,
+    device_name: &str,
+    kind: crate::save_rules::ReceiveItemKind,
+) -> Result<String, String> {
+    use crate::save_rules::{SaveTarget, resolve_save_dir};
+
+    let save_dir = resolve_save_dir(&SaveTarget {
+        device_name,
+        file_name: Some(file_name),
+        kind,
+    });
+    tokio::fs::create_dir_all(&save_dir)
+        .await
+        .map_err(|e| format!("create save directory failed: {e}"))?;
+    let path = crate::utils::generate_unique_filepath(save_dir.join(file_name))
+        .map_err(|e| format!("generate file path failed: {e}"))?;
+    tokio::fs::write(&path, data)
+        .await
+        .map_err(|e| format!("write {path} failed: {e}"))?;
+    tracing::info!("letter saved to: {}", path);
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_proofs_match_the_key_id() {
+        let mut id = hex::encode(compute_sha256(&compute_sha256(b"secret")));
+        id.truncate(16);
+        assert!(proves_key_id(&key_proof("secret"), &id));
+        assert!(!proves_key_id(&key_proof("other"), &id));
+        assert!(!proves_key_id("not hex", &id));
+        assert!(!proves_key_id(&key_proof("secret"), ""));
+    }
+
+    #[test]
+    fn mailboxes_keep_letters_within_quotas() {
+        let limits = MailboxLimits {
+            max_letter_bytes: 4,
+            max_device_bytes: 6,
+            max_total_bytes: 8,
+            max_ttl: Duration::from_secs(100),
+        };
+        let mut boxes = Mailboxes::default();
+        let now = 1000;
+        assert_eq!(
+            boxes.put("a", vec![0; 5], 2000, &limits, now),
+            Err("letter too large")
+        );
+        assert_eq!(
+            boxes.put("a", vec![0; 4], now, &limits, now),
+            Err("letter already expired")
+        );
+        let first = boxes.put("a", vec![1; 4], 2000, &limits, now).unwrap();
+        assert_eq!(
+            boxes.put("a", vec![0; 3], 2000, &limits, now),
+            Err("mailbox full")
+        );
+        let second = boxes.put("a", vec![2; 2], 1010, &limits, now).unwrap();
+        boxes.put("b", vec![3; 2], 2000, &limits, now).unwrap();
+        assert_eq!(
+            boxes.put("c", vec![0; 1], 2000, &limits, now),
+            Err("relay server mailboxes full")
+        );
+
+        let letters = boxes.fetch("a", now);
+        assert_eq!(letters.len(), 2);
+        // The expiry is capped by the relay server.
+        assert_eq!(letters[0].0.expires_at, now + 100);
+        assert_eq!(letters[1].1, vec![2; 2]);
+
+        boxes.ack("a", &[first]);
+        let letters = boxes.fetch("a", now);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].0.seq, second);
+        assert!(boxes.fetch("a", 1010).is_empty());
+        assert_eq!(boxes.total_bytes, 2);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::relay::select::RelayEndpoint;
+use crate::relay::transport::RelayStream;
 use crate::utils::encrypt::aes192_key_kdf;
 use std::future::Future;
 use std::pin::Pin;
@@ -5,8 +7,10 @@ use std::pin::Pin;
 pub enum RelayExitReason {
     /// Relay request received; carries the future to spawn for handling it.
     Spawned(Pin<Box<dyn Future<Output = ()> + Send>>),
-    /// Connection closed normally or heartbeat timeout.
+    /// Connection closed normally.
     Disconnected,
+    /// The relay server stopped sending heartbeats.
+    HeartbeatTimeout,
     /// Unrecoverable error during request handling.
     Failed,
 }
@@ -15,45 +19,148 @@ pub enum RelayExitReason {
 /// enters the request loop. Returns `None` if connection setup fails
 /// (triggering backoff in the outer loop), or `Some(reason)` describing
 /// why the request loop exited.
-pub async fn relay_main() -> Option<RelayExitReason> {
-    use crate::config;
+pub async fn relay_main(
+    relay: &RelayEndpoint,
+    idle_timeout: std::time::Duration,
+) -> Option<RelayExitReason> {
     use tracing::{debug, error};
 
-    let relay_server_address = config::read_config().relay_server_address.clone();
-    debug!("try to connect to relay server: {}", relay_server_address);
+    debug!("try to connect to relay server: {}", relay.address);
 
-    let mut tcp_stream = match tokio::net::TcpStream::connect(relay_server_address).await {
-        Ok(tcp_stream) => tcp_stream,
+    let outbound = match crate::utils::proxy::outbound() {
+        Ok(outbound) => outbound,
         Err(e) => {
-            error!("connect relay server error: {}", e);
+            error!("invalid outbound proxy: {}", e);
             return None;
         }
     };
+    let (mut conn, cipher, capabilities) = connect_and_handshake(relay, &outbound).await?;
+    let supports = |capability: &str| capabilities.iter().any(|c| c == capability);
+
+    if supports(crate::relay::protocol::CAPABILITY_MAILBOX) {
+        let (id, proof, letter_cipher) = {
+            let config = crate::config::read_config();
+            let letter_cipher =
+                crate::utils::encrypt::AesGcmCipher::new_from_hex(&config.secret_key_hex);
+            let proof = crate::relay::mailbox::key_proof(&config.secret_key_hex);
+            (config.get_secret_key_id(), proof, letter_cipher)
+        };
+        let letter_cipher = match letter_cipher {
+            Ok(cipher) => cipher,
+            Err(e) => {
+                error!("create letter cipher error: {}", e);
+                return None;
+            }
+        };
+        if crate::relay::mailbox::drain(&mut conn, &cipher, &id, &proof, &letter_cipher)
+            .await
+            .is_err()
+        {
+            shutdown_conn(&mut conn).await;
+            return None;
+        }
+    }
 
-    let cipher = match handshake(&mut tcp_stream).await {
-        Some(cipher) => cipher,
-        None => return None,
-    };
-
-    match send_connection_req(&mut tcp_stream, &cipher).await {
+    // Hole punching needs UDP, which does not go through the proxy.
+    let rendezvous = supports(crate::relay::protocol::CAPABILITY_RENDEZVOUS)
+        && outbound
+            .proxy_for(crate::relay::transport::host_of(&relay.address))
+            .is_none();
+    match send_connection_req(&mut conn, &cipher, rendezvous).await {
         Ok(_) => (),
         Err(_) => return None,
     }
 
-    tracing::info!("connect to relay server success");
+    tracing::info!("connect to relay server {} success", relay.address);
 
-    update_relay_server_status(true);
+    update_relay_server_status(&relay.address, true);
 
-    let reason = _handle_request(tcp_stream, Some(cipher)).await;
+    let reason = _handle_request(conn, Some(cipher), idle_timeout, &relay.address).await;
 
     if !matches!(reason, RelayExitReason::Spawned(_)) {
-        update_relay_server_status(false);
+        update_relay_server_status(&relay.address, false);
     }
 
     Some(reason)
 }
 
-pub fn update_relay_server_status(connected: bool) {
+/// Connects and completes the handshake with the secret key, or with the
+/// previous secret key while a key rotation is in its grace window, in case
+/// the relay server does not know the new key yet. Also returns the
+/// capabilities of the relay server.
+async fn connect_and_handshake(
+    relay: &RelayEndpoint,
+    outbound: &crate::utils::proxy::Outbound,
+) -> Option<(
+    RelayStream,
+    crate::utils::encrypt::AesGcmCipher,
+    Vec<String>,
+)> {
+    use tracing::{error, warn};
+    let mut attempts = vec![relay.clone()];
+    if let Some(previous) = &relay.previous_secret_key
+        && relay.secret_key.as_ref() != Some(previous)
+    {
+        attempts.push(RelayEndpoint {
+            secret_key: Some(previous.clone()),
+            ..relay.clone()
+        });
+    }
+    for (i, attempt) in attempts.iter().enumerate() {
+        let conn = crate::relay::transport::connect(&relay.address, &relay.transport, outbound);
+        let mut conn = match conn.await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("connect relay server {} error: {}", relay.address, e);
+                return None;
+            }
+        };
+        if let Some((cipher, capabilities)) = handshake(&mut conn, attempt).await {
+            if i > 0 {
+                warn!(
+                    "relay server {} still uses the previous secret key",
+                    relay.address
+                );
+            }
+            return Some((conn, cipher, capabilities));
+        }
+        shutdown_conn(&mut conn).await;
+    }
+    None
+}
+
+/// Measures the time to connect to a relay server and finish the handshake.
+pub async fn probe(relay: &RelayEndpoint) -> Option<std::time::Duration> {
+    let outbound = crate::utils::proxy::outbound().ok()?;
+    let start = std::time::Instant::now();
+    let (mut conn, _, _) = connect_and_handshake(relay, &outbound).await?;
+    let rtt = start.elapsed();
+    shutdown_conn(&mut conn).await;
+    Some(rtt)
+}
+
+/// Relay servers with a registered connection
+static CONNECTED_RELAYS: std::sync::LazyLock<std::sync::Mutex<std::collections::HashSet<String>>> =
+    std::sync::LazyLock::new(Default::default);
+
+/// Records whether the relay server at `address` has a registered connection.
+pub fn update_relay_server_status(address: &str, connected: bool) {
+    let mut relays = CONNECTED_RELAYS.lock().unwrap();
+    if connected {
+        relays.insert(address.to_string());
+    } else {
+        relays.remove(address);
+    }
+    publish_relay_server_status(!relays.is_empty());
+}
+
+/// Forgets all relay connections, once the relay has stopped.
+pub fn reset_relay_server_status() {
+    CONNECTED_RELAYS.lock().unwrap().clear();
+    publish_relay_server_status(false);
+}
+
+fn publish_relay_server_status(connected: bool) {
     use crate::status::RELAY_SERVER_CONNECTED;
     #[cfg(not(feature = "disable-systray-support"))]
     use crate::status::TX_UPDATE_RELAY_SERVER_CONNECTED;
@@ -67,23 +174,40 @@ pub fn update_relay_server_status(connected: bool) {
 }
 
 async fn send_connection_req(
-    tcp_stream: &mut tokio::net::TcpStream,
+    conn: &mut RelayStream,
     cipher: &crate::utils::encrypt::AesGcmCipher,
+    rendezvous: bool,
 ) -> Result<(), ()> {
     use crate::config;
     use tracing::{debug, error};
 
-    use crate::relay::protocol::{CommonReq, ConnectionReq, RespHead};
+    use crate::relay::protocol::{CommonReq, ConnectionReq, DeviceDescriptor, RespHead};
+    let name = hostname::get()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut capabilities: Vec<_> = crate::route::protocol::SUPPORTED_ACTIONS
+        .iter()
+        .map(|action| action.to_string())
+        .collect();
+    if rendezvous {
+        capabilities.push(crate::relay::protocol::CAPABILITY_RENDEZVOUS.to_string());
+    }
     let req = ConnectionReq {
         common: CommonReq {
             id: config::read_config().get_secret_key_id(),
         },
+        // Lets the other devices with the relay key see this one as online.
+        device: Some(DeviceDescriptor {
+            name,
+            version: crate::PROGRAM_VERSION.to_string(),
+            capabilities,
+        }),
     };
-    match req.write_to(tcp_stream, Some(cipher)).await {
+    match req.write_to(conn, Some(cipher)).await {
         Ok(_) => (),
         Err(_) => return Err(()),
     }
-    let head = match RespHead::read_from(tcp_stream, Some(cipher)).await {
+    let head = match RespHead::read_from(conn, Some(cipher)).await {
         Ok(head) => head,
         Err(_) => return Err(()),
     };
@@ -105,9 +229,9 @@ async fn send_connection_req(
 }
 
 async fn handshake(
-    conn: &mut tokio::net::TcpStream,
-) -> Option<crate::utils::encrypt::AesGcmCipher> {
-    use crate::config;
+    conn: &mut RelayStream,
+    relay: &RelayEndpoint,
+) -> Option<(crate::utils::encrypt::AesGcmCipher, Vec<String>)> {
     use crate::relay::protocol::{HandshakeResp, StatusCode};
     use crate::utils::encrypt;
     use base64::prelude::*;
@@ -117,7 +241,7 @@ async fn handshake(
     let secret = EphemeralSecret::random();
     let public = PublicKey::from(&secret);
 
-    let mut handshake_cipher = match write_handshake_req(conn, public).await {
+    let mut handshake_cipher = match write_handshake_req(conn, public, relay).await {
         Ok(cipher) => cipher,
         Err(_) => return None,
     };
@@ -126,15 +250,19 @@ async fn handshake(
         Ok(resp) => resp,
     };
     if resp.code == StatusCode::KdfSaltMismatch {
-        RELAY_SALT.lock().unwrap().set_kdf_key(
-            config::read_config().relay_secret_key.as_ref(),
-            Some(&resp.kdf_salt_b64),
-        );
+        {
+            let mut caches = RELAY_SALT.lock().unwrap();
+            caches
+                .entry(relay.address.clone())
+                .or_default()
+                .set_kdf_key(relay.secret_key.as_ref(), Some(&resp.kdf_salt_b64));
+            save_relay_salts(&caches);
+        }
         debug!(
             "kdf salt mismatch, retry handshake, new salt: {}",
             resp.kdf_salt_b64
         );
-        handshake_cipher = match write_handshake_req(conn, public).await {
+        handshake_cipher = match write_handshake_req(conn, public, relay).await {
             Ok(cipher) => cipher,
             Err(_) => return None,
         };
@@ -185,14 +313,14 @@ async fn handshake(
             return None;
         }
     };
-    Some(cipher)
+    Some((cipher, resp.capabilities))
 }
 
 async fn write_handshake_req(
-    conn: &mut tokio::net::TcpStream,
+    conn: &mut RelayStream,
     public: x25519_dalek::PublicKey,
+    relay: &RelayEndpoint,
 ) -> Result<Option<crate::utils::encrypt::AesGcmCipher>, ()> {
-    use crate::config;
     use crate::relay::protocol::HandshakeReq;
     use crate::utils::encrypt;
     use base64::prelude::*;
@@ -210,7 +338,9 @@ async fn write_handshake_req(
     let (relay_secret_key, salt_b64) = RELAY_SALT
         .lock()
         .unwrap()
-        .get_kdf_key_cached(config::read_config().relay_secret_key.as_ref());
+        .get_mut(&relay.address)
+        .map(|cache| cache.get_kdf_key_cached(relay.secret_key.as_ref()))
+        .unwrap_or_default();
 
     // let relay_secret_key_selector = get_aes192_key_selector(&relay_secret_key);
     let relay_secret_key_selector = relay_secret_key.map(|key| get_aes192_key_selector(&key));
@@ -234,7 +364,7 @@ async fn write_handshake_req(
         }
         None => None,
     };
-    if auth_field_b64.is_none() && config::read_config().relay_secret_key.is_some() {
+    if auth_field_b64.is_none() && relay.secret_key.is_some() {
         auth_field_b64 = Some(BASE64_STANDARD.encode("fetch_salt"));
     }
     // println!("auth_field_b64: {:?}", auth_field_b64);
@@ -263,19 +393,20 @@ async fn write_handshake_req(
 /// future for the outer loop to spawn), `Disconnected` on clean EOF /
 /// heartbeat timeout, or `Failed` on protocol errors.
 async fn _handle_request(
-    mut conn: tokio::net::TcpStream,
+    mut conn: RelayStream,
     cipher: Option<crate::utils::encrypt::AesGcmCipher>,
+    idle_timeout: std::time::Duration,
+    relay_address: &str,
 ) -> RelayExitReason {
     use crate::relay::protocol::{Action, CommonReqHead};
-    use tokio::time::Duration;
     use tracing::{debug, error};
-    const READ_TIMEOUT_DURATION: Duration = Duration::from_secs(180);
 
     loop {
         debug!("waiting for relay server request");
 
         let read_future = CommonReqHead::read_from(&mut conn, cipher.as_ref());
-        let read_result = tokio::time::timeout(READ_TIMEOUT_DURATION, read_future).await;
+        // The relay server sends heartbeats, silence means the connection is dead.
+        let read_result = tokio::time::timeout(idle_timeout, read_future).await;
         let common_req_head = match read_result {
             Ok(Ok(head)) => head,
             Ok(Err(_)) => {
@@ -285,7 +416,7 @@ async fn _handle_request(
             Err(_) => {
                 error!("read relay server request timeout");
                 shutdown_conn(&mut conn).await;
-                return RelayExitReason::Disconnected;
+                return RelayExitReason::HeartbeatTimeout;
             }
         };
 
@@ -298,6 +429,12 @@ async fn _handle_request(
                 let fut = Box::pin(handle_relay(conn));
                 return RelayExitReason::Spawned(fut);
             }
+            Action::Rendezvous => {
+                debug!("received rendezvous request, building spawned task");
+                let fut =
+                    handle_rendezvous(conn, common_req_head, cipher, relay_address.to_string());
+                return RelayExitReason::Spawned(Box::pin(fut));
+            }
             Action::Heartbeat => {
                 match handle_heartbeat(&mut conn, common_req_head, cipher.as_ref()).await {
                     Ok(_) => (),
@@ -316,9 +453,9 @@ async fn _handle_request(
     }
 }
 
-/// Best-effort TCP shutdown; errors are intentionally ignored because
+/// Best-effort shutdown; errors are intentionally ignored because
 /// the connection is being discarded regardless.
-async fn shutdown_conn(conn: &mut tokio::net::TcpStream) {
+async fn shutdown_conn(conn: &mut RelayStream) {
     use tokio::io::AsyncWriteExt;
     conn.shutdown().await.ok();
 }
@@ -329,47 +466,137 @@ async fn shutdown_conn(conn: &mut tokio::net::TcpStream) {
 ///
 /// # Two levels of connection reuse
 ///
-/// - **Bridge-level (this function)**: The Rust↔Go TCP connection is
-///   use-once. Once this function returns, the TCP stream is closed and the
+/// - **Bridge-level (this function)**: The Rust↔Go connection is
+///   use-once. Once this function returns, the stream is closed and the
 ///   outer loop establishes a replacement idle connection.
 ///
-/// - **Request-level (inside `main_process`)**: The Flutter↔Rust TLS session
-///   that runs *over* the Go bridge is long-lived. `main_process` loops over
+/// - **Request-level (inside `serve_transport`)**: The Flutter↔Rust TLS session
+///   that runs *over* the Go bridge is long-lived. `serve_transport` loops over
 ///   multiple Flutter requests (file chunks, clipboard, etc.) on the same TLS
 ///   stream, and Flutter reuses it via `ConnectionManager.get/put` — behavior
 ///   identical to the direct-connect path.
-async fn handle_relay(conn: tokio::net::TcpStream) {
+async fn handle_relay(conn: RelayStream) {
     use crate::config;
     use tokio::io::AsyncWriteExt;
     use tracing::{debug, error};
     debug!("new relay connection");
 
-    let tls_stream = match config::TLS_ACCEPTOR.accept(conn).await {
+    let tls_stream = match config::tls_acceptor().accept(conn).await {
         Ok(tls_stream) => tls_stream,
         Err(err) => {
             error!("relay tls accept error: {}", err);
-            // Underlying TCP stream is dropped here, closing the connection.
+            // Underlying stream is dropped here, closing the connection.
             return;
         }
     };
     debug!("relay tls accept success");
 
-    match crate::route::main_process(tls_stream).await {
+    // A relayed connection is not bound to a listen endpoint.
+    let remote_addr = std::net::SocketAddr::from(([0, 0, 0, 0], 0));
+    let policy = crate::listen::EndpointPolicy::unbound_from_config();
+    match crate::route::serve_transport(tls_stream, remote_addr, policy).await {
         Some(tls_conn) => {
             debug!("relay session completed normally");
             let (mut io, _) = tls_conn.into_inner();
             io.shutdown().await.ok();
         }
         None => {
-            // main_process returned None -- the TLS stream is already
-            // consumed / dropped, so the underlying TCP connection closes.
-            debug!("relay session ended (main_process returned None)");
+            // serve_transport returned None -- the TLS stream is already
+            // consumed / dropped, so the underlying connection closes.
+            debug!("relay session ended (serve_transport returned None)");
+        }
+    }
+}
+
+/// Answers a rendezvous offer with the endpoints of a new UDP socket, punches
+/// towards the client and serves its direct QUIC connections, if any arrive.
+/// The relay connection is used up either way.
+async fn handle_rendezvous(
+    conn: RelayStream,
+    head: crate::relay::protocol::CommonReqHead,
+    cipher: Option<crate::utils::encrypt::AesGcmCipher>,
+    relay_address: String,
+) {
+    let serve = |socket| {
+        crate::quic::serve_punched(socket, crate::relay::rendezvous::DIRECT_CONNECT_TIMEOUT)
+    };
+    rendezvous_with(conn, head, cipher, relay_address, serve).await;
+}
+
+/// [`handle_rendezvous`], with `serve` serving the punched socket
+async fn rendezvous_with<F, Fut>(
+    mut conn: RelayStream,
+    head: crate::relay::protocol::CommonReqHead,
+    cipher: Option<crate::utils::encrypt::AesGcmCipher>,
+    relay_address: String,
+    serve: F,
+) where
+    F: FnOnce(std::net::UdpSocket) -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    use crate::relay::protocol::{Action, RendezvousAnswer, RendezvousOffer, RespHead, StatusCode};
+    use crate::relay::rendezvous;
+    use tracing::{debug, error, info};
+
+    let Some(cipher) = cipher else {
+        shutdown_conn(&mut conn).await;
+        return;
+    };
+    let offer = crate::relay::transfer::read_from::<_, RendezvousOffer>(
+        &mut conn,
+        head.data_len,
+        Some(&cipher),
+    )
+    .await;
+    let Ok(offer) = offer else {
+        shutdown_conn(&mut conn).await;
+        return;
+    };
+    let (socket, local_endpoints) = match rendezvous::bind(&relay_address, &offer.token).await {
+        Ok(bound) => bound,
+        Err(e) => {
+            error!("bind rendezvous socket error: {}", e);
+            let resp = RespHead {
+                code: StatusCode::Error,
+                msg: e.to_string(),
+                action: Action::Rendezvous,
+                data_len: 0,
+            };
+            let _ = resp.write_to(&mut conn, Some(&cipher)).await;
+            shutdown_conn(&mut conn).await;
+            return;
         }
+    };
+    let answer = RendezvousAnswer { local_endpoints };
+    let answered = RespHead::write_with_body(Action::Rendezvous, &mut conn, &cipher, &answer).await;
+    shutdown_conn(&mut conn).await;
+    if answered.is_err() {
+        return;
+    }
+
+    let peers: Vec<std::net::SocketAddr> = offer
+        .peer_endpoints
+        .iter()
+        .take(rendezvous::MAX_ENDPOINTS)
+        .filter_map(|endpoint| endpoint.parse().ok())
+        .collect();
+    info!("punching towards {:?}", peers);
+    rendezvous::punch(&socket, &peers).await;
+    let socket = match socket.into_std() {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("convert rendezvous socket error: {}", e);
+            return;
+        }
+    };
+    match serve(socket).await {
+        Ok(()) => debug!("direct connection ended"),
+        Err(e) => error!("serve direct connection error: {}", e),
     }
 }
 
 async fn handle_heartbeat(
-    conn: &mut tokio::net::TcpStream,
+    conn: &mut RelayStream,
     head: crate::relay::protocol::CommonReqHead,
     cipher: Option<&crate::utils::encrypt::AesGcmCipher>,
 ) -> Result<(), ()> {
@@ -379,6 +606,15 @@ async fn handle_heartbeat(
     }
 
     let heartbeat_req = HeartbeatReq::read_from(conn, head.data_len, cipher).await?;
+    if let Some(missed) = heartbeat_req.missed_requests.filter(|n| *n > 0) {
+        use crate::relay::pool::POOL_METRICS;
+        POOL_METRICS.missed(missed);
+        tracing::info!(
+            "{} relay requests found no idle connection, pool: {:?}",
+            missed,
+            POOL_METRICS.stats()
+        );
+    }
     if heartbeat_req.need_resp {
         let resp_head = RespHead {
             code: StatusCode::Success,
@@ -394,49 +630,38 @@ async fn handle_heartbeat(
     Ok(())
 }
 
-fn hash_to_aes192_key(c: &[u8]) -> [u8; 24] {
+pub(crate) fn hash_to_aes192_key(c: &[u8]) -> [u8; 24] {
     use crate::utils::encrypt;
     let hash = encrypt::compute_sha256(c);
     hash[..24].try_into().unwrap()
 }
 
-fn get_aes192_key_selector(key: &[u8; 24]) -> String {
+pub(crate) fn get_aes192_key_selector(key: &[u8; 24]) -> String {
     use crate::utils::encrypt;
     let hash = encrypt::compute_sha256(key);
     hex::encode(&hash[..4])
 }
 
+/// The KDF salt of one relay server and the keys derived from it
+#[derive(Default)]
 struct SaltCache {
     salt_b64: Option<String>,
-    cur_pwd: Option<String>,
-    kdf_key: Option<crate::utils::encrypt::Aes192Key>,
-    // selector: String,
+    /// Derived keys by password, there are two during a key rotation
+    kdf_keys: std::collections::HashMap<String, crate::utils::encrypt::Aes192Key>,
 }
 
 impl SaltCache {
-    fn new() -> Self {
-        Self {
-            salt_b64: None,
-            cur_pwd: None,
-            kdf_key: None,
-        }
-    }
-
     fn get_kdf_key_cached(
         &mut self,
         pwd: Option<&String>,
     ) -> (Option<crate::utils::encrypt::Aes192Key>, Option<String>) {
-        // use crate::config;
-        let pwd = match pwd {
-            Some(pwd) => pwd,
-            _ => return (None, None),
+        let (Some(pwd), Some(salt_b64)) = (pwd, self.salt_b64.clone()) else {
+            return (None, None);
         };
-        if let (Some(p), Some(_)) = (&self.cur_pwd, &self.salt_b64)
-            && *p == *pwd
-        {
-            return (self.kdf_key, self.salt_b64.clone());
+        match self.kdf_key(pwd) {
+            Some(key) => (Some(key), Some(salt_b64)),
+            None => (None, None),
         }
-        (None, None)
     }
 
     fn set_kdf_key(
@@ -444,32 +669,253 @@ impl SaltCache {
         pwd: Option<&String>,
         salt_b64: Option<&String>,
     ) -> Option<crate::utils::encrypt::Aes192Key> {
-        use base64::prelude::*;
-        use tracing::error;
         let (pwd, salt_b64) = match (pwd, salt_b64) {
             (Some(pwd), Some(salt_b64)) => (pwd, salt_b64),
             _ => return None,
         };
-        if let (Some(p), Some(s)) = (&self.cur_pwd, &self.salt_b64)
-            && *p == *pwd
-            && *s == *salt_b64
-        {
-            return self.kdf_key;
+        if self.salt_b64.as_ref() != Some(salt_b64) {
+            self.salt_b64 = Some(salt_b64.clone());
+            self.kdf_keys.clear();
         }
-        let salt = match BASE64_STANDARD.decode(salt_b64) {
+        self.kdf_key(pwd)
+    }
+
+    fn kdf_key(&mut self, pwd: &String) -> Option<crate::utils::encrypt::Aes192Key> {
+        use base64::prelude::*;
+        use tracing::error;
+        if let Some(key) = self.kdf_keys.get(pwd) {
+            return Some(*key);
+        }
+        let salt = match BASE64_STANDARD.decode(self.salt_b64.as_ref()?) {
             Ok(salt) => salt,
             Err(e) => {
                 error!("decode salt error: {}", e);
                 return None;
             }
         };
-        self.cur_pwd = Some(pwd.clone());
-        self.salt_b64 = Some(salt_b64.clone());
         let kdf_key = aes192_key_kdf(pwd.as_bytes(), &salt);
-        // println!("kdf_key: {:?}", kdf_key);
-        self.kdf_key = Some(kdf_key);
+        self.kdf_keys.insert(pwd.clone(), kdf_key);
         Some(kdf_key)
     }
 }
-static RELAY_SALT: std::sync::LazyLock<std::sync::Mutex<SaltCache>> =
-    std::sync::LazyLock::new(|| std::sync::Mutex::new(SaltCache::new()));
+
+/// Salts by relay server address, persisted in `relay_salt.json` next to the
+/// config file so that a restart does not cost a salt exchange. Salts are
+/// handed out by the relay server to anyone, derived keys are never stored.
+static RELAY_SALT_FILE_PATH: std::sync::LazyLock<std::path::PathBuf> =
+    std::sync::LazyLock::new(|| {
+        crate::config::CONFIG_FILE_PATH
+            .parent()
+            .unwrap_or(std::path::Path::new(""))
+            .join("relay_salt.json")
+    });
+
+static RELAY_SALT: std::sync::LazyLock<
+    std::sync::Mutex<std::collections::HashMap<String, SaltCache>>,
+> = std::sync::LazyLock::new(|| {
+    use tracing::error;
+    let salts: std::collections::HashMap<String, String> = std::fs::read(&*RELAY_SALT_FILE_PATH)
+        .ok()
+        .and_then(|data| {
+            serde_json::from_slice(&data)
+                .inspect_err(|e| error!("parse {} error: {}", RELAY_SALT_FILE_PATH.display(), e))
+                .ok()
+        })
+        .unwrap_or_default();
+    let caches = salts
+        .into_iter()
+        .map(|(address, salt_b64)| {
+            let cache = SaltCache {
+                salt_b64: Some(salt_b64),
+                ..Default::default()
+            };
+            (address, cache)
+        })
+        .collect();
+    std::sync::Mutex::new(caches)
+});
+
+static SALTS_GENERATION: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+/// Generation of the salts on disk, held while writing so writes do not interleave.
+static SAVED_SALTS_GENERATION: std::sync::Mutex<u64> = std::sync::Mutex::new(0);
+
+/// Serializes the salts under the caller's lock and writes them on a blocking
+/// thread, so the handshake never waits on the disk.
+fn save_relay_salts(caches: &std::collections::HashMap<String, SaltCache>) {
+    let salts: std::collections::HashMap<_, _> = caches
+        .iter()
+        .filter_map(|(address, cache)| Some((address, cache.salt_b64.as_ref()?)))
+        .collect();
+    let data = match serde_json::to_vec(&salts) {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::error!("serialize relay salts error: {}", e);
+            return;
+        }
+    };
+    let generation = SALTS_GENERATION.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+    crate::RUNTIME.spawn_blocking(move || {
+        let mut saved = SAVED_SALTS_GENERATION.lock().unwrap();
+        if *saved > generation {
+            // Newer salts are on disk already.
+            return;
+        }
+        let tmp_path = RELAY_SALT_FILE_PATH.with_extension("json.tmp");
+        let r = std::fs::write(&tmp_path, data)
+            .and_then(|_| std::fs::rename(&tmp_path, &*RELAY_SALT_FILE_PATH));
+        match r {
+            Ok(()) => *saved = generation,
+            Err(e) => tracing::error!("save {} error: {}", RELAY_SALT_FILE_PATH.display(), e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn salt_cache_derives_keys_per_password() {
+        use base64::prelude::*;
+        let old = "old".to_string();
+        let new = "new".to_string();
+        let mut cache = SaltCache::default();
+        assert_eq!(cache.get_kdf_key_cached(Some(&new)), (None, None));
+
+        let salt_b64 = BASE64_STANDARD.encode(b"salt");
+        let key = cache.set_kdf_key(Some(&new), Some(&salt_b64)).unwrap();
+        assert_eq!(key, aes192_key_kdf(b"new", b"salt"));
+        // A persisted salt serves any password.
+        let (old_key, salt) = cache.get_kdf_key_cached(Some(&old));
+        assert_eq!(old_key, Some(aes192_key_kdf(b"old", b"salt")));
+        assert_eq!(salt, Some(salt_b64));
+
+        let other_salt_b64 = BASE64_STANDARD.encode(b"other");
+        cache.set_kdf_key(Some(&new), Some(&other_salt_b64));
+        assert_eq!(cache.kdf_keys.len(), 1);
+        assert_eq!(cache.get_kdf_key_cached(None), (None, None));
+    }
+
+    fn session_cipher() -> crate::utils::encrypt::AesGcmCipher {
+        crate::utils::encrypt::AesGcmCipher::new(&[7u8; 24]).unwrap()
+    }
+
+    /// A relay connection on which the relay server sent a rendezvous offer
+    /// for a client at `peer`, with the device's end of it and the head read.
+    async fn offered_rendezvous(
+        peer: std::net::SocketAddr,
+    ) -> (
+        tokio::net::TcpStream,
+        RelayStream,
+        crate::relay::protocol::CommonReqHead,
+        crate::utils::encrypt::AesGcmCipher,
+    ) {
+        use crate::relay::protocol::{Action, CommonReqHead, RendezvousOffer};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut relay = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (device, _) = listener.accept().await.unwrap();
+        let mut device: RelayStream = Box::new(device);
+        let cipher = session_cipher();
+        let offer = RendezvousOffer {
+            token: "0a1b".to_string(),
+            peer_endpoints: vec![peer.to_string()],
+        };
+        CommonReqHead::write_with_body(Action::Rendezvous, &mut relay, Some(&cipher), &offer)
+            .await
+            .unwrap();
+        let head = CommonReqHead::read_from(&mut device, Some(&cipher))
+            .await
+            .unwrap();
+        (relay, device, head, cipher)
+    }
+
+    #[tokio::test]
+    async fn rendezvous_punches_towards_the_client_and_serves_its_socket() {
+        use crate::relay::protocol::{RendezvousAnswer, RespHead, StatusCode};
+        use crate::relay::rendezvous;
+
+        // The relay server's UDP port, it observes the device's socket.
+        let relay_udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_address = relay_udp.local_addr().unwrap().to_string();
+        let observed = tokio::spawn(async move {
+            let mut buf = [0u8; 128];
+            loop {
+                let (n, from) = relay_udp.recv_from(&mut buf).await.unwrap();
+                if rendezvous::binding_token(&buf[..n]) == Some("0a1b") {
+                    let packet = rendezvous::bound_packet(from);
+                    relay_udp.send_to(&packet, from).await.unwrap();
+                    return from;
+                }
+            }
+        });
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mut relay, device, head, cipher) =
+            offered_rendezvous(client.local_addr().unwrap()).await;
+        let (served_tx, served_rx) = tokio::sync::oneshot::channel();
+        let serve = |socket| async move {
+            served_tx.send(socket).unwrap();
+            Ok(())
+        };
+        tokio::spawn(rendezvous_with(
+            device,
+            head,
+            Some(session_cipher()),
+            relay_address,
+            serve,
+        ));
+
+        let resp = RespHead::read_from(&mut relay, Some(&cipher))
+            .await
+            .unwrap();
+        assert_eq!(resp.code, StatusCode::Success);
+        let answer: RendezvousAnswer =
+            crate::relay::transfer::read_from(&mut relay, resp.data_len, Some(&cipher))
+                .await
+                .unwrap();
+        // Bound to all addresses, the local endpoint is the observed one on loopback.
+        assert!(answer.local_endpoints.is_empty());
+        let device_addr = observed.await.unwrap();
+
+        // The punch arrives from the socket the relay server observed.
+        let mut buf = [0u8; 64];
+        let (n, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"WSPUNCH");
+        assert_eq!(from.port(), device_addr.port());
+
+        let socket = served_rx.await.unwrap();
+        assert_eq!(socket.local_addr().unwrap().port(), device_addr.port());
+        client.send_to(b"direct", device_addr).await.unwrap();
+        let socket = tokio::net::UdpSocket::from_std(socket).unwrap();
+        loop {
+            let (n, _) = socket.recv_from(&mut buf).await.unwrap();
+            if &buf[..n] == b"direct" {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rendezvous_is_refused_when_the_relay_does_not_observe_the_device() {
+        use crate::relay::protocol::{RespHead, StatusCode};
+        use tokio::io::AsyncReadExt;
+
+        // Nothing answers the binding, the client falls back to the relay.
+        let relay_udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_address = relay_udp.local_addr().unwrap().to_string();
+        let (mut relay, device, head, cipher) =
+            offered_rendezvous("127.0.0.1:9".parse().unwrap()).await;
+        let serve = |_| async { panic!("nothing to serve") };
+        rendezvous_with(device, head, Some(session_cipher()), relay_address, serve).await;
+
+        let resp = RespHead::read_from(&mut relay, Some(&cipher))
+            .await
+            .unwrap();
+        assert_eq!(resp.code, StatusCode::Error);
+        let mut rest = Vec::new();
+        relay.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
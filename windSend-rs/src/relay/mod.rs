@@ -1,5 +1,11 @@
+pub mod mailbox;
 mod main;
+pub mod pool;
 pub mod protocol;
+pub mod rendezvous;
 pub mod run;
+pub mod select;
+pub mod server;
 pub mod transfer;
+pub mod transport;
 pub use main::*;
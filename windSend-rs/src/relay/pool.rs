@@ -0,0 +1,144 @@
+//! Sizing and metrics of the pool of idle relay connections.
+//!
+//! Every relayed session uses up one idle connection, and a request that
+//! arrives while the pool is empty waits for a reconnect, or is refused by the
+//! relay server. The pool therefore grows to the most sessions that ran at
+//! the same time within the last `DEMAND_WINDOW`, within `minIdle` and
+//! `maxIdle`. It shrinks by not replacing used connections once the demand
+//! is gone.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long a burst of sessions keeps the pool large
+const DEMAND_WINDOW: Duration = Duration::from_secs(120);
+
+/// Configurable as `relayPool`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayPoolConfig {
+    #[serde(rename = "minIdle")]
+    pub min_idle: usize,
+    #[serde(rename = "maxIdle")]
+    pub max_idle: usize,
+    /// An idle connection without heartbeat or request for this long is
+    /// considered dead and replaced
+    #[serde(rename = "idleTimeoutSecs")]
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for RelayPoolConfig {
+    fn default() -> Self {
+        Self {
+            min_idle: 1,
+            max_idle: 4,
+            idle_timeout_secs: 180,
+        }
+    }
+}
+
+impl RelayPoolConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs.max(1))
+    }
+}
+
+/// Recent numbers of concurrent relay sessions
+#[derive(Debug, Default)]
+pub struct Demand {
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl Demand {
+    /// Records the number of sessions running after one started.
+    pub fn record(&mut self, now: Instant, sessions: usize) {
+        self.samples.push_back((now, sessions));
+    }
+
+    /// Number of idle connections to keep
+    pub fn target(&mut self, now: Instant, config: &RelayPoolConfig) -> usize {
+        while let Some((at, _)) = self.samples.front()
+            && now.duration_since(*at) > DEMAND_WINDOW
+        {
+            self.samples.pop_front();
+        }
+        let peak = self.samples.iter().map(|(_, n)| *n).max().unwrap_or(0);
+        let min_idle = config.min_idle.max(1);
+        peak.clamp(min_idle, config.max_idle.max(min_idle))
+    }
+}
+
+/// Counters of the relay connection pool since the program started
+#[derive(Debug, Default)]
+pub struct PoolMetrics {
+    /// Relay requests served by an idle connection
+    hits: AtomicU64,
+    /// Relay requests that found no idle connection, as reported by the relay server
+    misses: AtomicU64,
+    /// Idle connections that stopped receiving heartbeats
+    unhealthy: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub unhealthy: u64,
+}
+
+impl PoolMetrics {
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn missed(&self, requests: u64) {
+        self.misses.fetch_add(requests, Ordering::Relaxed);
+    }
+
+    pub fn unhealthy(&self) {
+        self.unhealthy.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            unhealthy: self.unhealthy.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub static POOL_METRICS: std::sync::LazyLock<PoolMetrics> =
+    std::sync::LazyLock::new(PoolMetrics::default);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_follows_recent_peak() {
+        let config = RelayPoolConfig::default();
+        let mut demand = Demand::default();
+        let start = Instant::now();
+        assert_eq!(demand.target(start, &config), 1);
+
+        demand.record(start, 3);
+        demand.record(start + Duration::from_secs(10), 2);
+        assert_eq!(demand.target(start + Duration::from_secs(10), &config), 3);
+        demand.record(start + Duration::from_secs(20), 9);
+        assert_eq!(demand.target(start + Duration::from_secs(20), &config), 4);
+
+        // The burst is forgotten after the window.
+        let later = start + Duration::from_secs(20) + DEMAND_WINDOW + Duration::from_secs(1);
+        assert_eq!(demand.target(later, &config), 1);
+
+        let fixed = RelayPoolConfig {
+            min_idle: 2,
+            max_idle: 0,
+            ..Default::default()
+        };
+        assert_eq!(demand.target(later, &fixed), 2);
+    }
+}
@@ -79,8 +79,21 @@ pub struct HandshakeResp {
     pub kdf_salt_b64: String,
     #[serde(rename = "ecdhPublicKeyB64")]
     pub ecdh_public_key_b64: String,
+    /// Optional features of the relay server, like [`CAPABILITY_MAILBOX`]
+    #[serde(rename = "capabilities", default)]
+    pub capabilities: Vec<String>,
+    /// Token for the binding packet of a rendezvous, with [`CAPABILITY_RENDEZVOUS`]
+    #[serde(rename = "rendezvousToken", default)]
+    pub rendezvous_token: String,
 }
 
+/// The relay server keeps letters for offline devices
+pub const CAPABILITY_MAILBOX: &str = "mailbox";
+
+/// The relay server, or a device, takes part in hole punching, see
+/// [`super::rendezvous`]
+pub const CAPABILITY_RENDEZVOUS: &str = "rendezvous";
+
 impl HandshakeResp {
     pub async fn read_from<R>(conn: &mut R) -> Result<Self, ()>
     where
@@ -108,7 +121,6 @@ impl CommonReqHead {
         read_head_from(conn, cipher).await
     }
 
-    #[allow(dead_code)]
     pub async fn write_to<W>(
         &self,
         writer: &mut W,
@@ -199,12 +211,60 @@ impl RespHead {
     {
         write_head_to(self, writer, cipher).await
     }
+
+    pub async fn write_with_body<W, T>(
+        action: Action,
+        writer: &mut W,
+        cipher: &crate::utils::encrypt::AesGcmCipher,
+        body: &T,
+    ) -> Result<(), ()>
+    where
+        W: tokio::io::AsyncWrite + Unpin + ?Sized,
+        T: Serialize,
+    {
+        use tokio::io::AsyncWriteExt;
+        use tracing::error;
+
+        let json_buf =
+            serde_json::to_vec(body).map_err(|e| error!("json marshal failed, err: {}", e))?;
+        let json_buf = cipher
+            .encrypt(&json_buf, b"")
+            .map_err(|e| error!("encrypt failed, err: {}", e))?;
+        let head = RespHead {
+            code: StatusCode::Success,
+            msg: String::new(),
+            action,
+            data_len: json_buf.len() as i32,
+        };
+        head.write_to(writer, Some(cipher)).await?;
+        writer
+            .write_all(&json_buf)
+            .await
+            .map_err(|e| error!("write body failed, err: {}", e))?;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ConnectionReq {
     #[serde(flatten)]
     pub common: CommonReq,
+    #[serde(rename = "device", default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<DeviceDescriptor>,
+}
+
+/// What a device tells about itself when it registers, shown by `presence`
+/// to the holders of the device's secret key
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceDescriptor {
+    #[serde(rename = "name", default)]
+    pub name: String,
+    #[serde(rename = "version", default)]
+    pub version: String,
+    /// Route actions the device serves, and relay features like
+    /// [`CAPABILITY_RENDEZVOUS`]
+    #[serde(rename = "capabilities", default)]
+    pub capabilities: Vec<String>,
 }
 
 impl ConnectionReq {
@@ -231,6 +291,16 @@ pub enum Action {
     Close,
     #[serde(rename = "heartbeat")]
     Heartbeat,
+    #[serde(rename = "mailboxPut")]
+    MailboxPut,
+    #[serde(rename = "mailboxFetch")]
+    MailboxFetch,
+    #[serde(rename = "mailboxAck")]
+    MailboxAck,
+    #[serde(rename = "presence")]
+    Presence,
+    #[serde(rename = "rendezvous")]
+    Rendezvous,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -239,10 +309,17 @@ pub struct HeartbeatReq {
     pub common: CommonReq,
     #[serde(rename = "needResp")]
     pub need_resp: bool,
+    /// Relay requests for the device that found no idle connection since the
+    /// previous heartbeat, sent by the relay server
+    #[serde(
+        rename = "missedRequests",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub missed_requests: Option<u64>,
 }
 
 impl HeartbeatReq {
-    #[allow(dead_code)]
     pub async fn write_to<W>(
         &self,
         writer: &mut W,
@@ -266,6 +343,127 @@ impl HeartbeatReq {
     }
 }
 
+/// Deposits a letter for the device `id`, its `size` bytes follow the body.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailboxPutReq {
+    #[serde(flatten)]
+    pub common: CommonReq,
+    #[serde(rename = "size")]
+    pub size: u64,
+    /// Unix time in seconds, the relay server may shorten it
+    #[serde(rename = "expiresAt")]
+    pub expires_at: i64,
+}
+
+/// A letter kept by the relay server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailboxItem {
+    #[serde(rename = "seq")]
+    pub seq: u64,
+    #[serde(rename = "size")]
+    pub size: u64,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: i64,
+}
+
+/// Lists the letters of the device `id`, whose secret key the caller proves
+/// to hold with `keyProof`, see [`crate::relay::mailbox::key_proof`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailboxFetchReq {
+    #[serde(flatten)]
+    pub common: CommonReq,
+    #[serde(rename = "keyProof")]
+    pub key_proof: String,
+}
+
+/// Body of the response to `mailboxFetch`, the letters follow it in order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailboxFetchResp {
+    #[serde(rename = "items")]
+    pub items: Vec<MailboxItem>,
+}
+
+/// Deletes delivered letters from the mailbox of the device `id`, only
+/// letters fetched on the same connection are deleted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailboxAckReq {
+    #[serde(flatten)]
+    pub common: CommonReq,
+    #[serde(rename = "seqs")]
+    pub seqs: Vec<u64>,
+}
+
+/// Lists the connections registered for the device `id`, whose secret key
+/// the caller proves to hold like for `mailboxFetch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresenceReq {
+    #[serde(flatten)]
+    pub common: CommonReq,
+    #[serde(rename = "keyProof")]
+    pub key_proof: String,
+}
+
+/// Body of the response to `presence`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresenceResp {
+    /// Idle connections, oldest first
+    #[serde(rename = "connections")]
+    pub connections: Vec<PresenceEntry>,
+    #[serde(rename = "busyConns")]
+    pub busy_conns: usize,
+}
+
+/// An idle connection and the device that registered it, several devices
+/// sharing a secret key register under the same id
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresenceEntry {
+    #[serde(flatten)]
+    pub device: DeviceDescriptor,
+    /// Unix time in seconds of the registration or the last answered heartbeat
+    #[serde(rename = "lastHeartbeat")]
+    pub last_heartbeat: i64,
+}
+
+/// Asks for the endpoints of the device `id` for a direct connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RendezvousReq {
+    #[serde(flatten)]
+    pub common: CommonReq,
+    /// Token of the binding packet the client sent from its socket, as
+    /// issued in the handshake
+    #[serde(rename = "token")]
+    pub token: String,
+    /// Addresses of the client's socket on its own networks
+    #[serde(rename = "localEndpoints", default)]
+    pub local_endpoints: Vec<String>,
+}
+
+/// Body of the `rendezvous` request the relay server sends to a device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RendezvousOffer {
+    /// Token for the binding packet of the device's socket
+    #[serde(rename = "token")]
+    pub token: String,
+    /// The client's observed endpoint first, then its local ones
+    #[serde(rename = "peerEndpoints")]
+    pub peer_endpoints: Vec<String>,
+}
+
+/// Body of the device's response to a `rendezvous` offer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RendezvousAnswer {
+    #[serde(rename = "localEndpoints", default)]
+    pub local_endpoints: Vec<String>,
+}
+
+/// Body of the response to `rendezvous`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RendezvousResp {
+    /// The device's observed endpoint first, then its local ones
+    #[serde(rename = "peerEndpoints")]
+    pub peer_endpoints: Vec<String>,
+}
+
 // #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 // pub struct RelayReq {
 //     #[serde(flatten)]
@@ -0,0 +1,290 @@
+//! Direct connections between a client and a device, brokered by the relay.
+//!
+//! Relayed traffic is capped by the bandwidth of the relay server. When the
+//! relay server and the device support it, a client can first try a direct
+//! QUIC connection through UDP hole punching:
+//!
+//! 1. The client binds a UDP socket and sends a binding packet with the token
+//!    the relay server issued in the handshake to the relay server's UDP port,
+//!    which is the same as its TCP port. The relay server remembers the
+//!    address it saw and echoes it back. Packets with tokens it did not issue
+//!    are ignored, and only so many addresses are remembered, per source too.
+//! 2. The client sends `rendezvous` with the device id, its token and its
+//!    local endpoints. The relay server passes them, with the observed
+//!    endpoint, to an idle connection of the device.
+//! 3. The device binds a socket of its own the same way and answers with its
+//!    local endpoints, which the client gets after the device's observed one.
+//! 4. Both send packets to the other's endpoints, opening their NAT mappings,
+//!    and the client connects with QUIC. The device serves the route protocol
+//!    on that socket like the QUIC listener does, see
+//!    [`crate::quic::serve_punched`].
+//!
+//! When the rendezvous fails or no QUIC connection comes up, the client sends
+//! `relay` on the same relay connection and the session runs on the bridge.
+
+use crate::utils::encrypt;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// Start of a binding packet, followed by the token
+pub const BIND_PREFIX: &[u8] = b"WSBIND";
+/// Start of the answer to a binding packet, followed by the observed address
+pub const BOUND_PREFIX: &[u8] = b"WSBOUND";
+const PUNCH_PACKET: &[u8] = b"WSPUNCH";
+
+const BIND_ATTEMPTS: u32 = 5;
+const BIND_RETRY_INTERVAL: Duration = Duration::from_millis(300);
+const PUNCH_ROUNDS: u32 = 5;
+const PUNCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Time the device waits for the client's QUIC connection after punching
+pub const DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Endpoints passed on to a peer, more are dropped
+pub const MAX_ENDPOINTS: usize = 8;
+
+/// Tokens are random hex strings
+const MAX_TOKEN_LEN: usize = 64;
+
+/// Random bytes of an issued token, followed by as many bytes of its tag
+const TOKEN_NONCE_LEN: usize = 8;
+
+/// Time an observed endpoint waits for its `rendezvous` request
+const OBSERVATION_TTL: Duration = Duration::from_secs(60);
+
+/// Observed endpoints kept at most
+const MAX_OBSERVATIONS: usize = 4096;
+
+/// Observed endpoints kept at most for one source address
+const MAX_OBSERVATIONS_PER_SOURCE: usize = 16;
+
+/// Endpoints the relay server saw on its UDP port, by token
+pub struct Observations {
+    /// Tags the issued tokens
+    key: Vec<u8>,
+    seen: HashMap<String, (SocketAddr, Instant)>,
+    per_source: HashMap<IpAddr, usize>,
+}
+
+impl Default for Observations {
+    fn default() -> Self {
+        Self {
+            key: encrypt::rand_n_bytes2(32),
+            seen: HashMap::new(),
+            per_source: HashMap::new(),
+        }
+    }
+}
+
+impl Observations {
+    /// A token for a binding packet, only issued tokens are recorded.
+    pub fn issue_token(&self) -> String {
+        let nonce = encrypt::generate_rand_bytes_hex(TOKEN_NONCE_LEN);
+        let tag = self.tag(&nonce);
+        nonce + &tag
+    }
+
+    fn tag(&self, nonce: &str) -> String {
+        let mac = encrypt::compute_sha256(&[&self.key, nonce.as_bytes()].concat());
+        hex::encode(&mac[..TOKEN_NONCE_LEN])
+    }
+
+    fn issued(&self, token: &str) -> bool {
+        token.len() == TOKEN_NONCE_LEN * 4
+            && token.is_ascii()
+            && token[TOKEN_NONCE_LEN * 2..] == self.tag(&token[..TOKEN_NONCE_LEN * 2])
+    }
+
+    /// Records where a binding packet came from, returns whether it was
+    /// recorded and should be answered.
+    pub fn record(&mut self, token: &str, addr: SocketAddr, now: Instant) -> bool {
+        if !self.issued(token) {
+            return false;
+        }
+        self.expire(now);
+        // A retry replaces the earlier observation.
+        self.remove(token);
+        let from_source = self.per_source.get(&addr.ip()).copied().unwrap_or(0);
+        if from_source >= MAX_OBSERVATIONS_PER_SOURCE || self.seen.len() >= MAX_OBSERVATIONS {
+            return false;
+        }
+        *self.per_source.entry(addr.ip()).or_default() += 1;
+        self.seen.insert(token.to_string(), (addr, now));
+        true
+    }
+
+    pub fn take(&mut self, token: &str, now: Instant) -> Option<SocketAddr> {
+        let (addr, at) = self.remove(token)?;
+        (now.duration_since(at) < OBSERVATION_TTL).then_some(addr)
+    }
+
+    fn remove(&mut self, token: &str) -> Option<(SocketAddr, Instant)> {
+        let (addr, at) = self.seen.remove(token)?;
+        release(&mut self.per_source, addr.ip());
+        Some((addr, at))
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let per_source = &mut self.per_source;
+        self.seen.retain(|_, (addr, at)| {
+            let expired = now.duration_since(*at) >= OBSERVATION_TTL;
+            if expired {
+                release(per_source, addr.ip());
+            }
+            !expired
+        });
+    }
+}
+
+fn release(per_source: &mut HashMap<IpAddr, usize>, ip: IpAddr) {
+    if let Some(count) = per_source.get_mut(&ip) {
+        *count -= 1;
+        if *count == 0 {
+            per_source.remove(&ip);
+        }
+    }
+}
+
+/// The token of a binding packet
+pub fn binding_token(packet: &[u8]) -> Option<&str> {
+    let token = std::str::from_utf8(packet.strip_prefix(BIND_PREFIX)?).ok()?;
+    let valid = !token.is_empty()
+        && token.len() <= MAX_TOKEN_LEN
+        && token.bytes().all(|b| b.is_ascii_hexdigit());
+    valid.then_some(token)
+}
+
+pub fn bound_packet(addr: SocketAddr) -> Vec<u8> {
+    [BOUND_PREFIX, addr.to_string().as_bytes()].concat()
+}
+
+/// Binds a socket for punching and has the relay server at `relay_address`
+/// observe it under `token`. Returns the socket and its local endpoints.
+pub async fn bind(relay_address: &str, token: &str) -> std::io::Result<(UdpSocket, Vec<String>)> {
+    use tracing::debug;
+
+    let relay = tokio::net::lookup_host(relay_address)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::other(format!("resolve {relay_address} failed")))?;
+    let unspecified: SocketAddr = match relay {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(unspecified).await?;
+    let packet = [BIND_PREFIX, token.as_bytes()].concat();
+    let mut buf = [0u8; 128];
+    let mut observed = None;
+    for _ in 0..BIND_ATTEMPTS {
+        socket.send_to(&packet, relay).await?;
+        let deadline = tokio::time::Instant::now() + BIND_RETRY_INTERVAL;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        {
+            let (n, from) = received?;
+            if from != relay {
+                continue;
+            }
+            if let Some(addr) = buf[..n].strip_prefix(BOUND_PREFIX) {
+                observed = std::str::from_utf8(addr)
+                    .ok()
+                    .and_then(|addr| addr.parse::<SocketAddr>().ok());
+                break;
+            }
+        }
+        if observed.is_some() {
+            break;
+        }
+    }
+    let Some(observed) = observed else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("relay server {relay_address} did not answer the binding"),
+        ));
+    };
+    debug!("udp endpoint observed by the relay server: {}", observed);
+
+    // The address the system picks towards the relay server is the one on the local network.
+    let route = std::net::UdpSocket::bind(unspecified)?;
+    route.connect(relay)?;
+    let local = SocketAddr::new(route.local_addr()?.ip(), socket.local_addr()?.port());
+    let local_endpoints = if local == observed {
+        Vec::new()
+    } else {
+        vec![local.to_string()]
+    };
+    Ok((socket, local_endpoints))
+}
+
+/// Sends packets to the peer's endpoints, so that the NAT in front of
+/// `socket` lets the peer's packets in.
+pub async fn punch(socket: &UdpSocket, peers: &[SocketAddr]) {
+    for _ in 0..PUNCH_ROUNDS {
+        for peer in peers {
+            if let Err(e) = socket.send_to(PUNCH_PACKET, peer).await {
+                tracing::debug!("punch {} error: {}", peer, e);
+            }
+        }
+        tokio::time::sleep(PUNCH_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn observations_are_taken_once_and_expire() {
+        let addr: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let mut observations = Observations::default();
+        let start = Instant::now();
+        let (aa, bb) = (observations.issue_token(), observations.issue_token());
+        assert!(observations.record(&aa, addr, start));
+        assert!(observations.record(&bb, addr, start));
+        assert_eq!(observations.take(&aa, start), Some(addr));
+        assert_eq!(observations.take(&aa, start), None);
+        assert_eq!(observations.take(&bb, start + OBSERVATION_TTL), None);
+
+        assert_eq!(binding_token(b"WSBIND0a1b"), Some("0a1b"));
+        assert_eq!(binding_token(b"WSBIND"), None);
+        assert_eq!(binding_token(b"WSBINDnot hex"), None);
+        assert_eq!(binding_token(b"WSPUNCH"), None);
+        assert_eq!(bound_packet(addr), b"WSBOUND203.0.113.7:40000");
+    }
+
+    #[test]
+    fn observations_are_limited() {
+        let mut observations = Observations::default();
+        let now = Instant::now();
+        let addr: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        assert!(!observations.record("c0ffee", addr, now));
+        let token = observations.issue_token();
+        let other_server_token = Observations::default().issue_token();
+        assert!(!observations.record(&other_server_token, addr, now));
+        // Retries do not count twice.
+        assert!(observations.record(&token, addr, now));
+        assert!(observations.record(&token, addr, now));
+
+        for _ in 1..MAX_OBSERVATIONS_PER_SOURCE {
+            assert!(observations.record(&observations.issue_token(), addr, now));
+        }
+        assert!(!observations.record(&observations.issue_token(), addr, now));
+        for i in 1..MAX_OBSERVATIONS / MAX_OBSERVATIONS_PER_SOURCE {
+            let source = SocketAddr::from(([198, 51, 100, i as u8], 40000));
+            for _ in 0..MAX_OBSERVATIONS_PER_SOURCE {
+                assert!(observations.record(&observations.issue_token(), source, now));
+            }
+        }
+        let source: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        assert!(!observations.record(&observations.issue_token(), source, now));
+
+        // Taken and expired observations make room again.
+        assert_eq!(observations.take(&token, now), Some(addr));
+        assert!(observations.record(&observations.issue_token(), addr, now));
+        let later = now + OBSERVATION_TTL;
+        assert!(observations.record(&observations.issue_token(), source, later));
+        assert_eq!(observations.seen.len(), 1);
+        assert_eq!(observations.per_source.len(), 1);
+    }
+}
@@ -1,35 +1,123 @@
-/// Maintains exactly one idle Rust↔Go control connection at all times.
+/// Time a relay server is passed over after it failed or stopped sending heartbeats
+const FAILOVER_COOLDOWN: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// The selected relay server is probed against the others again after this long,
+/// so that a recovered relay with a higher priority is used again.
+const RESELECT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// Runs one slot, or two with `relayStandby` so that the device stays
+/// registered on a second relay server, and waits until they have drained.
+async fn run_relay_listener(
+    notify_channel: tokio::sync::watch::Receiver<()>,
+    shutdown_rx: tokio::sync::watch::Receiver<bool>,
+) {
+    use crate::config;
+    use tracing::{error, info};
+
+    let (relays, standby) = {
+        let config = config::read_config();
+        (config.relay_endpoints(), config.relay_standby)
+    };
+    let addresses: Vec<_> = relays.iter().map(|relay| relay.address.as_str()).collect();
+    info!(
+        "run relay server, addresses: {:?}, standby: {}",
+        addresses, standby
+    );
+
+    let in_use = std::sync::Arc::new(Mutex::new(std::collections::HashSet::new()));
+    let mut slots = tokio::task::JoinSet::new();
+    for slot in 0..if standby { 2 } else { 1 } {
+        slots.spawn(run_relay_slot(
+            slot,
+            notify_channel.clone(),
+            shutdown_rx.clone(),
+            in_use.clone(),
+        ));
+    }
+    while let Some(result) = slots.join_next().await {
+        if let Err(e) = result {
+            error!("relay slot panicked: {e}");
+        }
+    }
+    // Ensure UI reflects the true state regardless of how we exited the loop
+    // (shutdown signal, config disable, or repeated connection failures after Spawned).
+    crate::relay::reset_relay_server_status();
+    info!("relay listener shut down gracefully, all tasks drained");
+}
+
+/// Picks the best relay server that is neither used by another slot nor
+/// cooling down, and reserves it. Relays that are cooling down are still
+/// used when nothing else is left.
+async fn select_relay(
+    in_use: &Mutex<std::collections::HashSet<String>>,
+    cooldown: &std::collections::HashMap<String, tokio::time::Instant>,
+) -> Option<crate::relay::select::RelayEndpoint> {
+    let now = tokio::time::Instant::now();
+    let free: Vec<_> = {
+        let in_use = in_use.lock().unwrap();
+        crate::config::read_config()
+            .relay_endpoints()
+            .into_iter()
+            .filter(|relay| !in_use.contains(&relay.address))
+            .collect()
+    };
+    let (cooling, mut candidates): (Vec<_>, Vec<_>) = free.into_iter().partition(|relay| {
+        cooldown
+            .get(&relay.address)
+            .is_some_and(|until| *until > now)
+    });
+    if candidates.is_empty() {
+        candidates = cooling;
+    }
+    let ranked = crate::relay::select::select(candidates).await;
+    // Another slot may have reserved one of them while probing.
+    let mut in_use = in_use.lock().unwrap();
+    let relay = ranked
+        .into_iter()
+        .find(|relay| !in_use.contains(&relay.address))?;
+    in_use.insert(relay.address.clone());
+    Some(relay)
+}
+
+/// Maintains a pool of idle Rust↔Go control connections on one relay server.
 ///
 /// # Connection lifecycle ("use-once, replenish immediately")
 ///
-/// Each Rust↔Go TCP connection serves a single relay bridge session, then is
+/// Each Rust↔Go connection serves a single relay bridge session, then is
 /// closed. When a Relay command arrives, the connection is handed off to a
-/// spawned task and this loop immediately reconnects to establish a fresh idle
-/// connection for the next request.
+/// spawned task and this loop immediately opens a fresh idle connection to
+/// the same relay server, as long as the pool is below the size that recent
+/// demand asks for (see `relay::pool`). A relay server is only selected again
+/// when its connections are lost, or after `RESELECT_INTERVAL`.
 ///
 /// This is the **bridge-level** (session-level) lifecycle. *Within* a single
-/// bridge session, the Flutter↔Rust TLS connection is long-lived: `main_process`
+/// bridge session, the Flutter↔Rust TLS connection is long-lived: `serve_transport`
 /// handles multiple requests (e.g. file chunks) on the same TLS stream, and
 /// Flutter's `ConnectionManager.get/put` reuses it across workers — identical
 /// to the direct-connect path.
-async fn run_relay_listener(
+async fn run_relay_slot(
+    slot: usize,
     mut notify_channel: tokio::sync::watch::Receiver<()>,
     mut shutdown_rx: tokio::sync::watch::Receiver<bool>,
+    in_use: std::sync::Arc<Mutex<std::collections::HashSet<String>>>,
 ) {
     use crate::config;
+    use crate::relay::pool::{Demand, POOL_METRICS};
     use crate::relay::{RelayExitReason, relay_main};
     use tokio::select;
     use tokio::task::JoinSet;
-    use tracing::{debug, error, info};
-
-    info!(
-        "run relay server, address: {}",
-        config::read_config().relay_server_address,
-    );
+    use tokio::time::Instant;
+    use tracing::{debug, error, info, warn};
 
-    let mut wait_duration = std::time::Duration::from_secs(3);
+    // No new idle connection is opened before this, to back off after failures.
+    let mut retry_at = Instant::now() + std::time::Duration::from_secs(3);
     let mut try_count: u32 = 0;
+    // Relay sessions handed off by idle connections
     let mut join_set: JoinSet<()> = JoinSet::new();
+    let mut idle: JoinSet<Option<RelayExitReason>> = JoinSet::new();
+    let mut current: Option<(crate::relay::select::RelayEndpoint, Instant)> = None;
+    let mut cooldown = std::collections::HashMap::new();
+    let mut demand = Demand::default();
 
     const MAX_TRY_COUNT: u32 = 30;
 
@@ -48,65 +136,171 @@ async fn run_relay_listener(
             break;
         }
 
-        select! {
-            _ = tokio::time::sleep(wait_duration) => (),
-            _ = notify_channel.changed() => (),
+        let pool_config = config::read_config().relay_pool.clone();
+        let target = demand.target(std::time::Instant::now(), &pool_config);
+        let ended = select! {
+            // Only wait for the backoff while the pool needs connections.
+            _ = tokio::time::sleep_until(retry_at), if idle.len() < target => None,
+            _ = notify_channel.changed() => None,
+            Some(result) = idle.join_next() => Some(result),
             // Wake immediately when the shutdown signal arrives so we
             // stop spawning new tasks without waiting for the full
             // sleep/backoff duration to elapse.
             _ = shutdown_rx.changed() => {
                 break;
             }
-        }
+        };
 
-        {
-            let config = config::read_config();
-            if config.relay_server_address.is_empty() || !config.enable_relay {
-                debug!("relay server not configured, skip relay");
-                // If relay gets disabled right after a successful spawned relay,
-                // wait_duration may be ZERO. Reset it to avoid a tight loop.
-                wait_duration = std::time::Duration::from_secs(3);
-                try_count = 0;
-                continue;
+        if let Some(result) = ended {
+            let reason = match result {
+                Ok(reason) => reason,
+                // Aborted when switching relay servers.
+                Err(e) if e.is_cancelled() => continue,
+                Err(e) => {
+                    error!("relay connection task panicked: {e}");
+                    None
+                }
+            };
+            // Idle connections only run while a relay server is selected.
+            let address = current
+                .as_ref()
+                .map(|(relay, _)| relay.address.clone())
+                .unwrap_or_default();
+            match reason {
+                Some(RelayExitReason::Spawned(fut)) => {
+                    join_set.spawn(fut);
+                    demand.record(std::time::Instant::now(), join_set.len());
+                    POOL_METRICS.hit();
+                    debug!("relay pool: {:?}", POOL_METRICS.stats());
+                    // Reset backoff: the connection was healthy enough to
+                    // receive a relay request, so replenish right away.
+                    try_count = 0;
+                    retry_at = Instant::now();
+                }
+                Some(RelayExitReason::Disconnected) => {
+                    // Connection was established but dropped; short wait.
+                    // relay_main already set status to false for this path.
+                    try_count = 0;
+                    retry_at = Instant::now() + std::time::Duration::from_secs(3);
+                }
+                Some(RelayExitReason::HeartbeatTimeout) => {
+                    // The other idle connections are as likely to be dead.
+                    warn!(
+                        "relay server {} stopped sending heartbeats, fail over",
+                        address
+                    );
+                    POOL_METRICS.unhealthy();
+                    idle.abort_all();
+                    cooldown.insert(address.clone(), Instant::now() + FAILOVER_COOLDOWN);
+                    crate::relay::update_relay_server_status(&address, false);
+                    in_use.lock().unwrap().remove(&address);
+                    current = None;
+                    try_count = 0;
+                    retry_at = Instant::now();
+                }
+                Some(RelayExitReason::Failed) | None => {
+                    // Connection setup or protocol failure; apply backoff.
+                    try_count += 1;
+                    retry_at = Instant::now()
+                        + if try_count >= MAX_TRY_COUNT {
+                            std::time::Duration::from_secs(60)
+                        } else {
+                            std::time::Duration::from_secs(3)
+                        };
+                    // Select a relay server again once no connection to this one is left.
+                    if idle.is_empty() {
+                        // relay_main sets status=false for Failed, but returns None
+                        // without touching status on connection-setup failures. Reset
+                        // here to avoid a stale `true` left by a prior Spawned.
+                        crate::relay::update_relay_server_status(&address, false);
+                        cooldown.insert(address.clone(), Instant::now() + FAILOVER_COOLDOWN);
+                        in_use.lock().unwrap().remove(&address);
+                        current = None;
+                    }
+                }
             }
         }
 
-        try_count += 1;
-
-        match relay_main().await {
-            Some(RelayExitReason::Spawned(fut)) => {
-                join_set.spawn(fut);
-                // Reset backoff: the connection was healthy enough to
-                // receive a relay request, so reconnect immediately.
-                try_count = 0;
-                // Skip the sleep at loop top -- reconnect right away to
-                // maintain an idle connection for the next relay request.
-                wait_duration = std::time::Duration::ZERO;
-                continue;
-            }
-            Some(RelayExitReason::Disconnected) => {
-                // Connection was established but dropped; short wait.
-                // relay_main already set status to false for this path.
-                try_count = 0;
-                wait_duration = std::time::Duration::from_secs(3);
-            }
-            Some(RelayExitReason::Failed) | None => {
-                // Connection setup or protocol failure; apply backoff.
-                // relay_main sets status=false for Failed, but returns None
-                // without touching status on connection-setup failures. Reset
-                // here to avoid a stale `true` left by a prior Spawned.
-                crate::relay::update_relay_server_status(false);
-                if try_count >= MAX_TRY_COUNT {
-                    wait_duration = std::time::Duration::from_secs(60);
-                } else {
-                    // A previous Spawned path sets wait_duration to ZERO for
-                    // immediate reconnect. On failure, restore a non-zero wait
-                    // to avoid a hot reconnect loop.
-                    wait_duration = std::time::Duration::from_secs(3);
+        if config::read_config().relay_endpoints().is_empty() {
+            debug!("relay server not configured, skip relay");
+            retry_at = Instant::now() + std::time::Duration::from_secs(3);
+            try_count = 0;
+            continue;
+        }
+
+        if let Some((relay, selected_at)) = &current
+            && selected_at.elapsed() >= RESELECT_INTERVAL
+        {
+            let relay = relay.clone();
+            in_use.lock().unwrap().remove(&relay.address);
+            match select_relay(&in_use, &cooldown).await {
+                Some(best) if best.address != relay.address => {
+                    info!(
+                        "relay slot {} switches from relay server {} to {}",
+                        slot, relay.address, best.address
+                    );
+                    idle.abort_all();
+                    crate::relay::update_relay_server_status(&relay.address, false);
+                    current = Some((best, Instant::now()));
+                }
+                Some(_) => current = Some((relay, Instant::now())),
+                // Keep the working relay server even if it did not answer the probe.
+                None => {
+                    in_use.lock().unwrap().insert(relay.address.clone());
+                    current = Some((relay, Instant::now()));
                 }
             }
         }
+        let relay = match &current {
+            Some((relay, _)) => relay.clone(),
+            None => match select_relay(&in_use, &cooldown).await {
+                Some(relay) => {
+                    info!(
+                        "relay slot {} selected relay server {}",
+                        slot, relay.address
+                    );
+                    current = Some((relay.clone(), Instant::now()));
+                    relay
+                }
+                None if slot > 0 => {
+                    // Every relay server is taken by another slot.
+                    retry_at = Instant::now() + std::time::Duration::from_secs(60);
+                    continue;
+                }
+                None => {
+                    error!("no relay server is reachable");
+                    try_count += 1;
+                    retry_at = Instant::now()
+                        + if try_count >= MAX_TRY_COUNT {
+                            std::time::Duration::from_secs(60)
+                        } else {
+                            std::time::Duration::from_secs(3)
+                        };
+                    continue;
+                }
+            },
+        };
+
+        if Instant::now() < retry_at {
+            continue;
+        }
+        // After failures, try one connection before filling the pool.
+        let target = if try_count > 0 { 1 } else { target };
+        while idle.len() < target {
+            let relay = relay.clone();
+            let idle_timeout = pool_config.idle_timeout();
+            idle.spawn(async move { relay_main(&relay, idle_timeout).await });
+        }
+    }
+    // Idle connections have no session yet, close them right away.
+    idle.shutdown().await;
+    if let Some((relay, _)) = current {
+        in_use.lock().unwrap().remove(&relay.address);
     }
+    info!(
+        "relay slot {slot} stopped, pool: {:?}",
+        POOL_METRICS.stats()
+    );
 
     // Graceful shutdown: let all in-flight relay transfers complete
     // naturally instead of aborting them. Do NOT use join_set.shutdown()
@@ -114,7 +308,7 @@ async fn run_relay_listener(
     let remaining = join_set.len();
     if remaining > 0 {
         info!(
-            "relay listener shutting down, waiting for {remaining} in-flight relay task(s) to finish"
+            "relay slot {slot} shutting down, waiting for {remaining} in-flight relay task(s) to finish"
         );
         if tokio::time::timeout(std::time::Duration::from_secs(60), async {
             while let Some(result) = join_set.join_next().await {
@@ -133,10 +327,6 @@ async fn run_relay_listener(
             join_set.shutdown().await;
         }
     }
-    // Ensure UI reflects the true state regardless of how we exited the loop
-    // (shutdown signal, config disable, or repeated connection failures after Spawned).
-    crate::relay::update_relay_server_status(false);
-    info!("relay listener shut down gracefully, all tasks drained");
 }
 
 use std::sync::Mutex;
@@ -187,7 +377,6 @@ pub fn tick_relay() -> bool {
 ///
 /// Returns `true` if the shutdown signal was sent successfully, `false`
 /// if the relay listener was never started or the channel is closed.
-#[allow(dead_code)]
 pub fn shutdown_relay() -> bool {
     let mut state = RELAY_STATE.lock().unwrap();
     match state.as_mut() {
@@ -198,3 +387,18 @@ pub fn shutdown_relay() -> bool {
         _ => false,
     }
 }
+
+/// Stops the relay listener, waits until it has drained, and starts a new one
+/// if the relay is still enabled, so that it connects with the current config.
+pub async fn restart_relay() {
+    // Restarts of quickly repeated config edits run one after the other.
+    static RESTARTING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let _restarting = RESTARTING.lock().await;
+    shutdown_relay();
+    while RELAY_STATE.lock().unwrap().is_some() {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    if !crate::config::read_config().relay_endpoints().is_empty() {
+        tick_relay();
+    }
+}
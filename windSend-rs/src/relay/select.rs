@@ -0,0 +1,112 @@
+//! Choice between several relay servers.
+//!
+//! The relay servers are `relayServerAddress` and the entries of
+//! `relayServers`. Each is probed with a full handshake, and the reachable one
+//! with the lowest `priority` and then the lowest handshake round trip wins;
+//! ties keep the configured order. A relay that failed or stopped sending
+//! heartbeats is passed over for a while, as long as others are left.
+
+use super::transport::RelayTransportConfig;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Time a probe has to connect and finish the handshake
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Configurable as an entry of `relayServers`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayEndpoint {
+    pub address: String,
+    #[serde(rename = "secretKey", default)]
+    pub secret_key: Option<String>,
+    /// Tried when the relay server rejects `secretKey`, while a key rotation
+    /// has not reached every relay server
+    #[serde(rename = "previousSecretKey", default)]
+    pub previous_secret_key: Option<String>,
+    /// Lower is preferred, latency only decides between equal priorities
+    #[serde(default)]
+    pub priority: u32,
+    #[serde(default)]
+    pub transport: RelayTransportConfig,
+}
+
+/// Orders the reachable relays from best to worst.
+pub fn rank(mut probed: Vec<(usize, RelayEndpoint, Duration)>) -> Vec<RelayEndpoint> {
+    probed.sort_by_key(|(index, relay, rtt)| (relay.priority, *rtt, *index));
+    probed.into_iter().map(|(_, relay, _)| relay).collect()
+}
+
+/// Probes the relays at the same time and orders the reachable ones.
+pub async fn select(relays: Vec<RelayEndpoint>) -> Vec<RelayEndpoint> {
+    use tracing::debug;
+    if relays.len() <= 1 {
+        return relays;
+    }
+    let mut probes = tokio::task::JoinSet::new();
+    for (index, relay) in relays.into_iter().enumerate() {
+        probes.spawn(async move {
+            let rtt = tokio::time::timeout(PROBE_TIMEOUT, super::probe(&relay))
+                .await
+                .ok()
+                .flatten();
+            (index, relay, rtt)
+        });
+    }
+    let mut probed = Vec::new();
+    while let Some(result) = probes.join_next().await {
+        let Ok((index, relay, rtt)) = result else {
+            continue;
+        };
+        debug!("relay server {} probed, rtt: {:?}", relay.address, rtt);
+        if let Some(rtt) = rtt {
+            probed.push((index, relay, rtt));
+        }
+    }
+    rank(probed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay(address: &str, priority: u32) -> RelayEndpoint {
+        RelayEndpoint {
+            address: address.to_string(),
+            priority,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rank_by_priority_then_rtt_then_order() {
+        let ms = Duration::from_millis;
+        let ranked = rank(vec![
+            (0, relay("a", 1), ms(10)),
+            (1, relay("b", 0), ms(80)),
+            (2, relay("c", 0), ms(20)),
+            (3, relay("d", 0), ms(20)),
+        ]);
+        let addresses: Vec<_> = ranked.iter().map(|relay| relay.address.as_str()).collect();
+        assert_eq!(addresses, ["c", "d", "b", "a"]);
+    }
+
+    #[test]
+    fn endpoints_start_with_relay_server_address() {
+        let mut config: crate::config::Config = serde_yaml::from_str(
+            "serverPort: '6779'\nsecretKeyHex: '00ff'\nshowToolbarIcon: true\n\
+             autoStart: false\nsavePath: ./\nlanguage: en\n\
+             relayServers:\n- address: backup:16779\n  priority: 1\n- address: ''\n",
+        )
+        .unwrap();
+        assert!(config.relay_endpoints().is_empty());
+        config.enable_relay = true;
+        assert_eq!(config.relay_endpoints(), vec![relay("backup:16779", 1)]);
+        config.relay_server_address = "main:16779".to_string();
+        let addresses: Vec<_> = config
+            .relay_endpoints()
+            .into_iter()
+            .map(|relay| relay.address)
+            .collect();
+        assert_eq!(addresses, ["main:16779", "backup:16779"]);
+    }
+}
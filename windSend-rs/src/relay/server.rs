@@ -0,0 +1,1690 @@
+//! The relay server, run with `wind_send relay-server [config path]`.
+//!
+//! Devices that enable the relay connect to it, complete the handshake and
+//! register with `connect` under their key id. The server keeps each of these
+//! connections idle, with heartbeats, until a client sends `relay` with the
+//! same id. It then tells the device with a `relay` request, answers the
+//! client with success and copies bytes between the two connections; the TLS
+//! session runs end to end between the client and the device.
+//!
+//! With `secretKeys` configured, only holders of one of the keys can use the
+//! relay. Keys are stretched with the server's KDF salt, which clients fetch
+//! with a handshake that fails with `KdfSaltMismatch`, and are looked up by
+//! their selector. The salt is generated once and stored in the config file,
+//! so that clients keep their cached keys across restarts. To rotate a key,
+//! the new one is added and the old one moved to `retiredSecretKeys` with the
+//! end of its grace window, until then both are accepted.
+//!
+//! Devices describe themselves when they register, and `presence` lists the
+//! connections registered for a device id with the same relay secret key, to
+//! those that prove to hold the device's key. An open relay does not answer it.
+//!
+//! Letters for offline devices are kept in memory, see [`super::mailbox`].
+//! Only the holder of a device's secret key fetches its letters, and only the
+//! fetched ones can be acknowledged.
+//!
+//! With `rendezvous` the server also listens on UDP at `listenAddress` and
+//! brokers direct connections between clients and devices, see
+//! [`super::rendezvous`].
+//!
+//! With `tlsCertFile` and `tlsKeyFile` the server also accepts TLS, and on any
+//! transport a WebSocket upgrade, see [`super::transport`]. Plain connections
+//! keep working next to them.
+
+use crate::relay::mailbox::{MailboxLimits, Mailboxes};
+use crate::relay::protocol::{
+    Action, CAPABILITY_MAILBOX, CAPABILITY_RENDEZVOUS, CommonReq, CommonReqHead, ConnectionReq,
+    DeviceDescriptor, HandshakeReq, HandshakeResp, HeartbeatReq, MailboxAckReq, MailboxFetchReq,
+    MailboxFetchResp, MailboxPutReq, PresenceEntry, PresenceReq, PresenceResp, RendezvousAnswer,
+    RendezvousOffer, RendezvousReq, RendezvousResp, RespHead, StatusCode,
+};
+use crate::relay::rendezvous::{MAX_ENDPOINTS, Observations};
+use crate::relay::transfer::{read_from, read_head_from, write_head_to};
+use crate::relay::transport::RelayStream;
+use crate::utils::encrypt::{self, AesGcmCipher};
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, warn};
+
+/// Default path of the config file
+const CONFIG_FILE: &str = "relay-server.yaml";
+
+/// Time a connection has to finish the handshake and send its first request
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time a device has to answer a heartbeat
+const HEARTBEAT_RESP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request bodies are small JSON objects
+const MAX_BODY_LEN: i32 = 1024 * 10;
+
+/// Time a device has to bind its socket and answer a rendezvous offer
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayServerConfig {
+    #[serde(rename = "listenAddress")]
+    pub listen_address: String,
+    /// Passwords that give access to the relay, anyone can use it if empty
+    #[serde(rename = "secretKeys")]
+    pub secret_keys: Vec<String>,
+    /// Rotated out keys, accepted until they expire
+    #[serde(rename = "retiredSecretKeys")]
+    pub retired_secret_keys: Vec<RetiredSecretKey>,
+    /// Generated when empty
+    #[serde(rename = "kdfSaltB64")]
+    pub kdf_salt_b64: String,
+    #[serde(rename = "heartbeatIntervalSecs")]
+    pub heartbeat_interval_secs: u64,
+    /// Idle connections a single device may keep registered
+    #[serde(rename = "maxIdleConnsPerDevice")]
+    pub max_idle_conns_per_device: usize,
+    #[serde(rename = "logLevel")]
+    pub log_level: String,
+    /// PEM certificate chain, TLS is accepted when set with `tlsKeyFile`
+    #[serde(rename = "tlsCertFile")]
+    pub tls_cert_file: String,
+    /// PEM private key of the certificate
+    #[serde(rename = "tlsKeyFile")]
+    pub tls_key_file: String,
+    #[serde(rename = "mailboxMaxLetterBytes")]
+    pub mailbox_max_letter_bytes: u64,
+    #[serde(rename = "mailboxMaxDeviceBytes")]
+    pub mailbox_max_device_bytes: u64,
+    /// Memory all mailboxes together may use, `0` disables the mailboxes
+    #[serde(rename = "mailboxMaxTotalBytes")]
+    pub mailbox_max_total_bytes: u64,
+    #[serde(rename = "mailboxMaxTtlSecs")]
+    pub mailbox_max_ttl_secs: u64,
+    /// Broker direct connections, needs UDP on the listen port
+    #[serde(rename = "rendezvous")]
+    pub rendezvous: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetiredSecretKey {
+    pub key: String,
+    /// RFC 3339, like `2026-01-31T00:00:00Z`
+    #[serde(rename = "expiresAt")]
+    pub expires_at: String,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: "0.0.0.0:16779".to_string(),
+            secret_keys: Vec::new(),
+            retired_secret_keys: Vec::new(),
+            kdf_salt_b64: String::new(),
+            heartbeat_interval_secs: 60,
+            max_idle_conns_per_device: 8,
+            log_level: "INFO".to_string(),
+            tls_cert_file: String::new(),
+            tls_key_file: String::new(),
+            mailbox_max_letter_bytes: 8 * 1024 * 1024,
+            mailbox_max_device_bytes: 64 * 1024 * 1024,
+            mailbox_max_total_bytes: 512 * 1024 * 1024,
+            mailbox_max_ttl_secs: 7 * 24 * 3600,
+            rendezvous: true,
+        }
+    }
+}
+
+fn load_tls_acceptor(cert_file: &str, key_file: &str) -> Result<tokio_rustls::TlsAcceptor, String> {
+    use tokio_rustls::rustls;
+    let certs = std::fs::read(cert_file).map_err(|err| format!("read {cert_file} error: {err}"))?;
+    let certs = rustls_pemfile::certs(&mut certs.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("parse {cert_file} error: {err}"))?;
+    let key = std::fs::read(key_file).map_err(|err| format!("read {key_file} error: {err}"))?;
+    let key = rustls_pemfile::private_key(&mut key.as_slice())
+        .map_err(|err| format!("parse {key_file} error: {err}"))?
+        .ok_or(format!("no private key in {key_file}"))?;
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("invalid tls certificate: {err}"))?;
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Reads the config file, creating it or filling in a new salt if needed.
+fn load_config(path: &std::path::Path) -> Result<RelayServerConfig, String> {
+    let mut config: RelayServerConfig = if path.exists() {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("read {} error: {err}", path.display()))?;
+        serde_yaml::from_str(&content)
+            .map_err(|err| format!("deserialize {} error: {err}", path.display()))?
+    } else {
+        RelayServerConfig::default()
+    };
+    if config.kdf_salt_b64.is_empty() {
+        config.kdf_salt_b64 = BASE64_STANDARD.encode(encrypt::rand_n_bytes2(16));
+        let content = serde_yaml::to_string(&config)
+            .map_err(|err| format!("serialize config error: {err}"))?;
+        std::fs::write(path, content)
+            .map_err(|err| format!("write {} error: {err}", path.display()))?;
+    }
+    Ok(config)
+}
+
+/// Entry point of the `relay-server` mode.
+pub fn main(config_path: Option<String>) {
+    let config_path = std::path::PathBuf::from(config_path.unwrap_or(CONFIG_FILE.to_string()));
+    let config = match load_config(&config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    use tracing_subscriber::filter::EnvFilter;
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .or_else(|_| EnvFilter::try_new(&config.log_level))
+                .unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_line_number(true)
+        .init();
+
+    crate::RUNTIME.block_on(async {
+        let server = match RelayServer::new(&config) {
+            Ok(server) => Arc::new(server),
+            Err(err) => {
+                error!("invalid relay server config: {}", err);
+                return;
+            }
+        };
+        let listener = match TcpListener::bind(&config.listen_address).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("listen on {} failed: {}", config.listen_address, err);
+                return;
+            }
+        };
+        info!(
+            "relay server listening on {}, config: {}",
+            config.listen_address,
+            config_path.display()
+        );
+        if config.rendezvous {
+            match UdpSocket::bind(&config.listen_address).await {
+                Ok(socket) => {
+                    tokio::spawn(server.clone().serve_rendezvous(socket));
+                }
+                Err(err) => error!("listen on udp {} failed: {}", config.listen_address, err),
+            }
+        }
+        server.serve(listener).await;
+    });
+}
+
+/// A `connect` request with what the handshake told about the connection
+struct Registration {
+    id: String,
+    key_selector: Option<String>,
+    descriptor: Option<DeviceDescriptor>,
+}
+
+/// An idle device connection handed over for a relay
+struct DeviceConn {
+    conn: RelayStream,
+    cipher: AesGcmCipher,
+}
+
+type Handoff = oneshot::Sender<oneshot::Sender<DeviceConn>>;
+
+/// A registered idle connection
+struct IdleConn {
+    conn_id: u64,
+    handoff: Handoff,
+    /// Selector of the secret key the connection authenticated with
+    key_selector: Option<String>,
+    descriptor: DeviceDescriptor,
+    /// Unix time in seconds
+    last_heartbeat: i64,
+}
+
+#[derive(Default)]
+struct Device {
+    /// Registered idle connections, oldest first
+    idle: VecDeque<IdleConn>,
+    /// Connections currently bridged to a client
+    busy: usize,
+    /// Descriptor of the latest registration
+    descriptor: DeviceDescriptor,
+    /// Relay requests that found no idle connection, not reported to the device yet
+    missed_requests: u64,
+}
+
+/// A stretched secret key
+struct SecretKey {
+    key: encrypt::Aes192Key,
+    /// End of the grace window of a retired key
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+pub struct RelayServer {
+    /// Stretched secret keys by selector
+    keys: HashMap<String, SecretKey>,
+    kdf_salt_b64: String,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    heartbeat_interval: Duration,
+    max_idle_conns_per_device: usize,
+    devices: Mutex<HashMap<String, Device>>,
+    next_conn_id: std::sync::atomic::AtomicU64,
+    mailbox_limits: MailboxLimits,
+    mailboxes: Mutex<Mailboxes>,
+    rendezvous: bool,
+    observations: Mutex<Observations>,
+}
+
+enum Auth {
+    Open,
+    Key(AesGcmCipher),
+    SaltMismatch,
+    Failed(&'static str),
+}
+
+impl RelayServer {
+    pub fn new(config: &RelayServerConfig) -> Result<Self, String> {
+        let salt = BASE64_STANDARD
+            .decode(&config.kdf_salt_b64)
+            .map_err(|err| format!("invalid kdfSaltB64: {err}"))?;
+        let stretch = |pwd: &str, expires_at| {
+            let key = encrypt::aes192_key_kdf(pwd.as_bytes(), &salt);
+            (
+                super::get_aes192_key_selector(&key),
+                SecretKey { key, expires_at },
+            )
+        };
+        let mut keys = HashMap::new();
+        for retired in &config.retired_secret_keys {
+            let expires_at = chrono::DateTime::parse_from_rfc3339(&retired.expires_at)
+                .map_err(|err| format!("invalid expiresAt {}: {err}", retired.expires_at))?;
+            let (selector, key) = stretch(&retired.key, Some(expires_at.to_utc()));
+            keys.insert(selector, key);
+        }
+        // A key that is both current and retired does not expire.
+        keys.extend(config.secret_keys.iter().map(|pwd| stretch(pwd, None)));
+        let tls = match (config.tls_cert_file.as_str(), config.tls_key_file.as_str()) {
+            ("", "") => None,
+            ("", _) | (_, "") => return Err("tlsCertFile and tlsKeyFile go together".to_string()),
+            (cert_file, key_file) => Some(load_tls_acceptor(cert_file, key_file)?),
+        };
+        Ok(Self {
+            keys,
+            kdf_salt_b64: config.kdf_salt_b64.clone(),
+            tls,
+            heartbeat_interval: Duration::from_secs(config.heartbeat_interval_secs.max(1)),
+            max_idle_conns_per_device: config.max_idle_conns_per_device.max(1),
+            devices: Mutex::new(HashMap::new()),
+            next_conn_id: Default::default(),
+            mailbox_limits: MailboxLimits {
+                max_letter_bytes: config
+                    .mailbox_max_letter_bytes
+                    .min(config.mailbox_max_total_bytes),
+                max_device_bytes: config.mailbox_max_device_bytes,
+                max_total_bytes: config.mailbox_max_total_bytes,
+                max_ttl: Duration::from_secs(config.mailbox_max_ttl_secs),
+            },
+            mailboxes: Default::default(),
+            rendezvous: config.rendezvous,
+            observations: Default::default(),
+        })
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (conn, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("accept error: {}", err);
+                    continue;
+                }
+            };
+            debug!("accept a new connection from {}", addr);
+            let server = self.clone();
+            tokio::spawn(async move {
+                server.handle_conn(conn).await;
+                debug!("connection from {} closed", addr);
+            });
+        }
+    }
+
+    /// Answers binding packets with the address they came from.
+    pub async fn serve_rendezvous(self: Arc<Self>, socket: UdpSocket) {
+        let mut buf = [0u8; 128];
+        loop {
+            let (n, addr) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    // Like ICMP port unreachable for an earlier answer on Windows.
+                    debug!("udp recv error: {}", err);
+                    continue;
+                }
+            };
+            let Some(token) = super::rendezvous::binding_token(&buf[..n]) else {
+                continue;
+            };
+            let recorded =
+                self.observations
+                    .lock()
+                    .unwrap()
+                    .record(token, addr, std::time::Instant::now());
+            if !recorded {
+                continue;
+            }
+            let packet = super::rendezvous::bound_packet(addr);
+            if let Err(err) = socket.send_to(&packet, addr).await {
+                debug!("answer binding of {} error: {}", addr, err);
+            }
+        }
+    }
+
+    async fn handle_conn(self: Arc<Self>, conn: TcpStream) {
+        let handshake = async {
+            let mut conn = crate::relay::transport::accept(conn, self.tls.as_ref())
+                .await
+                .map_err(|err| debug!("accept transport error: {}", err))?;
+            let (cipher, key_selector) = self.handshake(&mut conn).await?;
+            Ok::<_, ()>((conn, cipher, key_selector))
+        };
+        let (mut conn, cipher, key_selector) =
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(established)) => established,
+                Ok(Err(_)) => return,
+                Err(_) => {
+                    warn!("relay handshake timed out");
+                    return;
+                }
+            };
+        // The device and the sequence numbers of the letters fetched last.
+        let mut fetched: Option<(String, Vec<u64>)> = None;
+        loop {
+            let head = CommonReqHead::read_from(&mut conn, Some(&cipher));
+            let head = match tokio::time::timeout(HANDSHAKE_TIMEOUT, head).await {
+                Ok(Ok(head)) => head,
+                _ => return,
+            };
+            if head.data_len < 0 || head.data_len > MAX_BODY_LEN {
+                error!("invalid data len: {}", head.data_len);
+                return;
+            }
+            match head.action {
+                Action::Connect => {
+                    let Ok(req) =
+                        read_from::<_, ConnectionReq>(&mut conn, head.data_len, Some(&cipher))
+                            .await
+                    else {
+                        return;
+                    };
+                    let registration = Registration {
+                        id: req.common.id,
+                        key_selector,
+                        descriptor: req.device,
+                    };
+                    self.register_device(registration, conn, cipher).await;
+                    return;
+                }
+                Action::Relay => {
+                    let Ok(req) =
+                        read_from::<_, CommonReq>(&mut conn, head.data_len, Some(&cipher)).await
+                    else {
+                        return;
+                    };
+                    self.relay(req.id, conn, cipher).await;
+                    return;
+                }
+                Action::Heartbeat => {
+                    if head.data_len == 0 {
+                        continue;
+                    }
+                    let Ok(req) =
+                        HeartbeatReq::read_from(&mut conn, head.data_len, Some(&cipher)).await
+                    else {
+                        return;
+                    };
+                    if req.need_resp
+                        && respond(
+                            &mut conn,
+                            &cipher,
+                            Action::Heartbeat,
+                            StatusCode::Success,
+                            "",
+                        )
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Action::Ping => {
+                    let mut body = vec![0u8; head.data_len as usize];
+                    if conn.read_exact(&mut body).await.is_err() {
+                        return;
+                    }
+                    if respond(&mut conn, &cipher, Action::Ping, StatusCode::Success, "")
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Action::Close => {
+                    conn.shutdown().await.ok();
+                    return;
+                }
+                Action::Presence => {
+                    let Ok(req) =
+                        read_from::<_, PresenceReq>(&mut conn, head.data_len, Some(&cipher)).await
+                    else {
+                        return;
+                    };
+                    let Some(key_selector) = &key_selector else {
+                        let msg = "presence needs a secret key";
+                        let _ =
+                            respond(&mut conn, &cipher, Action::Presence, StatusCode::Error, msg)
+                                .await;
+                        return;
+                    };
+                    if !super::mailbox::proves_key_id(&req.key_proof, &req.common.id) {
+                        warn!("presence of device {} without its key", req.common.id);
+                        let msg = "invalid key proof";
+                        let _ =
+                            respond(&mut conn, &cipher, Action::Presence, StatusCode::Error, msg)
+                                .await;
+                        return;
+                    }
+                    let resp = self.presence(&req.common.id, key_selector);
+                    if RespHead::write_with_body(Action::Presence, &mut conn, &cipher, &resp)
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Action::Rendezvous => {
+                    let Ok(req) =
+                        read_from::<_, RendezvousReq>(&mut conn, head.data_len, Some(&cipher))
+                            .await
+                    else {
+                        return;
+                    };
+                    // The client falls back to `relay` on this connection if this fails.
+                    let written = match self.rendezvous(req).await {
+                        Ok(resp) => {
+                            RespHead::write_with_body(Action::Rendezvous, &mut conn, &cipher, &resp)
+                                .await
+                        }
+                        Err((code, msg)) => {
+                            respond(&mut conn, &cipher, Action::Rendezvous, code, msg).await
+                        }
+                    };
+                    if written.is_err() {
+                        return;
+                    }
+                }
+                Action::MailboxPut => {
+                    let Ok(req) =
+                        read_from::<_, MailboxPutReq>(&mut conn, head.data_len, Some(&cipher))
+                            .await
+                    else {
+                        return;
+                    };
+                    if self.mailbox_put(&mut conn, &cipher, req).await.is_err() {
+                        return;
+                    }
+                }
+                Action::MailboxFetch => {
+                    let Ok(req) =
+                        read_from::<_, MailboxFetchReq>(&mut conn, head.data_len, Some(&cipher))
+                            .await
+                    else {
+                        return;
+                    };
+                    let id = req.common.id;
+                    if !super::mailbox::proves_key_id(&req.key_proof, &id) {
+                        warn!("mailbox fetch for device {} without its key", id);
+                        let msg = "invalid key proof";
+                        let _ = respond(
+                            &mut conn,
+                            &cipher,
+                            Action::MailboxFetch,
+                            StatusCode::Error,
+                            msg,
+                        )
+                        .await;
+                        return;
+                    }
+                    match self.mailbox_fetch(&mut conn, &cipher, &id).await {
+                        Ok(seqs) => fetched = Some((id, seqs)),
+                        Err(_) => return,
+                    }
+                }
+                Action::MailboxAck => {
+                    let Ok(req) =
+                        read_from::<_, MailboxAckReq>(&mut conn, head.data_len, Some(&cipher))
+                            .await
+                    else {
+                        return;
+                    };
+                    let Some((id, seqs)) = fetched.as_ref().filter(|(id, _)| *id == req.common.id)
+                    else {
+                        let msg = "no letters fetched for this device";
+                        if respond(
+                            &mut conn,
+                            &cipher,
+                            Action::MailboxAck,
+                            StatusCode::Error,
+                            msg,
+                        )
+                        .await
+                        .is_err()
+                        {
+                            return;
+                        }
+                        continue;
+                    };
+                    let acked: Vec<u64> = req
+                        .seqs
+                        .into_iter()
+                        .filter(|seq| seqs.contains(seq))
+                        .collect();
+                    self.mailboxes.lock().unwrap().ack(id, &acked);
+                    if respond(
+                        &mut conn,
+                        &cipher,
+                        Action::MailboxAck,
+                        StatusCode::Success,
+                        "",
+                    )
+                    .await
+                    .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The connections of the device `id` registered with the secret key of
+    /// `key_selector`
+    fn presence(&self, id: &str, key_selector: &str) -> PresenceResp {
+        let devices = self.devices.lock().unwrap();
+        let Some(device) = devices.get(id) else {
+            return PresenceResp {
+                connections: Vec::new(),
+                busy_conns: 0,
+            };
+        };
+        let connections = device
+            .idle
+            .iter()
+            .filter(|idle| idle.key_selector.as_deref() == Some(key_selector))
+            .map(|idle| PresenceEntry {
+                device: idle.descriptor.clone(),
+                last_heartbeat: idle.last_heartbeat,
+            })
+            .collect();
+        PresenceResp {
+            connections,
+            busy_conns: device.busy,
+        }
+    }
+
+    /// Records an answered heartbeat on a connection of a device.
+    fn touch(&self, id: &str, conn_id: u64) {
+        if let Some(device) = self.devices.lock().unwrap().get_mut(id)
+            && let Some(idle) = device.idle.iter_mut().find(|idle| idle.conn_id == conn_id)
+        {
+            idle.last_heartbeat = chrono::Utc::now().timestamp();
+        }
+    }
+
+    /// Stores a letter, whose bytes follow the request. A letter over the size
+    /// limit is not read, and the connection closed.
+    async fn mailbox_put(
+        &self,
+        conn: &mut RelayStream,
+        cipher: &AesGcmCipher,
+        req: MailboxPutReq,
+    ) -> Result<(), ()> {
+        let id = req.common.id;
+        if req.size > self.mailbox_limits.max_letter_bytes {
+            warn!("letter for device {} too large: {}", id, req.size);
+            let msg = "letter too large";
+            let _ = respond(conn, cipher, Action::MailboxPut, StatusCode::Error, msg).await;
+            return Err(());
+        }
+        let mut sealed = vec![0u8; req.size as usize];
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, conn.read_exact(&mut sealed)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                debug!("read letter error: {}", e);
+                return Err(());
+            }
+            Err(_) => {
+                warn!("read letter for device {} timed out", id);
+                return Err(());
+            }
+        }
+        let now = chrono::Utc::now().timestamp();
+        let stored = self.mailboxes.lock().unwrap().put(
+            &id,
+            sealed,
+            req.expires_at,
+            &self.mailbox_limits,
+            now,
+        );
+        match stored {
+            Ok(seq) => {
+                info!(
+                    "letter {} of {} bytes kept for device {}",
+                    seq, req.size, id
+                );
+                respond(conn, cipher, Action::MailboxPut, StatusCode::Success, "").await
+            }
+            Err(msg) => {
+                debug!("letter for device {} refused: {}", id, msg);
+                respond(conn, cipher, Action::MailboxPut, StatusCode::Error, msg).await
+            }
+        }
+    }
+
+    /// Sends the letters of a device, returning their sequence numbers.
+    async fn mailbox_fetch(
+        &self,
+        conn: &mut RelayStream,
+        cipher: &AesGcmCipher,
+        id: &str,
+    ) -> Result<Vec<u64>, ()> {
+        let now = chrono::Utc::now().timestamp();
+        let letters = self.mailboxes.lock().unwrap().fetch(id, now);
+        let resp = MailboxFetchResp {
+            items: letters.iter().map(|(item, _)| item.clone()).collect(),
+        };
+        RespHead::write_with_body(Action::MailboxFetch, conn, cipher, &resp).await?;
+        for (_, sealed) in &letters {
+            conn.write_all(sealed)
+                .await
+                .map_err(|e| debug!("write letter error: {}", e))?;
+        }
+        Ok(letters.into_iter().map(|(item, _)| item.seq).collect())
+    }
+
+    fn authenticate(&self, req: &HandshakeReq) -> Auth {
+        if self.keys.is_empty() {
+            return Auth::Open;
+        }
+        let Some(selector) = &req.secret_key_selector else {
+            // A client that knows a key but not the salt asks for it with any auth field.
+            return match req.auth_field_b64 {
+                Some(_) => Auth::SaltMismatch,
+                None => Auth::Failed("secret key required"),
+            };
+        };
+        if req.kdf_salt_b64.as_ref() != Some(&self.kdf_salt_b64) {
+            return Auth::SaltMismatch;
+        }
+        let Some(key) = self.keys.get(selector) else {
+            return Auth::Failed("unknown secret key");
+        };
+        if key
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= chrono::Utc::now())
+        {
+            return Auth::Failed("secret key expired");
+        }
+        let cipher = AesGcmCipher::new(&key.key).unwrap();
+        let Some(mut auth_field) = req
+            .auth_field_b64
+            .as_ref()
+            .and_then(|field| BASE64_STANDARD.decode(field).ok())
+        else {
+            return Auth::Failed("invalid auth field");
+        };
+        let aad = req.auth_aad.as_deref().unwrap_or_default();
+        match cipher.decrypt(&mut auth_field, aad.as_bytes()) {
+            Ok(plain) if plain.starts_with(b"AUTH") => Auth::Key(cipher),
+            _ => Auth::Failed("auth failed"),
+        }
+    }
+
+    fn capabilities(&self) -> Vec<String> {
+        let mut capabilities = Vec::new();
+        if self.mailbox_limits.max_total_bytes != 0 {
+            capabilities.push(CAPABILITY_MAILBOX.to_string());
+        }
+        if self.rendezvous {
+            capabilities.push(CAPABILITY_RENDEZVOUS.to_string());
+        }
+        capabilities
+    }
+
+    /// Answers handshakes until one succeeds, allowing one salt exchange.
+    /// Returns the session cipher and the selector of the secret key used.
+    async fn handshake(
+        &self,
+        conn: &mut RelayStream,
+    ) -> Result<(AesGcmCipher, Option<String>), ()> {
+        for _ in 0..2 {
+            let req: HandshakeReq = read_head_from(conn, None).await?;
+            let key_cipher = match self.authenticate(&req) {
+                Auth::Open => None,
+                Auth::Key(cipher) => Some(cipher),
+                Auth::SaltMismatch => {
+                    debug!("kdf salt mismatch, send the salt");
+                    let resp = HandshakeResp {
+                        code: StatusCode::KdfSaltMismatch,
+                        msg: "kdf salt mismatch".to_string(),
+                        kdf_salt_b64: self.kdf_salt_b64.clone(),
+                        ecdh_public_key_b64: String::new(),
+                        capabilities: Vec::new(),
+                        rendezvous_token: String::new(),
+                    };
+                    write_head_to(&resp, conn, None).await?;
+                    continue;
+                }
+                Auth::Failed(msg) => {
+                    warn!("relay handshake failed: {}", msg);
+                    let resp = HandshakeResp {
+                        code: StatusCode::AuthFailed,
+                        msg: msg.to_string(),
+                        kdf_salt_b64: String::new(),
+                        ecdh_public_key_b64: String::new(),
+                        capabilities: Vec::new(),
+                        rendezvous_token: String::new(),
+                    };
+                    write_head_to(&resp, conn, None).await?;
+                    return Err(());
+                }
+            };
+            let peer_public: [u8; 32] = BASE64_STANDARD
+                .decode(&req.ecdh_public_key_b64)
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(|| error!("invalid ecdh public key"))?;
+            let secret = x25519_dalek::EphemeralSecret::random();
+            let public = x25519_dalek::PublicKey::from(&secret);
+            let public = match &key_cipher {
+                Some(cipher) => cipher
+                    .encrypt(public.as_bytes(), b"AUTH")
+                    .map_err(|e| error!("encrypt ecdh public key error: {}", e))?,
+                None => public.as_bytes().to_vec(),
+            };
+            let resp = HandshakeResp {
+                code: StatusCode::Success,
+                msg: String::new(),
+                kdf_salt_b64: self.kdf_salt_b64.clone(),
+                ecdh_public_key_b64: BASE64_STANDARD.encode(public),
+                capabilities: self.capabilities(),
+                rendezvous_token: match self.rendezvous {
+                    true => self.observations.lock().unwrap().issue_token(),
+                    false => String::new(),
+                },
+            };
+            write_head_to(&resp, conn, None).await?;
+            let shared_secret = secret.diffie_hellman(&x25519_dalek::PublicKey::from(peer_public));
+            let cipher = AesGcmCipher::new(&super::hash_to_aes192_key(shared_secret.as_bytes()))
+                .map_err(|e| error!("create cipher error: {}", e))?;
+            let key_selector = key_cipher.and(req.secret_key_selector);
+            return Ok((cipher, key_selector));
+        }
+        warn!("relay handshake failed: kdf salt mismatch twice");
+        Err(())
+    }
+
+    /// Keeps a device connection idle until a client asks for it.
+    async fn register_device(
+        &self,
+        registration: Registration,
+        mut conn: RelayStream,
+        cipher: AesGcmCipher,
+    ) {
+        let id = registration.id;
+        let conn_id = self
+            .next_conn_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let (handoff_tx, mut handoff_rx) = oneshot::channel();
+        let registered = {
+            let mut devices = self.devices.lock().unwrap();
+            let device = devices.entry(id.clone()).or_default();
+            if device.idle.len() < self.max_idle_conns_per_device {
+                if let Some(descriptor) = &registration.descriptor {
+                    device.descriptor = descriptor.clone();
+                }
+                device.idle.push_back(IdleConn {
+                    conn_id,
+                    handoff: handoff_tx,
+                    key_selector: registration.key_selector,
+                    descriptor: registration.descriptor.unwrap_or_default(),
+                    last_heartbeat: chrono::Utc::now().timestamp(),
+                });
+                true
+            } else {
+                false
+            }
+        };
+        if !registered {
+            warn!("too many idle connections of device {}", id);
+            let _ = respond(
+                &mut conn,
+                &cipher,
+                Action::Connect,
+                StatusCode::Error,
+                "too many idle connections",
+            )
+            .await;
+            return;
+        }
+        if respond(&mut conn, &cipher, Action::Connect, StatusCode::Success, "")
+            .await
+            .is_err()
+        {
+            self.unregister(&id, conn_id);
+            return;
+        }
+        debug!("device {} registered an idle connection", id);
+
+        let mut heartbeat = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.heartbeat_interval,
+            self.heartbeat_interval,
+        );
+        let mut probe = [0u8; 1];
+        loop {
+            tokio::select! {
+                reply = &mut handoff_rx => {
+                    // The sender is only dropped once the entry has been removed.
+                    if let Ok(reply) = reply {
+                        let _ = reply.send(DeviceConn { conn, cipher });
+                    }
+                    return;
+                }
+                _ = heartbeat.tick() => {
+                    let missed = self.take_missed_requests(&id);
+                    if send_heartbeat(&mut conn, &cipher, &id, missed).await.is_err() {
+                        debug!("device {} missed a heartbeat", id);
+                        self.restore_missed_requests(&id, missed);
+                        break;
+                    }
+                    self.touch(&id, conn_id);
+                }
+                // Idle devices do not send anything, this only ends with an error or EOF.
+                _ = conn.read(&mut probe) => break,
+            }
+        }
+        self.unregister(&id, conn_id);
+    }
+
+    /// The misses the device is told about with the next heartbeat
+    fn take_missed_requests(&self, id: &str) -> u64 {
+        let mut devices = self.devices.lock().unwrap();
+        devices
+            .get_mut(id)
+            .map_or(0, |device| std::mem::take(&mut device.missed_requests))
+    }
+
+    fn restore_missed_requests(&self, id: &str, missed: u64) {
+        if let Some(device) = self.devices.lock().unwrap().get_mut(id) {
+            device.missed_requests += missed;
+        }
+    }
+
+    fn unregister(&self, id: &str, conn_id: u64) {
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(device) = devices.get_mut(id) {
+            device.idle.retain(|idle| idle.conn_id != conn_id);
+            if device.idle.is_empty() && device.busy == 0 {
+                devices.remove(id);
+            }
+        }
+    }
+
+    /// Takes the oldest idle connection of a device and counts it as busy,
+    /// or tells why there is none.
+    fn take_idle<'a>(&'a self, id: &'a str) -> Result<(Handoff, BusyGuard<'a>), StatusCode> {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.get_mut(id).ok_or(StatusCode::DeviceOffline)?;
+        let Some(IdleConn { handoff, .. }) = device.idle.pop_front() else {
+            if device.busy == 0 {
+                return Err(StatusCode::DeviceOffline);
+            }
+            device.missed_requests += 1;
+            return Err(StatusCode::DeviceBusy);
+        };
+        device.busy += 1;
+        Ok((handoff, BusyGuard { server: self, id }))
+    }
+
+    /// Takes an idle connection of a device away from its heartbeat loop.
+    async fn hand_over<'a>(
+        &'a self,
+        id: &'a str,
+    ) -> Result<(DeviceConn, BusyGuard<'a>), StatusCode> {
+        loop {
+            let (handoff, busy) = self.take_idle(id)?;
+            let (reply_tx, reply_rx) = oneshot::channel();
+            if handoff.send(reply_tx).is_err() {
+                continue;
+            }
+            let Ok(device) = reply_rx.await else {
+                continue;
+            };
+            return Ok((device, busy));
+        }
+    }
+
+    /// Swaps the endpoints of a client and a device for hole punching, using
+    /// up an idle connection of the device.
+    async fn rendezvous(
+        &self,
+        req: RendezvousReq,
+    ) -> Result<RendezvousResp, (StatusCode, &'static str)> {
+        let id = req.common.id;
+        if !self.rendezvous {
+            return Err((StatusCode::Error, "rendezvous disabled"));
+        }
+        let client_endpoint = self
+            .observations
+            .lock()
+            .unwrap()
+            .take(&req.token, std::time::Instant::now())
+            .ok_or((StatusCode::Error, "client endpoint not observed"))?;
+        let supported = self.devices.lock().unwrap().get(&id).map(|device| {
+            let capabilities = &device.descriptor.capabilities;
+            capabilities.iter().any(|c| c == CAPABILITY_RENDEZVOUS)
+        });
+        match supported {
+            None => return Err((StatusCode::DeviceOffline, "device offline")),
+            Some(false) => return Err((StatusCode::Error, "device does not support rendezvous")),
+            Some(true) => {}
+        }
+        let (mut device, _busy) = self
+            .hand_over(&id)
+            .await
+            .map_err(|code| (code, unavailable_msg(code)))?;
+
+        let token = self.observations.lock().unwrap().issue_token();
+        let offer = RendezvousOffer {
+            token: token.clone(),
+            peer_endpoints: std::iter::once(client_endpoint.to_string())
+                .chain(req.local_endpoints.into_iter().take(MAX_ENDPOINTS - 1))
+                .collect(),
+        };
+        let answer = async {
+            let DeviceConn { conn, cipher } = &mut device;
+            CommonReqHead::write_with_body(Action::Rendezvous, conn, Some(cipher), &offer).await?;
+            let head = RespHead::read_from(conn, Some(cipher)).await?;
+            if head.code != StatusCode::Success || head.data_len > MAX_BODY_LEN {
+                debug!("device {} refused the rendezvous: {}", id, head.msg);
+                return Err(());
+            }
+            read_from::<_, RendezvousAnswer>(conn, head.data_len, Some(cipher)).await
+        };
+        let answer = tokio::time::timeout(RENDEZVOUS_TIMEOUT, answer).await;
+        device.conn.shutdown().await.ok();
+        let Ok(Ok(answer)) = answer else {
+            return Err((StatusCode::Error, "device did not answer"));
+        };
+        let device_endpoint = self
+            .observations
+            .lock()
+            .unwrap()
+            .take(&token, std::time::Instant::now())
+            .ok_or((StatusCode::Error, "device endpoint not observed"))?;
+        info!(
+            "rendezvous of device {} at {} with client at {}",
+            id, device_endpoint, client_endpoint
+        );
+        Ok(RendezvousResp {
+            peer_endpoints: std::iter::once(device_endpoint.to_string())
+                .chain(answer.local_endpoints.into_iter().take(MAX_ENDPOINTS - 1))
+                .collect(),
+        })
+    }
+
+    async fn relay(&self, id: String, mut client: RelayStream, client_cipher: AesGcmCipher) {
+        let (mut device, _busy) = loop {
+            let (mut device, busy) = match self.hand_over(&id).await {
+                Ok(taken) => taken,
+                Err(code) => {
+                    debug!("relay to device {} failed: {:?}", id, code);
+                    let msg = unavailable_msg(code);
+                    let _ = respond(&mut client, &client_cipher, Action::Relay, code, msg).await;
+                    return;
+                }
+            };
+            let head = CommonReqHead {
+                action: Action::Relay,
+                data_len: 0,
+            };
+            if head
+                .write_to(&mut device.conn, Some(&device.cipher))
+                .await
+                .is_ok()
+            {
+                break (device, busy);
+            }
+        };
+        if respond(
+            &mut client,
+            &client_cipher,
+            Action::Relay,
+            StatusCode::Success,
+            "",
+        )
+        .await
+        .is_err()
+        {
+            device.conn.shutdown().await.ok();
+            return;
+        }
+        info!("relay to device {} started", id);
+        match tokio::io::copy_bidirectional(&mut client, &mut device.conn).await {
+            Ok((up, down)) => info!(
+                "relay to device {} finished, {} bytes up, {} bytes down",
+                id, up, down
+            ),
+            Err(err) => debug!("relay to device {} ended: {}", id, err),
+        }
+    }
+}
+
+/// Counts a device connection as busy until it is dropped.
+struct BusyGuard<'a> {
+    server: &'a RelayServer,
+    id: &'a str,
+}
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        let mut devices = self.server.devices.lock().unwrap();
+        if let Some(device) = devices.get_mut(self.id) {
+            device.busy -= 1;
+            if device.idle.is_empty() && device.busy == 0 {
+                devices.remove(self.id);
+            }
+        }
+    }
+}
+
+fn unavailable_msg(code: StatusCode) -> &'static str {
+    match code {
+        StatusCode::DeviceBusy => "device busy",
+        _ => "device offline",
+    }
+}
+
+async fn respond(
+    conn: &mut RelayStream,
+    cipher: &AesGcmCipher,
+    action: Action,
+    code: StatusCode,
+    msg: &str,
+) -> Result<(), ()> {
+    let head = RespHead {
+        code,
+        msg: msg.to_string(),
+        action,
+        data_len: 0,
+    };
+    head.write_to(conn, Some(cipher)).await
+}
+
+async fn send_heartbeat(
+    conn: &mut RelayStream,
+    cipher: &AesGcmCipher,
+    id: &str,
+    missed_requests: u64,
+) -> Result<(), ()> {
+    let req = HeartbeatReq {
+        common: CommonReq { id: id.to_string() },
+        need_resp: true,
+        missed_requests: (missed_requests > 0).then_some(missed_requests),
+    };
+    req.write_to(conn, Some(cipher)).await?;
+    let resp = tokio::time::timeout(
+        HEARTBEAT_RESP_TIMEOUT,
+        RespHead::read_from(conn, Some(cipher)),
+    )
+    .await
+    .map_err(|_| ())??;
+    if resp.code != StatusCode::Success {
+        return Err(());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn client_handshake(
+        conn: &mut TcpStream,
+        password: Option<&str>,
+        salt_b64: Option<String>,
+    ) -> Result<AesGcmCipher, StatusCode> {
+        let (cipher, _) = client_handshake_for_token(conn, password, salt_b64).await?;
+        Ok(cipher)
+    }
+
+    /// The client side of the handshake, as devices and the app do it. Also
+    /// returns the rendezvous token.
+    async fn client_handshake_for_token(
+        conn: &mut TcpStream,
+        password: Option<&str>,
+        mut salt_b64: Option<String>,
+    ) -> Result<(AesGcmCipher, String), StatusCode> {
+        loop {
+            let key = match (password, &salt_b64) {
+                (Some(pwd), Some(salt)) => Some(encrypt::aes192_key_kdf(
+                    pwd.as_bytes(),
+                    &BASE64_STANDARD.decode(salt).unwrap(),
+                )),
+                _ => None,
+            };
+            let cipher = key.map(|key| AesGcmCipher::new(&key).unwrap());
+            let aad = "aad".to_string();
+            let auth_field_b64 = match &cipher {
+                Some(cipher) => Some(
+                    BASE64_STANDARD.encode(cipher.encrypt(b"AUTH0123", aad.as_bytes()).unwrap()),
+                ),
+                None => password.map(|_| BASE64_STANDARD.encode("fetch_salt")),
+            };
+            let secret = x25519_dalek::EphemeralSecret::random();
+            let req = HandshakeReq {
+                secret_key_selector: key.map(|key| crate::relay::get_aes192_key_selector(&key)),
+                auth_field_b64,
+                auth_aad: Some(aad),
+                kdf_salt_b64: salt_b64.clone(),
+                ecdh_public_key_b64: BASE64_STANDARD.encode(x25519_dalek::PublicKey::from(&secret)),
+            };
+            req.write_to(conn).await.unwrap();
+            let resp = HandshakeResp::read_from(conn).await.unwrap();
+            match resp.code {
+                StatusCode::Success => {}
+                StatusCode::KdfSaltMismatch if salt_b64.as_ref() != Some(&resp.kdf_salt_b64) => {
+                    salt_b64 = Some(resp.kdf_salt_b64);
+                    continue;
+                }
+                code => return Err(code),
+            }
+            let mut public = BASE64_STANDARD.decode(resp.ecdh_public_key_b64).unwrap();
+            let public: [u8; 32] = match &cipher {
+                Some(cipher) => cipher.decrypt(&mut public, b"AUTH").unwrap().to_vec(),
+                None => public,
+            }
+            .try_into()
+            .unwrap();
+            let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(public));
+            let cipher =
+                AesGcmCipher::new(&crate::relay::hash_to_aes192_key(shared.as_bytes())).unwrap();
+            return Ok((cipher, resp.rendezvous_token));
+        }
+    }
+
+    async fn send_req(
+        conn: &mut TcpStream,
+        cipher: &AesGcmCipher,
+        action: Action,
+        id: &str,
+    ) -> RespHead {
+        let req = CommonReq { id: id.to_string() };
+        CommonReqHead::write_with_body(action, conn, Some(cipher), &req)
+            .await
+            .unwrap();
+        RespHead::read_from(conn, Some(cipher)).await.unwrap()
+    }
+
+    async fn start(secret_keys: &[&str]) -> std::net::SocketAddr {
+        start_with(RelayServerConfig {
+            secret_keys: secret_keys.iter().map(|key| key.to_string()).collect(),
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn start_with(mut config: RelayServerConfig) -> std::net::SocketAddr {
+        config.kdf_salt_b64 = BASE64_STANDARD.encode(b"salt");
+        let server = Arc::new(RelayServer::new(&config).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        tokio::spawn(server.clone().serve_rendezvous(socket));
+        tokio::spawn(server.serve(listener));
+        addr
+    }
+
+    #[tokio::test]
+    async fn relay_bridges_a_client_to_an_idle_device() {
+        let addr = start(&["pwd"]).await;
+
+        let mut device = TcpStream::connect(addr).await.unwrap();
+        // The device does not know the salt yet.
+        let device_cipher = client_handshake(&mut device, Some("pwd"), None)
+            .await
+            .unwrap();
+        let resp = send_req(&mut device, &device_cipher, Action::Connect, "dev").await;
+        assert_eq!(resp.code, StatusCode::Success);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let salt = Some(BASE64_STANDARD.encode(b"salt"));
+        let client_cipher = client_handshake(&mut client, Some("pwd"), salt.clone())
+            .await
+            .unwrap();
+        let resp = send_req(&mut client, &client_cipher, Action::Relay, "dev").await;
+        assert_eq!(resp.code, StatusCode::Success);
+
+        let head = CommonReqHead::read_from(&mut device, Some(&device_cipher))
+            .await
+            .unwrap();
+        assert_eq!(head.action, Action::Relay);
+        assert_eq!(head.data_len, 0);
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        device.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        device.write_all(b"world").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        // The only connection of the device is in use.
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        let other_cipher = client_handshake(&mut other, Some("pwd"), salt)
+            .await
+            .unwrap();
+        let resp = send_req(&mut other, &other_cipher, Action::Relay, "dev").await;
+        assert_eq!(resp.code, StatusCode::DeviceBusy);
+    }
+
+    #[tokio::test]
+    async fn heartbeats_report_requests_that_found_no_idle_connection() {
+        let addr = start_with(RelayServerConfig {
+            secret_keys: vec!["pwd".to_string()],
+            heartbeat_interval_secs: 1,
+            ..Default::default()
+        })
+        .await;
+        let salt = Some(BASE64_STANDARD.encode(b"salt"));
+        let register = || async {
+            let mut device = TcpStream::connect(addr).await.unwrap();
+            let cipher = client_handshake(&mut device, Some("pwd"), salt.clone())
+                .await
+                .unwrap();
+            let resp = send_req(&mut device, &cipher, Action::Connect, "dev").await;
+            assert_eq!(resp.code, StatusCode::Success);
+            (device, cipher)
+        };
+        let relay = || async {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let cipher = client_handshake(&mut client, Some("pwd"), salt.clone())
+                .await
+                .unwrap();
+            (
+                send_req(&mut client, &cipher, Action::Relay, "dev").await,
+                client,
+            )
+        };
+
+        let _busy = register().await;
+        let (resp, _client) = relay().await;
+        assert_eq!(resp.code, StatusCode::Success);
+        for _ in 0..2 {
+            assert_eq!(relay().await.0.code, StatusCode::DeviceBusy);
+        }
+
+        let (mut device, cipher) = register().await;
+        let head = CommonReqHead::read_from(&mut device, Some(&cipher))
+            .await
+            .unwrap();
+        assert_eq!(head.action, Action::Heartbeat);
+        let heartbeat = HeartbeatReq::read_from(&mut device, head.data_len, Some(&cipher))
+            .await
+            .unwrap();
+        assert_eq!(heartbeat.missed_requests, Some(2));
+    }
+
+    #[tokio::test]
+    async fn handshake_and_lookup_failures() {
+        let addr = start(&["pwd"]).await;
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let err = client_handshake(&mut conn, Some("wrong"), None).await;
+        assert_eq!(err.err(), Some(StatusCode::AuthFailed));
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let err = client_handshake(&mut conn, None, None).await;
+        assert_eq!(err.err(), Some(StatusCode::AuthFailed));
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let cipher = client_handshake(&mut conn, Some("pwd"), None)
+            .await
+            .unwrap();
+        let resp = send_req(&mut conn, &cipher, Action::Relay, "nobody").await;
+        assert_eq!(resp.code, StatusCode::DeviceOffline);
+
+        // Without secret keys the relay is open.
+        let addr = start(&[]).await;
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let cipher = client_handshake(&mut conn, None, None).await.unwrap();
+        let resp = send_req(&mut conn, &cipher, Action::Ping, "").await;
+        assert_eq!(resp.code, StatusCode::Success);
+    }
+
+    #[tokio::test]
+    async fn retired_keys_are_accepted_until_they_expire() {
+        let retired = |key: &str, expires_at: chrono::DateTime<chrono::Utc>| RetiredSecretKey {
+            key: key.to_string(),
+            expires_at: expires_at.to_rfc3339(),
+        };
+        let now = chrono::Utc::now();
+        let addr = start_with(RelayServerConfig {
+            secret_keys: vec!["new".to_string()],
+            retired_secret_keys: vec![
+                retired("old", now + chrono::Duration::hours(1)),
+                retired("older", now - chrono::Duration::hours(1)),
+            ],
+            ..Default::default()
+        })
+        .await;
+        let salt = Some(BASE64_STANDARD.encode(b"salt"));
+        for (key, ok) in [("new", true), ("old", true), ("older", false)] {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            let result = client_handshake(&mut conn, Some(key), salt.clone()).await;
+            assert_eq!(result.is_ok(), ok, "{key}");
+        }
+
+        let config = RelayServerConfig {
+            kdf_salt_b64: BASE64_STANDARD.encode(b"salt"),
+            retired_secret_keys: vec![RetiredSecretKey {
+                key: "old".to_string(),
+                expires_at: "tomorrow".to_string(),
+            }],
+            ..Default::default()
+        };
+        assert!(RelayServer::new(&config).is_err());
+    }
+
+    /// The key id of a device, as [`crate::config::Config::get_secret_key_id`] derives it
+    fn key_id(secret_key_hex: &str) -> String {
+        let hash = encrypt::compute_sha256(secret_key_hex.as_bytes());
+        let mut id = hex::encode(encrypt::compute_sha256(&hash));
+        id.truncate(16);
+        id
+    }
+
+    async fn fetch_letters(
+        conn: &mut TcpStream,
+        cipher: &AesGcmCipher,
+        id: &str,
+        key_proof: &str,
+    ) -> RespHead {
+        let req = MailboxFetchReq {
+            common: CommonReq { id: id.to_string() },
+            key_proof: key_proof.to_string(),
+        };
+        CommonReqHead::write_with_body(Action::MailboxFetch, conn, Some(cipher), &req)
+            .await
+            .unwrap();
+        RespHead::read_from(conn, Some(cipher)).await.unwrap()
+    }
+
+    async fn ack_letters(
+        conn: &mut TcpStream,
+        cipher: &AesGcmCipher,
+        id: &str,
+        seqs: Vec<u64>,
+    ) -> RespHead {
+        let ack = MailboxAckReq {
+            common: CommonReq { id: id.to_string() },
+            seqs,
+        };
+        CommonReqHead::write_with_body(Action::MailboxAck, conn, Some(cipher), &ack)
+            .await
+            .unwrap();
+        RespHead::read_from(conn, Some(cipher)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn mailbox_keeps_letters_until_acknowledged() {
+        let proof = crate::relay::mailbox::key_proof("secret");
+        let id = key_id("secret");
+
+        let addr = start(&[]).await;
+        let mut sender = TcpStream::connect(addr).await.unwrap();
+        let cipher = client_handshake(&mut sender, None, None).await.unwrap();
+        let put = |size: u64| MailboxPutReq {
+            common: CommonReq { id: id.clone() },
+            size,
+            expires_at: chrono::Utc::now().timestamp() + 60,
+        };
+        for letter in [&b"first"[..], b"second"] {
+            CommonReqHead::write_with_body(
+                Action::MailboxPut,
+                &mut sender,
+                Some(&cipher),
+                &put(letter.len() as u64),
+            )
+            .await
+            .unwrap();
+            sender.write_all(letter).await.unwrap();
+            let resp = RespHead::read_from(&mut sender, Some(&cipher))
+                .await
+                .unwrap();
+            assert_eq!(resp.code, StatusCode::Success);
+        }
+
+        // Knowing the id is not enough to read the letters or delete them.
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        let other_cipher = client_handshake(&mut other, None, None).await.unwrap();
+        let resp = ack_letters(&mut other, &other_cipher, &id, vec![1, 2]).await;
+        assert_eq!(resp.code, StatusCode::Error);
+        let wrong_proof = crate::relay::mailbox::key_proof("other");
+        let resp = fetch_letters(&mut other, &other_cipher, &id, &wrong_proof).await;
+        assert_eq!(resp.code, StatusCode::Error);
+
+        let mut device = TcpStream::connect(addr).await.unwrap();
+        let cipher = client_handshake(&mut device, None, None).await.unwrap();
+        let resp = fetch_letters(&mut device, &cipher, &id, &proof).await;
+        assert_eq!(resp.code, StatusCode::Success);
+        let fetched: MailboxFetchResp = read_from(&mut device, resp.data_len, Some(&cipher))
+            .await
+            .unwrap();
+        let sizes: Vec<_> = fetched.items.iter().map(|item| item.size).collect();
+        assert_eq!(sizes, [5, 6]);
+        let mut letters = [0u8; 11];
+        device.read_exact(&mut letters).await.unwrap();
+        assert_eq!(&letters, b"firstsecond");
+
+        let resp = ack_letters(&mut device, &cipher, &id, vec![fetched.items[0].seq]).await;
+        assert_eq!(resp.code, StatusCode::Success);
+        let resp = fetch_letters(&mut device, &cipher, &id, &proof).await;
+        let fetched: MailboxFetchResp = read_from(&mut device, resp.data_len, Some(&cipher))
+            .await
+            .unwrap();
+        assert_eq!(fetched.items.len(), 1);
+        assert_eq!(fetched.items[0].size, 6);
+    }
+
+    #[tokio::test]
+    async fn presence_lists_the_connections_of_the_own_device() {
+        let id = key_id("secret");
+        let addr = start(&["pwd", "other"]).await;
+        let salt = Some(BASE64_STANDARD.encode(b"salt"));
+        let mut devices = Vec::new();
+        for (id, key, name) in [
+            (id.as_str(), "pwd", "host-1"),
+            (id.as_str(), "pwd", "host-2"),
+            (id.as_str(), "other", "host-3"),
+            ("0123456789abcdef", "pwd", "host-4"),
+        ] {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            let cipher = client_handshake(&mut conn, Some(key), salt.clone())
+                .await
+                .unwrap();
+            let req = ConnectionReq {
+                common: CommonReq { id: id.to_string() },
+                device: Some(DeviceDescriptor {
+                    name: name.to_string(),
+                    version: "1.0.0".to_string(),
+                    capabilities: vec!["pasteText".to_string()],
+                }),
+            };
+            req.write_to(&mut conn, Some(&cipher)).await.unwrap();
+            let resp = RespHead::read_from(&mut conn, Some(&cipher)).await.unwrap();
+            assert_eq!(resp.code, StatusCode::Success);
+            devices.push(conn);
+        }
+
+        let presence_req = |key_proof: String| PresenceReq {
+            common: CommonReq { id: id.clone() },
+            key_proof,
+        };
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let cipher = client_handshake(&mut client, Some("pwd"), salt.clone())
+            .await
+            .unwrap();
+        let req = presence_req(crate::relay::mailbox::key_proof("secret"));
+        CommonReqHead::write_with_body(Action::Presence, &mut client, Some(&cipher), &req)
+            .await
+            .unwrap();
+        let resp = RespHead::read_from(&mut client, Some(&cipher))
+            .await
+            .unwrap();
+        assert_eq!(resp.code, StatusCode::Success);
+        let presence: PresenceResp = read_from(&mut client, resp.data_len, Some(&cipher))
+            .await
+            .unwrap();
+        let names: Vec<_> = presence
+            .connections
+            .iter()
+            .map(|entry| entry.device.name.as_str())
+            .collect();
+        assert_eq!(names, ["host-1", "host-2"]);
+        assert_eq!(presence.busy_conns, 0);
+        assert_eq!(presence.connections[0].device.capabilities, ["pasteText"]);
+        assert!(presence.connections[0].last_heartbeat > 0);
+
+        // The id alone does not tell who is connected.
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let cipher = client_handshake(&mut client, Some("pwd"), salt)
+            .await
+            .unwrap();
+        let req = presence_req(crate::relay::mailbox::key_proof("other"));
+        CommonReqHead::write_with_body(Action::Presence, &mut client, Some(&cipher), &req)
+            .await
+            .unwrap();
+        let resp = RespHead::read_from(&mut client, Some(&cipher))
+            .await
+            .unwrap();
+        assert_eq!(resp.code, StatusCode::Error);
+
+        // Without a key there is nothing to pair devices by.
+        let addr = start(&[]).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let cipher = client_handshake(&mut client, None, None).await.unwrap();
+        let req = presence_req(crate::relay::mailbox::key_proof("secret"));
+        CommonReqHead::write_with_body(Action::Presence, &mut client, Some(&cipher), &req)
+            .await
+            .unwrap();
+        let resp = RespHead::read_from(&mut client, Some(&cipher))
+            .await
+            .unwrap();
+        assert_eq!(resp.code, StatusCode::Error);
+    }
+
+    #[tokio::test]
+    async fn rendezvous_swaps_observed_endpoints() {
+        use crate::relay::rendezvous;
+        let addr = start(&[]).await;
+        let mut device = TcpStream::connect(addr).await.unwrap();
+        let device_cipher = client_handshake(&mut device, None, None).await.unwrap();
+        let req = ConnectionReq {
+            common: CommonReq {
+                id: "dev".to_string(),
+            },
+            device: Some(DeviceDescriptor {
+                capabilities: vec![CAPABILITY_RENDEZVOUS.to_string()],
+                ..Default::default()
+            }),
+        };
+        req.write_to(&mut device, Some(&device_cipher))
+            .await
+            .unwrap();
+        let resp = RespHead::read_from(&mut device, Some(&device_cipher))
+            .await
+            .unwrap();
+        assert_eq!(resp.code, StatusCode::Success);
+
+        let device_socket = tokio::spawn(async move {
+            let head = CommonReqHead::read_from(&mut device, Some(&device_cipher))
+                .await
+                .unwrap();
+            assert_eq!(head.action, Action::Rendezvous);
+            let offer: RendezvousOffer =
+                read_from(&mut device, head.data_len, Some(&device_cipher))
+                    .await
+                    .unwrap();
+            let (socket, _) = rendezvous::bind(&addr.to_string(), &offer.token)
+                .await
+                .unwrap();
+            let answer = RendezvousAnswer {
+                local_endpoints: vec!["192.168.1.2:5000".to_string()],
+            };
+            RespHead::write_with_body(Action::Rendezvous, &mut device, &device_cipher, &answer)
+                .await
+                .unwrap();
+            (socket, offer.peer_endpoints)
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (cipher, token) = client_handshake_for_token(&mut client, None, None)
+            .await
+            .unwrap();
+        // Binding packets with tokens the relay server did not issue go unanswered.
+        assert!(rendezvous::bind(&addr.to_string(), "c0ffee").await.is_err());
+        let (client_socket, _) = rendezvous::bind(&addr.to_string(), &token).await.unwrap();
+        let req = RendezvousReq {
+            common: CommonReq {
+                id: "dev".to_string(),
+            },
+            token,
+            local_endpoints: vec!["10.0.0.2:6000".to_string()],
+        };
+        CommonReqHead::write_with_body(Action::Rendezvous, &mut client, Some(&cipher), &req)
+            .await
+            .unwrap();
+        let resp = RespHead::read_from(&mut client, Some(&cipher))
+            .await
+            .unwrap();
+        assert_eq!(resp.code, StatusCode::Success);
+        let resp: RendezvousResp = read_from(&mut client, resp.data_len, Some(&cipher))
+            .await
+            .unwrap();
+
+        let (device_socket, client_endpoints) = device_socket.await.unwrap();
+        // The sockets are bound to all addresses, the relay server saw them on loopback.
+        let observed =
+            |socket: &UdpSocket| format!("127.0.0.1:{}", socket.local_addr().unwrap().port());
+        let client_addr = observed(&client_socket);
+        assert_eq!(client_endpoints, [client_addr.as_str(), "10.0.0.2:6000"]);
+        let device_addr = observed(&device_socket);
+        assert_eq!(
+            resp.peer_endpoints,
+            [device_addr.as_str(), "192.168.1.2:5000"]
+        );
+
+        // The idle connection was used up, the client falls back to the relay.
+        let resp = send_req(&mut client, &cipher, Action::Relay, "dev").await;
+        assert_eq!(resp.code, StatusCode::DeviceOffline);
+    }
+}
@@ -0,0 +1,374 @@
+//! Transports of connections to the relay server.
+//!
+//! A connection is plain TCP by default. With `tls` it is wrapped in TLS that
+//! is validated against pinned certificates or public keys rather than a CA,
+//! and with `websocketPath` it is carried in binary WebSocket messages, so it
+//! passes proxies and firewalls that only let HTTPS on port 443 through. The
+//! relay protocol runs unchanged on top of either.
+//!
+//! The relay server tells the transports apart by the first bytes a
+//! connection sends: a TLS record, an HTTP `GET` or a frame length.
+
+use crate::utils::tls::CertPin;
+use futures_util::{Sink, Stream};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{self, Bytes, Message};
+
+pub trait RelayIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> RelayIo for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// A connection to or from the relay server, whatever the transport
+pub type RelayStream = Box<dyn RelayIo>;
+
+/// Configurable as `relayTransport`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayTransportConfig {
+    pub tls: bool,
+    /// Certificates or public keys the relay server may present, at least one
+    /// is required with `tls`, see [`CertPin::parse`]
+    pub pins: Vec<String>,
+    /// TLS server name, the host of `relayServerAddress` if empty
+    #[serde(rename = "serverName")]
+    pub server_name: String,
+    /// Tunnels the connection through a WebSocket upgrade of this path, like `/relay`
+    #[serde(rename = "websocketPath")]
+    pub websocket_path: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    #[error("invalid relay transport config: {0}")]
+    Config(String),
+    #[error("connect error: {0}")]
+    Connect(io::Error),
+    #[error("tls handshake error: {0}")]
+    Tls(io::Error),
+    #[error("websocket handshake error: {0}")]
+    WebSocket(#[from] tungstenite::Error),
+}
+
+/// Host part of `host:port` or `[ipv6]:port`
+pub fn host_of(address: &str) -> &str {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Connects to the relay server at `address` over the configured transport.
+pub async fn connect(
+    address: &str,
+    config: &RelayTransportConfig,
+    outbound: &crate::utils::proxy::Outbound,
+) -> Result<RelayStream, TransportError> {
+    let tls = if config.tls {
+        let pins = config
+            .pins
+            .iter()
+            .map(|pin| CertPin::parse(pin))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(TransportError::Config)?;
+        if pins.is_empty() {
+            return Err(TransportError::Config(
+                "tls requires at least one pin".to_string(),
+            ));
+        }
+        let server_name = match config.server_name.as_str() {
+            "" => host_of(address),
+            name => name,
+        };
+        let server_name =
+            tokio_rustls::rustls::pki_types::ServerName::try_from(server_name.to_string())
+                .map_err(|e| TransportError::Config(format!("{server_name}: {e}")))?;
+        let connector = crate::utils::tls::pinned_tls_connector(pins)
+            .map_err(|e| TransportError::Config(e.to_string()))?;
+        Some((connector, server_name))
+    } else {
+        None
+    };
+
+    let conn = outbound
+        .connect(address)
+        .await
+        .map_err(TransportError::Connect)?;
+    let conn: RelayStream = match tls {
+        Some((connector, server_name)) => Box::new(
+            connector
+                .connect(server_name, conn)
+                .await
+                .map_err(TransportError::Tls)?,
+        ),
+        None => Box::new(conn),
+    };
+    if config.websocket_path.is_empty() {
+        return Ok(conn);
+    }
+    let path = config.websocket_path.trim_start_matches('/');
+    let scheme = if config.tls { "wss" } else { "ws" };
+    let url = format!("{scheme}://{address}/{path}");
+    let (ws, _) = tokio_tungstenite::client_async(url, conn).await?;
+    Ok(Box::new(WsStream::new(ws)))
+}
+
+/// Detects the transport of a connection accepted by the relay server.
+///
+/// Without an acceptor, TLS connections are refused.
+pub async fn accept<S>(
+    conn: S,
+    tls: Option<&tokio_rustls::TlsAcceptor>,
+) -> Result<RelayStream, TransportError>
+where
+    S: RelayIo + 'static,
+{
+    let (prefix, conn) = sniff(conn).await.map_err(TransportError::Connect)?;
+    // A handshake record of TLS 1.0 to 1.3, as a frame length this would be over 64 KiB.
+    if prefix[0] != 0x16 || prefix[1] != 0x03 || prefix[2] > 0x04 {
+        return upgrade(prefix, conn).await;
+    }
+    let Some(acceptor) = tls else {
+        return Err(TransportError::Config("tls is not enabled".to_string()));
+    };
+    let conn = acceptor.accept(conn).await.map_err(TransportError::Tls)?;
+    let (prefix, conn) = sniff(conn).await.map_err(TransportError::Connect)?;
+    upgrade(prefix, conn).await
+}
+
+async fn sniff<S: RelayIo>(mut conn: S) -> io::Result<([u8; 4], Rewind<S>)> {
+    let mut prefix = [0u8; 4];
+    conn.read_exact(&mut prefix).await?;
+    Ok((prefix, Rewind::new(prefix.to_vec(), conn)))
+}
+
+/// Accepts a WebSocket upgrade if the connection starts with one.
+async fn upgrade<S>(prefix: [u8; 4], conn: Rewind<S>) -> Result<RelayStream, TransportError>
+where
+    S: RelayIo + 'static,
+{
+    if &prefix != b"GET " {
+        return Ok(Box::new(conn));
+    }
+    let ws = tokio_tungstenite::accept_async(conn).await?;
+    Ok(Box::new(WsStream::new(ws)))
+}
+
+/// Replays bytes that were read ahead before reading from the stream.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self { prefix, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.prefix.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let n = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix[..n]);
+        self.prefix.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A byte stream over binary WebSocket messages
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    /// Rest of the last received message
+    read_buf: Bytes,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buf: Bytes::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read_buf.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.read_buf = data,
+                // Pings are answered by tungstenite.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected text message",
+                    )));
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+            }
+        }
+        let n = self.read_buf.len().min(buf.remaining());
+        let data = self.read_buf.split_to(n);
+        buf.put_slice(&data);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(io::Error::other)?;
+        Pin::new(&mut self.inner)
+            .start_send(Message::binary(Bytes::copy_from_slice(buf)))
+            .map_err(io::Error::other)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match ready!(Pin::new(&mut self.inner).poll_close(cx)) {
+            Ok(()) | Err(tungstenite::Error::ConnectionClosed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(io::Error::other(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn host_of_address() {
+        assert_eq!(host_of("relay.example.com:443"), "relay.example.com");
+        assert_eq!(host_of("[::1]:16779"), "::1");
+        assert_eq!(host_of("relay.example.com"), "relay.example.com");
+    }
+
+    /// Echoes the first read of each connection.
+    async fn echo_server(tls: Option<tokio_rustls::TlsAcceptor>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (conn, _) = listener.accept().await.unwrap();
+                let tls = tls.clone();
+                tokio::spawn(async move {
+                    let Ok(mut conn) = accept(conn, tls.as_ref()).await else {
+                        return;
+                    };
+                    let mut buf = [0u8; 16];
+                    let n = conn.read(&mut buf).await.unwrap();
+                    conn.write_all(&buf[..n]).await.unwrap();
+                    conn.flush().await.unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    async fn echo(addr: &str, config: &RelayTransportConfig) -> Result<(), TransportError> {
+        let outbound = crate::utils::proxy::Outbound::default();
+        let mut conn = connect(addr, config, &outbound).await?;
+        conn.write_all(b"echo").await.unwrap();
+        conn.flush().await.unwrap();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"echo");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_detects_plain_and_websocket() {
+        let addr = echo_server(None).await;
+        for websocket_path in ["", "/relay"] {
+            let config = RelayTransportConfig {
+                websocket_path: websocket_path.to_string(),
+                ..Default::default()
+            };
+            echo(&addr, &config).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn tls_is_validated_against_pins() {
+        use rcgen::PublicKeyData;
+        use tokio_rustls::rustls;
+        let certified = rcgen::generate_simple_self_signed(vec!["relay.test".to_string()]).unwrap();
+        let key =
+            rustls::pki_types::PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der());
+        let tls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![certified.cert.der().clone()], key.into())
+            .unwrap();
+        let addr = echo_server(Some(std::sync::Arc::new(tls).into())).await;
+
+        let spki =
+            crate::utils::encrypt::compute_sha256(&certified.signing_key.subject_public_key_info());
+        let cert = crate::utils::encrypt::compute_sha256(certified.cert.der());
+        for (pin, websocket_path) in [
+            (format!("spki:{}", hex::encode(spki)), ""),
+            (hex::encode(cert), "/relay"),
+        ] {
+            let config = RelayTransportConfig {
+                tls: true,
+                pins: vec![pin],
+                server_name: "relay.test".to_string(),
+                websocket_path: websocket_path.to_string(),
+            };
+            echo(&addr, &config).await.unwrap();
+        }
+
+        let config = RelayTransportConfig {
+            tls: true,
+            pins: vec![format!("spki:{}", hex::encode([0u8; 32]))],
+            ..Default::default()
+        };
+        assert!(matches!(
+            echo(&addr, &config).await,
+            Err(TransportError::Tls(_))
+        ));
+        // Plain connections are still accepted next to TLS ones.
+        echo(&addr, &RelayTransportConfig::default()).await.unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+//! Reading request bodies with the size limits of `Config::body_limits`.
+//!
+//! `head.data_len` comes from the client, so it is checked before anything is
+//! allocated. A rejected body is left unread, the connection has to be closed
+//! after the error response.
+
+use crate::route::protocol::{RouteErrorCode, RouteRecvHead};
+use crate::route::transfer::resp_error;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{error, info};
+
+/// What a body contains, each kind has its own limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    Text,
+    Json,
+    Image,
+}
+
+impl BodyKind {
+    fn max_len(self, limits: &crate::config::BodyLimits) -> u64 {
+        match self {
+            BodyKind::Text => limits.max_text_bytes,
+            BodyKind::Json => limits.max_json_bytes,
+            BodyKind::Image => limits.max_image_bytes,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BodyError {
+    #[error("invalid body length: {0}")]
+    InvalidLength(i64),
+    #[error("body of {len} bytes exceeds the limit of {max} bytes")]
+    TooLarge { len: i64, max: u64 },
+    #[error("read body failed, err: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl BodyError {
+    pub fn error_code(&self) -> RouteErrorCode {
+        match self {
+            BodyError::InvalidLength(_) => RouteErrorCode::InvalidRequest,
+            BodyError::TooLarge { .. } => RouteErrorCode::PayloadTooLarge,
+            BodyError::Io(e) => RouteErrorCode::from_io_error(e),
+        }
+    }
+}
+
+/// A pasted text, kept in memory or saved to a file if it is too large for the clipboard
+pub enum TextBody {
+    Memory(Vec<u8>),
+    File(PathBuf),
+}
+
+fn check_len(data_len: i64, max: u64) -> Result<usize, BodyError> {
+    if data_len < 0 {
+        return Err(BodyError::InvalidLength(data_len));
+    }
+    if data_len as u64 > max {
+        return Err(BodyError::TooLarge { len: data_len, max });
+    }
+    Ok(data_len as usize)
+}
+
+/// Reads the body of `head` if its length is within the limit of `kind`.
+pub async fn read_body<R>(
+    conn: &mut R,
+    head: &RouteRecvHead,
+    kind: BodyKind,
+) -> Result<Vec<u8>, BodyError>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let max = kind.max_len(&crate::config::read_config().body_limits);
+    let len = check_len(head.data_len, max)?;
+    let mut body = vec![0u8; len];
+    conn.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+/// Reads a pasted text. Texts above `maxTextBytes` are streamed to a file in the save directory.
+pub async fn read_text_body<R>(conn: &mut R, head: &RouteRecvHead) -> Result<TextBody, BodyError>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let limits = crate::config::read_config().body_limits.clone();
+    let len = check_len(head.data_len, limits.max_text_file_bytes)?;
+    if len as u64 <= limits.max_text_bytes {
+        let mut body = vec![0u8; len];
+        conn.read_exact(&mut body).await?;
+        return Ok(TextBody::Memory(body));
+    }
+
+    let file_name = format!(
+        "pasted_text_{}.txt",
+        chrono::Local::now().format("%Y%m%d%H%M%S%3f")
+    );
+    let save_dir = crate::save_rules::resolve_save_dir(&crate::save_rules::SaveTarget {
+        device_name: &head.device_name,
+        file_name: Some(&file_name),
+        kind: crate::save_rules::ReceiveItemKind::Text,
+    });
+    tokio::fs::create_dir_all(&save_dir).await?;
+    let path = PathBuf::from(crate::utils::generate_unique_filepath(
+        save_dir.join(&file_name),
+    )?);
+    // Declared before the file, so that the file is closed when it is removed.
+    let spill = SpillFile(Some(path.clone()));
+    let mut file = tokio::fs::File::create(&path).await?;
+    let n = tokio::io::copy(&mut (&mut *conn).take(len as u64), &mut file).await?;
+    if n < len as u64 {
+        return Err(BodyError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    file.flush().await?;
+    drop(file);
+    spill.keep();
+    info!("text of {} bytes saved to {}", len, path.display());
+    Ok(TextBody::File(path))
+}
+
+/// A text file being written, removed on drop unless it is kept.
+struct SpillFile(Option<PathBuf>);
+
+impl SpillFile {
+    fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take()
+            && let Err(e) = std::fs::remove_file(&path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            error!("remove partial text file {} error: {}", path.display(), e);
+        }
+    }
+}
+
+/// Responds with the error of a rejected body.
+///
+/// Always returns `false`: the rest of the body is still unread, so the connection can't be used further.
+pub async fn reject_body<W>(conn: &mut W, err: &BodyError) -> bool
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    error!("{}", err);
+    if !matches!(err, BodyError::Io(_)) {
+        let _ = resp_error(conn, err.error_code(), &err.to_string()).await;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lengths_are_checked_before_reading() {
+        assert_eq!(check_len(0, 10).unwrap(), 0);
+        assert_eq!(check_len(10, 10).unwrap(), 10);
+        assert!(matches!(
+            check_len(-1, 10),
+            Err(BodyError::InvalidLength(-1))
+        ));
+        let err = check_len(11, 10).unwrap_err();
+        assert!(matches!(err, BodyError::TooLarge { len: 11, max: 10 }));
+        assert_eq!(err.error_code(), RouteErrorCode::PayloadTooLarge);
+        assert_eq!(err.error_code().status_code(), 413);
+    }
+}
@@ -0,0 +1,268 @@
+//! A client for the route protocol, used when this program pushes data to
+//! another WindSend server (folder mirroring and similar background jobs).
+
+use crate::config::RemoteServer;
+use crate::route::protocol::{
+    ConflictPolicy, RouteAction, RouteErrorCode, RouteRecvHead, RouteRespHeadOwned,
+    UploadOperationInfo, UploadType,
+};
+use crate::route::transfer::SUCCESS_STATUS_CODE;
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tracing::debug;
+
+/// Files are sent in parts of at most this size
+const UPLOAD_PART_SIZE: i64 = 16 * 1024 * 1024;
+const MAX_RESP_HEAD_LEN: u32 = 1024 * 10;
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid remote server config: {0}")]
+    Config(String),
+    #[error("connect to {0} timeout")]
+    ConnectTimeout(String),
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("server responded {code} ({error_code:?}): {msg}")]
+    Server {
+        code: i32,
+        error_code: Option<RouteErrorCode>,
+        msg: String,
+    },
+}
+
+/// A file to upload and the path it is saved to on the server
+pub struct UploadItem<'a> {
+    pub local_path: &'a Path,
+    /// Relative save path, separated by `/`
+    pub remote_path: String,
+    pub size: i64,
+}
+
+pub struct RouteClient {
+    conn: TlsStream<TcpStream>,
+    cipher: crate::utils::encrypt::AesGcmCipher,
+    device_name: String,
+    host: String,
+}
+
+impl RouteClient {
+    pub async fn connect(server: &RemoteServer) -> Result<Self, ClientError> {
+        let pin = crate::utils::tls::parse_cert_pin(&server.certificate)
+            .map_err(|e| ClientError::Config(format!("{}: {}", server.name, e)))?;
+        let connector =
+            crate::utils::tls::pinned_tls_connector(vec![crate::utils::tls::CertPin::Cert(pin)])
+                .map_err(|e| ClientError::Config(e.to_string()))?;
+        let cipher = crate::utils::encrypt::AesGcmCipher::new_from_hex(&server.secret_key_hex)
+            .map_err(|e| ClientError::Config(format!("{}: {}", server.name, e)))?;
+
+        let outbound = crate::utils::proxy::outbound().map_err(ClientError::Config)?;
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, outbound.connect(&server.address))
+            .await
+            .map_err(|_| ClientError::ConnectTimeout(server.address.clone()))??;
+        // The verifier only looks at the pinned fingerprint, the name is just for SNI.
+        let server_name = tokio_rustls::rustls::pki_types::ServerName::try_from("localhost")
+            .map_err(|e| ClientError::Config(e.to_string()))?;
+        let conn = connector.connect(server_name, stream).await?;
+
+        let host = match server.address.rsplit_once(':') {
+            Some((host, _)) => host.trim_matches(['[', ']']).to_string(),
+            None => server.address.clone(),
+        };
+        let device_name = hostname::get()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(Self {
+            conn,
+            cipher,
+            device_name,
+            host,
+        })
+    }
+
+    /// Creates a head that passes `common_auth` on the server.
+    pub fn new_head(&self, action: RouteAction) -> Result<RouteRecvHead, ClientError> {
+        let time_ip = format!(
+            "{} {}",
+            chrono::Utc::now().format("%Y-%m-%d %H:%M:%S"),
+            self.host
+        );
+        let encrypted = self
+            .cipher
+            .encrypt(time_ip.as_bytes(), time_ip.as_bytes())
+            .map_err(|e| ClientError::Config(e.to_string()))?;
+        Ok(RouteRecvHead {
+            action,
+            device_name: self.device_name.clone(),
+            time_ip: hex::encode(encrypted),
+            aad: time_ip,
+            ..Default::default()
+        })
+    }
+
+    /// Sends a request and returns the response head and body.
+    ///
+    /// `head.data_len` is set to the length of `body`.
+    pub async fn request(
+        &mut self,
+        mut head: RouteRecvHead,
+        body: &[u8],
+    ) -> Result<(RouteRespHeadOwned, Vec<u8>), ClientError> {
+        head.data_len = body.len() as i64;
+        self.send_head(&head).await?;
+        self.conn.write_all(body).await?;
+        self.conn.flush().await?;
+        self.read_resp().await
+    }
+
+    /// Sends a JSON request body and decodes the JSON response body.
+    pub async fn request_json<T, R>(
+        &mut self,
+        action: RouteAction,
+        body: &T,
+    ) -> Result<R, ClientError>
+    where
+        T: serde::Serialize,
+        R: serde::de::DeserializeOwned,
+    {
+        let head = self.new_head(action)?;
+        let (_, resp_body) = self.request(head, &serde_json::to_vec(body)?).await?;
+        Ok(serde_json::from_slice(&resp_body)?)
+    }
+
+    /// Uploads files as a single `pasteFile` operation, replacing files that already exist.
+    ///
+    /// With `ignore_save_path_rules` the remote paths are relative to the server's `savePath`.
+    pub async fn upload_files(
+        &mut self,
+        items: &[UploadItem<'_>],
+        ignore_save_path_rules: bool,
+    ) -> Result<(), ClientError> {
+        self.upload_files_with_policy(
+            items,
+            Some(ConflictPolicy::Overwrite),
+            ignore_save_path_rules,
+        )
+        .await
+    }
+
+    /// Like [`Self::upload_files`], without `conflict_policy` the server's
+    /// configured policy applies.
+    pub async fn upload_files_with_policy(
+        &mut self,
+        items: &[UploadItem<'_>],
+        conflict_policy: Option<ConflictPolicy>,
+        ignore_save_path_rules: bool,
+    ) -> Result<(), ClientError> {
+        if items.is_empty() {
+            return Ok(());
+        }
+        let op_id = rand::random::<u32>();
+        let op_info = UploadOperationInfo {
+            files_size_in_this_op: items.iter().map(|item| item.size).sum(),
+            files_count_in_this_op: items.len() as i32,
+            upload_paths: None,
+            empty_dirs: None,
+            conflict_policy,
+            files: None,
+            ignore_save_path_rules,
+        };
+        let mut head = self.new_head(RouteAction::PasteFile)?;
+        head.upload_type = UploadType::UploadInfo;
+        head.op_id = op_id;
+        self.request(head, &serde_json::to_vec(&op_info)?).await?;
+
+        for item in items {
+            self.upload_file(op_id, item).await?;
+        }
+        Ok(())
+    }
+
+    async fn upload_file(&mut self, op_id: u32, item: &UploadItem<'_>) -> Result<(), ClientError> {
+        let mut file = tokio::fs::File::open(item.local_path).await?;
+        let file_id = rand::random::<u32>();
+        let mut start = 0;
+        loop {
+            let end = std::cmp::min(start + UPLOAD_PART_SIZE, item.size);
+            let mut head = self.new_head(RouteAction::PasteFile)?;
+            head.upload_type = UploadType::File;
+            head.op_id = op_id;
+            head.file_id = file_id;
+            head.file_size = item.size;
+            head.path = item.remote_path.clone();
+            head.start = start;
+            head.end = end;
+            head.data_len = end - start;
+            self.send_head(&head).await?;
+            let n = tokio::io::copy(&mut (&mut file).take((end - start) as u64), &mut self.conn)
+                .await?;
+            if n < (end - start) as u64 {
+                return Err(ClientError::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("{} changed while uploading", item.local_path.display()),
+                )));
+            }
+            self.conn.flush().await?;
+            let (resp, _) = self.read_resp().await?;
+            if resp.skipped == Some(true) || end >= item.size {
+                break;
+            }
+            start = end;
+        }
+        debug!("uploaded {}", item.remote_path);
+        Ok(())
+    }
+
+    pub async fn close(mut self) {
+        if let Ok(head) = self.new_head(RouteAction::EndConnection) {
+            let _ = self.send_head(&head).await;
+        }
+        let _ = self.conn.shutdown().await;
+    }
+
+    async fn send_head(&mut self, head: &RouteRecvHead) -> Result<(), ClientError> {
+        let head_buf = serde_json::to_vec(head)?;
+        self.conn
+            .write_all(&(head_buf.len() as u32).to_le_bytes())
+            .await?;
+        self.conn.write_all(&head_buf).await?;
+        Ok(())
+    }
+
+    async fn read_resp(&mut self) -> Result<(RouteRespHeadOwned, Vec<u8>), ClientError> {
+        let mut head_len = [0u8; 4];
+        self.conn.read_exact(&mut head_len).await?;
+        let head_len = u32::from_le_bytes(head_len);
+        if head_len == 0 || head_len > MAX_RESP_HEAD_LEN {
+            return Err(ClientError::InvalidResponse(format!(
+                "invalid head len: {head_len}"
+            )));
+        }
+        let mut head_buf = vec![0u8; head_len as usize];
+        self.conn.read_exact(&mut head_buf).await?;
+        let head: RouteRespHeadOwned = serde_json::from_slice(&head_buf)?;
+        if head.data_len < 0 {
+            return Err(ClientError::InvalidResponse(format!(
+                "invalid data len: {}",
+                head.data_len
+            )));
+        }
+        let mut body = vec![0u8; head.data_len as usize];
+        self.conn.read_exact(&mut body).await?;
+        if head.code != SUCCESS_STATUS_CODE {
+            return Err(ClientError::Server {
+                code: head.code,
+                error_code: head.error_code,
+                msg: head.msg,
+            });
+        }
+        Ok((head, body))
+    }
+}
@@ -1,13 +1,14 @@
-use crate::language::{LANGUAGE_MANAGER, LanguageKey};
-use crate::route::protocol::{RouteDataType, RouteRecvHead, RouteRespHead, RouteTransferInfo};
-use crate::route::transfer::{resp_common_error_msg, send_head, send_msg_with_body};
+use crate::language::LanguageKey;
+use crate::route::protocol::{
+    RouteDataType, RouteErrorCode, RouteRecvHead, RouteRespHead, RouteTransferInfo,
+};
+use crate::route::transfer::{resp_error, send_head, send_msg_with_body};
+use crate::route::{ConnContext, RouteConn};
 use crate::status;
 use std::path::PathBuf;
-use tokio::net::TcpStream;
-use tokio_rustls::server::TlsStream;
 use tracing::{debug, error, info, warn};
 
-pub async fn copy_handler(conn: &mut TlsStream<TcpStream>) {
+pub async fn copy_handler(conn: &mut RouteConn) {
     // Selected files
     let selected_files = status::SELECTED_FILES.lock().unwrap().clone();
     if !selected_files.is_empty() {
@@ -44,16 +45,14 @@ pub async fn copy_handler(conn: &mut TlsStream<TcpStream>) {
         }
     }
 
-    let clipboard_is_empty = LANGUAGE_MANAGER
-        .read()
-        .unwrap()
-        .translate(LanguageKey::ClipboardIsEmpty);
-    let _ = resp_common_error_msg(conn, clipboard_is_empty).await;
+    // The client gets the untranslated message, the translation is for the local log.
+    info!("{}", LanguageKey::ClipboardIsEmpty.translate());
+    let _ = resp_error(conn, RouteErrorCode::ClipboardEmpty, "clipboard is empty").await;
 }
 
 #[allow(dead_code)]
 async fn send_files<'a, T: IntoIterator<Item = &'a String> + std::fmt::Debug>(
-    conn: &mut TlsStream<TcpStream>,
+    conn: &mut RouteConn,
     paths: T,
 ) -> Result<(), ()> {
     debug!("send_files: {:?}", &paths);
@@ -147,7 +146,7 @@ async fn send_files<'a, T: IntoIterator<Item = &'a String> + std::fmt::Debug>(
     if resp_paths.is_empty() {
         let msg = "send_files unexpected empty paths";
         error!("{}", msg);
-        resp_common_error_msg(conn, &msg.to_string()).await.ok();
+        resp_error(conn, RouteErrorCode::NotFound, msg).await.ok();
         return Err(());
     }
     debug!("{:?}", &resp_paths);
@@ -156,7 +155,7 @@ async fn send_files<'a, T: IntoIterator<Item = &'a String> + std::fmt::Debug>(
         Err(err) => {
             let msg = format!("serde_json::to_vec failed, err: {err}");
             error!("{}", &msg);
-            let _ = resp_common_error_msg(conn, &msg).await;
+            let _ = resp_error(conn, RouteErrorCode::Internal, &msg).await;
             return Err(());
         }
     };
@@ -171,9 +170,7 @@ async fn send_files<'a, T: IntoIterator<Item = &'a String> + std::fmt::Debug>(
     .await
 }
 
-async fn send_clipboard_image(
-    conn: &mut TlsStream<TcpStream>,
-) -> Result<(), Box<dyn std::error::Error>> {
+async fn send_clipboard_image(conn: &mut RouteConn) -> Result<(), Box<dyn std::error::Error>> {
     let image_name = chrono::Local::now().format("%Y%m%d%H%M%S").to_string() + ".png";
     let raw_image = match crate::config::CLIPBOARD.read_image() {
         Ok(raw_image) => raw_image,
@@ -212,7 +209,7 @@ async fn send_clipboard_image(
     Ok(())
 }
 
-async fn send_clipboard_text(conn: &mut TlsStream<TcpStream>) -> Result<(), String> {
+async fn send_clipboard_text(conn: &mut RouteConn) -> Result<(), String> {
     let data_text = match crate::config::CLIPBOARD.read_text() {
         Ok(data_text) => data_text,
         Err(err) => return Err(format!("read clipboard text failed, err: {err}")),
@@ -229,10 +226,32 @@ async fn send_clipboard_text(conn: &mut TlsStream<TcpStream>) -> Result<(), Stri
 }
 
 /// This function returns whether to continue the loop (for example, not encountering a Socket Error)
-pub async fn download_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecvHead) -> bool {
+pub async fn download_handler(
+    conn: &mut RouteConn,
+    head: RouteRecvHead,
+    ctx: &ConnContext,
+) -> bool {
+    if let Some(format) = head.archive {
+        if !ctx.client_supports("chunkedBody") {
+            let msg = format!(
+                "archive downloads need a chunked body, client version: {}",
+                ctx.client_version().as_deref().unwrap_or("unknown")
+            );
+            error!("{}", msg);
+            return resp_error(conn, RouteErrorCode::Unsupported, &msg)
+                .await
+                .is_ok();
+        }
+        return archive_download_handler(conn, &head, format).await;
+    }
     if !std::path::Path::new(&head.path).exists() {
         error!("file not exists: {}", head.path);
-        let r = resp_common_error_msg(conn, &format!("file not exists: {}", head.path)).await;
+        let r = resp_error(
+            conn,
+            RouteErrorCode::NotFound,
+            &format!("file not exists: {}", head.path),
+        )
+        .await;
         return r.is_ok();
     }
     debug!(
@@ -242,15 +261,22 @@ pub async fn download_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecvHe
     let file = tokio::fs::File::open(&head.path).await;
     if let Err(err) = file {
         error!("open file failed, err: {}", err);
-        let r = resp_common_error_msg(conn, &format!("open file failed, err: {err}")).await;
+        let r = resp_error(
+            conn,
+            RouteErrorCode::from_io_error(&err),
+            &format!("open file failed, err: {err}"),
+        )
+        .await;
         return r.is_ok();
     }
     let resp = RouteRespHead {
         code: crate::route::transfer::SUCCESS_STATUS_CODE,
-        msg: &"start download".to_string(),
+        msg: "start download",
         total_file_size: None,
+        skipped: None,
         data_type: RouteDataType::Binary,
         data_len: head.end - head.start,
+        error_code: None,
     };
     if send_head(conn, &resp).await.is_err() {
         return false;
@@ -281,3 +307,73 @@ pub async fn download_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecvHe
     }
     true
 }
+
+/// Streams `head.path` as an archive generated on the fly.
+///
+/// The body is sent in chunks because its size is unknown in advance. If the
+/// archive cannot be completed, the connection is closed without the final
+/// empty chunk so that the client does not mistake it for a complete archive.
+async fn archive_download_handler(
+    conn: &mut RouteConn,
+    head: &RouteRecvHead,
+    format: crate::route::protocol::ArchiveFormat,
+) -> bool {
+    use crate::route::transfer::write_chunk;
+    use tokio::io::AsyncWriteExt;
+
+    let root = PathBuf::from(&head.path);
+    if !root.exists() {
+        error!("file not exists: {}", head.path);
+        let r = resp_error(
+            conn,
+            RouteErrorCode::NotFound,
+            &format!("file not exists: {}", head.path),
+        )
+        .await;
+        return r.is_ok();
+    }
+    let resp = RouteRespHead {
+        code: crate::route::transfer::SUCCESS_STATUS_CODE,
+        msg: "start download",
+        total_file_size: None,
+        skipped: None,
+        data_type: RouteDataType::Binary,
+        data_len: crate::route::protocol::CHUNKED_DATA_LEN,
+        error_code: None,
+    };
+    if send_head(conn, &resp).await.is_err() {
+        return false;
+    }
+    info!(
+        "streaming {} as {:?} archive, compress: {}",
+        head.path, format, head.compress
+    );
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(4);
+    let compress = head.compress;
+    let producer = tokio::task::spawn_blocking(move || {
+        let mut writer = crate::archive::ChannelWriter::new(tx);
+        let n = crate::archive::write_archive(&root, format, compress, &mut writer)?;
+        std::io::Write::flush(&mut writer)?;
+        Ok::<_, std::io::Error>(n)
+    });
+    while let Some(chunk) = rx.recv().await {
+        if let Err(err) = write_chunk(conn, &chunk).await {
+            error!("write archive chunk failed, err: {}", err);
+            // Dropping the receiver stops the producer.
+            return false;
+        }
+    }
+    match producer.await {
+        Ok(Ok(n)) => debug!("archive of {} files sent", n),
+        Ok(Err(err)) => {
+            error!("generate archive of {} failed, err: {}", head.path, err);
+            return false;
+        }
+        Err(err) => {
+            error!("archive task failed, err: {}", err);
+            return false;
+        }
+    }
+    write_chunk(conn, &[]).await.is_ok() && conn.flush().await.is_ok()
+}
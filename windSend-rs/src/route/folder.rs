@@ -0,0 +1,126 @@
+use crate::route::RouteConn;
+use crate::route::body::{BodyKind, read_body, reject_body};
+use crate::route::protocol::{
+    DeleteFilesReq, FolderManifest, FolderManifestReq, RouteDataType, RouteErrorCode, RouteRecvHead,
+};
+use crate::route::transfer::{resp_error, send_msg, send_msg_with_body};
+use std::path::Path;
+use tracing::{debug, error, info, warn};
+
+async fn read_json_body<T: serde::de::DeserializeOwned>(
+    conn: &mut RouteConn,
+    head: &RouteRecvHead,
+) -> Option<T> {
+    let body_buf = match read_body(conn, head, BodyKind::Json).await {
+        Ok(body_buf) => body_buf,
+        Err(e) => {
+            reject_body(conn, &e).await;
+            return None;
+        }
+    };
+    serde_json::from_slice(&body_buf)
+        .inspect_err(|e| error!("json unmarshal failed, err: {}", e))
+        .ok()
+}
+
+/// return whether should continue loop(like no socket error)
+pub async fn folder_manifest_handler(conn: &mut RouteConn, head: RouteRecvHead) -> bool {
+    let Some(req): Option<FolderManifestReq> = read_json_body(conn, &head).await else {
+        return false;
+    };
+    debug!("folder manifest req: {:?}", req);
+    let save_path = crate::save_rules::default_save_dir();
+    let dir = match crate::utils::join_sanitized(&save_path, &req.path) {
+        Ok(dir) => dir,
+        Err(e) => {
+            error!("{}", e);
+            return resp_error(conn, RouteErrorCode::UnsafePath, &e.to_string())
+                .await
+                .is_ok();
+        }
+    };
+    let entries = match crate::folder_sync::build_manifest(&dir, req.with_hash).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            let msg = format!("build manifest of {} failed, err: {}", dir.display(), e);
+            error!("{}", msg);
+            return resp_error(conn, RouteErrorCode::from_io_error(&e), &msg)
+                .await
+                .is_ok();
+        }
+    };
+    let body = match serde_json::to_vec(&FolderManifest { entries }) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("json marshal failed, err: {}", e);
+            return resp_error(conn, RouteErrorCode::Internal, &e.to_string())
+                .await
+                .is_ok();
+        }
+    };
+    send_msg_with_body(conn, &"".to_string(), RouteDataType::Text, &body)
+        .await
+        .is_ok()
+}
+
+/// return whether should continue loop(like no socket error)
+pub async fn delete_files_handler(conn: &mut RouteConn, head: RouteRecvHead) -> bool {
+    let Some(req): Option<DeleteFilesReq> = read_json_body(conn, &head).await else {
+        return false;
+    };
+    let save_path = crate::save_rules::default_save_dir();
+    let dir = match crate::utils::join_sanitized(&save_path, &req.path) {
+        Ok(dir) => dir,
+        Err(e) => {
+            error!("{}", e);
+            return resp_error(conn, RouteErrorCode::UnsafePath, &e.to_string())
+                .await
+                .is_ok();
+        }
+    };
+    let mut deleted = 0;
+    for file in &req.files {
+        let path = match crate::utils::join_sanitized(&dir, file) {
+            Ok(path) => path,
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            }
+        };
+        // Only regular files are removed, never directories or links.
+        match tokio::fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => {}
+            _ => continue,
+        }
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            warn!("delete {} failed, err: {}", path.display(), e);
+            continue;
+        }
+        deleted += 1;
+        remove_empty_parents(&dir, &path).await;
+    }
+    info!(
+        "{} deleted {} files in {}",
+        head.device_name,
+        deleted,
+        dir.display()
+    );
+    send_msg(conn, &format!("deleted {deleted} files"))
+        .await
+        .is_ok()
+}
+
+/// Removes the directories between `path` and `base` that became empty.
+async fn remove_empty_parents(base: &Path, path: &Path) {
+    let mut current = path.parent();
+    while let Some(dir) = current {
+        if dir == base || !dir.starts_with(base) {
+            break;
+        }
+        // Fails if the directory is not empty.
+        if tokio::fs::remove_dir(dir).await.is_err() {
+            break;
+        }
+        current = dir.parent();
+    }
+}
@@ -0,0 +1,264 @@
+//! Encodings of the length-prefixed heads of route requests, responses and sync frames.
+//!
+//! Heads are JSON unless the client asks for another codec in its `hello`
+//! request (`headCodecs`, in order of preference). The codec the server picks
+//! is returned as `headCodec` in the JSON `hello` response and applies to every
+//! head that follows on the connection, in both directions, including the sync
+//! frames of a clipboard subscription that takes the connection over.
+//!
+//! The binary codec encodes the same serde structs as JSON, with the same
+//! field names, so both sides keep a single set of head types.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HeadCodec {
+    #[default]
+    Json,
+    /// RFC 8949 Concise Binary Object Representation
+    Cbor,
+}
+
+/// The codecs this server accepts, as sent in the `hello` response
+pub const SUPPORTED_HEAD_CODECS: &[HeadCodec] = &[HeadCodec::Json, HeadCodec::Cbor];
+
+#[derive(Debug, thiserror::Error)]
+pub enum HeadCodecError {
+    #[error("json head error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("cbor head encode error: {0}")]
+    CborEncode(#[from] ciborium::ser::Error<std::io::Error>),
+    #[error("cbor head decode error: {0}")]
+    CborDecode(#[from] ciborium::de::Error<std::io::Error>),
+}
+
+impl HeadCodec {
+    pub fn name(self) -> &'static str {
+        match self {
+            HeadCodec::Json => "json",
+            HeadCodec::Cbor => "cbor",
+        }
+    }
+
+    /// Picks the first codec of the client's preference list that this server supports.
+    pub fn negotiate(preferred: &[String]) -> HeadCodec {
+        preferred
+            .iter()
+            .find_map(|name| {
+                SUPPORTED_HEAD_CODECS
+                    .iter()
+                    .find(|codec| codec.name() == name)
+            })
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(self, head: &T) -> Result<Vec<u8>, HeadCodecError> {
+        match self {
+            HeadCodec::Json => Ok(serde_json::to_vec(head)?),
+            HeadCodec::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(head, &mut buf)?;
+                Ok(buf)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, buf: &[u8]) -> Result<T, HeadCodecError> {
+        match self {
+            HeadCodec::Json => Ok(serde_json::from_slice(buf)?),
+            HeadCodec::Cbor => Ok(ciborium::from_reader(buf)?),
+        }
+    }
+}
+
+tokio::task_local! {
+    /// Codec of the route connection served by the current task
+    static CURRENT: Cell<HeadCodec>;
+}
+
+/// Runs `f` as the server of a connection that starts out with `codec`.
+pub async fn scope<F: Future>(codec: HeadCodec, f: F) -> F::Output {
+    CURRENT.scope(Cell::new(codec), f).await
+}
+
+/// The codec of the connection served by the current task, JSON outside of [`scope`].
+pub fn current() -> HeadCodec {
+    CURRENT.try_with(Cell::get).unwrap_or_default()
+}
+
+/// Switches the connection served by the current task to `codec`.
+pub fn set_current(codec: HeadCodec) {
+    if CURRENT.try_with(|current| current.set(codec)).is_err() {
+        warn!("no connection to switch to the {} head codec", codec.name());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::protocol::{
+        ArchiveFormat, RouteAction, RouteDataType, RouteErrorCode, RouteRecvHead, RouteRespHead,
+        RouteRespHeadOwned, UploadType,
+    };
+    use crate::sync::clipboard_domain::ClipboardPayloadKind;
+    use crate::sync::sync_frame::{
+        AckFrame, CloseCode, CloseFrame, EventFrame, HeartbeatFrame, SubscribeFrame,
+        SubscribeRequest, SubscribeResume, SyncCapabilities, SyncFrameHead,
+    };
+
+    fn recv_heads() -> Vec<RouteRecvHead> {
+        vec![
+            RouteRecvHead {
+                action: RouteAction::Ping,
+                device_name: "phone".to_string(),
+                time_ip: "00ff".to_string(),
+                aad: "aad".to_string(),
+                ..Default::default()
+            },
+            RouteRecvHead {
+                action: RouteAction::PasteFile,
+                device_name: "设备".to_string(),
+                file_id: 7,
+                file_size: 1 << 40,
+                path: "dir/文件.txt".to_string(),
+                upload_type: UploadType::File,
+                start: 4096,
+                end: 8192,
+                data_len: 4096,
+                op_id: u32::MAX,
+                sync_data_type: RouteDataType::ClipImage,
+                file_hash: Some("ab".repeat(32)),
+                mod_time: Some(-1),
+                ..Default::default()
+            },
+            RouteRecvHead {
+                action: RouteAction::Download,
+                data_len: -1,
+                archive: Some(ArchiveFormat::Zip),
+                compress: true,
+                ..Default::default()
+            },
+            RouteRecvHead {
+                action: RouteAction::Unknown("fromTheFuture".to_string()),
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn sync_heads() -> Vec<SyncFrameHead> {
+        vec![
+            SyncFrameHead::Subscribe(SubscribeFrame {
+                version: 1,
+                request: SubscribeRequest::Resume(SubscribeResume {
+                    session_id: "session".to_string(),
+                    resume_token: "token".to_string(),
+                    resume_ack_up_to: u64::MAX,
+                    replay_requirements: Default::default(),
+                }),
+                capabilities: SyncCapabilities::v2_default(),
+            }),
+            SyncFrameHead::Event(EventFrame {
+                event_id: 3,
+                payload_kind: ClipboardPayloadKind::ImagePng,
+                body_len: 1024,
+            }),
+            SyncFrameHead::Ack(AckFrame { ack_up_to: 3 }),
+            SyncFrameHead::Heartbeat(HeartbeatFrame {}),
+            SyncFrameHead::Close(CloseFrame {
+                close_code: CloseCode::ProtocolError,
+                close_reason: None,
+            }),
+        ]
+    }
+
+    fn assert_same_value<T>(value: &T)
+    where
+        T: Serialize + DeserializeOwned + std::fmt::Debug,
+    {
+        let from_json: T = HeadCodec::Json
+            .decode(&HeadCodec::Json.encode(value).unwrap())
+            .unwrap();
+        for codec in SUPPORTED_HEAD_CODECS {
+            let decoded: T = codec.decode(&codec.encode(value).unwrap()).unwrap();
+            // The heads do not all implement PartialEq, their Debug output is compared instead.
+            assert_eq!(format!("{decoded:?}"), format!("{value:?}"), "{codec:?}");
+            assert_eq!(
+                format!("{decoded:?}"),
+                format!("{from_json:?}"),
+                "{codec:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn every_codec_decodes_the_values_json_does() {
+        for head in recv_heads() {
+            assert_same_value(&head);
+        }
+        for head in sync_heads() {
+            assert_same_value(&head);
+        }
+        let msg = "file part written successfully".to_string();
+        let resp = RouteRespHead {
+            code: 413,
+            msg: &msg,
+            data_type: RouteDataType::Text,
+            data_len: 0,
+            total_file_size: Some(u64::MAX),
+            skipped: Some(true),
+            error_code: Some(RouteErrorCode::PayloadTooLarge),
+        };
+        for codec in SUPPORTED_HEAD_CODECS {
+            let owned: RouteRespHeadOwned = codec.decode(&codec.encode(&resp).unwrap()).unwrap();
+            assert_eq!(owned.code, 413);
+            assert_eq!(owned.msg, msg);
+            assert_eq!(owned.data_len, 0);
+            assert_eq!(owned.skipped, Some(true));
+            assert_eq!(owned.error_code, Some(RouteErrorCode::PayloadTooLarge));
+        }
+    }
+
+    #[test]
+    fn cbor_heads_are_smaller_and_not_json() {
+        for head in recv_heads() {
+            let json = HeadCodec::Json.encode(&head).unwrap();
+            let cbor = HeadCodec::Cbor.encode(&head).unwrap();
+            assert!(cbor.len() < json.len());
+            assert!(HeadCodec::Json.decode::<RouteRecvHead>(&cbor).is_err());
+            assert!(HeadCodec::Cbor.decode::<RouteRecvHead>(&json).is_err());
+        }
+    }
+
+    #[test]
+    fn negotiation_prefers_the_client_order() {
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(HeadCodec::negotiate(&[]), HeadCodec::Json);
+        assert_eq!(
+            HeadCodec::negotiate(&names(&["msgpack", "cbor", "json"])),
+            HeadCodec::Cbor
+        );
+        assert_eq!(
+            HeadCodec::negotiate(&names(&["json", "cbor"])),
+            HeadCodec::Json
+        );
+        assert_eq!(HeadCodec::negotiate(&names(&["msgpack"])), HeadCodec::Json);
+    }
+
+    #[tokio::test]
+    async fn codec_is_kept_per_connection() {
+        assert_eq!(current(), HeadCodec::Json);
+        let inner = scope(HeadCodec::Json, async {
+            set_current(HeadCodec::Cbor);
+            let other = tokio::spawn(scope(HeadCodec::Json, async { current() }));
+            (current(), other.await.unwrap())
+        })
+        .await;
+        assert_eq!(inner, (HeadCodec::Cbor, HeadCodec::Json));
+        assert_eq!(current(), HeadCodec::Json);
+    }
+}
@@ -1,4 +1,9 @@
+mod body;
+pub mod client;
 mod copy;
+mod folder;
+pub mod head_codec;
+mod mux;
 mod paste;
 mod sync_session;
 
@@ -0,0 +1,592 @@
+//! Independent request streams multiplexed over one route connection.
+//!
+//! After a successful `multiplex` request the connection carries frames
+//! instead of requests:
+//!
+//! ```text
+//! stream id: u32 LE | kind: u8 | payload length: u32 LE | payload
+//! ```
+//!
+//! The client opens streams with `open` on any unused non-zero id. Each stream
+//! is served like a connection of its own: requests are authenticated by
+//! `common_auth` and handled by the router, so file parts, pings and clipboard
+//! operations of different streams interleave. Streams start out with the head
+//! codec of the connection. `fin` ends the sending side of a stream and
+//! `reset` aborts it. `goAway` on stream 0 ends the session once the open
+//! streams are done, and the connection can be used for requests again.
+//!
+//! Every stream has its own flow control: a side may send at most
+//! [`INITIAL_WINDOW`] bytes of data that the other side has not yet granted
+//! back with `windowUpdate`, whose payload is the u32 LE number of bytes read.
+
+use crate::route::protocol::{RouteAction, RouteErrorCode};
+use crate::route::{ConnContext, RouterLoopOutcome};
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+const FRAME_HEADER_LEN: usize = 9;
+/// Data frames carry at most this many bytes
+pub const MAX_FRAME_PAYLOAD: usize = 64 * 1024;
+/// Bytes a side may send on a stream before the other side grants more
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+/// Open streams per session, further `open` frames are answered with `reset`
+const MAX_STREAMS: usize = 64;
+/// Id of the frames that concern the whole session
+const SESSION_STREAM_ID: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    Open = 0,
+    Data = 1,
+    WindowUpdate = 2,
+    Fin = 3,
+    Reset = 4,
+    GoAway = 5,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = std::io::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => FrameKind::Open,
+            1 => FrameKind::Data,
+            2 => FrameKind::WindowUpdate,
+            3 => FrameKind::Fin,
+            4 => FrameKind::Reset,
+            5 => FrameKind::GoAway,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown frame kind: {value}"),
+                ));
+            }
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub stream_id: u32,
+    pub kind: FrameKind,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(stream_id: u32, kind: FrameKind) -> Self {
+        Self {
+            stream_id,
+            kind,
+            payload: Vec::new(),
+        }
+    }
+
+    fn window_update(stream_id: u32, n: u32) -> Self {
+        Self {
+            stream_id,
+            kind: FrameKind::WindowUpdate,
+            payload: n.to_le_bytes().to_vec(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + self.payload.len());
+        buf.extend_from_slice(&self.stream_id.to_le_bytes());
+        buf.push(self.kind as u8);
+        buf.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    /// Reads a frame, `None` if the connection was closed between frames.
+    pub async fn read_from<R>(reader: &mut R) -> std::io::Result<Option<Self>>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let stream_id = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let kind = FrameKind::try_from(header[4])?;
+        let len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_PAYLOAD {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("frame payload of {len} bytes is too large"),
+            ));
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+        Ok(Some(Self {
+            stream_id,
+            kind,
+            payload,
+        }))
+    }
+}
+
+#[derive(Default)]
+struct StreamState {
+    inbound: VecDeque<Vec<u8>>,
+    /// Read position in the front chunk of `inbound`
+    offset: usize,
+    /// Bytes the peer may still send
+    recv_window: u32,
+    /// Bytes read but not yet granted back to the peer
+    consumed: u32,
+    /// `fin` received or the session ended
+    read_closed: bool,
+    read_waker: Option<Waker>,
+    send_window: u32,
+    /// `reset` received or the session ended
+    write_closed: bool,
+    fin_sent: bool,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        Self {
+            recv_window: INITIAL_WINDOW,
+            send_window: INITIAL_WINDOW,
+            ..Default::default()
+        }
+    }
+
+    /// Aborts the stream, data that was not read yet is dropped.
+    fn close(&mut self) {
+        self.inbound.clear();
+        self.offset = 0;
+        self.read_closed = true;
+        self.write_closed = true;
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+type StreamMap = Arc<Mutex<HashMap<u32, Arc<Mutex<StreamState>>>>>;
+
+/// One stream of a multiplexed connection
+pub struct MuxStream {
+    id: u32,
+    state: Arc<Mutex<StreamState>>,
+    frames: mpsc::UnboundedSender<Frame>,
+    streams: StreamMap,
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let Some(chunk) = state.inbound.front() else {
+            if !state.read_closed {
+                state.read_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            return Poll::Ready(Ok(()));
+        };
+        let n = std::cmp::min(buf.remaining(), chunk.len() - state.offset);
+        buf.put_slice(&chunk[state.offset..state.offset + n]);
+        state.offset += n;
+        if state.offset == chunk.len() {
+            state.inbound.pop_front();
+            state.offset = 0;
+        }
+        state.consumed += n as u32;
+        if state.consumed >= INITIAL_WINDOW / 2 && !state.read_closed {
+            let granted = std::mem::take(&mut state.consumed);
+            state.recv_window += granted;
+            let _ = self.frames.send(Frame::window_update(self.id, granted));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        if state.write_closed || state.fin_sent {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if state.send_window == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf
+            .len()
+            .min(state.send_window as usize)
+            .min(MAX_FRAME_PAYLOAD);
+        let frame = Frame {
+            stream_id: self.id,
+            kind: FrameKind::Data,
+            payload: buf[..n].to_vec(),
+        };
+        if self.frames.send(frame).is_err() {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        state.send_window -= n as u32;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // The session writer flushes whenever it runs out of frames.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.fin_sent && !state.write_closed {
+            state.fin_sent = true;
+            let _ = self.frames.send(Frame::new(self.id, FrameKind::Fin));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let mut streams = self.streams.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        if !state.fin_sent && !state.write_closed {
+            state.fin_sent = true;
+            let _ = self.frames.send(Frame::new(self.id, FrameKind::Fin));
+        }
+        // Nothing reads the stream any more, data the peer still sends resets it.
+        streams.remove(&self.id);
+    }
+}
+
+/// Serves multiplexed streams on `conn` until the client sends `goAway` or the connection fails.
+///
+/// Returns the connection after `goAway`, so that it can serve plain requests again.
+pub async fn serve<S>(
+    conn: S,
+    remote_addr: std::net::SocketAddr,
+    ctx: Arc<ConnContext>,
+) -> Option<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    info!("start multiplexed session with {}", remote_addr);
+    let (mut reader, writer) = tokio::io::split(conn);
+    let (frames_tx, frames_rx) = mpsc::unbounded_channel();
+    let writer_task = tokio::spawn(write_frames(writer, frames_rx));
+    let streams: StreamMap = Default::default();
+    let mut tasks = tokio::task::JoinSet::new();
+    let head_codec = crate::route::head_codec::current();
+
+    let go_away = loop {
+        let frame = match Frame::read_from(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break false,
+            Err(e) => {
+                error!("read mux frame failed, err: {}", e);
+                break false;
+            }
+        };
+        if frame.stream_id == SESSION_STREAM_ID {
+            if frame.kind == FrameKind::GoAway {
+                break true;
+            }
+            warn!("unexpected {:?} frame on the session stream", frame.kind);
+            continue;
+        }
+        if frame.kind == FrameKind::Open {
+            match open_stream(&streams, frame.stream_id, &frames_tx) {
+                Some(stream) => {
+                    tasks.spawn(crate::route::head_codec::scope(
+                        head_codec,
+                        serve_stream(stream, remote_addr, Arc::clone(&ctx)),
+                    ));
+                }
+                None => {
+                    let _ = frames_tx.send(Frame::new(frame.stream_id, FrameKind::Reset));
+                }
+            }
+            continue;
+        }
+        if let Err(frame) = dispatch(&streams, frame) {
+            // Data for a stream that does not exist (any more), or that overran its window.
+            let _ = frames_tx.send(Frame::new(frame.stream_id, FrameKind::Reset));
+        }
+    };
+
+    if go_away {
+        // Streams whose request is still running may finish, the client sends nothing more.
+        for state in streams.lock().unwrap().values() {
+            let mut state = state.lock().unwrap();
+            state.read_closed = true;
+            state.wake();
+        }
+        while tasks.join_next().await.is_some() {}
+    } else {
+        for state in streams.lock().unwrap().values() {
+            state.lock().unwrap().close();
+        }
+        tasks.abort_all();
+    }
+    drop(frames_tx);
+    drop(tasks);
+    let writer = match writer_task.await {
+        Ok(Ok(writer)) => writer,
+        Ok(Err(e)) => {
+            error!("write mux frame failed, err: {}", e);
+            return None;
+        }
+        Err(e) => {
+            error!("mux writer task failed, err: {}", e);
+            return None;
+        }
+    };
+    info!("multiplexed session with {} ended", remote_addr);
+    go_away.then(|| reader.unsplit(writer))
+}
+
+fn open_stream(
+    streams: &StreamMap,
+    id: u32,
+    frames: &mpsc::UnboundedSender<Frame>,
+) -> Option<MuxStream> {
+    let mut map = streams.lock().unwrap();
+    if map.contains_key(&id) {
+        warn!("mux stream {} is already open", id);
+        return None;
+    }
+    if map.len() >= MAX_STREAMS {
+        warn!("too many mux streams, reset stream {}", id);
+        return None;
+    }
+    let state = Arc::new(Mutex::new(StreamState::new()));
+    map.insert(id, state.clone());
+    debug!("open mux stream {}", id);
+    Some(MuxStream {
+        id,
+        state,
+        frames: frames.clone(),
+        streams: streams.clone(),
+    })
+}
+
+/// Applies a frame to its stream, returns the frame if the stream must be reset.
+fn dispatch(streams: &StreamMap, frame: Frame) -> Result<(), Frame> {
+    let mut map = streams.lock().unwrap();
+    let Some(state) = map.get(&frame.stream_id).cloned() else {
+        return match frame.kind {
+            // The stream was released in the meantime.
+            FrameKind::Fin | FrameKind::Reset | FrameKind::WindowUpdate => Ok(()),
+            _ => Err(frame),
+        };
+    };
+    let mut state = state.lock().unwrap();
+    match frame.kind {
+        FrameKind::Data => {
+            let len = frame.payload.len() as u32;
+            if state.read_closed || len > state.recv_window {
+                warn!("mux stream {} sent more than allowed", frame.stream_id);
+                state.close();
+                map.remove(&frame.stream_id);
+                return Err(frame);
+            }
+            state.recv_window -= len;
+            if !frame.payload.is_empty() {
+                state.inbound.push_back(frame.payload);
+            }
+        }
+        FrameKind::WindowUpdate => {
+            let Ok(n) = <[u8; 4]>::try_from(frame.payload.as_slice()) else {
+                state.close();
+                map.remove(&frame.stream_id);
+                return Err(frame);
+            };
+            state.send_window = state.send_window.saturating_add(u32::from_le_bytes(n));
+        }
+        FrameKind::Fin => state.read_closed = true,
+        FrameKind::Reset => {
+            state.close();
+            map.remove(&frame.stream_id);
+        }
+        FrameKind::Open | FrameKind::GoAway => {}
+    }
+    state.wake();
+    Ok(())
+}
+
+async fn write_frames<W>(
+    mut writer: W,
+    mut frames: mpsc::UnboundedReceiver<Frame>,
+) -> std::io::Result<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(frame) = frames.recv().await {
+        writer.write_all(&frame.encode()).await?;
+        while let Ok(frame) = frames.try_recv() {
+            writer.write_all(&frame.encode()).await?;
+        }
+        writer.flush().await?;
+    }
+    Ok(writer)
+}
+
+/// Serves the requests of one stream, like `serve_transport` does for a connection.
+async fn serve_stream(
+    mut stream: MuxStream,
+    remote_addr: std::net::SocketAddr,
+    ctx: Arc<ConnContext>,
+) {
+    loop {
+        let Ok(head) = crate::route::common_auth(&mut stream, remote_addr, ctx.policy).await else {
+            break;
+        };
+        debug!("mux stream {} recv head: {:?}", stream.id, head);
+        if matches!(
+            head.action,
+            RouteAction::SubscribeClipboard | RouteAction::Multiplex
+        ) {
+            let msg = format!("{:?} is not available on a multiplexed stream", head.action);
+            let _ =
+                crate::route::transfer::resp_error(&mut stream, RouteErrorCode::Unsupported, &msg)
+                    .await;
+            break;
+        }
+        match crate::route::route_once(&mut stream, head, &ctx).await {
+            RouterLoopOutcome::Continue => {}
+            RouterLoopOutcome::Close | RouterLoopOutcome::TakeOver(_) => break,
+        }
+    }
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_session() -> (StreamMap, MuxStream, mpsc::UnboundedReceiver<Frame>) {
+        let streams: StreamMap = Default::default();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stream = open_stream(&streams, 1, &tx).unwrap();
+        (streams, stream, rx)
+    }
+
+    fn data(stream_id: u32, payload: &[u8]) -> Frame {
+        Frame {
+            stream_id,
+            kind: FrameKind::Data,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let frame = data(7, b"hello");
+        let encoded = frame.encode();
+        assert_eq!(encoded.len(), FRAME_HEADER_LEN + 5);
+        let decoded = Frame::read_from(&mut &encoded[..]).await.unwrap().unwrap();
+        assert_eq!(decoded, frame);
+        assert!(Frame::read_from(&mut &[][..]).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reading_grants_window_back() {
+        let (streams, mut stream, mut rx) = test_session();
+        let chunk = vec![1u8; MAX_FRAME_PAYLOAD];
+        for _ in 0..INITIAL_WINDOW as usize / MAX_FRAME_PAYLOAD {
+            dispatch(&streams, data(1, &chunk)).unwrap();
+        }
+        // The window is used up, more data resets the stream.
+        assert!(dispatch(&streams, data(1, b"x")).is_err());
+
+        let (streams, mut stream2, mut rx2) = test_session();
+        for _ in 0..2 {
+            dispatch(&streams, data(1, &chunk)).unwrap();
+        }
+        let mut buf = vec![0u8; 2 * MAX_FRAME_PAYLOAD];
+        stream2.read_exact(&mut buf).await.unwrap();
+        let update = rx2.try_recv().unwrap();
+        assert_eq!(
+            update,
+            Frame::window_update(1, 2 * MAX_FRAME_PAYLOAD as u32)
+        );
+
+        let mut buf = [0u8; 1];
+        assert!(stream.read_exact(&mut buf).await.is_err());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn writes_wait_for_window_updates() {
+        let (streams, mut stream, mut rx) = test_session();
+        let payload = vec![2u8; INITIAL_WINDOW as usize + 10];
+        let write = tokio::spawn(async move {
+            stream.write_all(&payload).await.unwrap();
+            stream.shutdown().await.unwrap();
+            stream
+        });
+
+        let mut received = 0;
+        while received < INITIAL_WINDOW as usize {
+            let frame = rx.recv().await.unwrap();
+            assert_eq!(frame.kind, FrameKind::Data);
+            received += frame.payload.len();
+        }
+        assert_eq!(received, INITIAL_WINDOW as usize);
+        tokio::task::yield_now().await;
+        assert!(rx.try_recv().is_err());
+
+        dispatch(&streams, Frame::window_update(1, 10)).unwrap();
+        let frame = rx.recv().await.unwrap();
+        assert_eq!(frame.payload.len(), 10);
+        assert_eq!(rx.recv().await.unwrap().kind, FrameKind::Fin);
+
+        let stream = write.await.unwrap();
+        dispatch(&streams, Frame::new(1, FrameKind::Fin)).unwrap();
+        drop(stream);
+        assert!(streams.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn released_streams_free_their_slot() {
+        let streams: StreamMap = Default::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        for id in 1..=2 * MAX_STREAMS as u32 {
+            let mut stream = open_stream(&streams, id, &tx).expect("stream slot");
+            stream.write_all(b"done").await.unwrap();
+            // Released before the peer sent its fin.
+            drop(stream);
+        }
+        assert!(streams.lock().unwrap().is_empty());
+        assert_eq!(rx.recv().await.unwrap().kind, FrameKind::Data);
+        assert_eq!(rx.recv().await.unwrap().kind, FrameKind::Fin);
+        // A late fin is ignored, late data resets the stream.
+        dispatch(&streams, Frame::new(1, FrameKind::Fin)).unwrap();
+        assert!(dispatch(&streams, data(1, b"late")).is_err());
+    }
+}
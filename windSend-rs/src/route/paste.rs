@@ -1,34 +1,45 @@
 use crate::language::LanguageKey;
-use crate::route::protocol::{RouteDataType, RouteRecvHead};
-use crate::route::transfer::{resp_common_error_msg, send_msg, send_msg_with_body};
+use crate::route::RouteConn;
+use crate::route::body::BodyKind;
+use crate::route::protocol::{RouteDataType, RouteErrorCode, RouteRecvHead};
+use crate::route::transfer::{resp_error, send_msg, send_msg_with_body};
 use regex::bytes::Regex;
 use std::borrow::Cow;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio_rustls::server::TlsStream;
 use tracing::{debug, error, info, warn};
 
 static URL_REGEX: std::sync::LazyLock<Regex> =
     std::sync::LazyLock::new(|| Regex::new(r"https?://[^ \r\n]+").unwrap());
 
-pub async fn paste_text_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecvHead) {
-    let mut body_buf = vec![0u8; head.data_len as usize];
-    let r = conn.read_exact(&mut body_buf).await;
-    if let Err(e) = r {
-        error!("read body failed, err: {}", e);
-        return;
-    }
+/// return whether should continue loop(like no socket error)
+pub async fn paste_text_handler(conn: &mut RouteConn, head: RouteRecvHead) -> bool {
+    use crate::route::body::{TextBody, read_text_body, reject_body};
+    let body_buf = match read_text_body(conn, &head).await {
+        Ok(TextBody::Memory(body_buf)) => body_buf,
+        Ok(TextBody::File(path)) => {
+            let path = path.to_string_lossy().to_string();
+            let msg = format!("text too large for the clipboard, saved to {path}");
+            crate::utils::inform(&msg, &head.device_name, Some(&path));
+            return send_msg(conn, &msg).await.is_ok();
+        }
+        Err(e) => return reject_body(conn, &e).await,
+    };
     let body = String::from_utf8_lossy(&body_buf);
     debug!("paste text data: {}", body);
 
     if let Err(e) = crate::config::CLIPBOARD.write_text(Cow::clone(&body)) {
         error!("set clipboard text failed, err: {}", e);
-        resp_common_error_msg(conn, &format!("set clipboard failed, err: {e}"))
-            .await
-            .ok();
-        return;
+        return resp_error(
+            conn,
+            RouteErrorCode::ClipboardError,
+            &format!("set clipboard failed, err: {e}"),
+        )
+        .await
+        .is_ok();
+    }
+    if send_msg(conn, &"Paste success".to_string()).await.is_err() {
+        return false;
     }
-    send_msg(conn, &"Paste success".to_string()).await.ok();
 
     let mut notification_url: Option<&str> = None;
     const URL_SEARCH_LIMIT: usize = 300;
@@ -40,13 +51,11 @@ pub async fn paste_text_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecv
     }
 
     crate::utils::inform(&body, &head.device_name, notification_url);
+    true
 }
 
 /// Returns whether should continue loop (like no socket error)
-pub async fn legacy_sync_text_handler(
-    conn: &mut TlsStream<TcpStream>,
-    head: RouteRecvHead,
-) -> bool {
+pub async fn legacy_sync_text_handler(conn: &mut RouteConn, head: RouteRecvHead) -> bool {
     // For file parts (File/Dir), use paste_file_handler for proper acknowledgment
     if matches!(
         head.upload_type,
@@ -74,15 +83,13 @@ pub async fn legacy_sync_text_handler(
 
     // Handle text sync
     let mut body: Option<_> = None;
-    let mut body_buf = vec![0u8; head.data_len as usize];
-    if head.data_len > 0 {
-        let r = conn.read_exact(&mut body_buf).await;
-        if let Err(e) = r {
-            error!("read body failed, err: {}", e);
-        } else {
-            body = Some(String::from_utf8_lossy(&body_buf));
-            debug!("sync text received: {}", body.as_ref().unwrap());
-        }
+    let body_buf = match crate::route::body::read_body(conn, &head, BodyKind::Text).await {
+        Ok(body_buf) => body_buf,
+        Err(e) => return crate::route::body::reject_body(conn, &e).await,
+    };
+    if !body_buf.is_empty() {
+        body = Some(String::from_utf8_lossy(&body_buf));
+        debug!("sync text received: {}", body.as_ref().unwrap());
     }
 
     // Snapshot the server clipboard *before* it is overwritten below. Sync is a
@@ -98,7 +105,7 @@ pub async fn legacy_sync_text_handler(
         {
             let msg = format!("set clipboard text failed, err: {e}");
             error!("{}", msg);
-            let _ = resp_common_error_msg(conn, &msg).await;
+            let _ = resp_error(conn, RouteErrorCode::ClipboardError, &msg).await;
             return true;
         }
     }
@@ -132,14 +139,11 @@ fn capture_clipboard_snapshot() -> ClipboardSnapshot {
 }
 
 /// Sends a previously captured clipboard snapshot back to the client.
-async fn send_clipboard_snapshot(
-    conn: &mut TlsStream<TcpStream>,
-    snapshot: ClipboardSnapshot,
-) -> bool {
+async fn send_clipboard_snapshot(conn: &mut RouteConn, snapshot: ClipboardSnapshot) -> bool {
     match snapshot {
         ClipboardSnapshot::Image { name, data } => {
             // Save sent image to local file
-            if let Err(e) = save_image_to_file(&data, "sent").await {
+            if let Err(e) = save_image_to_file(&data, "sent", None).await {
                 warn!("save sent clipboard image to file failed, err: {}", e);
             }
             send_msg_with_body(conn, &name, RouteDataType::ClipImage, &data)
@@ -155,27 +159,23 @@ async fn send_clipboard_snapshot(
 }
 
 /// Handles receiving clipboard image from client and sends back current clipboard content
-async fn sync_clipboard_image_handler(
-    conn: &mut TlsStream<TcpStream>,
-    head: &RouteRecvHead,
-) -> bool {
+async fn sync_clipboard_image_handler(conn: &mut RouteConn, head: &RouteRecvHead) -> bool {
     // Snapshot the server clipboard *before* it is overwritten below, so the
     // swap returns what the server held rather than the image just received.
     let snapshot = capture_clipboard_snapshot();
 
-    let mut body_buf = vec![0u8; head.data_len as usize];
-    if head.data_len > 0 {
-        if let Err(e) = conn.read_exact(&mut body_buf).await {
-            error!("read clipboard image body failed, err: {}", e);
-            return false;
-        }
+    let body_buf = match crate::route::body::read_body(conn, head, BodyKind::Image).await {
+        Ok(body_buf) => body_buf,
+        Err(e) => return crate::route::body::reject_body(conn, &e).await,
+    };
+    if !body_buf.is_empty() {
         debug!(
             "sync clipboard image received, size: {} bytes",
             body_buf.len()
         );
 
         // Save received image to local file
-        if let Err(e) = save_image_to_file(&body_buf, "received").await {
+        if let Err(e) = save_image_to_file(&body_buf, "received", Some(&head.device_name)).await {
             warn!("save received clipboard image to file failed, err: {}", e);
         }
 
@@ -183,7 +183,7 @@ async fn sync_clipboard_image_handler(
         if let Err(e) = crate::config::CLIPBOARD.write_image_from_bytes(&body_buf) {
             let msg = format!("set clipboard image failed, err: {e}");
             error!("{}", msg);
-            let _ = resp_common_error_msg(conn, &msg).await;
+            let _ = resp_error(conn, RouteErrorCode::ClipboardError, &msg).await;
             return true;
         }
     }
@@ -192,15 +192,11 @@ async fn sync_clipboard_image_handler(
 }
 
 /// Handles sync file operation info (similar to paste_file_operation_handler but without sending response)
-async fn sync_file_operation_handler(
-    conn: &mut TlsStream<TcpStream>,
-    head: &RouteRecvHead,
-) -> bool {
-    let mut data_buf = vec![0u8; head.data_len as usize];
-    if let Err(e) = conn.read_exact(&mut data_buf).await {
-        error!("read body failed, err: {}", e);
-        return false;
-    }
+async fn sync_file_operation_handler(conn: &mut RouteConn, head: &RouteRecvHead) -> bool {
+    let data_buf = match crate::route::body::read_body(conn, head, BodyKind::Json).await {
+        Ok(data_buf) => data_buf,
+        Err(e) => return crate::route::body::reject_body(conn, &e).await,
+    };
     let op_info: crate::route::protocol::UploadOperationInfo =
         match serde_json::from_slice(&data_buf) {
             Ok(info) => info,
@@ -215,30 +211,42 @@ async fn sync_file_operation_handler(
     );
     debug!("sync file operation info: {:?}", op_info);
 
-    if let Err(e) = crate::file::GLOBAL_RECEIVER_SESSION_MANAGER
+    match crate::file::GLOBAL_RECEIVER_SESSION_MANAGER
         .create_op_info(head, &op_info)
         .await
     {
-        error!("create op info failed, err: {}", e);
-        let _ = resp_common_error_msg(conn, &e).await;
-        return false;
+        Ok(skipped_paths) if !skipped_paths.is_empty() => {
+            info!("sync file operation skips {} files", skipped_paths.len());
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("create op info failed, err: {}", e);
+            let _ = resp_error(conn, RouteErrorCode::InvalidRequest, &e).await;
+            return false;
+        }
     };
 
-    let save_path = crate::config::GLOBAL_CONFIG
-        .read()
-        .unwrap()
-        .save_path
-        .clone();
-    let download_dir = std::path::PathBuf::from(&save_path);
+    let mut save_path = crate::save_rules::default_save_dir()
+        .to_string_lossy()
+        .to_string();
 
-    use crate::utils::NormalizePath;
     if let Some(empty_dir) = &op_info.empty_dirs {
         for dir in empty_dir {
-            let dir = dir.normalize_path();
-            let r = tokio::fs::create_dir_all(download_dir.join(dir)).await;
+            let download_dir = empty_dir_save_dir(head, &op_info, dir);
+            save_path = download_dir.to_string_lossy().to_string();
+            let dir = match crate::utils::join_sanitized(&download_dir, dir) {
+                Ok(dir) => dir,
+                Err(err) => {
+                    error!("{}", err);
+                    let _ = resp_error(conn, RouteErrorCode::UnsafePath, &err.to_string()).await;
+                    return false;
+                }
+            };
+            let r = tokio::fs::create_dir_all(dir).await;
             if let Err(err) = r {
                 error!("create dir error: {}", err);
-                let _ = resp_common_error_msg(conn, &err.to_string()).await;
+                let _ =
+                    resp_error(conn, RouteErrorCode::from_io_error(&err), &err.to_string()).await;
                 return false;
             }
         }
@@ -254,20 +262,49 @@ async fn sync_file_operation_handler(
     true
 }
 
-/// Saves image data to a file in the configured save path
-async fn save_image_to_file(image_data: &[u8], prefix: &str) -> Result<String, String> {
-    let save_path = crate::config::GLOBAL_CONFIG
-        .read()
-        .unwrap()
-        .save_path
-        .clone();
+/// Directory for an empty directory of an upload operation, chosen by the
+/// save path rules for the top-level item it belongs to.
+fn empty_dir_save_dir(
+    head: &RouteRecvHead,
+    op_info: &crate::route::protocol::UploadOperationInfo,
+    dir: &str,
+) -> std::path::PathBuf {
+    use crate::save_rules::{
+        ReceiveItemKind, SaveTarget, default_save_dir, resolve_save_dir, top_level_item,
+    };
+    if op_info.ignore_save_path_rules {
+        return default_save_dir();
+    }
+    resolve_save_dir(&SaveTarget {
+        device_name: &head.device_name,
+        file_name: Some(top_level_item(dir)),
+        kind: ReceiveItemKind::File,
+    })
+}
+
+/// Saves image data to a file. Images received from `device_name` go to the
+/// directory chosen by the save path rules, sent ones to `save_path`.
+async fn save_image_to_file(
+    image_data: &[u8],
+    prefix: &str,
+    device_name: Option<&str>,
+) -> Result<String, String> {
+    use crate::save_rules::{ReceiveItemKind, SaveTarget, default_save_dir, resolve_save_dir};
 
     let file_name = format!(
         "{}_{}.png",
         prefix,
         chrono::Local::now().format("%Y%m%d%H%M%S%3f")
     );
-    let file_path = std::path::PathBuf::from(&save_path).join(&file_name);
+    let save_path = match device_name {
+        Some(device_name) => resolve_save_dir(&SaveTarget {
+            device_name,
+            file_name: Some(&file_name),
+            kind: ReceiveItemKind::ClipImage,
+        }),
+        None => default_save_dir(),
+    };
+    let file_path = save_path.join(&file_name);
 
     if let Err(e) = tokio::fs::create_dir_all(&save_path).await {
         return Err(format!("create save directory failed: {e}"));
@@ -315,16 +352,34 @@ fn try_get_clipboard_image_png() -> Option<Vec<u8>> {
 }
 
 /// return whether should continue loop(like no socket error)
-pub async fn paste_file_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecvHead) -> bool {
-    if let crate::route::protocol::UploadType::UploadInfo = head.upload_type {
-        return paste_file_operation_handler(conn, head).await;
+pub async fn paste_file_handler(conn: &mut RouteConn, head: RouteRecvHead) -> bool {
+    match head.upload_type {
+        crate::route::protocol::UploadType::UploadInfo => {
+            return paste_file_operation_handler(conn, head).await;
+        }
+        crate::route::protocol::UploadType::Archive => {
+            return paste_archive_handler(conn, head).await;
+        }
+        _ => {}
     }
 
+    if head.data_len < 0 || head.start < 0 {
+        let err_msg = &format!(
+            "invalid file part, dataLen:{}, start:{}",
+            head.data_len, head.start
+        );
+        error!("{}", err_msg);
+        return resp_error(conn, RouteErrorCode::InvalidRequest, err_msg)
+            .await
+            .is_ok();
+    }
     // head.End == 0 && head.Start == 0 means the file is empty
     if head.end <= head.start && !(head.end == 0 && head.start == 0) {
         let err_msg = &format!("invalid file part, start:{}, end:{}", head.start, head.end);
         error!("{}", err_msg);
-        return resp_common_error_msg(conn, err_msg).await.is_ok();
+        return resp_error(conn, RouteErrorCode::InvalidRequest, err_msg)
+            .await
+            .is_ok();
     }
     let data_len = head.end - head.start;
     if head.data_len != data_len {
@@ -333,28 +388,42 @@ pub async fn paste_file_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecv
             head.data_len, head.start, head.end
         );
         error!("{}", err_msg);
-        return resp_common_error_msg(conn, err_msg).await.is_ok();
+        return resp_error(conn, RouteErrorCode::InvalidRequest, err_msg)
+            .await
+            .is_ok();
     }
 
     // let file = (*crate::file::GLOBAL_FILE_RECEIVER).clone().borrow_mut();
     let file = crate::file::GLOBAL_RECEIVER_SESSION_MANAGER
         .setup_file_reception(&head)
         .await;
-    if let Err(err) = file {
-        error!("create file: {} error: {}", head.path, err);
-        let _ = tokio::io::copy(&mut conn.take(head.data_len as u64), &mut tokio::io::sink()).await;
-        return resp_common_error_msg(conn, &format!("create file error: {err}"))
+    let file = match file {
+        Ok(crate::file::FileReception::Write(file)) => file,
+        Ok(crate::file::FileReception::Skip) => return skip_file_part(conn, &head).await,
+        Err(err) => {
+            error!("create file: {} error: {}", head.path, err);
+            let _ =
+                tokio::io::copy(&mut conn.take(head.data_len as u64), &mut tokio::io::sink()).await;
+            return resp_error(
+                conn,
+                RouteErrorCode::from_io_error(&err),
+                &format!("create file error: {err}"),
+            )
             .await
             .is_ok();
-    }
-    let file = file.unwrap();
+        }
+    };
     let file_writer =
         crate::file::FilePartWriter::new(file, head.start as usize, head.end as usize).await;
     if let Err(err) = file_writer {
         error!("new file writer failed, err: {}", err);
-        return resp_common_error_msg(conn, &format!("new file writer failed, err: {err}"))
-            .await
-            .is_ok();
+        return resp_error(
+            conn,
+            RouteErrorCode::from_io_error(&err),
+            &format!("new file writer failed, err: {err}"),
+        )
+        .await
+        .is_ok();
     }
     let mut file_writer = file_writer.unwrap();
 
@@ -407,7 +476,9 @@ pub async fn paste_file_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecv
     if let Err(err) = n {
         let msg = format!("write file error: {err}");
         error!("{}", msg);
-        let resp_success = resp_common_error_msg(&mut conn_writer, &msg).await.is_ok();
+        let resp_success = resp_error(&mut conn_writer, RouteErrorCode::from_io_error(&err), &msg)
+            .await
+            .is_ok();
         crate::file::GLOBAL_RECEIVER_SESSION_MANAGER
             .report_file_part_completion(head.file_id, head.start, head.end, Some(msg))
             .await;
@@ -416,8 +487,9 @@ pub async fn paste_file_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecv
     let n = n.unwrap();
     if let Err(err) = file_buf_writer.flush().await {
         error!("flush file writer failed, err: {}", err);
-        return resp_common_error_msg(
+        return resp_error(
             &mut conn_writer,
+            RouteErrorCode::from_io_error(&err),
             &format!("flush file writer failed, err: {err}"),
         )
         .await
@@ -426,7 +498,9 @@ pub async fn paste_file_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecv
     if n < data_len as u64 {
         let msg = format!("write file error, n: {n}, dataLen: {data_len}");
         error!("{}", msg);
-        let resp_success = resp_common_error_msg(&mut conn_writer, &msg).await.is_ok();
+        let resp_success = resp_error(&mut conn_writer, RouteErrorCode::IoError, &msg)
+            .await
+            .is_ok();
         crate::file::GLOBAL_RECEIVER_SESSION_MANAGER
             .report_file_part_completion(head.file_id, head.start, head.end, Some(msg))
             .await;
@@ -450,6 +524,9 @@ pub async fn paste_file_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecv
         "write file part success, fileID: {}, start: {}, end: {}",
         head.file_id, head.start, head.end
     );
+    // Close the file first, a replaced file is renamed once all parts are in.
+    drop(file_buf_writer);
+    drop(file_writer);
     let (done, err_occurred) = crate::file::GLOBAL_RECEIVER_SESSION_MANAGER
         .report_file_part_completion(head.file_id, head.start, head.end, None)
         .await;
@@ -462,15 +539,150 @@ pub async fn paste_file_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecv
     resp_success
 }
 
-async fn paste_file_operation_handler(
-    conn: &mut TlsStream<TcpStream>,
-    head: RouteRecvHead,
-) -> bool {
-    let mut data_buf = vec![0u8; head.data_len as usize];
-    if let Err(e) = conn.read_exact(&mut data_buf).await {
-        error!("read body failed, err: {}", e);
+/// Extracts an uploaded archive while it is being received.
+///
+/// `head.path` is the directory relative to the save directory that the archive
+/// is extracted into. The body is either `head.data_len` bytes or, with
+/// `CHUNKED_DATA_LEN`, a sequence of chunks.
+async fn paste_archive_handler(conn: &mut RouteConn, head: RouteRecvHead) -> bool {
+    use crate::route::protocol::{ArchiveFormat, CHUNKED_DATA_LEN};
+    const MAX_CHUNK_LEN: usize = 16 * 1024 * 1024;
+
+    let format = head.archive.unwrap_or(ArchiveFormat::Tar);
+    let save_dir = crate::save_rules::resolve_save_dir(&crate::save_rules::SaveTarget {
+        device_name: &head.device_name,
+        file_name: None,
+        kind: crate::save_rules::ReceiveItemKind::File,
+    });
+    let dest = crate::utils::join_sanitized(&save_dir, &head.path);
+    if head.data_len < 0 && head.data_len != CHUNKED_DATA_LEN {
+        error!("invalid archive data len: {}", head.data_len);
+        return false;
+    }
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Vec<u8>>(4);
+    let extractor = match &dest {
+        Ok(dest) => {
+            let dest = dest.clone();
+            let policy = crate::config::read_config().file_conflict_policy;
+            let compress = head.compress;
+            Some(tokio::task::spawn_blocking(move || {
+                let reader = crate::archive::ChannelReader::new(rx);
+                crate::archive::extract_archive(format, compress, reader, &dest, policy)
+            }))
+        }
+        Err(_) => None,
+    };
+    // The body is read to the end even if the extraction fails, so that the
+    // connection can be used for further requests.
+    let mut tx = extractor.as_ref().map(|_| tx);
+    let read_result: std::io::Result<()> = async {
+        if head.data_len == CHUNKED_DATA_LEN {
+            while let Some(chunk) = crate::route::transfer::read_chunk(conn, MAX_CHUNK_LEN).await? {
+                if let Some(sender) = &tx
+                    && sender.send(chunk).await.is_err()
+                {
+                    tx = None;
+                }
+            }
+        } else {
+            let mut remaining = head.data_len as usize;
+            while remaining > 0 {
+                let mut chunk = vec![0u8; remaining.min(crate::archive::CHUNK_SIZE)];
+                conn.read_exact(&mut chunk).await?;
+                remaining -= chunk.len();
+                if let Some(sender) = &tx
+                    && sender.send(chunk).await.is_err()
+                {
+                    tx = None;
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+    drop(tx);
+    let extracted = match extractor {
+        Some(task) => task.await.map_err(std::io::Error::other).and_then(|r| r),
+        None => Err(std::io::Error::other("no destination")),
+    };
+    if let Err(err) = read_result {
+        error!("read archive body failed, err: {}", err);
+        return false;
+    }
+
+    let dest = match dest {
+        Ok(dest) => dest,
+        Err(err) => {
+            error!("{}", err);
+            return resp_error(conn, RouteErrorCode::UnsafePath, &err.to_string())
+                .await
+                .is_ok();
+        }
+    };
+    match extracted {
+        Ok(count) => {
+            info!(
+                "{} files extracted from archive to {}",
+                count,
+                dest.display()
+            );
+            let dest_str = dest.to_string_lossy();
+            crate::utils::inform(
+                format!(
+                    "{} {} {}",
+                    count,
+                    LanguageKey::NFilesSavedTo.translate(),
+                    dest_str
+                ),
+                &head.device_name,
+                Some(&dest_str),
+            );
+            send_msg(conn, &format!("extracted {count} files"))
+                .await
+                .is_ok()
+        }
+        Err(err) => {
+            let msg = format!("extract archive to {} failed, err: {}", dest.display(), err);
+            error!("{}", msg);
+            resp_error(conn, RouteErrorCode::from_io_error(&err), &msg)
+                .await
+                .is_ok()
+        }
+    }
+}
+
+/// Discards a file part whose file is skipped by the conflict policy.
+async fn skip_file_part(conn: &mut RouteConn, head: &RouteRecvHead) -> bool {
+    if head.data_len < 0 {
+        error!("invalid file part, dataLen:{}", head.data_len);
         return false;
     }
+    let n = tokio::io::copy(&mut conn.take(head.data_len as u64), &mut tokio::io::sink()).await;
+    if let Err(err) = n {
+        error!("discard skipped file part failed, err: {}", err);
+        return false;
+    }
+    crate::file::GLOBAL_RECEIVER_SESSION_MANAGER
+        .report_file_part_completion(head.file_id, head.start, head.end, None)
+        .await;
+    debug!(
+        "skip file part, fileID: {}, start: {}, end: {}",
+        head.file_id, head.start, head.end
+    );
+    crate::route::transfer::send_skipped_msg(
+        conn,
+        &format!("file skipped, fileID:{}, path:{}", head.file_id, head.path),
+    )
+    .await
+    .is_ok()
+}
+
+async fn paste_file_operation_handler(conn: &mut RouteConn, head: RouteRecvHead) -> bool {
+    let data_buf = match crate::route::body::read_body(conn, &head, BodyKind::Json).await {
+        Ok(data_buf) => data_buf,
+        Err(e) => return crate::route::body::reject_body(conn, &e).await,
+    };
     let op_info: crate::route::protocol::UploadOperationInfo =
         match serde_json::from_slice(&data_buf) {
             Ok(info) => info,
@@ -485,33 +697,62 @@ async fn paste_file_operation_handler(
     );
     debug!("paste file operation info: {:?}", op_info);
 
-    if let Err(e) = crate::file::GLOBAL_RECEIVER_SESSION_MANAGER
+    let skipped_paths = match crate::file::GLOBAL_RECEIVER_SESSION_MANAGER
         .create_op_info(&head, &op_info)
         .await
     {
-        error!("create op info failed, err: {}", e);
-        return resp_common_error_msg(conn, &e).await.is_ok();
+        Ok(skipped_paths) => skipped_paths,
+        Err(e) => {
+            error!("create op info failed, err: {}", e);
+            return resp_error(conn, RouteErrorCode::InvalidRequest, &e)
+                .await
+                .is_ok();
+        }
     };
 
-    let save_path = crate::config::GLOBAL_CONFIG
-        .read()
-        .unwrap()
-        .save_path
-        .clone(); // clone to avoid lock
-    let download_dir = std::path::PathBuf::from(&save_path);
+    let mut save_path = crate::save_rules::default_save_dir()
+        .to_string_lossy()
+        .to_string();
 
-    use crate::utils::NormalizePath;
     if let Some(empty_dir) = &op_info.empty_dirs {
         for dir in empty_dir {
-            let dir = dir.normalize_path();
-            let r = tokio::fs::create_dir_all(download_dir.join(dir)).await;
+            let download_dir = empty_dir_save_dir(&head, &op_info, dir);
+            save_path = download_dir.to_string_lossy().to_string();
+            let dir = match crate::utils::join_sanitized(&download_dir, dir) {
+                Ok(dir) => dir,
+                Err(err) => {
+                    error!("{}", err);
+                    return resp_error(conn, RouteErrorCode::UnsafePath, &err.to_string())
+                        .await
+                        .is_ok();
+                }
+            };
+            let r = tokio::fs::create_dir_all(dir).await;
             if let Err(err) = r {
                 error!("create dir error: {}", err);
-                return resp_common_error_msg(conn, &err.to_string()).await.is_ok();
+                return resp_error(conn, RouteErrorCode::from_io_error(&err), &err.to_string())
+                    .await
+                    .is_ok();
             }
         }
     }
-    if (send_msg(conn, &"create dirs success".to_string()).await).is_err() {
+    let msg = "create dirs success".to_string();
+    let r = if op_info.files.is_some() {
+        // Only clients that listed their files expect the skipped paths in the body.
+        let resp = crate::route::protocol::UploadOperationResp { skipped_paths };
+        match serde_json::to_vec(&resp) {
+            Ok(body) => send_msg_with_body(conn, &msg, RouteDataType::Text, &body).await,
+            Err(e) => {
+                error!("json marshal failed, err: {}", e);
+                return resp_error(conn, RouteErrorCode::Internal, &e.to_string())
+                    .await
+                    .is_ok();
+            }
+        }
+    } else {
+        send_msg(conn, &msg).await
+    };
+    if r.is_err() {
         return false;
     };
     if op_info.empty_dirs.is_some() && op_info.files_count_in_this_op == 0 {
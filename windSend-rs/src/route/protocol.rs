@@ -1,3 +1,4 @@
+use crate::route::head_codec::HeadCodec;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -23,6 +24,15 @@ pub enum RouteAction {
     SetRelayServer,
     #[serde(rename = "endConnection")]
     EndConnection,
+    #[serde(rename = "folderManifest")]
+    FolderManifest,
+    #[serde(rename = "deleteFiles")]
+    DeleteFiles,
+    #[serde(rename = "hello")]
+    Hello,
+    /// Switches the connection to multiplexed streams, see `route::mux`
+    #[serde(rename = "multiplex")]
+    Multiplex,
     #[serde(untagged)]
     Unknown(String),
 }
@@ -67,6 +77,101 @@ pub struct RouteRecvHead {
     /// The content type for sync operations (text, clip-image)
     #[serde(rename = "syncDataType", default)]
     pub sync_data_type: RouteDataType,
+    /// SHA-256 (hex) of the whole file, used by the `skipIfIdentical` conflict policy
+    #[serde(rename = "fileHash", default)]
+    pub file_hash: Option<String>,
+    /// Modification time of the source file in Unix milliseconds, used by the `keepNewer` conflict policy
+    #[serde(rename = "modTime", default)]
+    pub mod_time: Option<i64>,
+    /// `download`: stream `path` as an archive of this format.
+    /// `pasteFile` with `uploadType` archive: the format of the uploaded archive.
+    #[serde(rename = "archive", default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<ArchiveFormat>,
+    /// Compress the archive (tar.gz, or deflate entries for zip)
+    #[serde(rename = "compress", default)]
+    pub compress: bool,
+}
+
+/// The actions this server handles, as sent in the `hello` response
+pub const SUPPORTED_ACTIONS: &[&str] = &[
+    "ping",
+    "pasteText",
+    "pasteFile",
+    "copy",
+    "download",
+    "match",
+    "syncText",
+    "subscribeClipboard",
+    "setRelayServer",
+    "endConnection",
+    "folderManifest",
+    "deleteFiles",
+    "hello",
+    "multiplex",
+];
+
+/// Request body of `hello`, what the client advertises about itself
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HelloReq {
+    /// Version of the client application
+    #[serde(default)]
+    pub version: String,
+    /// Optional features the client supports
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Head codecs the client can use, most preferred first, see `route::head_codec`
+    #[serde(rename = "headCodecs", default)]
+    pub head_codecs: Vec<String>,
+}
+
+/// Response body of `hello`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerCapabilities {
+    pub version: String,
+    pub actions: Vec<String>,
+    /// Supported versions of the clipboard subscription frames
+    #[serde(rename = "syncFrameVersions")]
+    pub sync_frame_versions: Vec<u32>,
+    #[serde(rename = "maxBodySizes")]
+    pub max_body_sizes: crate::config::BodyLimits,
+    pub features: ServerFeatures,
+    #[serde(rename = "headCodecs")]
+    pub head_codecs: Vec<HeadCodec>,
+    /// Codec of the heads that follow this response on the connection
+    #[serde(rename = "headCodec")]
+    pub head_codec: HeadCodec,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerFeatures {
+    /// `fileHash` in heads and `withHash` in folder manifests
+    #[serde(rename = "fileHash")]
+    pub file_hash: bool,
+    #[serde(rename = "conflictPolicies")]
+    pub conflict_policies: Vec<ConflictPolicy>,
+    /// Formats of `download` with `archive`
+    #[serde(rename = "archiveDownload")]
+    pub archive_download: Vec<ArchiveFormat>,
+    /// Formats of `pasteFile` with `uploadType` archive
+    #[serde(rename = "archiveUpload")]
+    pub archive_upload: Vec<ArchiveFormat>,
+    /// `compress` of archives
+    pub compression: bool,
+    /// Bodies with `dataLen` set to `CHUNKED_DATA_LEN`
+    #[serde(rename = "chunkedBody")]
+    pub chunked_body: bool,
+}
+
+/// `dataLen` of a body of unknown length, sent as chunks of a 4-byte
+/// little-endian length followed by the data, terminated by an empty chunk
+pub const CHUNKED_DATA_LEN: i64 = -1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "zip")]
+    Zip,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -88,6 +193,67 @@ pub struct UploadOperationInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "emptyDirs")]
     pub empty_dirs: Option<Vec<String>>,
+
+    /// What to do when a received file already exists, overriding the configured default
+    #[serde(
+        rename = "conflictPolicy",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub conflict_policy: Option<ConflictPolicy>,
+
+    /// Every file of this operation (recursive). When present, the server replies with
+    /// the files it will skip so that the client does not need to send them.
+    #[serde(rename = "files", default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<UploadFileInfo>>,
+
+    /// Save relative to `savePath` even if a save path rule matches,
+    /// so that mirrored folders keep their layout
+    #[serde(
+        rename = "ignoreSavePathRules",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub ignore_save_path_rules: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadFileInfo {
+    /// The relative save path, same as `RouteRecvHead::path` of the file parts
+    pub path: String,
+    pub size: i64,
+    /// SHA-256 (hex) of the file
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// Modification time in Unix milliseconds
+    #[serde(rename = "modTime", default)]
+    pub mod_time: Option<i64>,
+}
+
+/// Response body of an `uploadInfo` request that listed its `files`
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct UploadOperationResp {
+    /// Relative save paths that will not be written because of the conflict policy
+    #[serde(rename = "skippedPaths")]
+    pub skipped_paths: Vec<String>,
+}
+
+/// How to handle a received file whose save path already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConflictPolicy {
+    /// Save as `name(1).ext`, `name(2).ext`, ...
+    #[default]
+    #[serde(rename = "rename")]
+    Rename,
+    /// Replace the existing file
+    #[serde(rename = "overwrite")]
+    Overwrite,
+    /// Skip when the existing file has the same size and SHA-256, otherwise rename
+    #[serde(rename = "skipIfIdentical")]
+    SkipIfIdentical,
+    /// Overwrite when the incoming file is newer, otherwise skip
+    #[serde(rename = "keepNewer")]
+    KeepNewer,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -101,7 +267,7 @@ pub struct PathInfo {
 #[derive(Debug, Serialize)]
 pub struct RouteRespHead<'a> {
     pub code: i32,
-    pub msg: &'a String,
+    pub msg: &'a str,
     /// only for dataTypeFiles
     #[serde(
         rename = "totalFileSize",
@@ -115,9 +281,121 @@ pub struct RouteRespHead<'a> {
     /// If the body has data, return the length of the data
     #[serde(rename = "dataLen")]
     pub data_len: i64,
+    /// Set when a file part was discarded because of the conflict policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<bool>,
+    /// Why the request failed, `msg` is only meant for humans
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<RouteErrorCode>,
     // pub paths: Vec<RoutePathInfo>,
 }
 
+/// Machine-readable reason of a failed request, independent of the language of `msg`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RouteErrorCode {
+    /// Authentication failed
+    Unauthorized,
+    /// `match` while the server does not allow to be searched
+    SearchNotAllowed,
+    UnknownAction,
+    /// Malformed head or body
+    InvalidRequest,
+    PayloadTooLarge,
+    /// A path that would leave the save directory
+    UnsafePath,
+    NotFound,
+    PermissionDenied,
+    DiskFull,
+    /// Any other file system error
+    IoError,
+    ClipboardEmpty,
+    ClipboardError,
+    /// The upload operation is unknown or has been terminated
+    OperationNotFound,
+    Unsupported,
+    Internal,
+    /// A code added in a later version
+    #[serde(other)]
+    Unknown,
+}
+
+impl RouteErrorCode {
+    /// The `code` sent along with this error
+    pub fn status_code(self) -> i32 {
+        match self {
+            RouteErrorCode::Unauthorized | RouteErrorCode::SearchNotAllowed => 401,
+            RouteErrorCode::PayloadTooLarge => 413,
+            _ => 400,
+        }
+    }
+
+    pub fn from_io_error(err: &std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => RouteErrorCode::NotFound,
+            std::io::ErrorKind::PermissionDenied => RouteErrorCode::PermissionDenied,
+            std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+                RouteErrorCode::DiskFull
+            }
+            std::io::ErrorKind::InvalidInput => RouteErrorCode::InvalidRequest,
+            std::io::ErrorKind::Unsupported => RouteErrorCode::Unsupported,
+            _ => RouteErrorCode::IoError,
+        }
+    }
+}
+
+/// Owned counterpart of `RouteRespHead`, used when this program acts as a client
+#[derive(Debug, Deserialize)]
+pub struct RouteRespHeadOwned {
+    pub code: i32,
+    #[serde(default)]
+    pub msg: String,
+    #[serde(rename = "dataLen", default)]
+    pub data_len: i64,
+    #[serde(default)]
+    pub skipped: Option<bool>,
+    #[serde(rename = "errorCode", default)]
+    pub error_code: Option<RouteErrorCode>,
+}
+
+/// Request body of `folderManifest`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FolderManifestReq {
+    /// Directory relative to `savePath`
+    pub path: String,
+    /// Compute the SHA-256 of every file, which is slow for large folders
+    #[serde(rename = "withHash", default)]
+    pub with_hash: bool,
+}
+
+/// Response body of `folderManifest`
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct FolderManifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Path relative to the manifest directory, separated by `/`
+    pub path: String,
+    pub size: i64,
+    /// Modification time in Unix milliseconds
+    #[serde(rename = "modTime")]
+    pub mod_time: i64,
+    /// SHA-256 (hex), only set when requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// Request body of `deleteFiles`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteFilesReq {
+    /// Directory relative to `savePath`
+    pub path: String,
+    /// Files relative to `path`
+    pub files: Vec<String>,
+}
+
 #[derive(Debug, Serialize, Default)]
 pub struct RouteTransferInfo {
     #[serde(rename = "path")]
@@ -147,6 +425,9 @@ pub enum UploadType {
     File,
     #[serde(rename = "uploadInfo")]
     UploadInfo,
+    /// An archive that is extracted on the fly into `path`
+    #[serde(rename = "archive")]
+    Archive,
     #[serde(untagged)]
     Unknown(String),
 }
@@ -171,6 +452,10 @@ pub struct SetRelayServerReq {
     pub relay_secret_key: Option<String>,
     #[serde(rename = "enableRelay", default)]
     pub enable_relay: bool,
+    /// Rotates the secret key: the previous one keeps being tried for this
+    /// long, until every relay server knows the new one. Capped at 30 days.
+    #[serde(rename = "secretGraceSecs", default)]
+    pub secret_grace_secs: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -181,6 +466,13 @@ pub struct MatchActionRespBody {
     pub secret_key_hex: String,
     #[serde(rename = "caCertificate")]
     pub ca_certificate: String,
+    /// `ip:port` addresses mapped on the gateway, see `port_mapping`
+    #[serde(
+        rename = "externalAddresses",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub external_addresses: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -198,3 +490,42 @@ pub enum RouteDataType {
     #[serde(other)]
     Unknown,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_code_is_sent_next_to_the_status_code() {
+        let msg = "body too large".to_string();
+        let head = RouteRespHead {
+            code: RouteErrorCode::PayloadTooLarge.status_code(),
+            msg: &msg,
+            total_file_size: None,
+            data_type: RouteDataType::Text,
+            data_len: 0,
+            skipped: None,
+            error_code: Some(RouteErrorCode::PayloadTooLarge),
+        };
+        let json = serde_json::to_string(&head).unwrap();
+        assert!(json.contains(r#""code":413"#));
+        assert!(json.contains(r#""errorCode":"payloadTooLarge""#));
+
+        let owned: RouteRespHeadOwned = serde_json::from_str(&json).unwrap();
+        assert_eq!(owned.error_code, Some(RouteErrorCode::PayloadTooLarge));
+        let owned: RouteRespHeadOwned =
+            serde_json::from_str(r#"{"code":400,"errorCode":"somethingNew"}"#).unwrap();
+        assert_eq!(owned.error_code, Some(RouteErrorCode::Unknown));
+    }
+
+    #[test]
+    fn supported_actions_are_known() {
+        for action in SUPPORTED_ACTIONS {
+            let parsed: RouteAction = serde_json::from_value(serde_json::json!(action)).unwrap();
+            assert!(
+                !matches!(parsed, RouteAction::Unknown(_)),
+                "{action} is not a RouteAction"
+            );
+        }
+    }
+}
@@ -1,7 +1,8 @@
-use crate::route::transfer::resp_error_msg;
+use crate::listen::EndpointPolicy;
+use crate::route::head_codec::{self, HeadCodec};
+use crate::route::transfer::resp_error;
+use std::sync::{Arc, RwLock};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio_rustls::server::TlsStream;
 use tracing::info;
 use tracing::{debug, error, trace, warn};
 
@@ -15,19 +16,79 @@ pub enum RouterLoopOutcome {
 
 pub enum SessionTakeOver {
     ClipboardSubscription,
+    Multiplex,
 }
 
-pub async fn main_process(mut conn: TlsStream<TcpStream>) -> Option<TlsStream<TcpStream>> {
+/// A byte stream that requests are served on, a TLS connection or a multiplexed stream
+pub trait RouteIo: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send {}
+
+impl<T> RouteIo for T where T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send {}
+
+pub type RouteConn = dyn RouteIo;
+
+/// State of a route connection that outlives a single request, shared by its multiplexed streams
+#[derive(Debug, Default)]
+pub struct ConnContext {
+    /// What the client advertised with `hello`, `None` for clients that never sent it
+    client: RwLock<Option<HelloReq>>,
+    /// Policy of the endpoint the connection was accepted on
+    pub policy: EndpointPolicy,
+}
+
+impl ConnContext {
+    pub fn client_version(&self) -> Option<String> {
+        let client = self.client.read().unwrap();
+        client.as_ref().map(|client| client.version.clone())
+    }
+
+    /// Whether the client advertised `feature`. Clients that never sent `hello`
+    /// predate the feature list and are assumed to support what they ask for.
+    pub fn client_supports(&self, feature: &str) -> bool {
+        let client = self.client.read().unwrap();
+        client
+            .as_ref()
+            .is_none_or(|client| client.features.iter().any(|f| f == feature))
+    }
+}
+
+/// Serves requests on any reliable byte stream, like a TLS connection or a QUIC stream.
+pub async fn serve_transport<S>(
+    conn: S,
+    remote_addr: std::net::SocketAddr,
+    policy: EndpointPolicy,
+) -> Option<S>
+where
+    S: RouteIo + 'static,
+{
+    let ctx = Arc::new(ConnContext {
+        client: RwLock::new(None),
+        policy,
+    });
+    head_codec::scope(
+        HeadCodec::default(),
+        serve_connection(conn, remote_addr, ctx),
+    )
+    .await
+}
+
+async fn serve_connection<S>(
+    mut conn: S,
+    remote_addr: std::net::SocketAddr,
+    ctx: Arc<ConnContext>,
+) -> Option<S>
+where
+    S: RouteIo + 'static,
+{
     loop {
-        let head = common_auth(&mut conn).await;
+        let head = common_auth(&mut conn, remote_addr, ctx.policy).await;
         if head.is_err() {
-            conn.get_mut().0.shutdown().await.ok();
+            conn.shutdown().await.ok();
             return None;
         }
         let head = head.unwrap();
         info!("recv head: {:?}", head);
 
-        match route_once(&mut conn, head).await {
+        match route_once(&mut conn, head, &ctx).await {
             RouterLoopOutcome::Continue => {}
             RouterLoopOutcome::Close => return Some(conn),
             RouterLoopOutcome::TakeOver(take_over) => {
@@ -35,7 +96,18 @@ pub async fn main_process(mut conn: TlsStream<TcpStream>) -> Option<TlsStream<Tc
                     error!("flush failed before take over, err: {}", e);
                     return None;
                 }
-                return crate::route::sync_session::handle_take_over(conn, take_over).await;
+                return match take_over {
+                    SessionTakeOver::ClipboardSubscription => {
+                        crate::route::sync_session::handle_clipboard_subscription(
+                            conn,
+                            head_codec::current(),
+                        )
+                        .await
+                    }
+                    SessionTakeOver::Multiplex => {
+                        crate::route::mux::serve(conn, remote_addr, ctx.clone()).await
+                    }
+                };
             }
         }
 
@@ -46,15 +118,18 @@ pub async fn main_process(mut conn: TlsStream<TcpStream>) -> Option<TlsStream<Tc
     }
 }
 
-async fn route_once(conn: &mut TlsStream<TcpStream>, head: RouteRecvHead) -> RouterLoopOutcome {
+pub(crate) async fn route_once(
+    conn: &mut RouteConn,
+    head: RouteRecvHead,
+    ctx: &ConnContext,
+) -> RouterLoopOutcome {
     match head.action {
         RouteAction::Ping => {
             let _ = crate::route::transfer::ping_handler(conn, head).await;
             RouterLoopOutcome::Continue
         }
         RouteAction::PasteText => {
-            crate::route::paste::paste_text_handler(conn, head).await;
-            RouterLoopOutcome::Continue
+            continue_or_close(crate::route::paste::paste_text_handler(conn, head).await)
         }
         RouteAction::PasteFile => {
             continue_or_close(crate::route::paste::paste_file_handler(conn, head).await)
@@ -64,7 +139,7 @@ async fn route_once(conn: &mut TlsStream<TcpStream>, head: RouteRecvHead) -> Rou
             RouterLoopOutcome::Continue
         }
         RouteAction::Download => {
-            continue_or_close(crate::route::copy::download_handler(conn, head).await)
+            continue_or_close(crate::route::copy::download_handler(conn, head, ctx).await)
         }
         RouteAction::Match => {
             let _ = match_handler(conn).await;
@@ -84,8 +159,20 @@ async fn route_once(conn: &mut TlsStream<TcpStream>, head: RouteRecvHead) -> Rou
             }
         }
         RouteAction::SetRelayServer => {
-            let _ = set_relay_server_handler(conn, head).await;
-            RouterLoopOutcome::Continue
+            continue_or_close(set_relay_server_handler(conn, head).await)
+        }
+        RouteAction::FolderManifest => {
+            continue_or_close(crate::route::folder::folder_manifest_handler(conn, head).await)
+        }
+        RouteAction::DeleteFiles => {
+            continue_or_close(crate::route::folder::delete_files_handler(conn, head).await)
+        }
+        RouteAction::Hello => continue_or_close(hello_handler(conn, head, ctx).await),
+        RouteAction::Multiplex => {
+            match crate::route::transfer::send_msg(conn, &"multiplex".to_string()).await {
+                Ok(()) => RouterLoopOutcome::TakeOver(SessionTakeOver::Multiplex),
+                Err(()) => RouterLoopOutcome::Close,
+            }
         }
         RouteAction::EndConnection => {
             // Relay-only: lets the caller distinguish clean shutdown (Some)
@@ -94,8 +181,11 @@ async fn route_once(conn: &mut TlsStream<TcpStream>, head: RouteRecvHead) -> Rou
             RouterLoopOutcome::Close
         }
         RouteAction::Unknown(action) => {
-            let msg = format!("unknown action: {action:?}");
-            let _ = crate::route::transfer::resp_common_error_msg(conn, &msg).await;
+            let msg = format!(
+                "unknown action: {action:?}, client version: {}",
+                ctx.client_version().as_deref().unwrap_or("unknown")
+            );
+            let _ = resp_error(conn, RouteErrorCode::UnknownAction, &msg).await;
             error!("{}", msg);
             RouterLoopOutcome::Continue
         }
@@ -110,19 +200,16 @@ fn continue_or_close(should_continue: bool) -> RouterLoopOutcome {
     }
 }
 
-pub async fn common_auth(conn: &mut TlsStream<TcpStream>) -> Result<RouteRecvHead, ()> {
-    const UNAUTHORIZED_CODE: i32 = 401;
+pub async fn common_auth(
+    conn: &mut RouteConn,
+    remote_addr: std::net::SocketAddr,
+    policy: EndpointPolicy,
+) -> Result<RouteRecvHead, ()> {
     // The header cannot exceed 10KB to prevent malicious attacks from causing memory overflow
     const MAX_HEAD_LEN: isize = 1024 * 10;
     const IDLE_CONNECTION_WAITING_TIME: tokio::time::Duration =
         tokio::time::Duration::from_secs(60 * 2);
 
-    let remote_addr = conn
-        .get_ref()
-        .0
-        .peer_addr()
-        .map_err(|e| error!("get peer addr failed, err: {}", e))
-        .unwrap_or(std::net::SocketAddr::from(([0, 0, 0, 0], 0)));
     debug!("try to read head, remote ip: {}", remote_addr);
 
     // Read the length of the json
@@ -154,11 +241,21 @@ pub async fn common_auth(conn: &mut TlsStream<TcpStream>) -> Result<RouteRecvHea
     conn.read_exact(&mut head_buf)
         .await
         .map_err(|e| error!("read head failed, err: {}", e))?;
-    debug!("head_buf: {:?}", String::from_utf8_lossy(&head_buf));
-    let head: RouteRecvHead = serde_json::from_slice(&head_buf)
-        .map_err(|e| error!("json unmarshal failed, err: {}", e))?;
+    let head: RouteRecvHead = head_codec::current()
+        .decode(&head_buf)
+        .map_err(|e| error!("decode head failed, err: {}", e))?;
 
     if let RouteAction::Match = head.action {
+        if !policy.allow_match {
+            let msg = format!(
+                "match is not allowed on this endpoint, deviceName: {}, ip: {}",
+                head.device_name,
+                remote_addr.ip()
+            );
+            warn!("{}", msg);
+            let _ = resp_error(conn, RouteErrorCode::SearchNotAllowed, &msg).await;
+            return Err(());
+        }
         if *crate::config::ALLOW_TO_BE_SEARCHED.lock().unwrap() {
             return Ok(head);
         }
@@ -168,20 +265,23 @@ pub async fn common_auth(conn: &mut TlsStream<TcpStream>) -> Result<RouteRecvHea
             remote_addr.ip()
         );
         warn!("{}", msg);
-        let _ = resp_error_msg(conn, UNAUTHORIZED_CODE, &msg).await;
+        let _ = resp_error(conn, RouteErrorCode::SearchNotAllowed, &msg).await;
         return Err(());
     }
 
     if head.time_ip.is_empty() {
         let msg = format!("time-ip field is empty, remote ip: {}", remote_addr.ip());
         error!(msg);
-        let _ = resp_error_msg(conn, UNAUTHORIZED_CODE, &msg).await;
+        crate::admission::record_auth_failure(remote_addr.ip().to_canonical());
+        let _ = resp_error(conn, RouteErrorCode::Unauthorized, &msg).await;
         return Err(());
     }
     // debug!("head: {:?}", head);
 
-    let mut time_and_ip_bytes =
-        hex::decode(&head.time_ip).map_err(|e| error!("hex decode failed, err: {}", e))?;
+    let mut time_and_ip_bytes = hex::decode(&head.time_ip).map_err(|e| {
+        error!("hex decode failed, err: {}", e);
+        crate::admission::record_auth_failure(remote_addr.ip().to_canonical());
+    })?;
     let decrypted = crate::config::get_cipher()
         .map_err(|e| error!("get_cipher failed, err: {}", e))?
         .decrypt(&mut time_and_ip_bytes, head.aad.as_bytes());
@@ -193,9 +293,13 @@ pub async fn common_auth(conn: &mut TlsStream<TcpStream>) -> Result<RouteRecvHea
             remote_addr.ip()
         );
         info!(msg);
-        let _ = resp_error_msg(conn, UNAUTHORIZED_CODE, &msg).await;
+        // Relayed connections all come from the relay server, but bans are only
+        // enforced when accepting direct connections.
+        crate::admission::record_auth_failure(remote_addr.ip().to_canonical());
+        let _ = resp_error(conn, RouteErrorCode::Unauthorized, &msg).await;
         return Err(());
     }
+    crate::admission::record_auth_success(remote_addr.ip().to_canonical());
     let decrypted = decrypted.unwrap();
     trace!(
         "time_str, remote access host: {}",
@@ -205,7 +309,7 @@ pub async fn common_auth(conn: &mut TlsStream<TcpStream>) -> Result<RouteRecvHea
     Ok(head)
 }
 
-async fn match_handler(conn: &mut TlsStream<TcpStream>) -> Result<(), ()> {
+async fn match_handler(conn: &mut RouteConn) -> Result<(), ()> {
     let hostname = hostname::get()
         .map_err(|e| error!("get hostname failed, err: {}", e))
         .unwrap_or_default();
@@ -214,7 +318,7 @@ async fn match_handler(conn: &mut TlsStream<TcpStream>) -> Result<(), ()> {
         Ok(ca_certificate) => ca_certificate,
         Err(e) => {
             error!("read ca certificate failed, err: {}", e);
-            let _ = crate::route::transfer::resp_common_error_msg(conn, &e.to_string()).await;
+            let _ = resp_error(conn, RouteErrorCode::Internal, &e.to_string()).await;
             return Err(());
         }
     };
@@ -226,12 +330,13 @@ async fn match_handler(conn: &mut TlsStream<TcpStream>) -> Result<(), ()> {
             .secret_key_hex
             .clone(),
         ca_certificate,
+        external_addresses: crate::port_mapping::external_addresses(),
     };
     let action_resp = serde_json::to_vec(&action_resp);
     if let Err(e) = &action_resp {
         let err = format!("json marshal failed, err: {e}");
         error!("{}", err);
-        let _ = crate::route::transfer::resp_common_error_msg(conn, &err).await;
+        let _ = resp_error(conn, RouteErrorCode::Internal, &err).await;
         return Err(());
     }
     let r =
@@ -256,6 +361,79 @@ async fn match_handler(conn: &mut TlsStream<TcpStream>) -> Result<(), ()> {
     }
 }
 
+/// return whether should continue loop(like no socket error)
+async fn hello_handler(conn: &mut RouteConn, head: RouteRecvHead, ctx: &ConnContext) -> bool {
+    use crate::route::body::{BodyKind, read_body, reject_body};
+
+    let body_buf = match read_body(conn, &head, BodyKind::Json).await {
+        Ok(body_buf) => body_buf,
+        Err(e) => return reject_body(conn, &e).await,
+    };
+    let hello: HelloReq = if body_buf.is_empty() {
+        HelloReq::default()
+    } else {
+        match serde_json::from_slice(&body_buf) {
+            Ok(hello) => hello,
+            Err(e) => {
+                let msg = format!("json unmarshal failed, err: {e}");
+                error!("{}", msg);
+                return resp_error(conn, RouteErrorCode::InvalidRequest, &msg)
+                    .await
+                    .is_ok();
+            }
+        }
+    };
+    info!(
+        "{} says hello, version: {}, features: {:?}, head codecs: {:?}",
+        head.device_name, hello.version, hello.features, hello.head_codecs
+    );
+    let head_codec = HeadCodec::negotiate(&hello.head_codecs);
+    *ctx.client.write().unwrap() = Some(hello);
+
+    let body = match serde_json::to_vec(&server_capabilities(head_codec)) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("json marshal failed, err: {}", e);
+            return resp_error(conn, RouteErrorCode::Internal, &e.to_string())
+                .await
+                .is_ok();
+        }
+    };
+    if crate::route::transfer::send_msg_with_body(conn, &"".to_string(), RouteDataType::Text, &body)
+        .await
+        .is_err()
+    {
+        return false;
+    }
+    // The response is still in the previous codec, the switch applies to the next head.
+    head_codec::set_current(head_codec);
+    true
+}
+
+fn server_capabilities(head_codec: HeadCodec) -> ServerCapabilities {
+    ServerCapabilities {
+        version: crate::PROGRAM_VERSION.to_string(),
+        actions: SUPPORTED_ACTIONS.iter().map(|a| a.to_string()).collect(),
+        sync_frame_versions: vec![crate::sync::sync_frame::SYNC_FRAME_VERSION],
+        max_body_sizes: crate::config::read_config().body_limits.clone(),
+        features: ServerFeatures {
+            file_hash: true,
+            conflict_policies: vec![
+                ConflictPolicy::Rename,
+                ConflictPolicy::Overwrite,
+                ConflictPolicy::SkipIfIdentical,
+                ConflictPolicy::KeepNewer,
+            ],
+            archive_download: vec![ArchiveFormat::Tar, ArchiveFormat::Zip],
+            archive_upload: vec![ArchiveFormat::Tar],
+            compression: true,
+            chunked_body: true,
+        },
+        head_codecs: head_codec::SUPPORTED_HEAD_CODECS.to_vec(),
+        head_codec,
+    }
+}
+
 fn cancel_allow_to_be_searched_in_config() {
     if !crate::config::GLOBAL_CONFIG
         .read()
@@ -269,35 +447,52 @@ fn cancel_allow_to_be_searched_in_config() {
     cnf.save().expect("save config file error");
 }
 
-async fn set_relay_server_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecvHead) {
+/// Upper bound for `secretGraceSecs`, a previous key is never kept longer.
+const MAX_SECRET_GRACE_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// return whether should continue loop(like no socket error)
+async fn set_relay_server_handler(conn: &mut RouteConn, head: RouteRecvHead) -> bool {
     use crate::config;
-    use crate::route::transfer::{resp_common_error_msg, send_msg};
+    use crate::route::body::{BodyKind, read_body, reject_body};
+    use crate::route::transfer::send_msg;
 
-    let mut body_buf = vec![0u8; head.data_len as usize];
-    let r = conn.read_exact(&mut body_buf).await;
-    if let Err(e) = r {
-        error!("read body failed, err: {}", e);
-        let _ = resp_common_error_msg(conn, &e.to_string()).await;
-        return;
-    }
+    let body_buf = match read_body(conn, &head, BodyKind::Json).await {
+        Ok(body_buf) => body_buf,
+        Err(e) => return reject_body(conn, &e).await,
+    };
     let req: SetRelayServerReq = match serde_json::from_slice(&body_buf) {
         Ok(req) => req,
         Err(e) => {
             error!("json unmarshal failed, err: {}", e);
-            let _ = resp_common_error_msg(conn, &e.to_string()).await;
-            return;
+            let _ = resp_error(conn, RouteErrorCode::InvalidRequest, &e.to_string()).await;
+            return true;
         }
     };
     debug!("set relay server req: {:?}", req);
     if req.enable_relay && req.relay_server_address.is_empty() {
         let msg = String::from("invalid relay server address");
         error!("set relay server failed, {}", msg);
-        let _ = resp_common_error_msg(conn, &msg).await;
-        return;
+        let _ = resp_error(conn, RouteErrorCode::InvalidRequest, &msg).await;
+        return true;
     }
     let err;
+    let changed;
     {
         let mut cnf = config::write_config();
+        changed = cnf.relay_server_address != req.relay_server_address
+            || cnf.relay_secret_key != req.relay_secret_key
+            || cnf.enable_relay != req.enable_relay;
+        if cnf.relay_secret_key != req.relay_secret_key {
+            if req.secret_grace_secs > 0 {
+                cnf.relay_previous_secret_key = cnf.relay_secret_key.clone();
+                let grace_secs = req.secret_grace_secs.min(MAX_SECRET_GRACE_SECS) as i64;
+                cnf.relay_secret_grace_until =
+                    chrono::Utc::now().timestamp().saturating_add(grace_secs);
+            } else {
+                cnf.relay_previous_secret_key = None;
+                cnf.relay_secret_grace_until = 0;
+            }
+        }
         cnf.relay_server_address = req.relay_server_address.clone();
         cnf.relay_secret_key = req.relay_secret_key.clone();
         cnf.enable_relay = req.enable_relay;
@@ -305,8 +500,8 @@ async fn set_relay_server_handler(conn: &mut TlsStream<TcpStream>, head: RouteRe
     }
     if let Err(e) = err {
         error!("save config failed, err: {}", e);
-        let _ = resp_common_error_msg(conn, &e).await;
-        return;
+        let _ = resp_error(conn, RouteErrorCode::Internal, &e).await;
+        return true;
     }
     if (send_msg(conn, &"success".to_string()).await).is_err() {
         error!("send success msg failed");
@@ -315,13 +510,10 @@ async fn set_relay_server_handler(conn: &mut TlsStream<TcpStream>, head: RouteRe
     use crate::language::{LanguageKey, translate};
     use crate::utils::inform;
 
-    if req.enable_relay && crate::relay::run::tick_relay() {
-        inform("", translate(LanguageKey::SettingSuccess), None);
-    } else {
-        inform(
-            translate(LanguageKey::EffectiveAfterProgramRestart),
-            translate(LanguageKey::SettingSuccess),
-            None,
-        );
+    if changed {
+        // Not awaited, the restart waits for running relay sessions, which may include this one.
+        crate::RUNTIME.spawn(crate::relay::run::restart_relay());
     }
+    inform("", translate(LanguageKey::SettingSuccess), None);
+    true
 }
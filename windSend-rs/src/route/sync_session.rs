@@ -6,13 +6,12 @@ use std::{
 
 use tokio::{
     io::{AsyncRead, AsyncWrite, AsyncWriteExt},
-    net::TcpStream,
     sync::mpsc,
 };
-use tokio_rustls::server::TlsStream;
 use tracing::{debug, info, warn};
 
-use crate::route::router::SessionTakeOver;
+use crate::route::RouteConn;
+use crate::route::head_codec::HeadCodec;
 use crate::route::transfer::send_msg;
 use crate::sync::{
     clipboard_domain::ClipboardPayload,
@@ -21,18 +20,19 @@ use crate::sync::{
     sync_frame::{
         AckFrame, CloseCode, CloseFrame, HeartbeatAckFrame, HeartbeatFrame, SYNC_FRAME_VERSION,
         SubscribeFrame, SubscribeRequest, SyncCapabilities, SyncFrame, SyncFrameCodecError,
-        SyncFrameHead, read_frame_from_with_progress, read_frame_head_from, write_frame_head_to,
+        SyncFrameHead, read_frame_head_with_codec, read_frame_with_codec,
+        write_frame_head_with_codec, write_frame_with_codec,
     },
 };
 
 #[cfg(test)]
-use crate::sync::sync_frame::read_frame_from;
+use crate::sync::sync_frame::{read_frame_from, read_frame_head_from, write_frame_head_to};
 
 const HEARTBEAT_IDLE_THRESHOLD: Duration = Duration::from_secs(30);
 const HEARTBEAT_ACK_TIMEOUT: Duration = Duration::from_secs(10);
 const PEER_SILENCE_TIMEOUT: Duration = Duration::from_secs(60);
 
-pub async fn prepare_subscription_take_over(conn: &mut TlsStream<TcpStream>) -> Result<(), ()> {
+pub async fn prepare_subscription_take_over(conn: &mut RouteConn) -> Result<(), ()> {
     send_transport_upgrade_ack(conn).await
 }
 
@@ -46,20 +46,17 @@ where
     send_msg(writer, &String::new()).await
 }
 
-pub async fn handle_take_over(
-    conn: TlsStream<TcpStream>,
-    take_over: SessionTakeOver,
-) -> Option<TlsStream<TcpStream>> {
-    match take_over {
-        SessionTakeOver::ClipboardSubscription => {
-            if let Err(error) =
-                run_clipboard_subscription_transport(conn, GLOBAL_SESSION_REGISTRY.clone()).await
-            {
-                warn!(?error, "clipboard sync transport ended with an error");
-            }
-            None
-        }
+pub async fn handle_clipboard_subscription<T>(conn: T, head_codec: HeadCodec) -> Option<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if let Err(error) =
+        run_clipboard_subscription_transport(conn, head_codec, GLOBAL_SESSION_REGISTRY.clone())
+            .await
+    {
+        warn!(?error, "clipboard sync transport ended with an error");
     }
+    None
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -72,41 +69,44 @@ pub enum SyncSessionLoopError {
 
 pub async fn run_clipboard_subscription_transport<T>(
     mut transport: T,
+    head_codec: HeadCodec,
     registry: SessionRegistryHandle,
 ) -> Result<(), SyncSessionLoopError>
 where
     T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
     let local_capabilities = SyncCapabilities::v2_default();
-    let initial_head = match read_frame_head_from(&mut transport).await {
-        Ok(head) => head,
-        Err(SyncFrameCodecError::Io(error)) if is_disconnect_error(&error) => return Ok(()),
-        Err(error) => {
-            best_effort_close(
-                &mut transport,
-                protocol_error_close(format!("failed to decode subscribe frame: {error}")),
-            )
-            .await;
-            return Ok(());
-        }
-    };
+    let initial_head =
+        match read_frame_head_with_codec(&mut transport, head_codec, &mut || {}).await {
+            Ok(head) => head,
+            Err(SyncFrameCodecError::Io(error)) if is_disconnect_error(&error) => return Ok(()),
+            Err(error) => {
+                best_effort_close(
+                    &mut transport,
+                    head_codec,
+                    protocol_error_close(format!("failed to decode subscribe frame: {error}")),
+                )
+                .await;
+                return Ok(());
+            }
+        };
 
     let attach_lease = match accept_attach(initial_head, &local_capabilities, &registry).await {
         Ok(attach_lease) => attach_lease,
         Err(close_frame) => {
-            best_effort_close(&mut transport, close_frame).await;
+            best_effort_close(&mut transport, head_codec, close_frame).await;
             return Ok(());
         }
     };
 
-    if let Err(error) = send_subscribe_ack(&mut transport, attach_lease.grant()).await {
+    if let Err(error) = send_subscribe_ack(&mut transport, head_codec, attach_lease.grant()).await {
         registry.rollback_attach(attach_lease.into_rollback()).await;
         return Err(error);
     }
 
     let attach = attach_lease.commit();
     let final_state =
-        run_attached_transport_loop(transport, registry.clipboard_hub(), &attach).await;
+        run_attached_transport_loop(transport, head_codec, registry.clipboard_hub(), &attach).await;
     match final_state {
         TransportFinalState::Detached => {
             registry
@@ -183,13 +183,15 @@ fn validate_subscribe_frame(
 
 async fn send_subscribe_ack<T>(
     transport: &mut T,
+    head_codec: HeadCodec,
     attach: &AttachGrant,
 ) -> Result<(), SyncSessionLoopError>
 where
     T: AsyncWrite + Unpin,
 {
-    write_frame_head_to(
+    write_frame_head_with_codec(
         &SyncFrameHead::SubscribeAck(attach.subscribe_ack_frame()),
+        head_codec,
         transport,
     )
     .await?;
@@ -199,6 +201,7 @@ where
 
 async fn run_attached_transport_loop<T>(
     transport: T,
+    head_codec: HeadCodec,
     clipboard_hub: crate::sync::clipboard_event_hub::ClipboardEventHubHandle,
     attach: &AttachGrant,
 ) -> TransportFinalState
@@ -212,10 +215,16 @@ where
 
     let reader_handle = tokio::spawn(run_reader_loop(
         reader,
+        head_codec,
         attach_event_tx.clone(),
         peer_read_progress_at.clone(),
     ));
-    let writer_handle = tokio::spawn(run_writer_loop(writer, writer_rx, attach_event_tx.clone()));
+    let writer_handle = tokio::spawn(run_writer_loop(
+        writer,
+        head_codec,
+        writer_rx,
+        attach_event_tx.clone(),
+    ));
 
     let mut generation_rx = attach.session.subscribe_generation();
     let mut heartbeat_tick = tokio::time::interval(Duration::from_secs(1));
@@ -480,6 +489,7 @@ fn handle_inbound_frame(
 
 async fn run_reader_loop<R>(
     mut reader: R,
+    head_codec: HeadCodec,
     event_tx: mpsc::UnboundedSender<AttachEvent>,
     peer_read_progress_at: Arc<Mutex<Instant>>,
 ) where
@@ -490,7 +500,7 @@ async fn run_reader_loop<R>(
             *peer_read_progress_at.lock().unwrap() = Instant::now();
         };
 
-        match read_frame_from_with_progress(&mut reader, &mut on_progress).await {
+        match read_frame_with_codec(&mut reader, head_codec, &mut on_progress).await {
             Ok(frame) => {
                 if event_tx.send(AttachEvent::Inbound(frame)).is_err() {
                     return;
@@ -510,14 +520,14 @@ async fn run_reader_loop<R>(
 
 async fn run_writer_loop<W>(
     mut writer: W,
+    head_codec: HeadCodec,
     mut rx: mpsc::Receiver<SyncFrame>,
     event_tx: mpsc::UnboundedSender<AttachEvent>,
 ) where
     W: AsyncWrite + Unpin + Send + 'static,
 {
     while let Some(frame) = rx.recv().await {
-        let write_result = match crate::sync::sync_frame::write_frame_to(&frame, &mut writer).await
-        {
+        let write_result = match write_frame_with_codec(&frame, head_codec, &mut writer).await {
             Ok(()) => writer.flush().await,
             Err(error) => Err(std::io::Error::other(error.to_string())),
         };
@@ -549,11 +559,12 @@ fn queue_frame(
     }
 }
 
-async fn best_effort_close<T>(transport: &mut T, close_frame: CloseFrame)
+async fn best_effort_close<T>(transport: &mut T, head_codec: HeadCodec, close_frame: CloseFrame)
 where
     T: AsyncWrite + Unpin,
 {
-    let _ = write_frame_head_to(&SyncFrameHead::Close(close_frame), transport).await;
+    let _ = write_frame_head_with_codec(&SyncFrameHead::Close(close_frame), head_codec, transport)
+        .await;
     let _ = transport.flush().await;
 }
 
@@ -761,7 +772,7 @@ mod tests {
 
         let server_task = tokio::spawn({
             let registry = registry.clone();
-            async move { run_clipboard_subscription_transport(server, registry).await }
+            async move { run_clipboard_subscription_transport(server, HeadCodec::Json, registry).await }
         });
 
         write_frame_head_to(
@@ -790,7 +801,7 @@ mod tests {
 
         let server_task = tokio::spawn({
             let registry = registry.clone();
-            async move { run_clipboard_subscription_transport(server, registry).await }
+            async move { run_clipboard_subscription_transport(server, HeadCodec::Json, registry).await }
         });
 
         write_frame_head_to(
@@ -825,7 +836,7 @@ mod tests {
 
         let server_task = tokio::spawn({
             let registry = registry.clone();
-            async move { run_clipboard_subscription_transport(server, registry).await }
+            async move { run_clipboard_subscription_transport(server, HeadCodec::Json, registry).await }
         });
 
         write_frame_head_to(&SyncFrameHead::Ack(AckFrame { ack_up_to: 7 }), &mut client)
@@ -851,7 +862,9 @@ mod tests {
         let (mut first_client, first_server) = duplex(4096);
         let first_task = tokio::spawn({
             let registry = registry.clone();
-            async move { run_clipboard_subscription_transport(first_server, registry).await }
+            async move {
+                run_clipboard_subscription_transport(first_server, HeadCodec::Json, registry).await
+            }
         });
 
         write_frame_head_to(
@@ -881,7 +894,9 @@ mod tests {
         let (mut second_client, second_server) = duplex(4096);
         let second_task = tokio::spawn({
             let registry = registry.clone();
-            async move { run_clipboard_subscription_transport(second_server, registry).await }
+            async move {
+                run_clipboard_subscription_transport(second_server, HeadCodec::Json, registry).await
+            }
         });
 
         write_frame_head_to(
@@ -936,7 +951,7 @@ mod tests {
 
         let server_task = tokio::spawn({
             let registry = registry.clone();
-            async move { run_clipboard_subscription_transport(server, registry).await }
+            async move { run_clipboard_subscription_transport(server, HeadCodec::Json, registry).await }
         });
 
         write_frame_head_to(
@@ -968,7 +983,9 @@ mod tests {
         let (mut resume_client, resume_server) = duplex(4096);
         let resume_task = tokio::spawn({
             let registry = registry.clone();
-            async move { run_clipboard_subscription_transport(resume_server, registry).await }
+            async move {
+                run_clipboard_subscription_transport(resume_server, HeadCodec::Json, registry).await
+            }
         });
 
         write_frame_head_to(
@@ -1005,7 +1022,7 @@ mod tests {
 
         let server_task = tokio::spawn({
             let registry = registry.clone();
-            async move { run_clipboard_subscription_transport(server, registry).await }
+            async move { run_clipboard_subscription_transport(server, HeadCodec::Json, registry).await }
         });
 
         write_frame_head_to(
@@ -1048,7 +1065,7 @@ mod tests {
 
         let server_task = tokio::spawn({
             let registry = registry.clone();
-            async move { run_clipboard_subscription_transport(server, registry).await }
+            async move { run_clipboard_subscription_transport(server, HeadCodec::Json, registry).await }
         });
 
         write_frame_head_to(
@@ -1090,7 +1107,7 @@ mod tests {
 
         let server_task = tokio::spawn({
             let registry = registry.clone();
-            async move { run_clipboard_subscription_transport(server, registry).await }
+            async move { run_clipboard_subscription_transport(server, HeadCodec::Json, registry).await }
         });
 
         write_frame_head_to(
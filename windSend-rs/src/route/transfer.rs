@@ -1,15 +1,13 @@
 use crate::language::{LANGUAGE_MANAGER, LanguageKey};
-use crate::route::protocol::{RouteDataType, RouteRecvHead, RouteRespHead};
-use tokio::io::{AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio_rustls::server::TlsStream;
+use crate::route::RouteConn;
+use crate::route::protocol::{RouteDataType, RouteErrorCode, RouteRecvHead, RouteRespHead};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::{error, trace};
 
 pub static SUCCESS_STATUS_CODE: i32 = 200;
-pub static ERROR_STATUS_CODE: i32 = 400;
 
 pub async fn send_msg_with_body(
-    conn: &mut TlsStream<TcpStream>,
+    conn: &mut RouteConn,
     msg: &String,
     datatype: crate::route::protocol::RouteDataType,
     body: &[u8],
@@ -18,7 +16,7 @@ pub async fn send_msg_with_body(
 }
 
 pub async fn send_msg_with_body2(
-    conn: &mut TlsStream<TcpStream>,
+    conn: &mut RouteConn,
     msg: &String,
     datatype: crate::route::protocol::RouteDataType,
     total_file_size: Option<u64>,
@@ -28,7 +26,7 @@ pub async fn send_msg_with_body2(
 }
 
 async fn _send_msg_with_body(
-    conn: &mut TlsStream<TcpStream>,
+    conn: &mut RouteConn,
     msg: &String,
     datatype: crate::route::protocol::RouteDataType,
     total_file_size: Option<u64>,
@@ -40,9 +38,12 @@ async fn _send_msg_with_body(
         data_type: datatype,
         data_len: body.len() as i64,
         total_file_size,
+        skipped: None,
+        error_code: None,
     };
-    let resp_buf =
-        serde_json::to_vec(&resp).map_err(|e| error!("json marshal failed, err: {}", e))?;
+    let resp_buf = crate::route::head_codec::current()
+        .encode(&resp)
+        .map_err(|e| error!("encode head failed, err: {}", e))?;
     let head_len = resp_buf.len();
     trace!(
         "send resp, head_len: {}, head: {:?},body_len: {}",
@@ -70,8 +71,9 @@ pub async fn send_head<'a, W>(
 where
     W: AsyncWrite + Unpin + ?Sized,
 {
-    let resp_buf =
-        serde_json::to_vec(head).map_err(|e| error!("json marshal failed, err: {}", e))?;
+    let resp_buf = crate::route::head_codec::current()
+        .encode(head)
+        .map_err(|e| error!("encode head failed, err: {}", e))?;
     let head_len = resp_buf.len();
     trace!("head_len: {}, head: {:?}", head_len, head);
     let head_len_buf = &(head_len as u32).to_le_bytes();
@@ -96,32 +98,82 @@ where
         data_type: RouteDataType::Text,
         data_len: 0,
         total_file_size: None,
+        skipped: None,
+        error_code: None,
     };
     send_head(writer, &resp).await
 }
 
-pub async fn resp_error_msg<'a, W>(writer: &'a mut W, code: i32, msg: &'a String) -> Result<(), ()>
+/// Acknowledges a file part that was discarded because of the conflict policy
+pub async fn send_skipped_msg<W>(writer: &mut W, msg: &String) -> Result<(), ()>
 where
     W: AsyncWrite + Unpin + ?Sized,
 {
     let resp = RouteRespHead {
-        code,
+        code: SUCCESS_STATUS_CODE,
         msg,
         data_type: RouteDataType::Text,
         data_len: 0,
         total_file_size: None,
+        skipped: Some(true),
+        error_code: None,
     };
     send_head(writer, &resp).await
 }
 
-pub async fn resp_common_error_msg<'a, W>(writer: &'a mut W, msg: &'a String) -> Result<(), ()>
+/// Writes one chunk of a `CHUNKED_DATA_LEN` body, an empty chunk ends the body
+pub async fn write_chunk<W>(writer: &mut W, data: &[u8]) -> std::io::Result<()>
 where
     W: AsyncWrite + Unpin + ?Sized,
 {
-    resp_error_msg(writer, ERROR_STATUS_CODE, msg).await
+    writer.write_all(&(data.len() as u32).to_le_bytes()).await?;
+    writer.write_all(data).await
+}
+
+/// Reads one chunk of a `CHUNKED_DATA_LEN` body, `None` at the end of the body
+pub async fn read_chunk<R>(reader: &mut R, max_len: usize) -> std::io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).await?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len == 0 {
+        return Ok(None);
+    }
+    if len > max_len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("chunk too large: {len}"),
+        ));
+    }
+    let mut chunk = vec![0u8; len];
+    reader.read_exact(&mut chunk).await?;
+    Ok(Some(chunk))
+}
+
+/// Responds with a failure, `msg` is a human-readable description of `error_code`.
+pub async fn resp_error<'a, W>(
+    writer: &'a mut W,
+    error_code: RouteErrorCode,
+    msg: &'a str,
+) -> Result<(), ()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let resp = RouteRespHead {
+        code: error_code.status_code(),
+        msg,
+        data_type: RouteDataType::Text,
+        data_len: 0,
+        total_file_size: None,
+        skipped: None,
+        error_code: Some(error_code),
+    };
+    send_head(writer, &resp).await
 }
 
-pub async fn ping_handler(conn: &mut TlsStream<TcpStream>, head: RouteRecvHead) -> Result<(), ()> {
+pub async fn ping_handler(conn: &mut RouteConn, head: RouteRecvHead) -> Result<(), ()> {
     // let mut body_buf = vec![0u8; head.data_len as usize];
     // if let Err(e) = conn.read_exact(&mut body_buf).await {
     //     error!("read body failed, err: {}", e);
@@ -0,0 +1,333 @@
+//! Routing rules that decide where received items are saved.
+//!
+//! Rules are evaluated in the order they appear in `config.yaml`; the first
+//! rule whose conditions all match wins. When no rule matches, items land in
+//! `Config::save_path` as before.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The kind of item being saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReceiveItemKind {
+    /// A file (or empty directory) received through `pasteFile`/`syncText`.
+    #[serde(rename = "file")]
+    File,
+    /// A clipboard image saved alongside a clipboard sync.
+    #[serde(rename = "clipImage")]
+    ClipImage,
+    /// A pasted text too large for the clipboard, saved as a file.
+    #[serde(rename = "text")]
+    Text,
+}
+
+/// Coarse file categories that can be used instead of listing extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileCategory {
+    #[serde(rename = "image")]
+    Image,
+    #[serde(rename = "video")]
+    Video,
+    #[serde(rename = "audio")]
+    Audio,
+    #[serde(rename = "document")]
+    Document,
+    #[serde(rename = "archive")]
+    Archive,
+}
+
+impl FileCategory {
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            FileCategory::Image => &[
+                "jpg", "jpeg", "png", "gif", "bmp", "webp", "ico", "heic", "heif", "tif", "tiff",
+                "svg",
+            ],
+            FileCategory::Video => &["mp4", "mkv", "mov", "avi", "webm", "flv", "wmv", "m4v"],
+            FileCategory::Audio => &["mp3", "wav", "flac", "aac", "ogg", "m4a", "opus", "wma"],
+            FileCategory::Document => &[
+                "pdf", "doc", "docx", "xls", "xlsx", "ppt", "pptx", "txt", "md", "odt", "ods",
+                "odp", "rtf", "csv",
+            ],
+            FileCategory::Archive => &["zip", "rar", "7z", "tar", "gz", "bz2", "xz", "zst"],
+        }
+    }
+
+    fn contains(self, ext: &str) -> bool {
+        self.extensions().contains(&ext)
+    }
+}
+
+/// A single save-path routing rule.
+///
+/// Every condition that is set must match; unset conditions match anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavePathRule {
+    /// Sending device name, compared case-insensitively. `*` and `?` wildcards are supported.
+    #[serde(rename = "device", default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// File extensions without the leading dot, e.g. `["png", "jpg"]`.
+    #[serde(
+        rename = "extensions",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub extensions: Option<Vec<String>>,
+    /// File categories, matched against the extension.
+    #[serde(rename = "fileTypes", default, skip_serializing_if = "Option::is_none")]
+    pub file_types: Option<Vec<FileCategory>>,
+    /// Restrict the rule to files or clipboard images.
+    #[serde(rename = "itemKind", default, skip_serializing_if = "Option::is_none")]
+    pub item_kind: Option<ReceiveItemKind>,
+    /// Target directory. Supports a leading `~` and the placeholders
+    /// `{device}`, `{yyyy}`, `{mm}`, `{dd}`, `{yyyy-mm}` and `{yyyy-mm-dd}`.
+    #[serde(rename = "target")]
+    pub target: String,
+}
+
+/// What is known about an incoming item when choosing its directory.
+#[derive(Debug, Clone, Copy)]
+pub struct SaveTarget<'a> {
+    pub device_name: &'a str,
+    /// Name of the item, the top-level file or folder for uploads, see
+    /// [`top_level_item`]; only the extension is inspected.
+    pub file_name: Option<&'a str>,
+    pub kind: ReceiveItemKind,
+}
+
+impl SavePathRule {
+    pub fn matches(&self, target: &SaveTarget) -> bool {
+        if let Some(kind) = self.item_kind
+            && kind != target.kind
+        {
+            return false;
+        }
+        if let Some(device) = &self.device
+            && !wildcard_match(&device.to_lowercase(), &target.device_name.to_lowercase())
+        {
+            return false;
+        }
+        if self.extensions.is_none() && self.file_types.is_none() {
+            return true;
+        }
+        let ext = match target.file_name.and_then(file_extension) {
+            Some(ext) => ext,
+            None => return false,
+        };
+        let ext_matched = self.extensions.as_ref().is_some_and(|exts| {
+            exts.iter()
+                .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(&ext))
+        });
+        let type_matched = self
+            .file_types
+            .as_ref()
+            .is_some_and(|types| types.iter().any(|t| t.contains(&ext)));
+        ext_matched || type_matched
+    }
+
+    pub fn expand_target(
+        &self,
+        device_name: &str,
+        now: chrono::DateTime<chrono::Local>,
+    ) -> PathBuf {
+        expand_placeholders(&self.target, device_name, now)
+    }
+}
+
+/// Returns the directory an item should be saved into.
+pub fn resolve_save_dir(target: &SaveTarget) -> PathBuf {
+    let config = crate::config::read_config();
+    resolve_with_rules(
+        &config.save_path_rules,
+        &config.save_path,
+        target,
+        chrono::Local::now(),
+    )
+}
+
+/// The first component of the relative path of an uploaded file or folder.
+///
+/// Rules are matched against it, so that a folder is saved in one place
+/// instead of being split up by the extensions of its files.
+pub fn top_level_item(relative_path: &str) -> &str {
+    relative_path
+        .split(['/', '\\'])
+        .find(|component| !component.is_empty())
+        .unwrap_or(relative_path)
+}
+
+/// Returns `Config::save_path` without consulting the rules.
+pub fn default_save_dir() -> PathBuf {
+    PathBuf::from(&crate::config::read_config().save_path)
+}
+
+fn resolve_with_rules(
+    rules: &[SavePathRule],
+    default_dir: &str,
+    target: &SaveTarget,
+    now: chrono::DateTime<chrono::Local>,
+) -> PathBuf {
+    match rules.iter().find(|rule| rule.matches(target)) {
+        Some(rule) => {
+            let dir = rule.expand_target(target.device_name, now);
+            tracing::debug!("save path rule matched: {:?} -> {}", rule, dir.display());
+            dir
+        }
+        None => PathBuf::from(default_dir),
+    }
+}
+
+fn file_extension(name: &str) -> Option<String> {
+    Path::new(name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+}
+
+fn expand_placeholders(
+    template: &str,
+    device_name: &str,
+    now: chrono::DateTime<chrono::Local>,
+) -> PathBuf {
+    // Device names come from the client, keep them to a single valid path component.
+    let device = crate::utils::sanitize_file_name(device_name);
+    let expanded = template
+        .replace("{device}", &device)
+        .replace("{yyyy-mm-dd}", &now.format("%Y-%m-%d").to_string())
+        .replace("{yyyy-mm}", &now.format("%Y-%m").to_string())
+        .replace("{yyyy}", &now.format("%Y").to_string())
+        .replace("{mm}", &now.format("%m").to_string())
+        .replace("{dd}", &now.format("%d").to_string());
+
+    crate::utils::expand_home_dir(&expanded)
+}
+
+/// Matches `text` against a pattern where `*` matches any run of characters
+/// and `?` matches exactly one.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn fixed_now() -> chrono::DateTime<chrono::Local> {
+        chrono::Local
+            .with_ymd_and_hms(2024, 3, 7, 12, 0, 0)
+            .single()
+            .unwrap()
+    }
+
+    fn rule(target: &str) -> SavePathRule {
+        SavePathRule {
+            target: target.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins_and_falls_back_to_default() {
+        let rules = vec![
+            SavePathRule {
+                device: Some("pixel*".to_string()),
+                file_types: Some(vec![FileCategory::Image]),
+                ..rule("/pics/{device}/{yyyy-mm}")
+            },
+            SavePathRule {
+                item_kind: Some(ReceiveItemKind::ClipImage),
+                ..rule("/clips")
+            },
+            SavePathRule {
+                extensions: Some(vec![".PDF".to_string()]),
+                ..rule("/docs")
+            },
+        ];
+        let resolve = |device, file_name, kind| {
+            resolve_with_rules(
+                &rules,
+                "/default",
+                &SaveTarget {
+                    device_name: device,
+                    file_name,
+                    kind,
+                },
+                fixed_now(),
+            )
+        };
+
+        assert_eq!(
+            resolve("Pixel 7", Some("a/b/photo.JPG"), ReceiveItemKind::File),
+            PathBuf::from("/pics/Pixel 7/2024-03")
+        );
+        assert_eq!(
+            resolve("iPhone", Some("photo.jpg"), ReceiveItemKind::File),
+            PathBuf::from("/default")
+        );
+        assert_eq!(
+            resolve("iPhone", Some("x.png"), ReceiveItemKind::ClipImage),
+            PathBuf::from("/clips")
+        );
+        assert_eq!(
+            resolve("iPhone", Some("report.pdf"), ReceiveItemKind::File),
+            PathBuf::from("/docs")
+        );
+        assert_eq!(
+            resolve("iPhone", None, ReceiveItemKind::File),
+            PathBuf::from("/default")
+        );
+    }
+
+    #[test]
+    fn uploads_are_matched_by_their_top_level_item() {
+        assert_eq!(top_level_item("photo.jpg"), "photo.jpg");
+        assert_eq!(top_level_item("album/2024/photo.jpg"), "album");
+        assert_eq!(top_level_item("album\\photo.jpg"), "album");
+        assert_eq!(top_level_item("/album/photo.jpg"), "album");
+    }
+
+    #[test]
+    fn placeholders_expand_and_device_stays_single_component() {
+        let dir = expand_placeholders("/r/{device}/{yyyy}/{mm}/{dd}", "../evil/pc", fixed_now());
+        assert_eq!(dir, PathBuf::from("/r/.._evil_pc/2024/03/07"));
+        // Reserved names on Windows would fail to create the directory.
+        for (name, device) in [("CON", "_CON"), ("nul", "_nul"), ("..", "_")] {
+            assert_eq!(
+                expand_placeholders("/r/{device}", name, fixed_now()),
+                PathBuf::from(format!("/r/{device}"))
+            );
+        }
+        assert_eq!(
+            expand_placeholders("/r/{yyyy-mm-dd}", "", fixed_now()),
+            PathBuf::from("/r/2024-03-07")
+        );
+    }
+
+    #[test]
+    fn wildcard_patterns() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("pixel*", "pixel 7 pro"));
+        assert!(wildcard_match("*book?", "macbook1"));
+        assert!(!wildcard_match("*book?", "macbook"));
+        assert!(!wildcard_match("pixel", "pixel 7"));
+    }
+}
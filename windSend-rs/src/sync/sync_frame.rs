@@ -1,3 +1,4 @@
+use crate::route::head_codec::{HeadCodec, HeadCodecError};
 use crate::sync::clipboard_domain::ClipboardPayloadKind;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeSet;
@@ -278,24 +279,37 @@ pub enum SyncFrameCodecError {
         declared: usize,
         actual: usize,
     },
-    #[error("sync frame JSON error: {0}")]
-    Json(#[from] serde_json::Error),
+    #[error("sync frame head error: {0}")]
+    Head(#[from] HeadCodecError),
     #[error("sync frame IO error: {0}")]
     Io(#[from] std::io::Error),
 }
 
+#[cfg(test)]
 pub async fn write_frame_head_to<W>(
     head: &SyncFrameHead,
     writer: &mut W,
 ) -> Result<(), SyncFrameCodecError>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    write_frame_head_with_codec(head, HeadCodec::Json, writer).await
+}
+
+/// Writes a frame head in the head codec of the route connection the session took over.
+pub async fn write_frame_head_with_codec<W>(
+    head: &SyncFrameHead,
+    codec: HeadCodec,
+    writer: &mut W,
+) -> Result<(), SyncFrameCodecError>
 where
     W: AsyncWrite + Unpin + ?Sized,
 {
     // The sync session deliberately reuses the repo's existing length-prefixed framing
     // so later work can focus on protocol semantics instead of inventing new byte boundaries.
-    let head_json = serde_json::to_vec(head)?;
+    let head_buf = codec.encode(head)?;
     let head_len =
-        u32::try_from(head_json.len()).map_err(|_| SyncFrameCodecError::HeadTooLarge {
+        u32::try_from(head_buf.len()).map_err(|_| SyncFrameCodecError::HeadTooLarge {
             head_len: u32::MAX,
             max_head_len: MAX_SYNC_FRAME_HEAD_LEN,
         })?;
@@ -310,19 +324,21 @@ where
     }
 
     writer.write_all(&head_len.to_le_bytes()).await?;
-    writer.write_all(&head_json).await?;
+    writer.write_all(&head_buf).await?;
     Ok(())
 }
 
+#[cfg(test)]
 pub async fn read_frame_head_from<R>(reader: &mut R) -> Result<SyncFrameHead, SyncFrameCodecError>
 where
     R: AsyncRead + Unpin + ?Sized,
 {
-    read_frame_head_from_with_progress(reader, &mut || {}).await
+    read_frame_head_with_codec(reader, HeadCodec::Json, &mut || {}).await
 }
 
-pub async fn read_frame_head_from_with_progress<R, F>(
+pub async fn read_frame_head_with_codec<R, F>(
     reader: &mut R,
+    codec: HeadCodec,
     on_progress: &mut F,
 ) -> Result<SyncFrameHead, SyncFrameCodecError>
 where
@@ -344,14 +360,26 @@ where
 
     let mut head_buf = vec![0u8; head_len as usize];
     read_exact_with_progress(reader, &mut head_buf, on_progress).await?;
-    Ok(serde_json::from_slice(&head_buf)?)
+    Ok(codec.decode(&head_buf)?)
 }
 
+#[cfg(test)]
 pub async fn write_frame_to<W>(frame: &SyncFrame, writer: &mut W) -> Result<(), SyncFrameCodecError>
 where
     W: AsyncWrite + Unpin + ?Sized,
 {
-    write_frame_head_to(&frame.head, writer).await?;
+    write_frame_with_codec(frame, HeadCodec::Json, writer).await
+}
+
+pub async fn write_frame_with_codec<W>(
+    frame: &SyncFrame,
+    codec: HeadCodec,
+    writer: &mut W,
+) -> Result<(), SyncFrameCodecError>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    write_frame_head_with_codec(&frame.head, codec, writer).await?;
     if !frame.body.is_empty() {
         writer.write_all(&frame.body).await?;
     }
@@ -363,18 +391,19 @@ pub async fn read_frame_from<R>(reader: &mut R) -> Result<SyncFrame, SyncFrameCo
 where
     R: AsyncRead + Unpin + ?Sized,
 {
-    read_frame_from_with_progress(reader, &mut || {}).await
+    read_frame_with_codec(reader, HeadCodec::Json, &mut || {}).await
 }
 
-pub async fn read_frame_from_with_progress<R, F>(
+pub async fn read_frame_with_codec<R, F>(
     reader: &mut R,
+    codec: HeadCodec,
     on_progress: &mut F,
 ) -> Result<SyncFrame, SyncFrameCodecError>
 where
     R: AsyncRead + Unpin + ?Sized,
     F: FnMut(),
 {
-    let head = read_frame_head_from_with_progress(reader, on_progress).await?;
+    let head = read_frame_head_with_codec(reader, codec, on_progress).await?;
     let mut body = vec![0u8; head.body_len()];
     if !body.is_empty() {
         read_exact_with_progress(reader, &mut body, on_progress).await?;
@@ -523,6 +552,27 @@ mod tests {
         assert_eq!(decoded.head, head);
     }
 
+    #[tokio::test]
+    async fn event_frame_round_trips_with_cbor_head() {
+        let head = SyncFrameHead::Event(EventFrame {
+            event_id: 8,
+            payload_kind: ClipboardPayloadKind::ImagePng,
+            body_len: 3,
+        });
+        let frame = SyncFrame::new(head, vec![1, 2, 3]).unwrap();
+
+        let (mut writer, mut reader) = tokio::io::duplex(1024);
+        write_frame_with_codec(&frame, HeadCodec::Cbor, &mut writer)
+            .await
+            .unwrap();
+        drop(writer);
+
+        let decoded = read_frame_with_codec(&mut reader, HeadCodec::Cbor, &mut || {})
+            .await
+            .unwrap();
+        assert_eq!(decoded, frame);
+    }
+
     #[tokio::test]
     async fn head_only_frame_round_trips_without_body() {
         let head = SyncFrameHead::Close(CloseFrame {
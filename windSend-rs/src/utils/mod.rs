@@ -4,7 +4,10 @@ pub mod encrypt;
 
 mod auto_start;
 pub mod clipboard;
+pub mod proxy;
+mod safe_path;
 pub mod tls;
 mod util;
 pub use auto_start::*;
+pub use safe_path::*;
 pub use util::*;
@@ -0,0 +1,152 @@
+//! Sanitization of the relative paths that clients ask us to save data to.
+//!
+//! Every received path goes through [`sanitize_relative_path`] before it is
+//! joined onto a save directory. Traversal and absolute paths are rejected,
+//! while names that are invalid on some platform are mapped to valid ones, so
+//! that a file received here can also be copied to any other system.
+
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters that are not allowed in file names on Windows
+const ILLEGAL_CHARS: [char; 9] = ['<', '>', ':', '"', '|', '?', '*', '/', '\\'];
+
+const RESERVED_NAMES: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum UnsafePathError {
+    #[error("absolute path is not allowed: {0}")]
+    Absolute(String),
+    #[error("path traversal is not allowed: {0}")]
+    Traversal(String),
+}
+
+/// Turns a client supplied relative path into one that stays inside any base directory.
+///
+/// Both `/` and `\` are separators. Empty and `.` components are dropped, `..`
+/// and absolute or drive-prefixed paths are rejected. Each remaining component
+/// is normalized to NFC and made a valid file name with [`sanitize_file_name`].
+/// An empty path results in an empty `PathBuf`.
+pub fn sanitize_relative_path(path: &str) -> Result<PathBuf, UnsafePathError> {
+    if path.starts_with(['/', '\\']) || has_drive_prefix(path) {
+        return Err(UnsafePathError::Absolute(path.to_string()));
+    }
+    let mut sanitized = PathBuf::new();
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => return Err(UnsafePathError::Traversal(path.to_string())),
+            name => sanitized.push(sanitize_file_name(name)),
+        }
+    }
+    Ok(sanitized)
+}
+
+/// Joins a client supplied relative path onto `base`, see [`sanitize_relative_path`].
+pub fn join_sanitized(base: &Path, path: &str) -> Result<PathBuf, UnsafePathError> {
+    Ok(base.join(sanitize_relative_path(path)?))
+}
+
+/// Makes a single path component a valid file name on every platform.
+///
+/// The name is normalized to NFC, as macOS sends decomposed names. Illegal and
+/// control characters are replaced with `_`, trailing dots and spaces (which
+/// Windows drops) are removed, and reserved device names get a `_` prefix.
+pub fn sanitize_file_name(name: &str) -> String {
+    let mut name: String = name
+        .nfc()
+        .map(|c| {
+            if c.is_control() || ILLEGAL_CHARS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let trimmed_len = name.trim_end_matches(['.', ' ']).len();
+    name.truncate(trimmed_len);
+    if name.is_empty() {
+        return "_".to_string();
+    }
+    if is_reserved_name(&name) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+fn has_drive_prefix(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+/// Whether Windows treats the name as a device, also with an extension like `nul.txt`
+fn is_reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let upper = stem.to_ascii_uppercase();
+    if RESERVED_NAMES.contains(&upper.as_str()) {
+        return true;
+    }
+    match upper
+        .strip_prefix("COM")
+        .or_else(|| upper.strip_prefix("LPT"))
+    {
+        Some(n) => matches!(
+            n,
+            "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" | "¹" | "²" | "³"
+        ),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traversal_and_absolute_paths_are_rejected() {
+        for path in [
+            "../etc",
+            "a/../../etc",
+            "a\\..\\b",
+            "/etc/passwd",
+            "\\\\server\\share",
+            "C:\\Windows",
+            "c:file",
+        ] {
+            assert!(sanitize_relative_path(path).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn separators_and_dots_are_normalized() {
+        let base = Path::new("/save");
+        assert_eq!(
+            join_sanitized(base, "a/./b\\c.txt").unwrap(),
+            base.join("a").join("b").join("c.txt")
+        );
+        assert_eq!(join_sanitized(base, "").unwrap(), base);
+        assert_eq!(
+            join_sanitized(base, "a//b/").unwrap(),
+            base.join("a").join("b")
+        );
+    }
+
+    #[test]
+    fn file_names_are_valid_on_windows() {
+        assert_eq!(sanitize_file_name("a:b?.txt"), "a_b_.txt");
+        assert_eq!(sanitize_file_name("tab\there"), "tab_here");
+        assert_eq!(sanitize_file_name("name. . "), "name");
+        assert_eq!(sanitize_file_name("..."), "_");
+        assert_eq!(sanitize_file_name("CON"), "_CON");
+        assert_eq!(sanitize_file_name("nul.tar.gz"), "_nul.tar.gz");
+        assert_eq!(sanitize_file_name("com1"), "_com1");
+        assert_eq!(sanitize_file_name("COM10"), "COM10");
+        assert_eq!(sanitize_file_name("console"), "console");
+    }
+
+    #[test]
+    fn decomposed_names_are_composed() {
+        // "é" as sent by macOS: "e" followed by a combining acute accent
+        assert_eq!(sanitize_file_name("caf\u{65}\u{301}.txt"), "caf\u{e9}.txt");
+    }
+}
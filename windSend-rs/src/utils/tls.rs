@@ -40,6 +40,15 @@ pub fn generate_signed_certificate(
     issuer_params: &CertificateParams,
     issuer_key: &KeyPair,
     fake_domain: &str,
+) -> Result<(Certificate, KeyPair), Box<dyn std::error::Error>> {
+    let issuer = rcgen::Issuer::from_params(issuer_params, issuer_key);
+    sign_certificate(&issuer, fake_domain, &[])
+}
+
+fn sign_certificate(
+    issuer: &rcgen::Issuer<'_, &KeyPair>,
+    fake_domain: &str,
+    extra_ips: &[std::net::IpAddr],
 ) -> Result<(Certificate, KeyPair), Box<dyn std::error::Error>> {
     let mut params = CertificateParams::default();
     let mut distinguished_name = DistinguishedName::new();
@@ -62,6 +71,9 @@ pub fn generate_signed_certificate(
         ))),
         SanType::DnsName(rcgen::string::Ia5String::from_str(fake_domain)?),
     ];
+    params
+        .subject_alt_names
+        .extend(extra_ips.iter().copied().map(SanType::IpAddress));
     // Backdate by 1 day to tolerate clock skew between this host (at the moment
     // of generation) and the verifying client. A freshly installed machine whose
     // clock hasn't synced via NTP yet can otherwise stamp not_before in the
@@ -78,9 +90,7 @@ pub fn generate_signed_certificate(
     // params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
 
     let key_pair = rcgen::KeyPair::generate()?;
-
-    let issuer = rcgen::Issuer::from_params(issuer_params, issuer_key);
-    Ok((params.signed_by(&key_pair, &issuer)?, key_pair))
+    Ok((params.signed_by(&key_pair, issuer)?, key_pair))
 }
 
 pub fn generate_self_signed_ca_certificate(
@@ -133,3 +143,200 @@ pub fn generate_ca_and_signed_certificate_pair(
         [ca_cert.pem(), ca_key.serialize_pem()],
     ))
 }
+
+/// Signs a new server certificate with the existing CA, for the domain of
+/// `cert_pem` and additionally `extra_ips`. Paired devices trust the CA, so
+/// they accept the new certificate without pairing again.
+pub fn reissue_signed_certificate(
+    cert_pem: &str,
+    ca_cert_pem: &str,
+    ca_key_pem: &str,
+    extra_ips: &[std::net::IpAddr],
+) -> Result<[String; 2], Box<dyn std::error::Error>> {
+    use tokio_rustls::rustls::pki_types::{CertificateDer, pem::PemObject};
+    let cert = CertificateDer::from_pem_slice(cert_pem.as_bytes())?;
+    let cert = webpki::EndEntityCert::try_from(&cert)?;
+    // localhost is on every certificate, it is only the domain in mode 2.
+    let fake_domain = cert
+        .valid_dns_names()
+        .find(|name| *name != "localhost")
+        .unwrap_or("localhost")
+        .to_string();
+    let ca_key = KeyPair::from_pem(ca_key_pem)?;
+    let issuer = rcgen::Issuer::from_ca_cert_pem(ca_cert_pem, &ca_key)?;
+    let (cert, key_pair) = sign_certificate(&issuer, &fake_domain, extra_ips)?;
+    Ok([cert.pem(), key_pair.serialize_pem()])
+}
+
+/// Signs a new server certificate with the existing CA for a new domain of
+/// `domain_mode` and additionally `extra_ips`.
+pub fn resign_for_domain_mode(
+    ca_cert_pem: &str,
+    ca_key_pem: &str,
+    domain_mode: u8,
+    extra_ips: &[std::net::IpAddr],
+) -> Result<[String; 2], Box<dyn std::error::Error>> {
+    let fake_domain = generate_domain_by_mode(domain_mode);
+    let ca_key = KeyPair::from_pem(ca_key_pem)?;
+    let issuer = rcgen::Issuer::from_ca_cert_pem(ca_cert_pem, &ca_key)?;
+    let (cert, key_pair) = sign_certificate(&issuer, &fake_domain, extra_ips)?;
+    Ok([cert.pem(), key_pair.serialize_pem()])
+}
+
+/// Whether the certificate in `cert_pem` is valid for `ip`
+pub fn certificate_has_ip(cert_pem: &str, ip: std::net::IpAddr) -> bool {
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, pem::PemObject};
+    let Ok(cert) = CertificateDer::from_pem_slice(cert_pem.as_bytes()) else {
+        return false;
+    };
+    webpki::EndEntityCert::try_from(&cert).is_ok_and(|cert| {
+        cert.verify_is_valid_for_subject_name(&ServerName::IpAddress(ip.into()))
+            .is_ok()
+    })
+}
+
+/// SHA-256 fingerprint of a DER encoded certificate.
+pub type CertFingerprint = [u8; 32];
+
+/// Parses a certificate pin: a PEM certificate, the path of a PEM file, or a
+/// SHA-256 fingerprint in hex (colons allowed).
+pub fn parse_cert_pin(pin: &str) -> Result<CertFingerprint, String> {
+    let pin = pin.trim();
+    let hex_pin = pin.replace(':', "");
+    if hex_pin.len() == 64
+        && let Ok(fingerprint) = hex::decode(&hex_pin)
+    {
+        return Ok(fingerprint.try_into().unwrap());
+    }
+    let pem_bytes = if pin.starts_with("-----BEGIN") {
+        pin.as_bytes().to_vec()
+    } else {
+        std::fs::read(pin).map_err(|e| format!("read certificate {pin} error: {e}"))?
+    };
+    let cert = rustls_pemfile::certs(&mut pem_bytes.as_slice())
+        .next()
+        .ok_or("no certificate found")?
+        .map_err(|e| format!("parse certificate error: {e}"))?;
+    Ok(crate::utils::encrypt::compute_sha256(&cert))
+}
+
+/// A pinned certificate, or a pinned public key that stays the same when the
+/// certificate is renewed with the same key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertPin {
+    Cert(CertFingerprint),
+    /// SHA-256 of the DER encoded SubjectPublicKeyInfo
+    Spki(CertFingerprint),
+}
+
+impl CertPin {
+    /// Parses `spki:` and a SHA-256 in hex or base64, or anything [`parse_cert_pin`] accepts.
+    pub fn parse(pin: &str) -> Result<Self, String> {
+        use base64::prelude::*;
+        let Some(spki) = pin.trim().strip_prefix("spki:") else {
+            return parse_cert_pin(pin).map(CertPin::Cert);
+        };
+        let spki = spki.trim();
+        let hash = match hex::decode(spki.replace(':', "")) {
+            Ok(hash) => hash,
+            Err(_) => BASE64_STANDARD
+                .decode(spki)
+                .map_err(|e| format!("invalid spki pin {spki}: {e}"))?,
+        };
+        hash.try_into()
+            .map(CertPin::Spki)
+            .map_err(|_| format!("spki pin {spki} is not a SHA-256"))
+    }
+
+    fn matches(&self, cert: &tokio_rustls::rustls::pki_types::CertificateDer<'_>) -> bool {
+        match self {
+            CertPin::Cert(fingerprint) => {
+                crate::utils::encrypt::compute_sha256(cert) == *fingerprint
+            }
+            CertPin::Spki(fingerprint) => webpki::EndEntityCert::try_from(cert).is_ok_and(|cert| {
+                crate::utils::encrypt::compute_sha256(cert.subject_public_key_info().as_ref())
+                    == *fingerprint
+            }),
+        }
+    }
+}
+
+/// Accepts exactly the pinned certificates, ignoring names and chains.
+///
+/// The certificates generated by `generate_signed_certificate` are marked as CA,
+/// which webpki refuses as end-entity certificates, so peers are pinned by
+/// fingerprint instead of being validated against their CA.
+#[derive(Debug)]
+pub struct PinnedCertVerifier {
+    pins: Vec<CertPin>,
+    algorithms: tokio_rustls::rustls::crypto::WebPkiSupportedAlgorithms,
+}
+
+impl tokio_rustls::rustls::client::danger::ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &tokio_rustls::rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[tokio_rustls::rustls::pki_types::CertificateDer<'_>],
+        _server_name: &tokio_rustls::rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: tokio_rustls::rustls::pki_types::UnixTime,
+    ) -> Result<tokio_rustls::rustls::client::danger::ServerCertVerified, tokio_rustls::rustls::Error>
+    {
+        if self.pins.iter().any(|pin| pin.matches(end_entity)) {
+            return Ok(tokio_rustls::rustls::client::danger::ServerCertVerified::assertion());
+        }
+        Err(tokio_rustls::rustls::Error::General(format!(
+            "certificate {} is not pinned",
+            hex::encode(crate::utils::encrypt::compute_sha256(end_entity))
+        )))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &tokio_rustls::rustls::pki_types::CertificateDer<'_>,
+        dss: &tokio_rustls::rustls::DigitallySignedStruct,
+    ) -> Result<
+        tokio_rustls::rustls::client::danger::HandshakeSignatureValid,
+        tokio_rustls::rustls::Error,
+    > {
+        tokio_rustls::rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &tokio_rustls::rustls::pki_types::CertificateDer<'_>,
+        dss: &tokio_rustls::rustls::DigitallySignedStruct,
+    ) -> Result<
+        tokio_rustls::rustls::client::danger::HandshakeSignatureValid,
+        tokio_rustls::rustls::Error,
+    > {
+        tokio_rustls::rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<tokio_rustls::rustls::SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Builds a TLS connector that only trusts the pinned certificates.
+pub fn pinned_tls_connector(
+    pins: Vec<CertPin>,
+) -> Result<tokio_rustls::TlsConnector, Box<dyn std::error::Error + Send + Sync>> {
+    use std::sync::Arc;
+    use tokio_rustls::rustls;
+    let provider = rustls::crypto::CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+    let verifier = PinnedCertVerifier {
+        pins,
+        algorithms: provider.signature_verification_algorithms,
+    };
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(tokio_rustls::TlsConnector::from(Arc::new(config)))
+}
@@ -336,6 +336,28 @@ pub fn generate_unique_filepath(path: impl AsRef<std::path::Path>) -> std::io::R
     ))
 }
 
+/// Replaces a leading `~` with the home directory
+pub fn expand_home_dir(path: &str) -> std::path::PathBuf {
+    if let Some(rest) = path.strip_prefix('~')
+        && (rest.is_empty() || rest.starts_with(['/', '\\']))
+        && let Some(home) = dirs::home_dir()
+    {
+        return home.join(rest.trim_start_matches(['/', '\\']));
+    }
+    std::path::PathBuf::from(path)
+}
+
+/// The path of `path` relative to `root`, separated by `/`
+pub fn relative_slash_path(root: &std::path::Path, path: &std::path::Path) -> Option<String> {
+    let rel = path.strip_prefix(root).ok()?;
+    Some(
+        rel.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+    )
+}
+
 pub trait ToFloat64 {
     fn to_f64(&self) -> Option<f64>;
 }
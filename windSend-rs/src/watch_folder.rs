@@ -0,0 +1,635 @@
+//! Watched folders whose new files are sent to a remote server automatically.
+//!
+//! New files are detected with filesystem notifications, or by polling when
+//! notifications are unavailable. A file is queued once its size and
+//! modification time have not changed for `stableSecs`. The queue is persisted
+//! so that pending files survive a restart, and failed uploads are retried
+//! with an increasing delay.
+
+use crate::route::client::{ClientError, RouteClient, UploadItem};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+fn default_stable_secs() -> u64 {
+    3
+}
+
+fn default_poll_interval_secs() -> u64 {
+    10
+}
+
+fn default_max_retries() -> u32 {
+    10
+}
+
+/// What happens to a local file after it has been sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AfterSend {
+    #[default]
+    #[serde(rename = "keep")]
+    Keep,
+    #[serde(rename = "delete")]
+    Delete,
+    /// Move into `moveTo`, keeping the relative path
+    #[serde(rename = "move")]
+    Move,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchFolder {
+    pub name: String,
+    /// The watched directory, a leading `~` is expanded
+    pub dir: String,
+    /// Name of an entry in `remoteServers`
+    #[serde(rename = "remoteServer")]
+    pub remote_server: String,
+    /// Prefix of the remote save path, the remote save path rules still apply
+    #[serde(rename = "remoteDir", default)]
+    pub remote_dir: String,
+    /// Seconds a file must stay unchanged before it is sent
+    #[serde(rename = "stableSecs", default = "default_stable_secs")]
+    pub stable_secs: u64,
+    /// Scan interval when filesystem notifications are unavailable
+    #[serde(rename = "pollIntervalSecs", default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    /// Always poll, e.g. for network shares that do not deliver notifications
+    #[serde(rename = "forcePolling", default)]
+    pub force_polling: bool,
+    /// Give up on a file after this many failed attempts, 0 retries forever
+    #[serde(rename = "maxRetries", default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(rename = "afterSend", default)]
+    pub after_send: AfterSend,
+    /// Target directory of `AfterSend::Move`, must not be inside `dir`
+    #[serde(rename = "moveTo", default, skip_serializing_if = "Option::is_none")]
+    pub move_to: Option<String>,
+    #[serde(default = "crate::config::default_enabled")]
+    pub enabled: bool,
+}
+
+impl WatchFolder {
+    fn check(&self) -> Result<(), String> {
+        if self.after_send != AfterSend::Move {
+            return Ok(());
+        }
+        let move_to = match &self.move_to {
+            Some(move_to) if !move_to.is_empty() => crate::utils::expand_home_dir(move_to),
+            _ => return Err("afterSend is move but moveTo is empty".to_string()),
+        };
+        if move_to.starts_with(crate::utils::expand_home_dir(&self.dir)) {
+            return Err("moveTo must not be inside the watched dir".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    size: u64,
+    /// Unix milliseconds
+    #[serde(rename = "modTime")]
+    mod_time: i64,
+}
+
+impl FileStamp {
+    fn of(metadata: &std::fs::Metadata) -> Self {
+        let mod_time = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+        Self {
+            size: metadata.len(),
+            mod_time,
+        }
+    }
+
+    fn read(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        metadata.is_file().then(|| Self::of(&metadata))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QueuedFile {
+    folder: String,
+    path: PathBuf,
+    stamp: FileStamp,
+    attempts: u32,
+    /// Unix milliseconds
+    #[serde(rename = "nextAttempt")]
+    next_attempt: i64,
+}
+
+/// Persisted in `watch_queue.json` next to the config file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueState {
+    queue: Vec<QueuedFile>,
+    /// Files kept after sending, so they are not sent again unless they change
+    sent: HashMap<PathBuf, FileStamp>,
+}
+
+impl QueueState {
+    fn is_known(&self, path: &Path, stamp: FileStamp) -> bool {
+        self.sent.get(path) == Some(&stamp) || self.queue.iter().any(|q| q.path == path)
+    }
+}
+
+static QUEUE_FILE_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
+    crate::config::CONFIG_FILE_PATH
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join("watch_queue.json")
+});
+
+static QUEUE_STATE: LazyLock<Mutex<QueueState>> = LazyLock::new(|| {
+    let state = std::fs::read(&*QUEUE_FILE_PATH)
+        .ok()
+        .and_then(|data| {
+            serde_json::from_slice(&data)
+                .inspect_err(|e| error!("parse {} error: {}", QUEUE_FILE_PATH.display(), e))
+                .ok()
+        })
+        .unwrap_or_default();
+    Mutex::new(state)
+});
+
+/// Incremented for every saved state, while `QUEUE_STATE` is locked
+static QUEUE_GENERATION: AtomicU64 = AtomicU64::new(0);
+/// Generation of the state in `watch_queue.json`
+static SAVED_GENERATION: Mutex<u64> = Mutex::new(0);
+
+/// Writes `state` to disk in the background, called with `QUEUE_STATE` locked.
+fn save_queue_state(state: &QueueState) {
+    let data = match serde_json::to_vec(state) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("serialize watch queue error: {}", e);
+            return;
+        }
+    };
+    let generation = QUEUE_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    crate::RUNTIME.spawn_blocking(move || {
+        let mut saved = SAVED_GENERATION.lock().unwrap();
+        if *saved > generation {
+            // A newer state is on disk already.
+            return;
+        }
+        let tmp_path = QUEUE_FILE_PATH.with_extension("json.tmp");
+        let r = std::fs::write(&tmp_path, data)
+            .and_then(|_| std::fs::rename(&tmp_path, &*QUEUE_FILE_PATH));
+        match r {
+            Ok(()) => *saved = generation,
+            Err(e) => error!("save {} error: {}", QUEUE_FILE_PATH.display(), e),
+        }
+    });
+}
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Spawns a task for every enabled watched folder, only the first call has an effect.
+pub fn start() {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let folders = crate::config::read_config().watch_folders.clone();
+    {
+        let mut state = QUEUE_STATE.lock().unwrap();
+        let before = state.queue.len();
+        state
+            .queue
+            .retain(|q| folders.iter().any(|f| f.name == q.folder));
+        if state.queue.len() != before {
+            warn!(
+                "dropped {} queued files of removed watch folders",
+                before - state.queue.len()
+            );
+            save_queue_state(&state);
+        }
+    }
+    for folder in folders.into_iter().filter(|f| f.enabled) {
+        if let Err(e) = folder.check() {
+            error!("watch folder {} is invalid: {}", folder.name, e);
+            continue;
+        }
+        info!("start watch folder: {}", folder.name);
+        crate::RUNTIME.spawn(run_folder(folder));
+    }
+}
+
+/// Files that were seen but have not been stable long enough yet
+#[derive(Debug, Default)]
+struct StabilityTracker {
+    pending: HashMap<PathBuf, (FileStamp, Instant)>,
+}
+
+impl StabilityTracker {
+    fn observe(&mut self, path: PathBuf, stamp: FileStamp, now: Instant) {
+        match self.pending.get_mut(&path) {
+            Some(entry) if entry.0 == stamp => {}
+            Some(entry) => *entry = (stamp, now),
+            None => {
+                self.pending.insert(path, (stamp, now));
+            }
+        }
+    }
+
+    /// Re-reads the pending files and returns those unchanged for `stable`.
+    fn take_stable(
+        &mut self,
+        now: Instant,
+        stable: Duration,
+        read_stamp: impl Fn(&Path) -> Option<FileStamp>,
+    ) -> Vec<(PathBuf, FileStamp)> {
+        let mut stable_files = Vec::new();
+        self.pending.retain(|path, (stamp, since)| {
+            let Some(current) = read_stamp(path) else {
+                return false;
+            };
+            if current != *stamp {
+                *stamp = current;
+                *since = now;
+                return true;
+            }
+            if now.duration_since(*since) >= stable {
+                stable_files.push((path.clone(), current));
+                return false;
+            }
+            true
+        });
+        stable_files
+    }
+}
+
+/// Partially written downloads and hidden files are never sent.
+fn is_ignored(path: &Path) -> bool {
+    const PARTIAL_EXTS: &[&str] = &["tmp", "part", "crdownload", "download", "partial"];
+    let Some(name) = path.file_name().map(|n| n.to_string_lossy()) else {
+        return true;
+    };
+    if name.starts_with('.') || name.starts_with("~$") {
+        return true;
+    }
+    path.extension()
+        .is_some_and(|ext| PARTIAL_EXTS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+}
+
+fn start_watcher(
+    dir: &Path,
+    tx: tokio::sync::mpsc::UnboundedSender<PathBuf>,
+) -> notify::Result<notify::RecommendedWatcher> {
+    use notify::{EventKind, RecursiveMode, Watcher};
+    let mut watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        let _ = tx.send(path);
+                    }
+                }
+            }
+            Err(e) => warn!("watch error: {}", e),
+        })?;
+    watcher.watch(dir, RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+async fn run_folder(folder: WatchFolder) {
+    let dir = crate::utils::expand_home_dir(&folder.dir);
+    if let Err(e) = tokio::fs::create_dir_all(&dir).await {
+        error!("create watch folder {} error: {}", dir.display(), e);
+        return;
+    }
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let watcher = if folder.force_polling {
+        None
+    } else {
+        start_watcher(&dir, tx)
+            .inspect_err(|e| warn!("watch {} error: {}, fall back to polling", dir.display(), e))
+            .ok()
+    };
+    let polling = watcher.is_none();
+    let poll_interval = Duration::from_secs(folder.poll_interval_secs.max(1));
+    let stable = Duration::from_secs(folder.stable_secs);
+
+    let mut tracker = StabilityTracker::default();
+    // Files that appeared while the program was not running.
+    scan(&dir, &mut tracker).await;
+    let mut last_scan = Instant::now();
+    let mut delivery: Option<tokio::task::JoinHandle<()>> = None;
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            Some(path) = rx.recv() => observe_path(path, &mut tracker).await,
+            _ = tick.tick() => {
+                if polling && last_scan.elapsed() >= poll_interval {
+                    scan(&dir, &mut tracker).await;
+                    last_scan = Instant::now();
+                }
+                let stable_files = tracker.take_stable(Instant::now(), stable, FileStamp::read);
+                enqueue(&folder, stable_files);
+                // Uploads must not hold up the events, one delivery at a time.
+                if delivery.as_ref().is_none_or(|task| task.is_finished()) {
+                    let (folder, dir) = (folder.clone(), dir.clone());
+                    delivery = Some(crate::RUNTIME.spawn(async move {
+                        deliver_due(&folder, &dir).await;
+                    }));
+                }
+            }
+        }
+    }
+}
+
+async fn observe_path(path: PathBuf, tracker: &mut StabilityTracker) {
+    if is_ignored(&path) {
+        return;
+    }
+    let Ok(metadata) = tokio::fs::metadata(&path).await else {
+        return;
+    };
+    if metadata.is_dir() {
+        // A folder moved in at once only reports the folder itself.
+        scan(&path, tracker).await;
+        return;
+    }
+    let stamp = FileStamp::of(&metadata);
+    if !QUEUE_STATE.lock().unwrap().is_known(&path, stamp) {
+        tracker.observe(path, stamp, Instant::now());
+    }
+}
+
+async fn scan(dir: &Path, tracker: &mut StabilityTracker) {
+    let root = dir.to_path_buf();
+    let files = tokio::task::spawn_blocking(move || {
+        walkdir::WalkDir::new(root)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file() && !is_ignored(entry.path()))
+            .filter_map(|entry| {
+                let stamp = FileStamp::of(&entry.metadata().ok()?);
+                Some((entry.into_path(), stamp))
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+    let now = Instant::now();
+    let mut state = QUEUE_STATE.lock().unwrap();
+    for (path, stamp) in files {
+        if !state.is_known(&path, stamp) {
+            tracker.observe(path, stamp, now);
+        }
+    }
+    // Forget kept files that have since been removed by the user.
+    if dir.exists() {
+        let before = state.sent.len();
+        state
+            .sent
+            .retain(|path, _| !path.starts_with(dir) || path.exists());
+        if state.sent.len() != before {
+            save_queue_state(&state);
+        }
+    }
+}
+
+fn enqueue(folder: &WatchFolder, files: Vec<(PathBuf, FileStamp)>) {
+    if files.is_empty() {
+        return;
+    }
+    let mut state = QUEUE_STATE.lock().unwrap();
+    for (path, stamp) in files {
+        if state.is_known(&path, stamp) {
+            continue;
+        }
+        debug!("queue {} of watch folder {}", path.display(), folder.name);
+        state.queue.push(QueuedFile {
+            folder: folder.name.clone(),
+            path,
+            stamp,
+            attempts: 0,
+            next_attempt: 0,
+        });
+    }
+    save_queue_state(&state);
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    const MAX_DELAY: Duration = Duration::from_secs(60 * 30);
+    let delay = Duration::from_secs(5).saturating_mul(1 << attempts.min(16));
+    delay.min(MAX_DELAY)
+}
+
+async fn deliver_due(folder: &WatchFolder, dir: &Path) {
+    let now = chrono::Utc::now().timestamp_millis();
+    let due: Vec<QueuedFile> = QUEUE_STATE
+        .lock()
+        .unwrap()
+        .queue
+        .iter()
+        .filter(|q| q.folder == folder.name && q.next_attempt <= now)
+        .cloned()
+        .collect();
+    if due.is_empty() {
+        return;
+    }
+    let mut client = match connect(folder).await {
+        Ok(client) => Some(client),
+        Err(e) => {
+            warn!("watch folder {}: connect failed: {}", folder.name, e);
+            None
+        }
+    };
+    let remote_dir = normalize_remote_dir(&folder.remote_dir);
+    for file in due {
+        let result = match client.as_mut() {
+            Some(client) => send_file(client, &remote_dir, dir, &file).await,
+            None => Err(ClientError::Config("not connected".to_string())),
+        };
+        match result {
+            Ok(()) => {
+                info!("watch folder {}: sent {}", folder.name, file.path.display());
+                finish_sent(folder, dir, &file).await;
+            }
+            Err(e) => {
+                warn!(
+                    "watch folder {}: send {} failed: {}",
+                    folder.name,
+                    file.path.display(),
+                    e
+                );
+                record_failure(folder, &file, &e);
+                // The connection is in an unknown state after an error.
+                if let Some(client) = client.take() {
+                    client.close().await;
+                }
+            }
+        }
+    }
+    if let Some(client) = client {
+        client.close().await;
+    }
+}
+
+async fn connect(folder: &WatchFolder) -> Result<RouteClient, ClientError> {
+    let server = crate::config::read_config()
+        .find_remote_server(&folder.remote_server)
+        .cloned()
+        .ok_or_else(|| {
+            ClientError::Config(format!("remote server {} not found", folder.remote_server))
+        })?;
+    RouteClient::connect(&server).await
+}
+
+async fn send_file(
+    client: &mut RouteClient,
+    remote_dir: &str,
+    dir: &Path,
+    file: &QueuedFile,
+) -> Result<(), ClientError> {
+    let rel = crate::utils::relative_slash_path(dir, &file.path)
+        .ok_or_else(|| ClientError::Config(format!("{} is outside", file.path.display())))?;
+    let item = UploadItem {
+        local_path: &file.path,
+        remote_path: remote_path(remote_dir, &rel),
+        size: file.stamp.size as i64,
+    };
+    client.upload_files_with_policy(&[item], None, false).await
+}
+
+async fn finish_sent(folder: &WatchFolder, dir: &Path, file: &QueuedFile) {
+    let kept = match folder.after_send {
+        AfterSend::Keep => true,
+        AfterSend::Delete => {
+            if let Err(e) = tokio::fs::remove_file(&file.path).await {
+                error!("delete sent file {} error: {}", file.path.display(), e);
+            }
+            false
+        }
+        AfterSend::Move => {
+            let move_to = crate::utils::expand_home_dir(folder.move_to.as_deref().unwrap_or(""));
+            match move_sent_file(dir, &file.path, &move_to).await {
+                Ok(()) => false,
+                Err(e) => {
+                    error!("move sent file {} error: {}", file.path.display(), e);
+                    true
+                }
+            }
+        }
+    };
+    let mut state = QUEUE_STATE.lock().unwrap();
+    state.queue.retain(|q| q.path != file.path);
+    if kept {
+        state.sent.insert(file.path.clone(), file.stamp);
+    }
+    save_queue_state(&state);
+}
+
+async fn move_sent_file(dir: &Path, path: &Path, move_to: &Path) -> std::io::Result<()> {
+    let rel = path.strip_prefix(dir).map_err(std::io::Error::other)?;
+    let target = move_to.join(rel);
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let target = crate::utils::generate_unique_filepath(&target)?;
+    if tokio::fs::rename(path, &target).await.is_err() {
+        // Renaming fails across file systems.
+        tokio::fs::copy(path, &target).await?;
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(())
+}
+
+fn record_failure(folder: &WatchFolder, file: &QueuedFile, err: &ClientError) {
+    let mut state = QUEUE_STATE.lock().unwrap();
+    let Some(queued) = state.queue.iter_mut().find(|q| q.path == file.path) else {
+        return;
+    };
+    queued.attempts += 1;
+    if folder.max_retries != 0 && queued.attempts >= folder.max_retries {
+        error!(
+            "watch folder {}: give up {} after {} attempts",
+            folder.name,
+            file.path.display(),
+            queued.attempts
+        );
+        crate::utils::inform(
+            format!("{}: {}", file.path.display(), err),
+            &folder.name,
+            None,
+        );
+        state.queue.retain(|q| q.path != file.path);
+    } else {
+        queued.next_attempt =
+            chrono::Utc::now().timestamp_millis() + retry_delay(queued.attempts).as_millis() as i64;
+    }
+    save_queue_state(&state);
+}
+
+/// Trims the separators of a configured remote directory and uses `/` only
+fn normalize_remote_dir(remote_dir: &str) -> String {
+    remote_dir.trim_matches(['/', '\\']).replace('\\', "/")
+}
+
+/// Joins a relative path onto a normalized remote directory
+fn remote_path(remote_dir: &str, rel: &str) -> String {
+    if remote_dir.is_empty() {
+        rel.to_string()
+    } else {
+        format!("{remote_dir}/{rel}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_stable_after_unchanged_period() {
+        let start = Instant::now();
+        let stable = Duration::from_secs(3);
+        let stamp = |size| FileStamp { size, mod_time: 1 };
+        let sizes: std::cell::RefCell<HashMap<PathBuf, u64>> = Default::default();
+        let read = |path: &Path| sizes.borrow().get(path).map(|size| stamp(*size));
+
+        let mut tracker = StabilityTracker::default();
+        sizes.borrow_mut().insert(PathBuf::from("a"), 1);
+        sizes.borrow_mut().insert(PathBuf::from("b"), 1);
+        tracker.observe(PathBuf::from("a"), stamp(1), start);
+        tracker.observe(PathBuf::from("b"), stamp(1), start);
+        tracker.observe(PathBuf::from("gone"), stamp(1), start);
+
+        // "b" is still growing
+        sizes.borrow_mut().insert(PathBuf::from("b"), 2);
+        let ready = tracker.take_stable(start + Duration::from_secs(1), stable, read);
+        assert!(ready.is_empty());
+
+        let ready = tracker.take_stable(start + Duration::from_secs(3), stable, read);
+        assert_eq!(ready, vec![(PathBuf::from("a"), stamp(1))]);
+
+        let ready = tracker.take_stable(start + Duration::from_secs(4), stable, read);
+        assert_eq!(ready, vec![(PathBuf::from("b"), stamp(2))]);
+        assert!(tracker.pending.is_empty());
+    }
+
+    #[test]
+    fn ignores_partial_and_hidden_files() {
+        assert!(is_ignored(Path::new("/out/.DS_Store")));
+        assert!(is_ignored(Path::new("/out/movie.mkv.crdownload")));
+        assert!(is_ignored(Path::new("/out/~$report.docx")));
+        assert!(!is_ignored(Path::new("/out/report.docx")));
+    }
+
+    #[test]
+    fn retry_delay_grows_and_is_capped() {
+        assert_eq!(retry_delay(0), Duration::from_secs(5));
+        assert_eq!(retry_delay(1), Duration::from_secs(10));
+        assert_eq!(retry_delay(30), Duration::from_secs(60 * 30));
+    }
+}
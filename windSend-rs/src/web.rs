@@ -27,8 +27,19 @@ fn init_my_url() -> String {
     my_url
 }
 
+/// A client builder that picks the proxy like the other outbound connections, see `utils::proxy`.
+fn client_builder() -> Result<reqwest::ClientBuilder, Box<dyn std::error::Error>> {
+    let outbound = crate::utils::proxy::outbound()?;
+    // A custom proxy also keeps reqwest from reading the environment itself.
+    Ok(
+        reqwest::ClientBuilder::new().proxy(reqwest::Proxy::custom(move |url| {
+            outbound.proxy_for(url.host_str()?).map(|proxy| proxy.url())
+        })),
+    )
+}
+
 pub async fn get_content_from_web() -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+    let client = client_builder()?.build()?;
     let resp = client
         .get(&*MY_URL)
         .header("User-Agent", USER_AGENT)
@@ -46,10 +57,7 @@ pub async fn get_content_from_web() -> Result<Vec<u8>, Box<dyn std::error::Error
 pub async fn post_content_to_web(context: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
     let encrypted_data = crate::config::get_cipher()?.encrypt(context, "".as_bytes())?;
     let encrypted_data_hex = hex::encode(&encrypted_data);
-    let client = reqwest::ClientBuilder::new()
-        .cookie_store(true)
-        .build()
-        .unwrap();
+    let client = client_builder()?.cookie_store(true).build()?;
     let csrfmiddlewaretoken = get_post_csrfmiddlewaretoken(&client).await?;
     let mut payload = reqwest::multipart::Form::new();
     debug!("csrfmiddlewaretoken: {}", csrfmiddlewaretoken);